@@ -1,11 +1,10 @@
 //! Mqtt over WebSockets
 use std::io;
 
-use ntex::http::{body, h1, HttpService, Request, Response, ResponseError};
+use ntex::http::{h1, HttpService, Request, Response};
 use ntex::io::{Filter, Io};
 use ntex::service::{pipeline_factory, ServiceFactory};
 use ntex::util::{variant, Ready};
-use ntex::ws;
 use ntex_mqtt::{v3, v5, MqttError, MqttServer};
 use ntex_tls::openssl::Acceptor;
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
@@ -120,50 +119,12 @@ async fn main() -> std::io::Result<()> {
                             // websocket handler, we need to verify websocket handshake
                             // and then switch to websokets streaming
                             .upgrade(
-                                // validate ws request and init ws transport
+                                // validate ws request, negotiate `mqtt` subprotocol
+                                // and init ws transport, ws transport converts incoming
+                                // stream of ws frames into bytes stream
                                 pipeline_factory(
                                     |(req, io, codec): (Request, Io<_>, h1::Codec)| {
-                                        async move {
-                                            match ws::handshake(req.head()) {
-                                                // invalid websockets handshake request
-                                                Err(e) => {
-                                                    // send http handshake respone
-                                                    io.send(
-                                                        h1::Message::Item((
-                                                            e.error_response().drop_body(),
-                                                            body::BodySize::None,
-                                                        )),
-                                                        &codec,
-                                                    )
-                                                    .await?;
-                                                    return Err(MqttError::ServerError(
-                                                        "WebSockets handshake error",
-                                                    ));
-                                                }
-                                                Ok(mut res) => {
-                                                    // send http handshake respone
-                                                    io.encode(
-                                                        h1::Message::Item((
-                                                            res.finish().drop_body(),
-                                                            body::BodySize::None,
-                                                        )),
-                                                        &codec,
-                                                    )?;
-                                                }
-                                            }
-
-                                            // enable websocket streamimng, ws transport
-                                            // converts incoming stream of ws frames into bytes stream
-                                            io.add_filter(ws::WsTransportFactory::new(
-                                                ws::Codec::default(),
-                                            ))
-                                            .await
-                                            .map_err(|_| {
-                                                MqttError::ServerError(
-                                                    "Cannot construct ws transport",
-                                                )
-                                            })
-                                        }
+                                        ntex_mqtt::ws::accept(req, io, codec)
                                     },
                                 )
                                 // and then start mqtt server
@@ -97,6 +97,7 @@ fn control_service_factory() -> impl ServiceFactory<
             v5::ControlMessage::Unsubscribe(s) => Ready::Ok(s.ack()),
             v5::ControlMessage::Closed(c) => Ready::Ok(c.ack()),
             v5::ControlMessage::PeerGone(c) => Ready::Ok(c.ack()),
+            v5::ControlMessage::ServerDisconnecting(d) => Ready::Ok(d.ack()),
         }))
     })
 }
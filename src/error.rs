@@ -30,6 +30,10 @@ pub enum ProtocolError {
     /// Mqtt parse error
     #[display(fmt = "Decode error: {:?}", _0)]
     Decode(DecodeError),
+    /// Mqtt parse error with hex dump of the beginning of malformed packet
+    #[display(fmt = "Decode error: {:?}, packet: {}", _0, _1)]
+    #[from(ignore)]
+    DecodeInput(DecodeError, String),
     /// Mqtt encode error
     #[display(fmt = "Encode error: {:?}", _0)]
     Encode(EncodeError),
@@ -51,6 +55,21 @@ pub enum ProtocolError {
     /// Keep alive timeout
     #[display(fmt = "Keep alive timeout")]
     KeepAliveTimeout,
+    /// Topic name of publish packet contains wildcard characters
+    #[display(fmt = "Topic name contains wildcard characters")]
+    TopicNameInvalid,
+}
+
+impl ProtocolError {
+    /// Hex dump of the beginning of malformed packet
+    ///
+    /// Packet bytes are captured if decode error capture is enabled.
+    pub fn decode_input(&self) -> Option<&str> {
+        match self {
+            ProtocolError::DecodeInput(_, dump) => Some(dump),
+            _ => None,
+        }
+    }
 }
 
 impl error::Error for ProtocolError {}
@@ -105,6 +124,9 @@ pub enum DecodeError {
     PacketIdRequired,
     MaxSizeExceeded,
     Utf8Error,
+    // MQTT v5 only
+    EmptyTopic,
+    InvalidTopicAlias,
 }
 
 impl error::Error for DecodeError {}
@@ -135,6 +157,8 @@ impl PartialEq for DecodeError {
             (DecodeError::MaxSizeExceeded, DecodeError::MaxSizeExceeded) => true,
             (DecodeError::MalformedPacket, DecodeError::MalformedPacket) => true,
             (DecodeError::Utf8Error, DecodeError::Utf8Error) => true,
+            (DecodeError::EmptyTopic, DecodeError::EmptyTopic) => true,
+            (DecodeError::InvalidTopicAlias, DecodeError::InvalidTopicAlias) => true,
             _ => false,
         }
     }
@@ -10,6 +10,8 @@ use ntex::service::{IntoService, Service};
 use ntex::time::Seconds;
 use ntex::util::{ready, Pool};
 
+use crate::time::TimeSource;
+
 type Response<U> = <U as Encoder>::Item;
 
 pin_project_lite::pin_project! {
@@ -30,6 +32,7 @@ pin_project_lite::pin_project! {
         st: IoDispatcherState,
         flags: Cell<Flags>,
         pool: Pool,
+        flush: Option<Rc<dyn Fn() -> bool>>,
         #[pin]
         response: Option<S::Future>,
         response_idx: usize,
@@ -46,6 +49,9 @@ bitflags::bitflags! {
 struct DispatcherInner {
     io: IoBoxed,
     keepalive_timeout: Cell<time::Duration>,
+    time: Option<Rc<dyn TimeSource>>,
+    ka_expire: Cell<time::Instant>,
+    ka_timer: RefCell<Option<Pin<Box<dyn Future<Output = ()>>>>>,
 }
 
 struct DispatcherState<S: Service<DispatchItem<U>>, U: Encoder + Decoder> {
@@ -127,7 +133,8 @@ where
             response: None,
             response_idx: 0,
             flags: Cell::new(Flags::empty()),
-            inner: DispatcherInner { io, keepalive_timeout },
+            flush: None,
+            inner: DispatcherInner::new(io, keepalive_timeout),
         }
     }
 
@@ -137,9 +144,29 @@ where
     ///
     /// By default keep-alive timeout is set to 30 seconds.
     pub(crate) fn keepalive_timeout(self, timeout: Seconds) -> Self {
-        let timeout = timeout.into();
-        self.inner.io.start_keepalive_timer(timeout);
-        self.inner.keepalive_timeout.set(timeout);
+        self.inner.keepalive_timeout.set(timeout.into());
+        self.inner.update_keepalive();
+        self
+    }
+
+    /// Set time source for keep-alive timer.
+    ///
+    /// By default io keep-alive timer is used.
+    pub(crate) fn time_source(mut self, time: Option<Rc<dyn TimeSource>>) -> Self {
+        if time.is_some() {
+            self.inner.io.remove_keepalive_timer();
+            self.inner.time = time;
+            self.inner.update_keepalive();
+        }
+        self
+    }
+
+    /// Set write buffer flush check.
+    ///
+    /// If check returns `true`, dispatcher is woken up when buffered data is written.
+    /// By default dispatcher is not woken up on write buffer flush.
+    pub(crate) fn flush_wakeup(mut self, check: Rc<dyn Fn() -> bool>) -> Self {
+        self.flush = Some(check);
         self
     }
 
@@ -158,16 +185,64 @@ where
 }
 
 impl DispatcherInner {
+    fn new(io: IoBoxed, keepalive_timeout: Cell<time::Duration>) -> Self {
+        DispatcherInner {
+            io,
+            keepalive_timeout,
+            time: None,
+            ka_expire: Cell::new(time::Instant::now()),
+            ka_timer: RefCell::new(None),
+        }
+    }
+
     fn update_keepalive(&self) {
-        // update keep-alive timer
-        self.io.start_keepalive_timer(self.keepalive_timeout.get());
+        let timeout = self.keepalive_timeout.get();
+
+        if let Some(ref time) = self.time {
+            // update keep-alive expire time, timer get re-armed on wake up
+            if timeout == time::Duration::ZERO {
+                *self.ka_timer.borrow_mut() = None;
+            } else {
+                self.ka_expire.set(time.now() + timeout);
+                let mut timer = self.ka_timer.borrow_mut();
+                if timer.is_none() {
+                    *timer = Some(time.sleep(timeout));
+                }
+            }
+        } else {
+            // update keep-alive timer
+            self.io.start_keepalive_timer(timeout);
+        }
     }
 
     fn unregister_keepalive(&self) {
         // unregister keep-alive timer
-        self.io.remove_keepalive_timer();
+        if self.time.is_some() {
+            *self.ka_timer.borrow_mut() = None;
+        } else {
+            self.io.remove_keepalive_timer();
+        }
         self.keepalive_timeout.set(time::Duration::ZERO);
     }
+
+    /// Check keep-alive timer of custom time source
+    fn poll_keepalive(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(ref time) = self.time {
+            let mut timer = self.ka_timer.borrow_mut();
+            while let Some(ref mut fut) = *timer {
+                ready!(fut.as_mut().poll(cx));
+
+                let now = time.now();
+                let expire = self.ka_expire.get();
+                if now >= expire {
+                    *timer = None;
+                    return Poll::Ready(());
+                }
+                *timer = Some(time.sleep(expire - now));
+            }
+        }
+        Poll::Pending
+    }
 }
 
 impl<S, U> DispatcherState<S, U>
@@ -269,10 +344,26 @@ where
             match this.st {
                 IoDispatcherState::Processing => {
                     // println!("IO-DISP state :{:?}:", io.flags());
-                    match this.service.poll_ready(cx) {
+                    let ready = this.service.poll_ready(cx);
+
+                    // service could wait for write buffer flush,
+                    // wake up dispatcher when buffered data is written
+                    if let Some(ref check) = this.flush {
+                        if check() && io.with_write_buf(|buf| !buf.is_empty()).unwrap_or(false)
+                        {
+                            let _ = io.poll_flush(cx, true);
+                        }
+                    }
+
+                    match ready {
                         Poll::Ready(Ok(_)) => {
                             // decode incoming bytes stream
-                            let item = match ready!(io.poll_recv(this.codec, cx)) {
+                            let result = if this.inner.poll_keepalive(cx).is_ready() {
+                                Err(RecvError::KeepAlive)
+                            } else {
+                                ready!(io.poll_recv(this.codec, cx))
+                            };
+                            let item = match result {
                                 Ok(el) => {
                                     // update keep-alive timer
                                     this.inner.update_keepalive();
@@ -448,6 +539,18 @@ where
     }
 }
 
+/// Tls session check of connection
+pub(crate) type TlsCheck = Rc<dyn Fn(&IoBoxed) -> bool>;
+
+/// Check if connection has established tls session
+///
+/// Tls filters of `ntex-tls` (openssl and rustls) answer protocol query for
+/// every established session, with or without ALPN and client certificates.
+/// Plain tcp and in-memory streams do not answer it.
+pub(crate) fn is_tls(io: &IoBoxed) -> bool {
+    io.query::<ntex::io::types::HttpProtocol>().get().is_some()
+}
+
 #[cfg(test)]
 mod tests {
     use std::cell::Cell;
@@ -496,7 +599,7 @@ mod tests {
                     response_idx: 0,
                     pool: io.memory_pool().pool(),
                     flags: Cell::new(Flags::empty()),
-                    inner: DispatcherInner { keepalive_timeout, io: IoBoxed::from(io) },
+                    inner: DispatcherInner::new(IoBoxed::from(io), keepalive_timeout),
                 },
                 rio,
             )
@@ -9,20 +9,29 @@ mod topic;
 mod utils;
 
 pub mod error;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod v3;
 pub mod v5;
+pub mod ws;
 
 mod io;
+mod publish;
 mod server;
 mod service;
 mod session;
+mod time;
 pub mod types;
 mod version;
 
 pub use self::error::MqttError;
+pub use self::publish::{any_publish, AnyPublish, AnyPublishFactory, AnyPublishService};
 pub use self::server::MqttServer;
-pub use self::session::Session;
-pub use self::topic::{Level as TopicLevel, Topic};
+pub use self::service::ServerHandle;
+pub use self::session::{Negotiated, Session};
+pub use self::time::TimeSource;
+pub use self::topic::{Level as TopicLevel, SubscriptionTrie, Topic};
+pub use self::types::MqttVersion;
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
 pub const TCP_PORT: u16 = 1883;
@@ -0,0 +1,176 @@
+use std::task::{Context, Poll};
+use std::{future::Future, marker::PhantomData, pin::Pin};
+
+use ntex::router::Path;
+use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::util::{ByteString, Bytes};
+
+use crate::types::{MqttVersion, QoS};
+use crate::{v3, v5};
+
+/// Version-agnostic publish message
+///
+/// Exposes fields common to both protocol versions, so the same publish
+/// handler could be used by `v3::Router` and `v5::Router`.
+pub enum AnyPublish {
+    V3(v3::Publish),
+    V5(v5::Publish),
+}
+
+impl AnyPublish {
+    /// Protocol version of the publish packet
+    pub fn version(&self) -> MqttVersion {
+        match self {
+            AnyPublish::V3(_) => MqttVersion::V3,
+            AnyPublish::V5(_) => MqttVersion::V5,
+        }
+    }
+
+    #[inline]
+    /// the information channel to which payload data is published.
+    pub fn publish_topic(&self) -> &str {
+        match self {
+            AnyPublish::V3(p) => p.publish_topic(),
+            AnyPublish::V5(p) => p.publish_topic(),
+        }
+    }
+
+    #[inline]
+    /// matched topic with captured params
+    pub fn topic(&self) -> &Path<ByteString> {
+        match self {
+            AnyPublish::V3(p) => p.topic(),
+            AnyPublish::V5(p) => p.topic(),
+        }
+    }
+
+    #[inline]
+    /// the Application Message that is being published.
+    pub fn payload(&self) -> &Bytes {
+        match self {
+            AnyPublish::V3(p) => p.payload(),
+            AnyPublish::V5(p) => p.payload(),
+        }
+    }
+
+    #[inline]
+    /// the level of assurance for delivery of an Application Message.
+    pub fn qos(&self) -> QoS {
+        match self {
+            AnyPublish::V3(p) => p.qos(),
+            AnyPublish::V5(p) => p.qos(),
+        }
+    }
+
+    #[inline]
+    pub fn retain(&self) -> bool {
+        match self {
+            AnyPublish::V3(p) => p.retain(),
+            AnyPublish::V5(p) => p.retain(),
+        }
+    }
+}
+
+impl From<v3::Publish> for AnyPublish {
+    fn from(publish: v3::Publish) -> Self {
+        AnyPublish::V3(publish)
+    }
+}
+
+impl From<v5::Publish> for AnyPublish {
+    fn from(publish: v5::Publish) -> Self {
+        AnyPublish::V5(publish)
+    }
+}
+
+/// Adapt version-agnostic publish handler
+///
+/// Returned factory could be used as a resource of `v3::Router` and
+/// of `v5::Router`. For v5 publishes successful handler completion is
+/// acknowledged with `Success` reason code.
+pub fn any_publish<F, U, S>(service: F) -> AnyPublishFactory<U, S>
+where
+    F: IntoServiceFactory<U, AnyPublish, S>,
+    U: ServiceFactory<AnyPublish, S, Response = ()>,
+{
+    AnyPublishFactory { factory: service.into_factory(), _t: PhantomData }
+}
+
+/// Factory of version-agnostic publish handler, see `any_publish()`
+pub struct AnyPublishFactory<T, S> {
+    factory: T,
+    _t: PhantomData<S>,
+}
+
+impl<T, S, R> ServiceFactory<R, S> for AnyPublishFactory<T, S>
+where
+    T: ServiceFactory<AnyPublish, S, Response = ()>,
+    T::Future: 'static,
+    AnyPublishService<T::Service>: Service<R, Error = T::Error>,
+{
+    type Response = <AnyPublishService<T::Service> as Service<R>>::Response;
+    type Error = T::Error;
+    type InitError = T::InitError;
+    type Service = AnyPublishService<T::Service>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Service, T::InitError>>>>;
+
+    fn new_service(&self, session: S) -> Self::Future {
+        let fut = self.factory.new_service(session);
+        Box::pin(async move { Ok(AnyPublishService(fut.await?)) })
+    }
+}
+
+/// Version-agnostic publish handler service, see `any_publish()`
+pub struct AnyPublishService<T>(T);
+
+impl<T> Service<v3::Publish> for AnyPublishService<T>
+where
+    T: Service<AnyPublish, Response = ()>,
+{
+    type Response = ();
+    type Error = T::Error;
+    type Future = T::Future;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.0.poll_shutdown(cx, is_error)
+    }
+
+    #[inline]
+    fn call(&self, req: v3::Publish) -> Self::Future {
+        self.0.call(AnyPublish::V3(req))
+    }
+}
+
+impl<T> Service<v5::Publish> for AnyPublishService<T>
+where
+    T: Service<AnyPublish, Response = ()>,
+    T::Future: 'static,
+{
+    type Response = v5::PublishAck;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<v5::PublishAck, T::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.0.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: v5::Publish) -> Self::Future {
+        let fut = self.0.call(AnyPublish::V5(req));
+        Box::pin(async move {
+            fut.await?;
+            Ok(v5::PublishAck::new(v5::codec::PublishAckReason::Success))
+        })
+    }
+}
@@ -1,3 +1,4 @@
+use std::sync::{atomic::AtomicU16, atomic::Ordering, Arc};
 use std::task::{Context, Poll};
 use std::{fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc};
 
@@ -7,20 +8,65 @@ use ntex::service::{Service, ServiceFactory};
 use ntex::time::{Seconds, Sleep};
 use ntex::util::{select, Either};
 
-use crate::io::Dispatcher;
+use crate::{io::Dispatcher, time::TimeSource};
 
 type ResponseItem<U> = Option<<U as Encoder>::Item>;
 
+/// Handle for adjusting settings of running server
+///
+/// Handle could be shared by all server workers, updated settings
+/// are used by new connections.
+#[derive(Clone, Debug)]
+pub struct ServerHandle(Arc<AtomicU16>);
+
+impl ServerHandle {
+    /// Create new handle with provided disconnect timeout
+    pub fn new(disconnect_timeout: Seconds) -> Self {
+        ServerHandle(Arc::new(AtomicU16::new(disconnect_timeout.0)))
+    }
+
+    /// Disconnect timeout for new connections
+    pub fn disconnect_timeout(&self) -> Seconds {
+        Seconds(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Set disconnect timeout for new connections
+    pub fn set_disconnect_timeout(&self, timeout: Seconds) {
+        self.0.store(timeout.0, Ordering::Relaxed);
+    }
+}
+
 pub struct MqttServer<St, C, T, Codec> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: Seconds,
+    handle: Option<ServerHandle>,
+    time: Option<Rc<dyn TimeSource>>,
     _t: PhantomData<(St, Codec)>,
 }
 
 impl<St, C, T, Codec> MqttServer<St, C, T, Codec> {
     pub(crate) fn new(connect: C, service: T, disconnect_timeout: Seconds) -> Self {
-        MqttServer { connect, disconnect_timeout, handler: Rc::new(service), _t: PhantomData }
+        MqttServer {
+            connect,
+            disconnect_timeout,
+            handler: Rc::new(service),
+            handle: None,
+            time: None,
+            _t: PhantomData,
+        }
+    }
+
+    /// Set time source for keep-alive timer
+    pub(crate) fn time_source(mut self, time: Option<Rc<dyn TimeSource>>) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Set handle for runtime settings
+    pub(crate) fn server_handle(mut self, handle: Option<ServerHandle>) -> Self {
+        self.handle = handle;
+        self
     }
 }
 
@@ -34,12 +80,16 @@ where
         let fut = self.connect.new_service(());
         let handler = self.handler.clone();
         let disconnect_timeout = self.disconnect_timeout;
+        let handle = self.handle.clone();
+        let time = self.time.clone();
 
         // create connect service and then create service impl
         async move {
             Ok(MqttHandler {
                 handler,
                 disconnect_timeout,
+                handle,
+                time,
                 connect: fut.await?,
                 _t: PhantomData,
             })
@@ -127,9 +177,17 @@ pub struct MqttHandler<St, C, T, Codec> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: Seconds,
+    handle: Option<ServerHandle>,
+    time: Option<Rc<dyn TimeSource>>,
     _t: PhantomData<(St, Codec)>,
 }
 
+impl<St, C, T, Codec> MqttHandler<St, C, T, Codec> {
+    fn disconnect_timeout(&self) -> Seconds {
+        self.handle.as_ref().map(|h| h.disconnect_timeout()).unwrap_or(self.disconnect_timeout)
+    }
+}
+
 impl<St, C, T, Codec> Service<IoBoxed> for MqttHandler<St, C, T, Codec>
 where
     St: 'static,
@@ -161,7 +219,8 @@ where
     #[inline]
     fn call(&self, req: IoBoxed) -> Self::Future {
         let handler = self.handler.clone();
-        let timeout = self.disconnect_timeout;
+        let timeout = self.disconnect_timeout();
+        let time = self.time.clone();
         let handshake = self.connect.call(req);
 
         Box::pin(async move {
@@ -177,6 +236,7 @@ where
             Dispatcher::new(io, codec, handler)
                 .keepalive_timeout(keepalive)
                 .disconnect_timeout(timeout)
+                .time_source(time)
                 .await
         })
     }
@@ -248,7 +308,8 @@ where
     #[inline]
     fn call(&self, (io, delay): (IoBoxed, Option<Sleep>)) -> Self::Future {
         let handler = self.handler.clone();
-        let timeout = self.disconnect_timeout;
+        let timeout = self.disconnect_timeout();
+        let time = self.time.clone();
         let handshake = self.connect.call(io);
 
         Box::pin(async move {
@@ -292,7 +353,73 @@ where
             Dispatcher::new(io, codec, handler)
                 .keepalive_timeout(ka)
                 .disconnect_timeout(timeout)
+                .time_source(time)
                 .await
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::codec::BytesCodec;
+    use ntex::service::{fn_factory_with_config, fn_service};
+    use ntex::util::{Bytes, Ready};
+
+    use super::*;
+
+    #[test]
+    fn test_server_handle() {
+        let handle = ServerHandle::new(Seconds(3));
+        let handler = MqttHandler::<(), (), (), ()> {
+            connect: (),
+            handler: Rc::new(()),
+            disconnect_timeout: Seconds(5),
+            handle: Some(handle.clone()),
+            time: None,
+            _t: PhantomData,
+        };
+        assert_eq!(handler.disconnect_timeout(), Seconds(3));
+
+        // updated timeout is used for next connection
+        handle.set_disconnect_timeout(Seconds(1));
+        assert_eq!(handle.disconnect_timeout(), Seconds(1));
+        assert_eq!(handler.disconnect_timeout(), Seconds(1));
+
+        let handler = MqttHandler::<(), (), (), ()> { handle: None, ..handler };
+        assert_eq!(handler.disconnect_timeout(), Seconds(5));
+    }
+
+    #[ntex::test]
+    async fn test_server_handle_factory() {
+        let handle = ServerHandle::new(Seconds(5));
+        let server = MqttServer::<(), _, _, BytesCodec>::new(
+            fn_service(|io: IoBoxed| Ready::Ok::<_, ()>((io, BytesCodec, (), Seconds::ZERO))),
+            fn_factory_with_config(|_: ()| {
+                Ready::Ok::<_, ()>(fn_service(|_: DispatchItem<BytesCodec>| {
+                    Ready::Ok::<_, ()>(None::<Bytes>)
+                }))
+            }),
+            Seconds(3),
+        )
+        .server_handle(Some(handle.clone()));
+        let srv = ServiceFactory::<IoBoxed>::new_service(&server, ()).await.unwrap();
+        assert_eq!(srv.disconnect_timeout(), Seconds(5));
+
+        // dispatcher of next connection gets updated timeout
+        handle.set_disconnect_timeout(Seconds(1));
+        assert_eq!(srv.disconnect_timeout(), Seconds(1));
+
+        // server without handle
+        let server = MqttServer::<(), _, _, BytesCodec>::new(
+            fn_service(|io: IoBoxed| Ready::Ok::<_, ()>((io, BytesCodec, (), Seconds::ZERO))),
+            fn_factory_with_config(|_: ()| {
+                Ready::Ok::<_, ()>(fn_service(|_: DispatchItem<BytesCodec>| {
+                    Ready::Ok::<_, ()>(None::<Bytes>)
+                }))
+            }),
+            Seconds(3),
+        );
+        let srv = ServiceFactory::<IoBoxed>::new_service(&server, ()).await.unwrap();
+        assert_eq!(srv.disconnect_timeout(), Seconds(3));
+    }
+}
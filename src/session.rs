@@ -1,5 +1,9 @@
-use std::ops::Deref;
-use std::rc::Rc;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::{ops::Deref, rc::Rc};
+
+use ntex::{time::Seconds, util::Extensions};
+
+use crate::types::{MqttVersion, QoS};
 
 /// Mqtt connection session
 pub struct Session<T, St>(Rc<SessionInner<T, St>>);
@@ -7,8 +11,64 @@ pub struct Session<T, St>(Rc<SessionInner<T, St>>);
 struct SessionInner<T, St> {
     st: St,
     sink: T,
+    version: MqttVersion,
     max_receive: u16,
     max_topic_alias: u16,
+    keepalive: Cell<(Seconds, Seconds)>,
+    negotiated: RefCell<Option<Negotiated>>,
+    extensions: RefCell<Extensions>,
+}
+
+/// Protocol features negotiated during handshake
+///
+/// Server limits are sent to the client in CONNACK packet, client limits
+/// are received in CONNECT packet. MQTT v3.1.1 connections do not negotiate
+/// limits, default values are reported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Protocol version
+    pub version: MqttVersion,
+    /// Keep-alive interval client must use for the connection
+    pub keepalive: Seconds,
+    /// Max packet size accepted by server, `None` if not limited
+    pub max_packet_size: Option<u32>,
+    /// Max packet size accepted by client, `None` if not limited
+    pub client_max_packet_size: Option<u32>,
+    /// Receive maximum of server, `0` if not set
+    pub receive_max: u16,
+    /// Receive maximum of client
+    pub client_receive_max: u16,
+    /// Topic alias maximum of server
+    pub topic_alias_max: u16,
+    /// Topic alias maximum of client
+    pub client_topic_alias_max: u16,
+    /// Max QoS supported by server
+    pub max_qos: QoS,
+    /// Retained messages are supported by server
+    pub retain_available: bool,
+    /// Wildcard subscriptions are supported by server
+    pub wildcard_subscription_available: bool,
+    /// Shared subscriptions are supported by server
+    pub shared_subscription_available: bool,
+}
+
+impl Negotiated {
+    pub(crate) fn new(version: MqttVersion) -> Self {
+        Self {
+            version,
+            keepalive: Seconds::ZERO,
+            max_packet_size: None,
+            client_max_packet_size: None,
+            receive_max: 0,
+            client_receive_max: u16::MAX,
+            topic_alias_max: 0,
+            client_topic_alias_max: 0,
+            max_qos: QoS::ExactlyOnce,
+            retain_available: true,
+            wildcard_subscription_available: true,
+            shared_subscription_available: true,
+        }
+    }
 }
 
 impl<T, St> Clone for Session<T, St> {
@@ -20,11 +80,29 @@ impl<T, St> Clone for Session<T, St> {
 
 impl<T, St> Session<T, St> {
     pub(crate) fn new(st: St, sink: T) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive: 0, max_topic_alias: 0 }))
+        Session(Rc::new(SessionInner {
+            st,
+            sink,
+            version: MqttVersion::V3,
+            max_receive: 0,
+            max_topic_alias: 0,
+            keepalive: Cell::new((Seconds::ZERO, Seconds::ZERO)),
+            negotiated: RefCell::new(None),
+            extensions: RefCell::new(Extensions::new()),
+        }))
     }
 
     pub(crate) fn new_v5(st: St, sink: T, max_receive: u16, max_topic_alias: u16) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive, max_topic_alias }))
+        Session(Rc::new(SessionInner {
+            st,
+            sink,
+            version: MqttVersion::V5,
+            max_receive,
+            max_topic_alias,
+            keepalive: Cell::new((Seconds::ZERO, Seconds::ZERO)),
+            negotiated: RefCell::new(None),
+            extensions: RefCell::new(Extensions::new()),
+        }))
     }
 
     #[inline]
@@ -37,6 +115,60 @@ impl<T, St> Session<T, St> {
         &self.0.st
     }
 
+    /// Protocol version negotiated during handshake
+    #[inline]
+    pub fn protocol_version(&self) -> MqttVersion {
+        self.0.version
+    }
+
+    /// Keep-alive interval requested by client in CONNECT packet
+    #[inline]
+    pub fn requested_keepalive(&self) -> Seconds {
+        self.0.keepalive.get().0
+    }
+
+    /// Keep-alive interval client must use for the connection
+    ///
+    /// Differs from requested interval if server overrides it with
+    /// `Server Keep Alive` property (MQTT v5 only).
+    #[inline]
+    pub fn effective_keepalive(&self) -> Seconds {
+        self.0.keepalive.get().1
+    }
+
+    /// Snapshot of protocol features negotiated during handshake
+    pub fn negotiated(&self) -> Negotiated {
+        let mut negotiated = self
+            .0
+            .negotiated
+            .borrow()
+            .clone()
+            .unwrap_or_else(|| Negotiated::new(self.0.version));
+        negotiated.version = self.0.version;
+        negotiated.keepalive = self.effective_keepalive();
+        negotiated
+    }
+
+    /// Connection scoped extensions
+    #[inline]
+    pub fn extensions(&self) -> Ref<'_, Extensions> {
+        self.0.extensions.borrow()
+    }
+
+    /// Mutable reference to a connection scoped extensions
+    #[inline]
+    pub fn extensions_mut(&self) -> RefMut<'_, Extensions> {
+        self.0.extensions.borrow_mut()
+    }
+
+    pub(crate) fn set_keepalive(&self, requested: u16, effective: u16) {
+        self.0.keepalive.set((Seconds(requested), Seconds(effective)));
+    }
+
+    pub(crate) fn set_negotiated(&self, negotiated: Negotiated) {
+        *self.0.negotiated.borrow_mut() = Some(negotiated);
+    }
+
     pub(crate) fn params(&self) -> (u16, u16) {
         (self.0.max_receive, self.0.max_topic_alias)
     }
@@ -50,3 +182,54 @@ impl<T, St> Deref for Session<T, St> {
         &self.0.st
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extensions() {
+        let session1 = Session::new((), ());
+        let session2 = Session::new((), ());
+
+        session1.extensions_mut().insert(10u32);
+        session1.extensions_mut().insert("tenant");
+        session2.extensions_mut().insert(20u32);
+
+        assert_eq!(session1.extensions().get::<u32>(), Some(&10));
+        assert_eq!(session1.extensions().get::<&str>(), Some(&"tenant"));
+        assert_eq!(session2.extensions().get::<u32>(), Some(&20));
+        assert!(session2.extensions().get::<&str>().is_none());
+
+        let session3 = session1.clone();
+        assert_eq!(session3.extensions().get::<u32>(), Some(&10));
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        assert_eq!(Session::new((), ()).protocol_version(), MqttVersion::V3);
+        assert_eq!(Session::new_v5((), (), 0, 0).protocol_version(), MqttVersion::V5);
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let session = Session::new_v5((), (), 0, 0);
+        assert_eq!(session.requested_keepalive(), Seconds::ZERO);
+        assert_eq!(session.effective_keepalive(), Seconds::ZERO);
+
+        session.set_keepalive(60, 30);
+        assert_eq!(session.requested_keepalive(), Seconds(60));
+        assert_eq!(session.effective_keepalive(), Seconds(30));
+    }
+
+    #[test]
+    fn test_negotiated() {
+        let session = Session::new((), ());
+        session.set_keepalive(60, 60);
+        let negotiated = session.negotiated();
+        assert_eq!(negotiated.version, MqttVersion::V3);
+        assert_eq!(negotiated.keepalive, Seconds(60));
+        assert_eq!(negotiated.max_qos, QoS::ExactlyOnce);
+        assert!(negotiated.retain_available);
+    }
+}
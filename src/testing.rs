@@ -0,0 +1,262 @@
+//! Protocol conformance testing helpers
+//!
+//! `Harness` runs mqtt server on in-memory transport, so tests could send
+//! hand-crafted byte sequences and check exact response bytes.
+//! `with_latency()` delays server responses, for checking client
+//! retransmit and time-out behavior.
+//!
+//! Module is available with `testing` feature.
+use std::cell::{Cell, RefCell};
+use std::task::{Context, Poll};
+use std::{any, collections::VecDeque, fmt, future::Future, io, pin::Pin};
+
+use ntex::io::{Filter, FilterFactory, Io, IoBoxed, IoRef, ReadStatus, WriteStatus};
+use ntex::service::{Service, ServiceFactory};
+use ntex::testing::Io as IoTest;
+use ntex::time::{sleep, timeout, Millis, Sleep};
+use ntex::util::{Bytes, BytesMut, BytesVec, Ready};
+
+/// Max time to wait for server response
+const RESPONSE_TIMEOUT: Millis = Millis(1_000);
+
+/// In-memory connection to mqtt server
+pub struct Harness {
+    client: IoTest,
+}
+
+impl Harness {
+    /// Start server on in-memory transport
+    ///
+    /// panics if server factory fails to create service
+    pub async fn new<F>(factory: F) -> Self
+    where
+        F: ServiceFactory<IoBoxed>,
+        F::Service: 'static,
+        F::InitError: fmt::Debug,
+    {
+        let (client, server) = IoTest::create();
+        client.remote_buffer_cap(64 * 1024);
+
+        start(factory, IoBoxed::from(Io::new(server))).await;
+        Harness { client }
+    }
+
+    /// Send raw bytes to server
+    pub fn send(&self, data: &[u8]) {
+        self.client.write(data);
+    }
+
+    /// Receive available response bytes
+    ///
+    /// Returns `None` if server does not respond within one second.
+    pub async fn recv(&self) -> Option<Bytes> {
+        match timeout(RESPONSE_TIMEOUT, self.client.read()).await {
+            Ok(Ok(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Send raw bytes and check exact response bytes
+    ///
+    /// panics if response does not match
+    pub async fn assert_response(&self, input: &[u8], expected: &[u8]) {
+        self.send(input);
+
+        let mut buf = BytesMut::new();
+        while buf.len() < expected.len() {
+            match self.recv().await {
+                Some(data) => buf.extend_from_slice(&data),
+                None => break,
+            }
+        }
+        assert_eq!(&buf[..], expected, "Unexpected response for {:x?}", input);
+    }
+}
+
+/// Response latency distribution
+#[derive(Copy, Clone, Debug)]
+pub enum Latency {
+    /// Constant delay
+    Fixed(Millis),
+    /// Delay is uniformly distributed between min and max values
+    ///
+    /// Delays are pseudo-random but the sequence is the same for every run.
+    Uniform(Millis, Millis),
+}
+
+impl Latency {
+    fn next(&self, seed: &Cell<u32>) -> Millis {
+        match *self {
+            Latency::Fixed(delay) => delay,
+            Latency::Uniform(min, max) if max.0 > min.0 => {
+                // xorshift32
+                let mut x = seed.get();
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                seed.set(x);
+                Millis(min.0 + x % (max.0 - min.0).saturating_add(1))
+            }
+            Latency::Uniform(min, _) => min,
+        }
+    }
+}
+
+/// Start server with delayed responses
+///
+/// Every chunk of data written by the server is delivered to the client
+/// after delay sampled from `latency`. Client data is delivered immediately.
+pub async fn with_latency<F>(factory: F, latency: Latency) -> Harness
+where
+    F: ServiceFactory<IoBoxed>,
+    F::Service: 'static,
+    F::InitError: fmt::Debug,
+{
+    let (client, server) = IoTest::create();
+    client.remote_buffer_cap(64 * 1024);
+
+    let io = Io::new(server).add_filter(LatencyFactory(latency)).await.unwrap();
+    start(factory, IoBoxed::from(io)).await;
+    Harness { client }
+}
+
+/// Io filter factory for delaying written data
+struct LatencyFactory(Latency);
+
+impl<F: Filter> FilterFactory<F> for LatencyFactory {
+    type Filter = LatencyFilter<F>;
+    type Error = io::Error;
+    type Future = Ready<Io<LatencyFilter<F>>, io::Error>;
+
+    fn create(self, io: Io<F>) -> Self::Future {
+        let latency = self.0;
+        Ready::from(io.map_filter(|inner: F| {
+            Ok::<_, io::Error>(LatencyFilter {
+                inner,
+                latency,
+                seed: Cell::new(0x9e37_79b9),
+                delayed: RefCell::new(VecDeque::new()),
+            })
+        }))
+    }
+}
+
+/// Io filter, written data is passed to inner filter after delay
+struct LatencyFilter<F> {
+    inner: F,
+    latency: Latency,
+    seed: Cell<u32>,
+    delayed: RefCell<VecDeque<(Sleep, BytesVec)>>,
+}
+
+impl<F: Filter> LatencyFilter<F> {
+    fn write_inner(&self, data: &[u8]) -> io::Result<()> {
+        let mut buf = self.inner.get_write_buf().unwrap_or_else(BytesVec::new);
+        buf.extend_from_slice(data);
+        self.inner.release_write_buf(buf)
+    }
+
+    /// Pass data with elapsed delay to inner filter
+    fn poll_delayed(&self, cx: &mut Context<'_>) -> io::Result<()> {
+        let mut delayed = self.delayed.borrow_mut();
+        while let Some((delay, _)) = delayed.front_mut() {
+            if Pin::new(delay).poll(cx).is_pending() {
+                break;
+            }
+            let (_, data) = delayed.pop_front().unwrap();
+            self.write_inner(&data)?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: Filter> Filter for LatencyFilter<F> {
+    fn query(&self, id: any::TypeId) -> Option<Box<dyn any::Any>> {
+        self.inner.query(id)
+    }
+
+    fn get_read_buf(&self) -> Option<BytesVec> {
+        self.inner.get_read_buf()
+    }
+
+    fn release_read_buf(&self, buf: BytesVec) {
+        self.inner.release_read_buf(buf)
+    }
+
+    fn process_read_buf(&self, io: &IoRef, n: usize) -> io::Result<(usize, usize)> {
+        self.inner.process_read_buf(io, n)
+    }
+
+    fn get_write_buf(&self) -> Option<BytesVec> {
+        // every write gets new buffer, so it could be delayed separately
+        None
+    }
+
+    fn release_write_buf(&self, buf: BytesVec) -> io::Result<()> {
+        if !buf.is_empty() {
+            let delay = sleep(self.latency.next(&self.seed));
+            self.delayed.borrow_mut().push_back((delay, buf));
+        }
+        Ok(())
+    }
+
+    fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<ReadStatus> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<WriteStatus> {
+        if self.poll_delayed(cx).is_err() {
+            return Poll::Ready(WriteStatus::Terminate);
+        }
+        self.inner.poll_write_ready(cx)
+    }
+
+    fn poll_shutdown(&self) -> Poll<io::Result<()>> {
+        // delayed data is written before shutdown
+        for (_, data) in self.delayed.borrow_mut().drain(..) {
+            self.write_inner(&data)?;
+        }
+        self.inner.poll_shutdown()
+    }
+}
+
+async fn start<F>(factory: F, io: IoBoxed)
+where
+    F: ServiceFactory<IoBoxed>,
+    F::Service: 'static,
+    F::InitError: fmt::Debug,
+{
+    let srv = factory.new_service(()).await.expect("Cannot create server");
+    ntex::rt::spawn(async move {
+        let _ = srv.call(io).await;
+    });
+}
+
+/// Start server, send raw bytes and check exact response bytes
+///
+/// panics if response does not match
+pub async fn assert_response<F>(factory: F, input: &[u8], expected: &[u8])
+where
+    F: ServiceFactory<IoBoxed>,
+    F::Service: 'static,
+    F::InitError: fmt::Debug,
+{
+    Harness::new(factory).await.assert_response(input, expected).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency() {
+        let seed = Cell::new(1);
+        assert_eq!(Latency::Fixed(Millis(10)).next(&seed), Millis(10));
+        assert_eq!(Latency::Uniform(Millis(10), Millis(10)).next(&seed), Millis(10));
+
+        let latency = Latency::Uniform(Millis(10), Millis(20));
+        let delays: Vec<_> = (0..100).map(|_| latency.next(&seed).0).collect();
+        assert!(delays.iter().all(|d| (10..=20).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+}
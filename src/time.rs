@@ -0,0 +1,33 @@
+use std::{future::Future, pin::Pin, rc::Rc, time::Duration, time::Instant};
+
+/// Source of time for timers of v5 server connections
+///
+/// By default timers are driven by ntex's timer. Custom time source
+/// allows to control keep-alive expiry, publish pacing and coalescing,
+/// subscription ttl, session expiry, rate limits and other connection timers,
+/// for example for deterministic testing. Handshake timeout, disconnect
+/// timeout, v3 and client connections always use ntex's timer.
+pub trait TimeSource {
+    /// Returns current time
+    fn now(&self) -> Instant;
+
+    /// Returns future that resolves after `dur` elapses
+    fn sleep(&self, dur: Duration) -> Pin<Box<dyn Future<Output = ()>>>;
+}
+
+/// Sleep future of time source
+pub(crate) type Delay = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Current time of time source, real time if time source is not set
+pub(crate) fn now(time: Option<&Rc<dyn TimeSource>>) -> Instant {
+    time.map_or_else(Instant::now, |time| time.now())
+}
+
+/// Sleep future of time source, ntex's timer if time source is not set
+pub(crate) fn sleep(time: Option<&Rc<dyn TimeSource>>, dur: Duration) -> Delay {
+    if let Some(time) = time {
+        time.sleep(dur)
+    } else {
+        Box::pin(ntex::time::sleep(dur))
+    }
+}
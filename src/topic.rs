@@ -1,6 +1,8 @@
 use std::fmt::{self, Write};
 use std::{io, ops, str::FromStr};
 
+use ntex::util::HashMap;
+
 fn is_metadata<T: AsRef<str>>(s: T) -> bool {
     s.as_ref().starts_with('$')
 }
@@ -112,6 +114,27 @@ macro_rules! matches {
 pub struct Topic(Vec<Level>);
 
 impl Topic {
+    /// Validate topic name of publish packet
+    ///
+    /// Topic name must not contain wildcard characters [MQTT-3.3.2-2].
+    pub fn validate_name(name: &str) -> Result<(), TopicError> {
+        if name.contains(|c| c == '+' || c == '#') {
+            Err(TopicError::InvalidTopic)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Validate number of levels of subscription filter
+    ///
+    /// Filters that could not be parsed are not checked.
+    pub fn validate_filter_levels(filter: &str, max: usize) -> Result<(), TopicError> {
+        match Topic::from_str(filter) {
+            Ok(topic) if topic.levels().len() > max => Err(TopicError::InvalidTopic),
+            _ => Ok(()),
+        }
+    }
+
     #[inline]
     pub fn levels(&self) -> &Vec<Level> {
         &self.0
@@ -294,6 +317,137 @@ impl fmt::Display for Topic {
     }
 }
 
+/// Index of topic filters
+///
+/// Trie maps topic filters to values of type `T` and finds values of all
+/// filters that match topic name. Topic names starting with `$` are not
+/// matched by filters with wildcard at the first level.
+#[derive(Debug)]
+pub struct SubscriptionTrie<T> {
+    root: TrieNode<T>,
+}
+
+#[derive(Debug)]
+struct TrieNode<T> {
+    values: Vec<T>,
+    children: HashMap<Level, TrieNode<T>>,
+}
+
+impl<T> Default for TrieNode<T> {
+    fn default() -> Self {
+        TrieNode { values: Vec::new(), children: HashMap::default() }
+    }
+}
+
+impl<T> Default for SubscriptionTrie<T> {
+    fn default() -> Self {
+        SubscriptionTrie { root: TrieNode::default() }
+    }
+}
+
+impl<T> SubscriptionTrie<T> {
+    /// Create empty trie
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if trie does not contain any filters
+    pub fn is_empty(&self) -> bool {
+        self.root.values.is_empty() && self.root.children.is_empty()
+    }
+
+    /// Add value for topic filter
+    pub fn insert(&mut self, filter: &Topic, value: T) {
+        let mut node = &mut self.root;
+        for level in filter.levels() {
+            node = node.children.entry(level.clone()).or_default();
+        }
+        node.values.push(value);
+    }
+
+    /// Remove all values of topic filter
+    pub fn remove(&mut self, filter: &Topic) -> Vec<T> {
+        self.root.remove(filter.levels())
+    }
+
+    /// Check if any topic filter matches topic name
+    pub fn has_match(&self, topic: &str) -> bool {
+        let levels: Vec<_> = topic.split('/').collect();
+        self.root.walk(&levels, true, &mut |_| true)
+    }
+
+    /// Values of all topic filters that match topic name
+    pub fn matching<'a>(&'a self, topic: &str) -> impl Iterator<Item = &'a T> {
+        let levels: Vec<_> = topic.split('/').collect();
+        let mut values = Vec::new();
+        self.root.walk(&levels, true, &mut |v| {
+            values.push(v);
+            false
+        });
+        values.into_iter()
+    }
+}
+
+impl<T> TrieNode<T> {
+    fn remove(&mut self, levels: &[Level]) -> Vec<T> {
+        match levels.split_first() {
+            None => std::mem::take(&mut self.values),
+            Some((level, rest)) => {
+                if let Some(node) = self.children.get_mut(level) {
+                    let values = node.remove(rest);
+                    if node.values.is_empty() && node.children.is_empty() {
+                        self.children.remove(level);
+                    }
+                    values
+                } else {
+                    Vec::new()
+                }
+            }
+        }
+    }
+
+    /// Visit values of matching filters, stops if `f` returns true
+    fn walk<'a, F>(&'a self, levels: &[&str], root: bool, f: &mut F) -> bool
+    where
+        F: FnMut(&'a T) -> bool,
+    {
+        // [MQTT-4.7.2-1] wildcards at first level do not match `$` topics
+        let wildcards = !(root && levels.first().map_or(false, |l| is_metadata(l)));
+
+        if wildcards {
+            if let Some(node) = self.children.get(&Level::MultiWildcard) {
+                if node.values.iter().any(|v| f(v)) {
+                    return true;
+                }
+            }
+        }
+
+        match levels.split_first() {
+            None => self.values.iter().any(|v| f(v)),
+            Some((level, rest)) => {
+                let key = if level.is_empty() {
+                    Level::Blank
+                } else if is_metadata(level) {
+                    Level::Metadata((*level).to_string())
+                } else {
+                    Level::Normal((*level).to_string())
+                };
+                if let Some(node) = self.children.get(&key) {
+                    if node.walk(rest, false, f) {
+                        return true;
+                    }
+                }
+                if wildcards {
+                    if let Some(node) = self.children.get(&Level::SingleWildcard) {
+                        return node.walk(rest, false, f);
+                    }
+                }
+                false
+            }
+        }
+    }
+}
+
 pub(crate) trait WriteTopicExt: io::Write {
     fn write_level(&mut self, level: &Level) -> io::Result<usize> {
         match *level {
@@ -349,6 +503,22 @@ mod tests {
         assert!(!Level::Metadata(String::from("SYS+")).is_valid());
     }
 
+    #[test]
+    fn test_validate_name() {
+        assert_eq!(Topic::validate_name("sport/tennis"), Ok(()));
+        assert_eq!(Topic::validate_name("$SYS/info"), Ok(()));
+        assert_eq!(Topic::validate_name("a/+/b"), Err(TopicError::InvalidTopic));
+        assert_eq!(Topic::validate_name("a/#"), Err(TopicError::InvalidTopic));
+    }
+
+    #[test]
+    fn test_validate_filter_levels() {
+        assert_eq!(Topic::validate_filter_levels("a/b/c", 3), Ok(()));
+        assert_eq!(Topic::validate_filter_levels("a/+/#", 3), Ok(()));
+        assert_eq!(Topic::validate_filter_levels("a/b/c/d", 3), Err(TopicError::InvalidTopic));
+        assert_eq!(Topic::validate_filter_levels("a//b/", 3), Err(TopicError::InvalidTopic));
+    }
+
     #[test]
     fn test_valid_topic() {
         assert!(Topic(vec![
@@ -488,4 +658,45 @@ mod tests {
         assert!(Topic::from_str(&"$SYS/#").unwrap().matches_str("$SYS/"));
         assert!(Topic::from_str("$SYS/monitor/+").unwrap().matches_str("$SYS/monitor/Clients"));
     }
+
+    #[test]
+    fn test_subscription_trie() {
+        let mut trie = SubscriptionTrie::new();
+        assert!(trie.is_empty());
+        assert!(!trie.has_match("sport/tennis"));
+
+        trie.insert(&topic!("sport/tennis/+"), 1);
+        trie.insert(&topic!("sport/#"), 2);
+        trie.insert(&topic!("+/+"), 3);
+        trie.insert(&topic!("$SYS/#"), 4);
+        trie.insert(&topic!("#"), 5);
+
+        let matching = |trie: &SubscriptionTrie<i32>, topic| {
+            let mut v: Vec<_> = trie.matching(topic).copied().collect();
+            v.sort_unstable();
+            v
+        };
+
+        assert_eq!(matching(&trie, "sport/tennis/player1"), vec![1, 2, 5]);
+        assert_eq!(matching(&trie, "sport/tennis"), vec![2, 3, 5]);
+        assert_eq!(matching(&trie, "sport"), vec![2, 5]);
+        assert_eq!(matching(&trie, "/finance"), vec![3, 5]);
+        assert_eq!(matching(&trie, "$SYS/monitor"), vec![4]);
+        assert!(trie.has_match("finance"));
+
+        // wildcards at first level do not match `$` topics
+        trie.remove(&topic!("$SYS/#"));
+        assert!(!trie.has_match("$SYS/monitor"));
+        assert!(!trie.has_match("$SYS"));
+
+        assert_eq!(trie.remove(&topic!("#")), vec![5]);
+        assert!(!trie.has_match("finance"));
+        assert!(trie.has_match("finance/stock"));
+        assert!(trie.remove(&topic!("finance/#")).is_empty());
+
+        trie.remove(&topic!("sport/tennis/+"));
+        trie.remove(&topic!("sport/#"));
+        trie.remove(&topic!("+/+"));
+        assert!(trie.is_empty());
+    }
 }
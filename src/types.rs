@@ -31,6 +31,15 @@ prim_enum! {
     }
 }
 
+/// Mqtt protocol version
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MqttVersion {
+    /// MQTT v3.1.1
+    V3,
+    /// MQTT v5
+    V5,
+}
+
 bitflags::bitflags! {
     pub struct ConnectFlags: u8 {
         const USERNAME    = 0b1000_0000;
@@ -130,6 +130,39 @@ impl Decode for ByteString {
     }
 }
 
+/// Decode string, if `lenient` is set invalid utf-8 sequences get replaced with `U+FFFD`
+pub(crate) fn decode_string(src: &mut Bytes, lenient: bool) -> Result<ByteString, DecodeError> {
+    if !lenient {
+        return ByteString::decode(src);
+    }
+    let bytes = Bytes::decode(src)?;
+    match ByteString::try_from(bytes.clone()) {
+        Ok(s) => Ok(s),
+        Err(_) => Ok(ByteString::from(String::from_utf8_lossy(&bytes).into_owned())),
+    }
+}
+
+/// Decode user property, see `decode_string()`
+pub(crate) fn decode_user_property(
+    src: &mut Bytes,
+    lenient: bool,
+) -> Result<(ByteString, ByteString), DecodeError> {
+    let key = decode_string(src, lenient)?;
+    let val = decode_string(src, lenient)?;
+    Ok((key, val))
+}
+
+/// Read string property, e.g. reason string or content type, see `decode_string()`
+pub(crate) fn read_string_property(
+    val: &mut Option<ByteString>,
+    src: &mut Bytes,
+    lenient: bool,
+) -> Result<(), DecodeError> {
+    ensure!(val.is_none(), DecodeError::MalformedPacket); // property is set twice while not allowed
+    *val = Some(decode_string(src, lenient)?);
+    Ok(())
+}
+
 pub(crate) fn take_properties(src: &mut Bytes) -> Result<Bytes, DecodeError> {
     let prop_len = decode_variable_length_cursor(src)?;
     ensure!(src.remaining() >= prop_len as usize, DecodeError::InvalidLength);
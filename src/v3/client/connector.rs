@@ -141,6 +141,8 @@ where
     /// Set handshake timeout.
     ///
     /// Handshake includes `connect` packet and response `connect-ack`.
+    /// If `connect-ack` packet is not received in time, connection is closed
+    /// and connect fails with `ClientError::HandshakeTimeout` error.
     /// By default handshake timeuot is disabled.
     pub fn handshake_timeout(mut self, timeout: Seconds) -> Self {
         self.handshake_timeout = timeout;
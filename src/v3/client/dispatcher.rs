@@ -1,5 +1,6 @@
 use std::cell::RefCell;
 use std::task::{Context, Poll};
+use std::time::Instant;
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::io::DispatchItem;
@@ -113,6 +114,7 @@ where
         log::trace!("Dispatch packet: {:#?}", packet);
         match packet {
             DispatchItem::Item(codec::Packet::Publish(publish)) => {
+                let received_at = Instant::now();
                 let inner = self.inner.clone();
                 let packet_id = publish.packet_id;
 
@@ -128,7 +130,7 @@ where
                 Either::Left(PublishResponse {
                     packet_id,
                     inner,
-                    fut: self.publish.call(Publish::new(publish)),
+                    fut: self.publish.call(Publish::with_received_at(publish, received_at)),
                     fut_c: None,
                     _t: PhantomData,
                 })
@@ -309,7 +311,9 @@ where
                     this.inner.sink.close();
                     Some(codec::Packet::Disconnect)
                 }
-                ControlResultKind::Closed | ControlResultKind::Nothing => None,
+                ControlResultKind::Closed
+                | ControlResultKind::Nothing
+                | ControlResultKind::CustomPacket => None,
             },
             Poll::Pending => return Poll::Pending,
         };
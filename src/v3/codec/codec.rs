@@ -5,7 +5,7 @@ use ntex::util::{Buf, BytesMut};
 
 use super::{decode, encode, Packet, Publish};
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, QoS};
+use crate::types::{packet_type, FixedHeader, QoS};
 use crate::utils::decode_variable_length;
 
 #[derive(Debug)]
@@ -13,6 +13,8 @@ use crate::utils::decode_variable_length;
 pub struct Codec {
     state: Cell<DecodeState>,
     max_size: Cell<u32>,
+    max_connect_size: Cell<u32>,
+    custom_packets: Cell<bool>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -24,7 +26,12 @@ enum DecodeState {
 impl Codec {
     /// Create `Codec` instance
     pub fn new() -> Self {
-        Codec { state: Cell::new(DecodeState::FrameHeader), max_size: Cell::new(0) }
+        Codec {
+            state: Cell::new(DecodeState::FrameHeader),
+            max_size: Cell::new(0),
+            max_connect_size: Cell::new(0),
+            custom_packets: Cell::new(false),
+        }
     }
 
     /// Set max inbound frame size.
@@ -43,6 +50,23 @@ impl Codec {
     pub fn set_max_size(&self, size: u32) {
         self.max_size.set(size);
     }
+
+    /// Set max inbound CONNECT packet size.
+    ///
+    /// Size is checked before packet is buffered.
+    /// If max size is set to `0`, size is limited by max inbound frame size only.
+    /// By default max size is set to `0`
+    pub fn set_max_connect_size(&self, size: u32) {
+        self.max_connect_size.set(size);
+    }
+
+    /// Decode packets with reserved packet types
+    ///
+    /// Such packets are decoded as `Packet::Custom` instead of producing decode error.
+    /// By default custom packets are disabled
+    pub fn set_custom_packets(&self, val: bool) {
+        self.custom_packets.set(val);
+    }
 }
 
 impl Default for Codec {
@@ -71,6 +95,13 @@ impl Decoder for Codec {
                             if max_size != 0 && max_size < remaining_length {
                                 return Err(DecodeError::MaxSizeExceeded);
                             }
+                            let max_connect_size = self.max_connect_size.get();
+                            if first_byte == packet_type::CONNECT
+                                && max_connect_size != 0
+                                && max_connect_size < remaining_length
+                            {
+                                return Err(DecodeError::MaxSizeExceeded);
+                            }
                             src.advance(consumed + 1);
                             self.state.set(DecodeState::Frame(FixedHeader {
                                 first_byte,
@@ -93,8 +124,17 @@ impl Decoder for Codec {
                     if src.len() < fixed.remaining_length as usize {
                         return Ok(None);
                     }
-                    let packet_buf = src.split_to(fixed.remaining_length as usize);
-                    let packet = decode::decode_packet(packet_buf.freeze(), fixed.first_byte)?;
+                    let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
+                    let packet =
+                        match decode::decode_packet(packet_buf.clone(), fixed.first_byte) {
+                            Ok(packet) => packet,
+                            Err(DecodeError::UnsupportedPacketType)
+                                if self.custom_packets.get() =>
+                            {
+                                Packet::Custom(fixed.first_byte, packet_buf)
+                            }
+                            Err(err) => return Err(err),
+                        };
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(2);
                     return Ok(Some(packet));
@@ -135,6 +175,40 @@ mod tests {
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
 
+    #[test]
+    fn test_max_connect_size() {
+        let codec = Codec::new();
+        codec.set_max_connect_size(5);
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x10\x09");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\x09");
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn test_custom_packets() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\xf0\x03abc\xc0\x00");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::UnsupportedPacketType));
+
+        let codec = Codec::new();
+        codec.set_custom_packets(true);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\xf0\x03abc\xc0\x00");
+        let pkt = Packet::Custom(0xf0, Bytes::from_static(b"abc"));
+        assert_eq!(codec.decode(&mut buf), Ok(Some(pkt.clone())));
+        assert_eq!(codec.decode(&mut buf), Ok(Some(Packet::PingRequest)));
+
+        let mut buf = BytesMut::new();
+        codec.encode(pkt, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"\xf0\x03abc");
+    }
+
     #[test]
     fn test_packet() {
         let codec = Codec::new();
@@ -59,6 +59,8 @@ pub(crate) fn get_encoded_size(packet: &Packet) -> usize {
         }
 
         Packet::PingRequest | Packet::PingResponse | Packet::Disconnect => 0,
+
+        Packet::Custom(_, ref body) => body.len(),
     }
 }
 
@@ -157,6 +159,11 @@ pub(crate) fn encode(
         Packet::PingRequest => dst.put_slice(&[packet_type::PINGREQ, 0]),
         Packet::PingResponse => dst.put_slice(&[packet_type::PINGRESP, 0]),
         Packet::Disconnect => dst.put_slice(&[packet_type::DISCONNECT, 0]),
+        Packet::Custom(first_byte, body) => {
+            dst.put_u8(*first_byte);
+            write_variable_length(content_size, dst);
+            dst.put_slice(body);
+        }
     }
 
     Ok(())
@@ -190,6 +190,11 @@ pub enum Packet {
     PingResponse,
     /// Client is disconnecting
     Disconnect,
+
+    /// Packet with reserved packet type, first byte of fixed header and packet body
+    ///
+    /// Decoded only if custom packets are enabled for codec.
+    Custom(u8, Bytes),
 }
 
 impl From<Connect> for Packet {
@@ -221,6 +226,7 @@ impl Packet {
             Packet::PingRequest => packet_type::PINGREQ,
             Packet::PingResponse => packet_type::PINGRESP,
             Packet::Disconnect => packet_type::DISCONNECT,
+            Packet::Custom(first_byte, _) => *first_byte,
         }
     }
 }
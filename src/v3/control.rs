@@ -1,4 +1,4 @@
-use ntex::util::ByteString;
+use ntex::util::{ByteString, Bytes};
 use std::{io, marker::PhantomData, num::NonZeroU16};
 
 use super::codec;
@@ -22,6 +22,10 @@ pub enum ControlMessage<E> {
     ProtocolError(ProtocolError),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Packet with reserved packet type
+    ///
+    /// Message is sent only if custom packets are enabled for server.
+    CustomPacket(CustomPacket),
 }
 
 #[derive(Debug)]
@@ -38,6 +42,7 @@ pub(crate) enum ControlResultKind {
     Subscribe(SubscribeResult),
     Unsubscribe(UnsubscribeResult),
     Closed,
+    CustomPacket,
 }
 
 impl<E> ControlMessage<E> {
@@ -65,8 +70,8 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect)
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn closed(is_error: bool, clean: bool) -> Self {
+        ControlMessage::Closed(Closed { is_error, clean })
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -82,6 +87,10 @@ impl<E> ControlMessage<E> {
         ControlMessage::PeerGone(PeerGone(err))
     }
 
+    pub(super) fn custom_packet(first_byte: u8, payload: Bytes) -> Self {
+        ControlMessage::CustomPacket(CustomPacket(first_byte, payload))
+    }
+
     /// Disconnects the client by sending DISCONNECT packet.
     pub fn disconnect(&self) -> ControlResult {
         ControlResult { result: ControlResultKind::Disconnect }
@@ -200,7 +209,15 @@ impl Subscribe {
 
     #[inline]
     /// convert subscription to a result
-    pub fn ack(self) -> ControlResult {
+    pub fn ack(mut self) -> ControlResult {
+        // duplicated topics get return code of the last entry
+        for idx in 0..self.topics.len() {
+            if let Some(last) = self.topics.iter().rposition(|(t, _)| t == &self.topics[idx].0)
+            {
+                self.codes[idx] = self.codes[last];
+            }
+        }
+
         ControlResult {
             result: ControlResultKind::Subscribe(SubscribeResult {
                 codes: self.codes,
@@ -230,6 +247,18 @@ impl<'a> SubscribeIter<'a> {
     fn next_unsafe(&mut self) -> Option<Subscription<'a>> {
         let subs = unsafe { &mut *self.subs };
 
+        // skip duplicated topics, last entry wins
+        while self.entry < subs.topics.len() {
+            if subs.topics[self.entry + 1..]
+                .iter()
+                .any(|(t, _)| t == &subs.topics[self.entry].0)
+            {
+                self.entry += 1;
+            } else {
+                break;
+            }
+        }
+
         if self.entry < subs.topics.len() {
             let s = Subscription {
                 topic: &subs.topics[self.entry].0,
@@ -335,11 +364,20 @@ impl Unsubscribe {
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    clean: bool,
 }
 
 impl Closed {
     pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+        Self { is_error, clean: false }
+    }
+
+    /// Check if connection is closed cleanly
+    ///
+    /// Connection is closed cleanly if client sent DISCONNECT packet,
+    /// otherwise connection is lost.
+    pub fn is_clean(&self) -> bool {
+        self.clean
     }
 
     /// Returns error state on connection close
@@ -372,3 +410,24 @@ impl PeerGone {
         ControlResult { result: ControlResultKind::Nothing }
     }
 }
+
+/// Packet with reserved packet type
+#[derive(Debug)]
+pub struct CustomPacket(u8, Bytes);
+
+impl CustomPacket {
+    /// Returns first byte of fixed header, packet type and flags
+    pub fn first_byte(&self) -> u8 {
+        self.0
+    }
+
+    /// Returns packet body
+    pub fn payload(&self) -> &Bytes {
+        &self.1
+    }
+
+    /// Ack custom packet, connection stays open
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::CustomPacket }
+    }
+}
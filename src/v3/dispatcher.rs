@@ -1,5 +1,6 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::task::{Context, Poll};
+use std::time::Instant;
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::io::DispatchItem;
@@ -9,6 +10,7 @@ use ntex::util::{
 };
 
 use crate::error::{MqttError, ProtocolError};
+use crate::topic::Topic;
 
 use super::control::{
     ControlMessage, ControlResult, ControlResultKind, Subscribe, Unsubscribe,
@@ -68,6 +70,7 @@ pub(crate) struct Dispatcher<St, T, C: Service<ControlMessage<E>>, E> {
     session: Session<St>,
     publish: T,
     shutdown: RefCell<Option<Pin<Box<C::Future>>>>,
+    disconnected: Cell<bool>,
     inner: Rc<Inner<C>>,
     _t: PhantomData<(E,)>,
 }
@@ -91,6 +94,7 @@ where
             session,
             publish,
             shutdown: RefCell::new(None),
+            disconnected: Cell::new(false),
             inner: Rc::new(Inner { sink, control, inflight: RefCell::new(HashSet::default()) }),
             _t: PhantomData,
         }
@@ -125,8 +129,11 @@ where
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
             self.inner.sink.close();
-            *shutdown =
-                Some(Box::pin(self.inner.control.call(ControlMessage::closed(is_error))));
+            *shutdown = Some(Box::pin(
+                self.inner
+                    .control
+                    .call(ControlMessage::closed(is_error, self.disconnected.get())),
+            ));
         }
 
         let res0 = shutdown.as_mut().expect("guard above").as_mut().poll(cx);
@@ -142,8 +149,29 @@ where
     fn call(&self, req: DispatchItem<Rc<MqttShared>>) -> Self::Future {
         log::trace!("Dispatch v3 packet: {:#?}", req);
 
+        // [MQTT-3.14.4-2] client must not send packets after DISCONNECT
+        if self.disconnected.get() {
+            if let DispatchItem::Item(ref pkt) = req {
+                log::debug!("Packet is received after DISCONNECT, ignore: {:?}", pkt);
+                return Either::Right(Either::Left(Ready::Ok(None)));
+            }
+        }
+
         match req {
             DispatchItem::Item(codec::Packet::Publish(publish)) => {
+                let received_at = Instant::now();
+
+                if Topic::validate_name(&publish.topic).is_err() {
+                    log::trace!(
+                        "MQTT-3.3.2-2: Publish topic contains wildcards {:?}",
+                        publish.topic
+                    );
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::TopicNameInvalid),
+                        &self.inner,
+                    )));
+                }
+
                 let inner = self.inner.clone();
                 let packet_id = publish.packet_id;
 
@@ -161,7 +189,7 @@ where
                     packet_id,
                     inner,
                     state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
+                        fut: self.publish.call(Publish::with_received_at(publish, received_at)),
                     },
                 })
             }
@@ -204,9 +232,44 @@ where
                     &self.inner,
                 )))
             }
-            DispatchItem::Item(codec::Packet::Disconnect) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::remote_disconnect(), &self.inner),
-            )),
+            DispatchItem::Item(codec::Packet::Disconnect) => {
+                self.disconnected.set(true);
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::remote_disconnect(),
+                    &self.inner,
+                )))
+            }
+            DispatchItem::Item(pkt @ codec::Packet::Connect(_)) => {
+                log::trace!("MQTT-3.1.0-2: CONNECT packet is received after handshake");
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::proto_error(ProtocolError::Unexpected(
+                        pkt.packet_type(),
+                        "MQTT-3.1.0-2: Second CONNECT packet",
+                    )),
+                    &self.inner,
+                )))
+            }
+            DispatchItem::Item(pkt @ codec::Packet::PingResponse) => {
+                if self.inner.sink.strict_packet_direction() {
+                    log::trace!("PINGRESP packet is received from client");
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::Unexpected(
+                            pkt.packet_type(),
+                            "PINGRESP packet is sent by server only",
+                        )),
+                        &self.inner,
+                    )))
+                } else {
+                    log::trace!("Ignore PINGRESP packet from client");
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::Custom(first_byte, payload)) => {
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::custom_packet(first_byte, payload),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
             DispatchItem::EncoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
@@ -349,6 +412,7 @@ where
                         this.inner.sink.close();
                         None
                     }
+                    ControlResultKind::CustomPacket => None,
                     ControlResultKind::PublishAck(_) => unreachable!(),
                 };
                 Poll::Ready(Ok(packet))
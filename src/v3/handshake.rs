@@ -26,6 +26,12 @@ impl Handshake {
         &mut self.pkt
     }
 
+    #[inline]
+    /// Returns retain flag of will message, `false` if will is not set
+    pub fn will_retain(&self) -> bool {
+        self.pkt.last_will.as_ref().map(|will| will.retain).unwrap_or(false)
+    }
+
     #[inline]
     pub fn io(&self) -> &IoBoxed {
         &self.io
@@ -55,52 +61,43 @@ impl Handshake {
         }
     }
 
-    /// Create connect ack object with `identifier rejected` return code
-    pub fn identifier_rejected<St>(self) -> HandshakeAck<St> {
+    /// Create connect ack object with failure return code
+    ///
+    /// `ConnectionAccepted` is not a failure, use `ack()` to accept connection.
+    pub fn failed<St>(self, return_code: mqtt::ConnectAckReason) -> HandshakeAck<St> {
         HandshakeAck {
             io: self.io,
             shared: self.shared,
             session: None,
             session_present: false,
             keepalive: Seconds(30),
-            return_code: mqtt::ConnectAckReason::IdentifierRejected,
+            return_code,
         }
     }
 
+    /// Create connect ack object with `unacceptable protocol version` return code
+    pub fn unacceptable_protocol_version<St>(self) -> HandshakeAck<St> {
+        self.failed(mqtt::ConnectAckReason::UnacceptableProtocolVersion)
+    }
+
+    /// Create connect ack object with `identifier rejected` return code
+    pub fn identifier_rejected<St>(self) -> HandshakeAck<St> {
+        self.failed(mqtt::ConnectAckReason::IdentifierRejected)
+    }
+
     /// Create connect ack object with `bad user name or password` return code
     pub fn bad_username_or_pwd<St>(self) -> HandshakeAck<St> {
-        HandshakeAck {
-            io: self.io,
-            shared: self.shared,
-            session: None,
-            session_present: false,
-            keepalive: Seconds(30),
-            return_code: mqtt::ConnectAckReason::BadUserNameOrPassword,
-        }
+        self.failed(mqtt::ConnectAckReason::BadUserNameOrPassword)
     }
 
     /// Create connect ack object with `not authorized` return code
     pub fn not_authorized<St>(self) -> HandshakeAck<St> {
-        HandshakeAck {
-            io: self.io,
-            shared: self.shared,
-            session: None,
-            session_present: false,
-            keepalive: Seconds(30),
-            return_code: mqtt::ConnectAckReason::NotAuthorized,
-        }
+        self.failed(mqtt::ConnectAckReason::NotAuthorized)
     }
 
     /// Create connect ack object with `service unavailable` return code
     pub fn service_unavailable<St>(self) -> HandshakeAck<St> {
-        HandshakeAck {
-            io: self.io,
-            shared: self.shared,
-            session: None,
-            session_present: false,
-            keepalive: Seconds(30),
-            return_code: mqtt::ConnectAckReason::ServiceUnavailable,
-        }
+        self.failed(mqtt::ConnectAckReason::ServiceUnavailable)
     }
 }
 
@@ -1,16 +1,17 @@
-use std::{mem, num::NonZeroU16};
+use std::{mem, num::NonZeroU16, time::Instant};
 
 use ntex::router::Path;
 use ntex::util::{ByteString, Bytes};
 use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
 
-use crate::v3::codec;
+use crate::{types::packet_type, v3::codec};
 
 /// Publish message
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    received_at: Instant,
 }
 
 #[derive(Debug)]
@@ -22,7 +23,18 @@ impl Publish {
     /// packet
     #[doc(hidden)]
     pub fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+        Self::with_received_at(publish, Instant::now())
+    }
+
+    /// Create a new `Publish` message from a PUBLISH packet received at `received_at`
+    pub(crate) fn with_received_at(publish: codec::Publish, received_at: Instant) -> Self {
+        Self { topic: Path::new(publish.topic.clone()), publish, received_at }
+    }
+
+    #[inline]
+    /// Instant when PUBLISH packet is received by connection dispatcher.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
     }
 
     #[inline]
@@ -36,12 +48,32 @@ impl Publish {
         self.publish.retain
     }
 
+    #[inline]
+    /// retained publish with empty payload, clears retained message of the topic.
+    pub fn is_retain_clear(&self) -> bool {
+        self.publish.retain && self.publish.payload.is_empty()
+    }
+
     #[inline]
     /// the level of assurance for delivery of an Application Message.
     pub fn qos(&self) -> codec::QoS {
         self.publish.qos
     }
 
+    #[inline]
+    /// raw fixed header byte of the publish packet, includes packet type,
+    /// DUP, QoS and RETAIN bits.
+    pub fn fixed_header_byte(&self) -> u8 {
+        let mut byte = packet_type::PUBLISH_START | (u8::from(self.publish.qos) << 1);
+        if self.publish.dup {
+            byte |= 0b1000;
+        }
+        if self.publish.retain {
+            byte |= 0b0001;
+        }
+        byte
+    }
+
     #[inline]
     /// the information channel to which payload data is published.
     pub fn publish_topic(&self) -> &str {
@@ -100,3 +132,57 @@ impl std::fmt::Debug for Publish {
         self.publish.fmt(f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::{codec::Decoder, util::BytesMut};
+
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Publish {
+        let mut buf = BytesMut::from(bytes);
+        match codec::Codec::new().decode(&mut buf).unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => Publish::new(pkt),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_fixed_header() {
+        let p = decode(b"\x30\x04\x00\x02/a");
+        assert!(!p.dup());
+        assert!(!p.retain());
+        assert_eq!(p.qos(), codec::QoS::AtMostOnce);
+        assert_eq!(p.fixed_header_byte(), 0b0011_0000);
+
+        let p = decode(b"\x3b\x06\x00\x02/a\x00\x01");
+        assert!(p.dup());
+        assert!(p.retain());
+        assert_eq!(p.qos(), codec::QoS::AtLeastOnce);
+        assert_eq!(p.fixed_header_byte(), 0b0011_1011);
+
+        let p = decode(b"\x34\x06\x00\x02/a\x00\x01");
+        assert!(!p.dup());
+        assert!(!p.retain());
+        assert_eq!(p.qos(), codec::QoS::ExactlyOnce);
+        assert_eq!(p.fixed_header_byte(), 0b0011_0100);
+
+        let p = decode(b"\x38\x04\x00\x02/a");
+        assert!(p.dup());
+        assert_eq!(p.fixed_header_byte(), 0b0011_1000);
+    }
+
+    #[test]
+    fn test_retain_clear() {
+        let p = decode(b"\x31\x04\x00\x02/a");
+        assert!(p.payload().is_empty());
+        assert!(p.is_retain_clear());
+
+        let p = decode(b"\x31\x05\x00\x02/ax");
+        assert!(!p.is_retain_clear());
+
+        let p = decode(b"\x30\x04\x00\x02/a");
+        assert!(p.payload().is_empty());
+        assert!(!p.is_retain_clear());
+    }
+}
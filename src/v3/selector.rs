@@ -9,6 +9,7 @@ use crate::error::{MqttError, ProtocolError};
 
 use super::control::{ControlMessage, ControlResult};
 use super::handshake::{Handshake, HandshakeAck};
+use super::server::recv_connect;
 use super::shared::{MqttShared, MqttSinkPool};
 use super::{codec as mqtt, MqttServer, Publish, Session};
 
@@ -92,6 +93,10 @@ where
             + From<P::InitError>
             + fmt::Debug,
     {
+        self.pool.max_connect_size.set(server.pool.max_connect_size.get());
+        self.pool.strict_direction.set(server.pool.strict_direction.get());
+        *self.pool.require_tls.borrow_mut() = server.pool.require_tls.borrow().clone();
+        self.pool.custom_packets.set(server.pool.custom_packets.get());
         server.pool = self.pool.clone();
         self.servers.push(boxed::factory(server.finish_selector(check)));
         self
@@ -247,17 +252,7 @@ where
 
         Box::pin(async move {
             // read first packet
-            let packet = io
-                .recv(&shared.codec)
-                .await
-                .map_err(|err| {
-                    log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::from(err)
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Disconnected(None)
-                })?;
+            let packet = recv_connect(&io, &shared).await?;
 
             let connect = match packet {
                 mqtt::Packet::Connect(connect) => connect,
@@ -316,17 +311,7 @@ where
 
         Box::pin(async move {
             // read first packet
-            let packet = io
-                .recv(&shared.codec)
-                .await
-                .map_err(|err| {
-                    log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::from(err)
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Disconnected(None)
-                })?;
+            let packet = recv_connect(&io, &shared).await?;
 
             let connect = match packet {
                 mqtt::Packet::Connect(connect) => connect,
@@ -6,8 +6,8 @@ use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
 use ntex::time::{timeout_checked, Millis, Seconds};
 use ntex::util::Either;
 
-use crate::error::{MqttError, ProtocolError};
-use crate::{io::Dispatcher, service};
+use crate::error::{DecodeError, MqttError, ProtocolError};
+use crate::{io::is_tls, io::Dispatcher, service, service::ServerHandle};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
@@ -25,6 +25,7 @@ pub struct MqttServer<St, C, Cn, P> {
     inflight: usize,
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
+    handle: Option<ServerHandle>,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -49,6 +50,7 @@ where
             inflight: 16,
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
+            handle: None,
             pool: Default::default(),
             _t: PhantomData,
         }
@@ -90,6 +92,14 @@ where
         self
     }
 
+    /// Set handle for adjusting settings of running server.
+    ///
+    /// Disconnect timeout of the handle overrides `disconnect_timeout()` value.
+    pub fn server_handle(mut self, handle: ServerHandle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -99,6 +109,62 @@ where
         self
     }
 
+    /// Set max inbound CONNECT packet size.
+    ///
+    /// Size is checked as soon as fixed header is received, connection with
+    /// oversized CONNECT packet is closed before packet is buffered.
+    /// If max size is set to `0`, size is limited by max inbound frame size only.
+    /// By default max size is set to `0`
+    pub fn max_connect_size(self, size: u32) -> Self {
+        self.pool.max_connect_size.set(size);
+        self
+    }
+
+    /// Accept packets with reserved packet types
+    ///
+    /// Some brokers use reserved packet types for protocol extensions. Such packets
+    /// are delivered to control service as `ControlMessage::CustomPacket` instead of
+    /// closing connection with decode error.
+    /// By default custom packets are disabled.
+    pub fn custom_packets(self, val: bool) -> Self {
+        self.pool.custom_packets.set(val);
+        self
+    }
+
+    /// Treat packets that only server sends as protocol violation
+    ///
+    /// In strict mode PINGRESP packet from client closes connection with
+    /// protocol error, otherwise such packet is ignored.
+    /// By default strict mode is disabled.
+    pub fn strict_packet_direction(self, val: bool) -> Self {
+        self.pool.strict_direction.set(val);
+        self
+    }
+
+    /// Reject connections without tls session
+    ///
+    /// Connection without tls session is closed before handshake service
+    /// is called. Tls session is detected with protocol query, tls filters
+    /// of `ntex-tls` answer it for every established session. Use
+    /// `require_tls_with()` for other tls filters.
+    /// By default plain connections are accepted.
+    pub fn require_tls(self, val: bool) -> Self {
+        *self.pool.require_tls.borrow_mut() = if val { Some(Rc::new(is_tls)) } else { None };
+        self
+    }
+
+    /// Reject connections that fail custom tls session check
+    ///
+    /// Check is called before handshake service, connection is rejected
+    /// the same way as with `require_tls()` if check returns `false`.
+    pub fn require_tls_with<F>(self, f: F) -> Self
+    where
+        F: Fn(&IoBoxed) -> bool + 'static,
+    {
+        *self.pool.require_tls.borrow_mut() = Some(Rc::new(f));
+        self
+    }
+
     /// Number of in-flight concurrent messages.
     ///
     /// By default in-flight is set to 16 messages
@@ -126,6 +192,7 @@ where
             inflight: self.inflight,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            handle: self.handle,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -146,6 +213,7 @@ where
             inflight: self.inflight,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            handle: self.handle,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -182,6 +250,7 @@ where
             factory(self.publish, self.control, self.inflight),
             self.disconnect_timeout,
         )
+        .server_handle(self.handle)
     }
 
     /// Set service to handle publish packets and create mqtt server factory
@@ -204,6 +273,7 @@ where
             handler: Rc::new(factory(self.publish, self.control, self.inflight)),
             max_size: self.max_size,
             disconnect_timeout: self.disconnect_timeout,
+            handle: self.handle,
             _t: PhantomData,
         }
     }
@@ -287,20 +357,12 @@ where
 
         let f = async move {
             // read first packet
-            let packet = io
-                .recv(&shared.codec)
-                .await
-                .map_err(|err| {
-                    log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::from(err)
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Disconnected(None)
-                })?;
+            let packet = recv_connect(&io, &shared).await?;
 
             match packet {
                 mqtt::Packet::Connect(connect) => {
+                    let keep_alive = connect.keep_alive;
+
                     // authenticate mqtt connection
                     let ack = service
                         .call(Handshake::new(connect, io, shared))
@@ -317,12 +379,11 @@ where
                             log::trace!("Sending success handshake ack: {:#?}", pkt);
 
                             ack.io.send(pkt, &ack.shared.codec).await?;
-                            Ok((
-                                ack.io,
-                                ack.shared.clone(),
-                                Session::new(session, MqttSink::new(ack.shared)),
-                                ack.keepalive,
-                            ))
+
+                            let session =
+                                Session::new(session, MqttSink::new(ack.shared.clone()));
+                            session.set_keepalive(keep_alive, keep_alive);
+                            Ok((ack.io, ack.shared, session, ack.keepalive))
                         }
                         None => {
                             let pkt = mqtt::Packet::ConnectAck {
@@ -358,10 +419,47 @@ where
     }
 }
 
+/// Read first packet of the connection
+///
+/// Empty client id with persistent session is rejected with
+/// `identifier rejected` return code [MQTT-3.1.3-8].
+pub(super) async fn recv_connect<E>(
+    io: &IoBoxed,
+    shared: &MqttShared,
+) -> Result<mqtt::Packet, MqttError<E>> {
+    match io.recv(&shared.codec).await {
+        Ok(Some(_)) if !shared.check_tls(io) => {
+            log::trace!("Connection without tls session is rejected");
+            let _ = io.shutdown().await;
+            Err(MqttError::Disconnected(None))
+        }
+        Ok(Some(packet)) => Ok(packet),
+        Ok(None) => {
+            log::trace!("Server mqtt is disconnected during handshake");
+            Err(MqttError::Disconnected(None))
+        }
+        Err(Either::Left(DecodeError::InvalidClientId)) => {
+            log::trace!("MQTT-3.1.3-8: Empty client id with persistent session");
+            let pkt = mqtt::Packet::ConnectAck {
+                session_present: false,
+                return_code: mqtt::ConnectAckReason::IdentifierRejected,
+            };
+            io.send(pkt, &shared.codec).await?;
+            let _ = io.shutdown().await;
+            Err(MqttError::Protocol(ProtocolError::Decode(DecodeError::InvalidClientId)))
+        }
+        Err(err) => {
+            log::trace!("Error is received during mqtt handshake: {:?}", err);
+            Err(MqttError::from(err))
+        }
+    }
+}
+
 pub(crate) struct ServerSelector<St, C, T, F, R> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: Seconds,
+    handle: Option<ServerHandle>,
     check: Rc<F>,
     max_size: u32,
     _t: PhantomData<(St, R)>,
@@ -392,6 +490,7 @@ where
         let fut = self.connect.new_service(());
         let handler = self.handler.clone();
         let disconnect_timeout = self.disconnect_timeout;
+        let handle = self.handle.clone();
         let check = self.check.clone();
         let max_size = self.max_size;
 
@@ -400,6 +499,7 @@ where
             Ok(ServerSelectorImpl {
                 handler,
                 disconnect_timeout,
+                handle,
                 check,
                 max_size,
                 connect: Rc::new(fut.await?),
@@ -414,6 +514,7 @@ pub(crate) struct ServerSelectorImpl<St, C, T, F, R> {
     connect: Rc<C>,
     handler: Rc<T>,
     disconnect_timeout: Seconds,
+    handle: Option<ServerHandle>,
     max_size: u32,
     _t: PhantomData<(St, R)>,
 }
@@ -454,7 +555,11 @@ where
         let check = self.check.clone();
         let connect = self.connect.clone();
         let handler = self.handler.clone();
-        let timeout = self.disconnect_timeout;
+        let timeout = self
+            .handle
+            .as_ref()
+            .map(|h| h.disconnect_timeout())
+            .unwrap_or(self.disconnect_timeout);
         let max_size = self.max_size;
 
         Box::pin(async move {
@@ -473,6 +578,8 @@ where
             if !result.map_err(MqttError::Service)? {
                 Ok(Either::Left((hnd, delay)))
             } else {
+                let keep_alive = hnd.packet().keep_alive;
+
                 // authenticate mqtt connection
                 let ack = if let Some(ref mut delay) = delay {
                     let fut = connect.call(hnd);
@@ -505,6 +612,7 @@ where
                         ack.io.send(pkt, &ack.shared.codec).await.map_err(MqttError::from)?;
 
                         let session = Session::new(session, MqttSink::new(ack.shared.clone()));
+                        session.set_keepalive(keep_alive, keep_alive);
                         let handler = handler.new_service(session).await?;
                         log::trace!("Connection handler is created, starting dispatcher");
 
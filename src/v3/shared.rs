@@ -2,11 +2,11 @@ use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc:
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
-use ntex::io::IoRef;
+use ntex::io::{IoBoxed, IoRef};
 use ntex::util::{BytesMut, HashMap, PoolId, PoolRef};
 
 use crate::error::{DecodeError, EncodeError};
-use crate::{types::packet_type, v3::codec};
+use crate::{io::TlsCheck, types::packet_type, v3::codec};
 
 pub(super) enum Ack {
     Publish(NonZeroU16),
@@ -25,6 +25,10 @@ pub(super) struct MqttSinkPool {
     pub(super) queue: pool::Pool<Ack>,
     pub(super) waiters: pool::Pool<()>,
     pub(super) pool: Cell<PoolRef>,
+    pub(super) max_connect_size: Cell<u32>,
+    pub(super) strict_direction: Cell<bool>,
+    pub(super) require_tls: RefCell<Option<TlsCheck>>,
+    pub(super) custom_packets: Cell<bool>,
 }
 
 impl Default for MqttSinkPool {
@@ -33,6 +37,10 @@ impl Default for MqttSinkPool {
             queue: pool::new(),
             waiters: pool::new(),
             pool: Cell::new(PoolId::P5.pool_ref()),
+            max_connect_size: Cell::new(0),
+            strict_direction: Cell::new(false),
+            require_tls: RefCell::new(None),
+            custom_packets: Cell::new(false),
         }
     }
 }
@@ -59,6 +67,8 @@ impl MqttShared {
         cap: usize,
         pool: Rc<MqttSinkPool>,
     ) -> Self {
+        codec.set_max_connect_size(pool.max_connect_size.get());
+        codec.set_custom_packets(pool.custom_packets.get());
         Self {
             io,
             pool,
@@ -73,6 +83,11 @@ impl MqttShared {
         }
     }
 
+    /// Check tls session of connection, if server requires tls
+    pub(super) fn check_tls(&self, io: &IoBoxed) -> bool {
+        self.pool.require_tls.borrow().as_ref().map_or(true, |f| (*f)(io))
+    }
+
     pub(super) fn with_queues<R>(&self, f: impl FnOnce(&mut MqttSharedQueues) -> R) -> R {
         let mut queues = self.queues.borrow_mut();
         f(&mut queues)
@@ -24,6 +24,14 @@ impl MqttSink {
         self.0.cap.get() - self.0.with_queues(|q| q.inflight.len())
     }
 
+    /// Number of bytes queued in connection's write buffer
+    ///
+    /// Write buffer is flushed to the peer by io write task, large value
+    /// indicates that peer does not keep up with sent packets.
+    pub fn buffered_bytes(&self) -> usize {
+        self.0.io.with_write_buf(|buf| buf.len()).unwrap_or(0)
+    }
+
     /// Get notification when packet could be send to the peer.
     ///
     /// Result indicates if connection is alive
@@ -64,6 +72,11 @@ impl MqttSink {
         });
     }
 
+    /// Check if packets of wrong direction are treated as protocol violation
+    pub(super) fn strict_packet_direction(&self) -> bool {
+        self.0.pool.strict_direction.get()
+    }
+
     /// Send ping
     pub(super) fn ping(&self) -> bool {
         self.0.io.encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
@@ -84,6 +97,18 @@ impl MqttSink {
         }
     }
 
+    /// Create publish packet builder for will message
+    ///
+    /// Topic, payload and retain flag of the will are applied,
+    /// will message should be sent with QoS of the will. Server does not publish
+    /// will messages itself, control service could publish will to subscribers
+    /// on `ControlMessage::Closed` if connection is not closed cleanly.
+    pub fn publish_will(&self, will: &codec::LastWill) -> PublishBuilder {
+        let mut builder = self.publish(will.topic.clone(), will.message.clone());
+        builder.packet.retain = will.retain;
+        builder
+    }
+
     /// Create subscribe packet builder
     ///
     /// panics if id is 0
@@ -169,6 +194,9 @@ impl PublishBuilder {
     }
 
     /// this might be re-delivery of an earlier attempt to send the Packet.
+    ///
+    /// DUP flag is managed by sink, method is available with `testing` feature.
+    #[cfg(any(test, feature = "testing"))]
     pub fn dup(mut self, val: bool) -> Self {
         self.packet.dup = val;
         self
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Write budget of the server
+///
+/// Tracks total size of write buffers of server connections. Cloned handle
+/// refers to the same budget, so the same handle could be used by all server workers.
+#[derive(Clone, Debug)]
+pub struct WriteBudget(Arc<BudgetInner>);
+
+#[derive(Debug)]
+struct BudgetInner {
+    size: usize,
+    used: AtomicUsize,
+    // dispatchers paused until total size drops to budget
+    waiters: Mutex<Vec<Waker>>,
+}
+
+impl WriteBudget {
+    /// Create new write budget handle
+    pub fn new(size: usize) -> Self {
+        WriteBudget(Arc::new(BudgetInner {
+            size,
+            used: AtomicUsize::new(0),
+            waiters: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// Budget size
+    pub fn size(&self) -> usize {
+        self.0.size
+    }
+
+    /// Total size of write buffers of all connections
+    pub fn used(&self) -> usize {
+        self.0.used.load(Ordering::SeqCst)
+    }
+
+    fn is_exceeded(&self) -> bool {
+        self.used() > self.0.size
+    }
+
+    /// Replace accounted size of connection's write buffer
+    pub(super) fn update(&self, prev: usize, size: usize) {
+        if size > prev {
+            self.0.used.fetch_add(size - prev, Ordering::SeqCst);
+        } else if size < prev {
+            self.0.used.fetch_sub(prev - size, Ordering::SeqCst);
+            if !self.is_exceeded() {
+                let waiters = std::mem::take(&mut *self.0.waiters.lock().unwrap());
+                for waker in waiters {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    /// Check budget, dispatcher gets woken up when total size drops to budget
+    pub(super) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_exceeded() {
+            return Poll::Ready(());
+        }
+
+        // check again under lock, connection could drain its buffer concurrently
+        let mut waiters = self.0.waiters.lock().unwrap();
+        if self.is_exceeded() {
+            if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::util::lazy;
+
+    #[ntex::test]
+    async fn test_write_budget() {
+        let budget = WriteBudget::new(10);
+        let budget2 = budget.clone();
+
+        budget.update(0, 8);
+        budget2.update(0, 4);
+        assert_eq!(budget.used(), 12);
+        assert!(lazy(|cx| budget.poll_ready(cx)).await.is_pending());
+
+        budget2.update(4, 2);
+        assert_eq!(budget.used(), 10);
+        assert!(budget.0.waiters.lock().unwrap().is_empty());
+        assert!(lazy(|cx| budget2.poll_ready(cx)).await.is_ready());
+
+        budget.update(8, 0);
+        budget2.update(2, 0);
+        assert_eq!(budget.used(), 0);
+    }
+}
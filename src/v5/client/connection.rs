@@ -65,6 +65,13 @@ impl Client {
         MqttSink::new(self.shared.clone())
     }
 
+    /// Get notification when all in-flight subscribe packets get acknowledged.
+    ///
+    /// Result indicates if connection is alive
+    pub fn wait_subscriptions_ready(&self) -> impl Future<Output = bool> {
+        MqttSink::new(self.shared.clone()).wait_subscriptions_ready()
+    }
+
     #[inline]
     /// Indicates whether there is already stored Session state
     pub fn session_present(&self) -> bool {
@@ -126,7 +133,38 @@ impl Client {
             }),
         );
 
-        let _ = Dispatcher::new(self.io, self.shared, dispatcher)
+        let _ = Dispatcher::new(self.io, self.shared.clone(), dispatcher)
+            .flush_wakeup(self.shared.flush_check())
+            .keepalive_timeout(Seconds::ZERO)
+            .disconnect_timeout(self.disconnect_timeout)
+            .await;
+    }
+
+    /// Run client with default control messages handler, every publish is
+    /// passed to `f` and acknowledged with `Success` reason code.
+    pub(super) async fn start_publish<F>(self, f: F)
+    where
+        F: Fn(Publish) + 'static,
+    {
+        if self.keepalive.non_zero() {
+            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+        }
+
+        let dispatcher = create_dispatcher(
+            MqttSink::new(self.shared.clone()),
+            self.max_receive,
+            16,
+            into_service(move |pkt: Publish| {
+                f(pkt);
+                Ready::Ok(Either::Right(PublishAck::new(codec::PublishAckReason::Success)))
+            }),
+            into_service(|msg: ControlMessage<()>| {
+                Ready::Ok(msg.disconnect(codec::Disconnect::default()))
+            }),
+        );
+
+        let _ = Dispatcher::new(self.io, self.shared.clone(), dispatcher)
+            .flush_wakeup(self.shared.flush_check())
             .keepalive_timeout(Seconds::ZERO)
             .disconnect_timeout(self.disconnect_timeout)
             .await;
@@ -151,7 +189,8 @@ impl Client {
             service.into_service(),
         );
 
-        Dispatcher::new(self.io, self.shared, dispatcher)
+        Dispatcher::new(self.io, self.shared.clone(), dispatcher)
+            .flush_wakeup(self.shared.flush_check())
             .keepalive_timeout(Seconds::ZERO)
             .disconnect_timeout(self.disconnect_timeout)
             .await
@@ -216,7 +255,8 @@ where
             }),
         );
 
-        let _ = Dispatcher::new(self.io, self.shared, dispatcher)
+        let _ = Dispatcher::new(self.io, self.shared.clone(), dispatcher)
+            .flush_wakeup(self.shared.flush_check())
             .keepalive_timeout(Seconds::ZERO)
             .disconnect_timeout(self.disconnect_timeout)
             .await;
@@ -240,7 +280,8 @@ where
             service.into_service(),
         );
 
-        Dispatcher::new(self.io, self.shared, dispatcher)
+        Dispatcher::new(self.io, self.shared.clone(), dispatcher)
+            .flush_wakeup(self.shared.flush_check())
             .keepalive_timeout(Seconds::ZERO)
             .disconnect_timeout(self.disconnect_timeout)
             .await
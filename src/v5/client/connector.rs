@@ -9,6 +9,24 @@ use ntex::util::{ByteString, Bytes, PoolId};
 use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
 use crate::v5::shared::{MqttShared, MqttSinkPool};
 
+/// Enhanced authentication handler
+///
+/// Handler processes `AUTH` packets received from server during handshake
+/// and returns next `AUTH` packet to send.
+pub trait AuthHandler {
+    /// Process server's `AUTH` packet and return response
+    fn handle(&self, pkt: codec::Auth) -> Result<codec::Auth, ClientError>;
+}
+
+impl<F> AuthHandler for F
+where
+    F: Fn(codec::Auth) -> Result<codec::Auth, ClientError>,
+{
+    fn handle(&self, pkt: codec::Auth) -> Result<codec::Auth, ClientError> {
+        (self)(pkt)
+    }
+}
+
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
     address: A,
@@ -16,6 +34,7 @@ pub struct MqttConnector<A, T> {
     pkt: codec::Connect,
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
+    auth: Option<Rc<dyn AuthHandler>>,
     pool: Rc<MqttSinkPool>,
 }
 
@@ -32,6 +51,7 @@ where
             connector: Connector::default(),
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
+            auth: None,
             pool: Rc::new(MqttSinkPool::default()),
         }
     }
@@ -84,6 +104,19 @@ where
         self
     }
 
+    /// Set auth-method and initial auth-data for enhanced authentication.
+    ///
+    /// Handler processes `AUTH` packets from server until `CONNECT-ACK` is received.
+    pub fn enhanced_auth<H>(mut self, method: ByteString, data: Bytes, handler: H) -> Self
+    where
+        H: AuthHandler + 'static,
+    {
+        self.pkt.auth_method = Some(method);
+        self.pkt.auth_data = Some(data);
+        self.auth = Some(Rc::new(handler));
+        self
+    }
+
     #[inline]
     /// Username can be used by the Server for authentication and authorization.
     pub fn username(mut self, val: ByteString) -> Self {
@@ -148,6 +181,8 @@ where
     /// Set handshake timeout.
     ///
     /// Handshake includes `connect` packet and response `connect-ack`.
+    /// If `connect-ack` packet is not received in time, connection is closed
+    /// and connect fails with `ClientError::HandshakeTimeout` error.
     /// By default handshake timeuot is disabled.
     pub fn handshake_timeout(mut self, timeout: Seconds) -> Self {
         self.handshake_timeout = timeout;
@@ -189,6 +224,7 @@ where
             address: self.address,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            auth: self.auth,
             pool: self.pool,
         }
     }
@@ -218,6 +254,7 @@ where
         let max_packet_size = pkt.max_packet_size.map(|v| v.get()).unwrap_or(0);
         let max_receive = pkt.receive_max.map(|v| v.get()).unwrap_or(0);
         let disconnect_timeout = self.disconnect_timeout;
+        let auth = self.auth.clone();
         let pool = self.pool.clone();
 
         async move {
@@ -226,11 +263,23 @@ where
 
             io.send(codec::Packet::Connect(Box::new(pkt)), &codec).await?;
 
-            let packet =
-                io.recv(&codec).await.map_err(ClientError::from)?.ok_or_else(|| {
-                    log::trace!("Mqtt server is disconnected during handshake");
-                    ClientError::Disconnected(None)
-                })?;
+            let packet = loop {
+                let packet =
+                    io.recv(&codec).await.map_err(ClientError::from)?.ok_or_else(|| {
+                        log::trace!("Mqtt server is disconnected during handshake");
+                        ClientError::Disconnected(None)
+                    })?;
+
+                // enhanced authentication exchange
+                match (packet, &auth) {
+                    (codec::Packet::Auth(pkt), Some(auth)) => {
+                        log::trace!("Auth packet from server: {:#?}", pkt);
+                        let pkt = auth.handle(pkt)?;
+                        io.send(codec::Packet::Auth(pkt), &codec).await?;
+                    }
+                    (packet, _) => break packet,
+                }
+            };
 
             let shared = Rc::new(MqttShared::new(io.get_ref(), codec, 0, pool));
 
@@ -28,11 +28,12 @@ impl<E> ControlMessage<E> {
     }
 
     pub(super) fn dis(pkt: codec::Disconnect) -> Self {
-        ControlMessage::Disconnect(Disconnect(pkt))
+        let session_expiry = pkt.session_expiry_interval_secs.unwrap_or(0);
+        ControlMessage::Disconnect(Disconnect(pkt, session_expiry))
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn closed(is_error: bool, reason: Option<codec::DisconnectReasonCode>) -> Self {
+        ControlMessage::Closed(Closed::new(is_error).with_reason(reason))
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -1,10 +1,12 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::task::{Context, Poll};
+use std::time::Instant;
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::io::DispatchItem;
 use ntex::service::Service;
-use ntex::util::{buffer::BufferService, inflight::InFlightService, Either, HashSet, Ready};
+use ntex::util::{buffer::BufferService, inflight::InFlightService, ByteString, Either};
+use ntex::util::{HashMap, HashSet, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::types::packet_type;
@@ -42,6 +44,8 @@ pub(crate) struct Dispatcher<T, C: Service<ControlMessage<E>>, E> {
     shutdown: RefCell<Option<Pin<Box<C::Future>>>>,
     max_receive: usize,
     max_topic_alias: u16,
+    // reason code of received DISCONNECT packet
+    disconnected: Cell<Option<codec::DisconnectReasonCode>>,
     inner: Rc<Inner<C>>,
     _t: PhantomData<E>,
 }
@@ -54,7 +58,7 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<NonZeroU16>,
-    aliases: HashSet<NonZeroU16>,
+    aliases: HashMap<NonZeroU16, ByteString>,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -74,11 +78,12 @@ where
             max_receive,
             max_topic_alias,
             shutdown: RefCell::new(None),
+            disconnected: Cell::new(None),
             inner: Rc::new(Inner {
                 control,
                 sink,
                 info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
+                    aliases: HashMap::default(),
                     inflight: HashSet::default(),
                 }),
             }),
@@ -101,6 +106,9 @@ where
     >;
 
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // queued publishes
+        self.inner.sink.poll_queue(cx);
+
         let res1 = self.publish.poll_ready(cx).map_err(MqttError::Service)?;
         let res2 = self.inner.control.poll_ready(cx)?;
 
@@ -115,8 +123,11 @@ where
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
             self.inner.sink.drop_sink();
-            *shutdown =
-                Some(Box::pin(self.inner.control.call(ControlMessage::closed(is_error))));
+            *shutdown = Some(Box::pin(
+                self.inner
+                    .control
+                    .call(ControlMessage::closed(is_error, self.disconnected.get())),
+            ));
         }
 
         let res0 = shutdown.as_mut().expect("guard above").as_mut().poll(cx);
@@ -133,7 +144,8 @@ where
         log::trace!("Dispatch packet: {:#?}", request);
 
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
+                let received_at = Instant::now();
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
 
@@ -173,7 +185,7 @@ where
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if !inner.aliases.contains_key(&alias) {
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -190,16 +202,39 @@ where
                             }
 
                             // record new alias
-                            inner.aliases.insert(alias);
+                            inner.aliases.insert(alias, publish.topic.clone());
                         }
                     }
+
+                    // one-shot subscriptions, see `MqttSink::take_one()`
+                    let topic = match publish.properties.topic_alias {
+                        Some(ref alias) if publish.topic.is_empty() => &inner.aliases[alias],
+                        _ => &publish.topic,
+                    };
+                    if let Some(tx) = self.inner.sink.take_one_sender(topic) {
+                        publish.topic = topic.clone();
+                        let _ = tx.send(Publish::with_received_at(publish, received_at));
+
+                        return if let Some(pid) = packet_id {
+                            inner.inflight.remove(&pid);
+                            Either::Right(Either::Left(Ready::Ok(Some(
+                                codec::Packet::PublishAck(codec::PublishAck {
+                                    packet_id: pid,
+                                    reason_code: codec::PublishAckReason::Success,
+                                    ..Default::default()
+                                }),
+                            ))))
+                        } else {
+                            Either::Right(Either::Left(Ready::Ok(None)))
+                        };
+                    }
                 }
 
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
                     inner: info,
                     state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
+                        fut: self.publish.call(Publish::with_received_at(publish, received_at)),
                     },
                     _t: PhantomData,
                 })
@@ -214,6 +249,26 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived(packet)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Receive(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete(packet)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Complete(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
             DispatchItem::Item(codec::Packet::SubscribeAck(packet)) => {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Subscribe(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
@@ -237,9 +292,13 @@ where
             DispatchItem::Item(codec::Packet::PingRequest) => {
                 Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
             }
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::dis(pkt), &self.inner),
-            )),
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                self.disconnected.set(Some(pkt.reason_code));
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::dis(pkt),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(codec::Packet::Auth(_)) => {
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::proto_error(ProtocolError::Unexpected(
@@ -4,11 +4,14 @@ mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod pool;
 
 pub use self::connection::{Client, ClientRouter};
-pub use self::connector::MqttConnector;
+pub use self::connector::{AuthHandler, MqttConnector};
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::pool::ClientPool;
 
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
-pub use crate::v5::{codec, error, sink::MqttSink};
+pub use crate::v5::sink::{FlushPolicy, MqttSink, OverflowPolicy, PubAck};
+pub use crate::v5::{codec, error};
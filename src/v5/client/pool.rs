@@ -0,0 +1,109 @@
+use std::task::{Context, Poll};
+use std::{fmt, hash::Hash, pin::Pin};
+
+use ntex::channel::mpsc;
+use ntex::util::{stream_recv, ByteString, Bytes, HashMap, Stream};
+
+use crate::v5::publish::Publish;
+use crate::v5::sink::{MqttSink, PublishBuilder};
+
+use super::connection::Client;
+
+/// Pool of mqtt client connections
+///
+/// Pool runs added clients and multiplexes their incoming publishes into
+/// a single stream, every publish is tagged with id of the source connection.
+/// Received publishes are acknowledged by the pool.
+pub struct ClientPool<K> {
+    sinks: HashMap<K, MqttSink>,
+    tx: mpsc::Sender<(K, Publish)>,
+    rx: mpsc::Receiver<(K, Publish)>,
+}
+
+impl<K: fmt::Debug> fmt::Debug for ClientPool<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("v5::ClientPool").field("clients", &self.sinks.keys()).finish()
+    }
+}
+
+impl<K> Default for ClientPool<K>
+where
+    K: Clone + Eq + Hash + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> ClientPool<K>
+where
+    K: Clone + Eq + Hash + 'static,
+{
+    /// Create empty pool
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        ClientPool { tx, rx, sinks: HashMap::default() }
+    }
+
+    /// Add client to the pool and start it
+    ///
+    /// Client with the same id is replaced, connection of replaced
+    /// client gets closed.
+    pub fn add(&mut self, id: K, client: Client) {
+        if let Some(sink) = self.sinks.insert(id.clone(), client.sink()) {
+            sink.close();
+        }
+
+        let tx = self.tx.clone();
+        ntex::rt::spawn(client.start_publish(move |pkt| {
+            let _ = tx.send((id.clone(), pkt));
+        }));
+    }
+
+    /// Remove client from the pool and close its connection
+    pub fn remove(&mut self, id: &K) -> Option<MqttSink> {
+        let sink = self.sinks.remove(id);
+        if let Some(ref sink) = sink {
+            sink.close();
+        }
+        sink
+    }
+
+    /// Get sink of the client
+    pub fn sink(&self, id: &K) -> Option<&MqttSink> {
+        self.sinks.get(id)
+    }
+
+    /// Create publish packet builder for the client
+    ///
+    /// Returns `None` if client with provided id does not exist.
+    pub fn publish<U>(&self, id: &K, topic: U, payload: Bytes) -> Option<PublishBuilder>
+    where
+        ByteString: From<U>,
+    {
+        self.sinks.get(id).map(|sink| sink.publish(topic, payload))
+    }
+
+    /// Number of clients in the pool
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    /// Check if pool is empty
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Receive next publish from any client
+    pub async fn recv(&mut self) -> Option<(K, Publish)> {
+        stream_recv(&mut self.rx).await
+    }
+}
+
+impl<K> Stream for ClientPool<K> {
+    type Item = (K, Publish);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
@@ -1,24 +1,32 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
 
 use ntex::codec::{Decoder, Encoder};
 use ntex::util::{Buf, BytesMut};
 
-use super::{decode::decode_packet, encode::EncodeLtd, Packet};
+use super::{decode::decode_packet, encode::EncodeLtd, Packet, Subscribe};
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, MAX_PACKET_SIZE};
+use crate::types::{packet_type, FixedHeader, MAX_PACKET_SIZE};
 use crate::utils::decode_variable_length;
 
 #[derive(Debug)]
 pub struct Codec {
     state: Cell<DecodeState>,
     max_in_size: Cell<u32>,
+    max_connect_size: Cell<u32>,
     max_out_size: Cell<u32>,
+    max_sub_filters: Cell<usize>,
+    capture_size: Cell<usize>,
+    captured: RefCell<Option<String>>,
+    header: Cell<([u8; 5], usize)>,
     flags: Cell<CodecFlags>,
 }
 
 bitflags::bitflags! {
     pub struct CodecFlags: u8 {
         const NO_PROBLEM_INFO = 0b0000_0001;
+        const LENIENT_UTF8    = 0b0000_0010;
+        const CUSTOM_PACKETS  = 0b0000_0100;
     }
 }
 
@@ -34,7 +42,12 @@ impl Codec {
         Codec {
             state: Cell::new(DecodeState::FrameHeader),
             max_in_size: Cell::new(0),
+            max_connect_size: Cell::new(0),
             max_out_size: Cell::new(0),
+            max_sub_filters: Cell::new(0),
+            capture_size: Cell::new(0),
+            captured: RefCell::new(None),
+            header: Cell::new(([0; 5], 0)),
             flags: Cell::new(CodecFlags::empty()),
         }
     }
@@ -61,6 +74,28 @@ impl Codec {
         self
     }
 
+    /// Set max number of topic filters in inbound SUBSCRIBE packet.
+    ///
+    /// If max number is set to `0`, number of filters is unlimited.
+    /// By default max number is set to `0`
+    pub fn max_subscribe_filters(self, max: usize) -> Self {
+        self.max_sub_filters.set(max);
+        self
+    }
+
+    /// Decode reason strings and user properties with invalid utf-8 sequences.
+    ///
+    /// In strict mode packet with invalid utf-8 string fails to decode
+    /// with `DecodeError::Utf8Error`. In lenient mode invalid sequences of
+    /// reason strings, content types and user properties get replaced with `U+FFFD`.
+    /// Topic names, response topics, topic filters, client ids and other strings
+    /// are always decoded strictly, replaced sequences would change topic of delivery.
+    /// By default strict mode is used
+    pub fn lenient_utf8(self, val: bool) -> Self {
+        self.set_lenient_utf8(val);
+        self
+    }
+
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -69,6 +104,25 @@ impl Codec {
         self.max_in_size.set(size);
     }
 
+    /// Set max inbound CONNECT packet size.
+    ///
+    /// Size is checked before packet is buffered.
+    /// If max size is set to `0`, size is limited by max inbound frame size only.
+    /// By default max size is set to `0`
+    pub fn set_max_connect_size(&self, size: u32) {
+        self.max_connect_size.set(size);
+    }
+
+    /// Decode packets with reserved packet types
+    ///
+    /// Such packets are decoded as `Packet::Custom` instead of producing decode error.
+    /// By default custom packets are disabled
+    pub fn set_custom_packets(&self, val: bool) {
+        let mut flags = self.flags.get();
+        flags.set(CodecFlags::CUSTOM_PACKETS, val);
+        self.flags.set(flags);
+    }
+
     /// Set max outbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -76,6 +130,51 @@ impl Codec {
     pub fn set_max_outbound_size(&self, size: u32) {
         self.max_out_size.set(size);
     }
+
+    /// Set max number of topic filters in inbound SUBSCRIBE packet.
+    ///
+    /// If max number is set to `0`, number of filters is unlimited.
+    /// By default max number is set to `0`
+    pub fn set_max_subscribe_filters(&self, max: usize) {
+        self.max_sub_filters.set(max);
+    }
+
+    /// Decode reason strings and user properties with invalid utf-8 sequences.
+    ///
+    /// In lenient mode invalid sequences of reason strings, content types
+    /// and user properties get replaced with `U+FFFD`.
+    /// By default strict mode is used
+    pub fn set_lenient_utf8(&self, val: bool) {
+        let mut flags = self.flags.get();
+        flags.set(CodecFlags::LENIENT_UTF8, val);
+        self.flags.set(flags);
+    }
+
+    /// Set max number of captured bytes of malformed packet.
+    ///
+    /// Hex dump of the beginning of packet that failed to decode,
+    /// starting with fixed header bytes, is available via `take_decode_input()`.
+    /// If max number is set to `0`, packets are not captured.
+    /// By default max number is set to `0`
+    pub fn set_decode_capture_size(&self, size: usize) {
+        self.capture_size.set(size);
+    }
+
+    /// Take hex dump of the beginning of last malformed packet
+    pub fn take_decode_input(&self) -> Option<String> {
+        self.captured.borrow_mut().take()
+    }
+
+    fn capture(&self, header: &[u8], packet: &[u8]) {
+        let size = self.capture_size.get();
+        if size != 0 {
+            let mut dump = String::with_capacity(size * 2);
+            for b in header.iter().chain(packet.iter()).take(size) {
+                let _ = write!(dump, "{:02x}", b);
+            }
+            *self.captured.borrow_mut() = Some(dump);
+        }
+    }
 }
 
 impl Default for Codec {
@@ -97,7 +196,11 @@ impl Decoder for Codec {
                     }
                     let src_slice = src.as_ref();
                     let first_byte = src_slice[0];
-                    match decode_variable_length(&src_slice[1..])? {
+                    let len = decode_variable_length(&src_slice[1..]).map_err(|err| {
+                        self.capture(src_slice, &[]);
+                        err
+                    })?;
+                    match len {
                         Some((remaining_length, consumed)) => {
                             // check max message size
                             let max_in_size = self.max_in_size.get();
@@ -107,8 +210,26 @@ impl Decoder for Codec {
                                     max_in_size,
                                     remaining_length
                                 );
+                                self.capture(src_slice, &[]);
+                                return Err(DecodeError::MaxSizeExceeded);
+                            }
+                            let max_connect_size = self.max_connect_size.get();
+                            if first_byte == packet_type::CONNECT
+                                && max_connect_size != 0
+                                && max_connect_size < remaining_length
+                            {
+                                log::debug!(
+                                    "MaxSizeExceeded max-connect-size: {}, remaining: {}",
+                                    max_connect_size,
+                                    remaining_length
+                                );
+                                self.capture(src_slice, &[]);
                                 return Err(DecodeError::MaxSizeExceeded);
                             }
+                            // keep fixed header bytes for capture of malformed packet
+                            let mut header = [0; 5];
+                            header[..consumed + 1].copy_from_slice(&src_slice[..consumed + 1]);
+                            self.header.set((header, consumed + 1));
                             src.advance(consumed + 1);
                             self.state.set(DecodeState::Frame(FixedHeader {
                                 first_byte,
@@ -132,7 +253,30 @@ impl Decoder for Codec {
                         return Ok(None);
                     }
                     let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
-                    let packet = decode_packet(packet_buf, fixed.first_byte)?;
+                    let max_filters = self.max_sub_filters.get();
+                    let lenient = self.flags.get().contains(CodecFlags::LENIENT_UTF8);
+                    let result = if fixed.first_byte == packet_type::SUBSCRIBE
+                        && max_filters != 0
+                    {
+                        // check limit before all topic filters get decoded
+                        Subscribe::decode_limited(&mut packet_buf.clone(), max_filters, lenient)
+                            .map(Packet::Subscribe)
+                    } else {
+                        decode_packet(packet_buf.clone(), fixed.first_byte, lenient)
+                    };
+                    let packet = match result {
+                        Ok(packet) => packet,
+                        Err(DecodeError::UnsupportedPacketType)
+                            if self.flags.get().contains(CodecFlags::CUSTOM_PACKETS) =>
+                        {
+                            Packet::Custom(fixed.first_byte, packet_buf)
+                        }
+                        Err(err) => {
+                            let (header, len) = self.header.get();
+                            self.capture(&header[..len], &packet_buf);
+                            return Err(err);
+                        }
+                    };
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(5); // enough to fix 1 fixed header byte + 4 bytes max variable packet length
 
@@ -200,7 +344,23 @@ impl Encoder for Codec {
 
 #[cfg(test)]
 mod tests {
+    use ntex::util::{ByteString, Bytes};
+    use std::num::{NonZeroU16, NonZeroU32};
+
     use super::*;
+    use crate::types::QoS;
+    use crate::v5::codec::{
+        Connect, ConnectAck, Disconnect, LastWill, Publish, PublishProperties, Subscribe,
+        SubscriptionOptions,
+    };
+
+    fn round_trip(pkt: Packet) {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), pkt);
+        assert!(buf.is_empty());
+    }
 
     #[test]
     fn test_max_size() {
@@ -209,4 +369,322 @@ mod tests {
         buf.extend_from_slice(b"\0\x09");
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
+
+    #[test]
+    fn test_max_connect_size() {
+        let codec = Codec::new();
+        codec.set_max_connect_size(5);
+
+        // only header of oversized connect packet is received
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x10\xff\x7f");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+
+        // limit does not apply to other packets
+        let codec = Codec::new();
+        codec.set_max_connect_size(5);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\x09");
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+    }
+
+    #[test]
+    fn test_custom_packets() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x00\x03abc\xc0\x00");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::UnsupportedPacketType));
+
+        let codec = Codec::new();
+        codec.set_custom_packets(true);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x00\x03abc\xc0\x00");
+        let pkt = Packet::Custom(0x00, Bytes::from_static(b"abc"));
+        assert_eq!(codec.decode(&mut buf), Ok(Some(pkt.clone())));
+        assert_eq!(codec.decode(&mut buf), Ok(Some(Packet::PingRequest)));
+
+        let mut buf = BytesMut::new();
+        codec.encode(pkt, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"\x00\x03abc");
+    }
+
+    #[test]
+    fn test_max_subscribe_filters() {
+        let pkt = b"\x82\x12\x12\x34\x00\x00\x02t1\x01\x00\x02t2\x01\x00\x02t3\x01";
+
+        let codec = Codec::new().max_subscribe_filters(3);
+        let mut buf = BytesMut::from(&pkt[..]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Packet::Subscribe(pkt))) => assert_eq!(pkt.topic_filters.len(), 3),
+            _ => panic!("expected subscribe packet"),
+        }
+
+        let codec = Codec::new().max_subscribe_filters(2);
+        let mut buf = BytesMut::from(&pkt[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+    }
+
+    #[test]
+    fn test_lenient_utf8() {
+        // PUBACK with invalid utf-8 value of user property
+        let pkt = b"\x40\x0c\x00\x01\x00\x08\x26\x00\x01k\x00\x02\xc3\x28";
+
+        let codec = Codec::new();
+        let mut buf = BytesMut::from(&pkt[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::Utf8Error));
+
+        let codec = Codec::new().lenient_utf8(true);
+        let mut buf = BytesMut::from(&pkt[..]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Packet::PublishAck(pkt))) => assert_eq!(
+                pkt.properties,
+                vec![(ByteString::from_static("k"), ByteString::from_static("\u{FFFD}("))]
+            ),
+            res => panic!("expected puback packet, got {:?}", res),
+        }
+
+        // valid strings are not affected
+        let mut buf = BytesMut::from(&b"\x40\x0b\x00\x01\x00\x07\x26\x00\x01k\x00\x01v"[..]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Packet::PublishAck(pkt))) => assert_eq!(
+                pkt.properties,
+                vec![(ByteString::from_static("k"), ByteString::from_static("v"))]
+            ),
+            res => panic!("expected puback packet, got {:?}", res),
+        }
+
+        // topic name is decoded strictly
+        let mut buf = BytesMut::from(&b"\x30\x06\x00\x02\xc3\x28\x00p"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::Utf8Error));
+
+        // content type of PUBLISH
+        let mut buf = BytesMut::from(&b"\x30\x09\x00\x01t\x05\x03\x00\x02\xc3\x28"[..]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Packet::Publish(pkt))) => assert_eq!(
+                pkt.properties.content_type,
+                Some(ByteString::from_static("\u{FFFD}("))
+            ),
+            res => panic!("expected publish packet, got {:?}", res),
+        }
+
+        // response topic is a topic name, it is decoded strictly
+        let mut buf = BytesMut::from(&b"\x30\x09\x00\x01t\x05\x08\x00\x02\xc3\x28"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::Utf8Error));
+    }
+
+    #[test]
+    fn test_decode_capture() {
+        // PUBACK with packet id 0
+        let pkt = b"\x40\x06\x00\x00\x00\x00\x00\x00";
+
+        let codec = Codec::new();
+        let mut buf = BytesMut::from(&pkt[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MalformedPacket));
+        assert_eq!(codec.take_decode_input(), None);
+
+        let codec = Codec::new();
+        codec.set_decode_capture_size(4);
+        let mut buf = BytesMut::from(&pkt[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MalformedPacket));
+        assert_eq!(codec.take_decode_input().as_deref(), Some("40060000"));
+        assert_eq!(codec.take_decode_input(), None);
+
+        // multi-byte remaining length is captured as is
+        let mut pkt = b"\x40\x83\x00\x00\x00\x00".to_vec();
+        pkt.extend_from_slice(&[0; 3]);
+        let codec = Codec::new();
+        codec.set_decode_capture_size(4);
+        let mut buf = BytesMut::from(&pkt[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MalformedPacket));
+        assert_eq!(codec.take_decode_input().as_deref(), Some("40830000"));
+
+        // fixed header errors
+        let codec = Codec::new().max_inbound_size(2);
+        codec.set_decode_capture_size(4);
+        let mut buf = BytesMut::from(&pkt[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+        assert_eq!(codec.take_decode_input().as_deref(), Some("40830000"));
+
+        let mut buf = BytesMut::from(&b"\x30\xff\xff\xff\xff\x01"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidLength));
+        assert_eq!(codec.take_decode_input().as_deref(), Some("30ffffff"));
+    }
+
+    #[test]
+    fn test_connect_will_properties() {
+        let pkt = Packet::Connect(Box::new(Connect {
+            last_will: Some(LastWill {
+                qos: QoS::AtLeastOnce,
+                retain: true,
+                topic: ByteString::from_static("will/topic"),
+                message: Bytes::from_static(b"bye"),
+                will_delay_interval_sec: Some(30),
+                correlation_data: Some(Bytes::from_static(b"corr")),
+                message_expiry_interval: NonZeroU32::new(60),
+                content_type: Some(ByteString::from_static("text/plain")),
+                user_properties: vec![(
+                    ByteString::from_static("key"),
+                    ByteString::from_static("value"),
+                )],
+                is_utf8_payload: Some(true),
+                response_topic: Some(ByteString::from_static("will/resp")),
+            }),
+            ..Connect::default().client_id("user")
+        }));
+
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded, pkt);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_will_payload_zero_copy() {
+        let payload = Bytes::from(vec![0x5a; 64 * 1024]);
+        let pkt = Packet::Connect(Box::new(Connect {
+            last_will: Some(LastWill {
+                topic: ByteString::from_static("will/topic"),
+                message: payload.clone(),
+                qos: QoS::AtMostOnce,
+                retain: false,
+                will_delay_interval_sec: None,
+                correlation_data: None,
+                message_expiry_interval: None,
+                content_type: None,
+                user_properties: Vec::new(),
+                is_utf8_payload: None,
+                response_topic: None,
+            }),
+            ..Connect::default().client_id("user")
+        }));
+
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(pkt, &mut buf).unwrap();
+        let range = buf.as_ptr() as usize..buf.as_ptr() as usize + buf.len();
+
+        let will = match codec.decode(&mut buf).unwrap().unwrap() {
+            Packet::Connect(pkt) => pkt.last_will.unwrap(),
+            _ => panic!("expected connect packet"),
+        };
+        // payload refers to read buffer memory
+        assert!(range.contains(&(will.message.as_ptr() as usize)));
+
+        // read buffer is released, payload is still accessible
+        drop(buf);
+        assert_eq!(will.message, payload);
+    }
+
+    #[test]
+    fn test_integer_properties_round_trip() {
+        for (u16_val, u32_val) in [(1, 1), (u16::MAX, u32::MAX)] {
+            round_trip(Packet::Connect(Box::new(Connect {
+                session_expiry_interval_secs: Some(u32_val),
+                receive_max: NonZeroU16::new(u16_val),
+                max_packet_size: NonZeroU32::new(u32_val),
+                topic_alias_max: u16_val,
+                last_will: Some(LastWill {
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                    topic: ByteString::from_static("will"),
+                    message: Bytes::new(),
+                    will_delay_interval_sec: Some(u32_val),
+                    correlation_data: None,
+                    message_expiry_interval: NonZeroU32::new(u32_val),
+                    content_type: None,
+                    user_properties: Vec::new(),
+                    is_utf8_payload: None,
+                    response_topic: None,
+                }),
+                ..Connect::default().client_id("user")
+            })));
+
+            round_trip(Packet::ConnectAck(Box::new(ConnectAck {
+                session_expiry_interval_secs: Some(u32_val),
+                receive_max: NonZeroU16::new(u16_val),
+                max_packet_size: Some(u32_val),
+                topic_alias_max: u16_val,
+                server_keepalive_sec: Some(u16_val),
+                ..ConnectAck::default()
+            })));
+
+            round_trip(Packet::Disconnect(Disconnect {
+                session_expiry_interval_secs: Some(u32_val),
+                ..Disconnect::default()
+            }));
+
+            round_trip(Packet::Publish(Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::AtMostOnce,
+                topic: ByteString::from_static("t"),
+                packet_id: None,
+                payload: Bytes::new(),
+                properties: PublishProperties {
+                    topic_alias: NonZeroU16::new(u16_val),
+                    message_expiry_interval: NonZeroU32::new(u32_val),
+                    ..PublishProperties::default()
+                },
+            }));
+        }
+
+        // subscription identifier, variable byte integer
+        for id in [1, 127, 128, 16_383, 16_384, 2_097_151, 2_097_152, 268_435_455] {
+            round_trip(Packet::Subscribe(Subscribe {
+                packet_id: NonZeroU16::new(1).unwrap(),
+                id: NonZeroU32::new(id),
+                user_properties: Vec::new(),
+                topic_filters: vec![(
+                    ByteString::from_static("t"),
+                    SubscriptionOptions {
+                        qos: QoS::AtMostOnce,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: crate::v5::codec::RetainHandling::AtSubscribe,
+                    },
+                )],
+            }));
+        }
+    }
+
+    #[test]
+    fn test_decode_out_of_range_properties() {
+        let codec = Codec::new();
+        let decode = |pkt: &'static [u8]| codec.decode(&mut BytesMut::from(pkt));
+
+        // connect receive maximum and maximum packet size of zero
+        assert_eq!(
+            decode(b"\x10\x10\x00\x04MQTT\x05\x00\x00\x00\x03\x21\x00\x00\x00\x00"),
+            Err(DecodeError::MalformedPacket)
+        );
+        assert_eq!(
+            decode(b"\x10\x12\x00\x04MQTT\x05\x00\x00\x00\x05\x27\x00\x00\x00\x00\x00\x00"),
+            Err(DecodeError::MalformedPacket)
+        );
+        // connect session expiry interval, truncated four byte integer
+        assert_eq!(
+            decode(b"\x10\x11\x00\x04MQTT\x05\x00\x00\x00\x04\x11\xff\xff\xff\x00\x00"),
+            Err(DecodeError::InvalidLength)
+        );
+
+        // connack receive maximum and maximum packet size of zero
+        assert_eq!(
+            decode(b"\x20\x06\x00\x00\x03\x21\x00\x00"),
+            Err(DecodeError::MalformedPacket)
+        );
+        assert_eq!(
+            decode(b"\x20\x08\x00\x00\x05\x27\x00\x00\x00\x00"),
+            Err(DecodeError::MalformedPacket)
+        );
+        // connack server keep alive, truncated two byte integer
+        assert_eq!(decode(b"\x20\x05\x00\x00\x02\x13\xff"), Err(DecodeError::InvalidLength));
+
+        // disconnect session expiry interval, truncated four byte integer
+        assert_eq!(
+            decode(b"\xe0\x06\x00\x04\x11\xff\xff\xff"),
+            Err(DecodeError::InvalidLength)
+        );
+    }
 }
@@ -5,25 +5,47 @@ use crate::error::DecodeError;
 use crate::types::packet_type;
 use crate::utils::Decode;
 
-pub(super) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, DecodeError> {
+pub(super) fn decode_packet(
+    mut src: Bytes,
+    first_byte: u8,
+    lenient: bool,
+) -> Result<Packet, DecodeError> {
     match first_byte {
         packet_type::PUBLISH_START..=packet_type::PUBLISH_END => {
-            Ok(Packet::Publish(Publish::decode(src, first_byte & 0b0000_1111)?))
+            Ok(Packet::Publish(Publish::decode(src, first_byte & 0b0000_1111, lenient)?))
         }
-        packet_type::PUBACK => Ok(Packet::PublishAck(PublishAck::decode(&mut src)?)),
+        packet_type::PUBACK => Ok(Packet::PublishAck(PublishAck::decode(&mut src, lenient)?)),
         packet_type::PINGREQ => Ok(Packet::PingRequest),
         packet_type::PINGRESP => Ok(Packet::PingResponse),
-        packet_type::SUBSCRIBE => Ok(Packet::Subscribe(Subscribe::decode(&mut src)?)),
-        packet_type::SUBACK => Ok(Packet::SubscribeAck(SubscribeAck::decode(&mut src)?)),
-        packet_type::UNSUBSCRIBE => Ok(Packet::Unsubscribe(Unsubscribe::decode(&mut src)?)),
-        packet_type::UNSUBACK => Ok(Packet::UnsubscribeAck(UnsubscribeAck::decode(&mut src)?)),
-        packet_type::CONNECT => Ok(Packet::Connect(Box::new(Connect::decode(&mut src)?))),
-        packet_type::CONNACK => Ok(Packet::ConnectAck(Box::new(ConnectAck::decode(&mut src)?))),
-        packet_type::DISCONNECT => Ok(Packet::Disconnect(Disconnect::decode(&mut src)?)),
-        packet_type::AUTH => Ok(Packet::Auth(Auth::decode(&mut src)?)),
-        packet_type::PUBREC => Ok(Packet::PublishReceived(PublishAck::decode(&mut src)?)),
-        packet_type::PUBREL => Ok(Packet::PublishRelease(PublishAck2::decode(&mut src)?)),
-        packet_type::PUBCOMP => Ok(Packet::PublishComplete(PublishAck2::decode(&mut src)?)),
+        packet_type::SUBSCRIBE => Ok(Packet::Subscribe(Subscribe::decode(&mut src, lenient)?)),
+        packet_type::SUBACK => {
+            Ok(Packet::SubscribeAck(SubscribeAck::decode(&mut src, lenient)?))
+        }
+        packet_type::UNSUBSCRIBE => {
+            Ok(Packet::Unsubscribe(Unsubscribe::decode(&mut src, lenient)?))
+        }
+        packet_type::UNSUBACK => {
+            Ok(Packet::UnsubscribeAck(UnsubscribeAck::decode(&mut src, lenient)?))
+        }
+        packet_type::CONNECT => {
+            Ok(Packet::Connect(Box::new(Connect::decode(&mut src, lenient)?)))
+        }
+        packet_type::CONNACK => {
+            Ok(Packet::ConnectAck(Box::new(ConnectAck::decode(&mut src, lenient)?)))
+        }
+        packet_type::DISCONNECT => {
+            Ok(Packet::Disconnect(Disconnect::decode(&mut src, lenient)?))
+        }
+        packet_type::AUTH => Ok(Packet::Auth(Auth::decode(&mut src, lenient)?)),
+        packet_type::PUBREC => {
+            Ok(Packet::PublishReceived(PublishAck::decode(&mut src, lenient)?))
+        }
+        packet_type::PUBREL => {
+            Ok(Packet::PublishRelease(PublishAck2::decode(&mut src, lenient)?))
+        }
+        packet_type::PUBCOMP => {
+            Ok(Packet::PublishComplete(PublishAck2::decode(&mut src, lenient)?))
+        }
         _ => Err(DecodeError::UnsupportedPacketType),
     }
 }
@@ -39,7 +61,7 @@ impl Decode for UserProperty {
 #[cfg(test)]
 mod tests {
     use ntex::util::{Bytes, BytesMut};
-    use std::num::NonZeroU16;
+    use std::num::{NonZeroU16, NonZeroU32};
 
     use super::*;
     use crate::types::QoS;
@@ -62,7 +84,7 @@ mod tests {
             &mut tmp,
         )
         .unwrap();
-        let decoded = decode_packet(cur, fixed);
+        let decoded = decode_packet(cur, fixed, false);
         let res = Ok(res);
         if decoded != res {
             panic!("decoded packet does not match expectations.\nexpected: {:?}\nactual: {:?}\nencoding output for expected: {:X?}", res, decoded, tmp.as_ref());
@@ -73,9 +95,12 @@ mod tests {
     #[test]
     fn test_decode_connect_packets() {
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x05\xC0\x00\x3C\x00\x00\x0512345\x00\x04user\x00\x04pass"
-            )),
+            Connect::decode(
+                &mut Bytes::from_static(
+                    b"\x00\x04MQTT\x05\xC0\x00\x3C\x00\x00\x0512345\x00\x04user\x00\x04pass"
+                ),
+                false
+            ),
             Ok(Connect {
                 clean_start: false,
                 keep_alive: 60,
@@ -98,7 +123,7 @@ mod tests {
         assert_eq!(
             Connect::decode(&mut Bytes::from_static(
                 b"\x00\x04MQTT\x05\x14\x00\x3C\x00\x00\x0512345\x00\x00\x05topic\x00\x07message"
-            )),
+            ), false),
             Ok(Connect {
                 clean_start: false,
                 keep_alive: 60,
@@ -131,26 +156,33 @@ mod tests {
         );
 
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(b"\x00\x02MQ00000000000000000000")),
+            Connect::decode(&mut Bytes::from_static(b"\x00\x02MQ00000000000000000000"), false),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(b"\x00\x04MQAA00000000000000000000")),
+            Connect::decode(
+                &mut Bytes::from_static(b"\x00\x04MQAA00000000000000000000"),
+                false
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(b"\x00\x04MQTT\x0300000000000000000000")),
+            Connect::decode(
+                &mut Bytes::from_static(b"\x00\x04MQTT\x0300000000000000000000"),
+                false
+            ),
             Err(DecodeError::UnsupportedProtocolLevel),
         );
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x05\xff00000000000000000000"
-            )),
+            Connect::decode(
+                &mut Bytes::from_static(b"\x00\x04MQTT\x05\xff00000000000000000000"),
+                false
+            ),
             Err(DecodeError::ConnectReservedFlagSet)
         );
 
         assert_eq!(
-            ConnectAck::decode(&mut Bytes::from_static(b"\x01\x86\x00")),
+            ConnectAck::decode(&mut Bytes::from_static(b"\x01\x86\x00"), false),
             Ok(ConnectAck {
                 session_present: true,
                 reason_code: ConnectAckReason::BadUserNameOrPassword,
@@ -159,7 +191,7 @@ mod tests {
         );
 
         assert_eq!(
-            ConnectAck::decode(&mut Bytes::from_static(b"\x03\x86\x00")),
+            ConnectAck::decode(&mut Bytes::from_static(b"\x03\x86\x00"), false),
             Err(DecodeError::ConnAckReservedFlagSet)
         );
 
@@ -257,6 +289,102 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_decode_publish_topic_alias() {
+        assert_decode_packet(
+            b"\x30\x0A\x00\x00\x03\x23\x00\x05data",
+            Packet::Publish(Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::AtMostOnce,
+                topic: ByteString::default(),
+                packet_id: None,
+                payload: Bytes::from_static(b"data"),
+                properties: PublishProperties {
+                    topic_alias: NonZeroU16::new(5),
+                    ..PublishProperties::default()
+                },
+            }),
+        );
+
+        // empty topic without topic alias
+        assert_eq!(
+            decode_packet(Bytes::from_static(b"\x00\x00\x00data"), 0x30, false),
+            Err(DecodeError::EmptyTopic)
+        );
+
+        // topic alias 0
+        assert_eq!(
+            decode_packet(Bytes::from_static(b"\x00\x00\x03\x23\x00\x00data"), 0x30, false),
+            Err(DecodeError::InvalidTopicAlias)
+        );
+    }
+
+    #[test]
+    fn test_decode_integer_properties() {
+        fn decode_publish(props: &[u8]) -> Result<Packet, DecodeError> {
+            let mut buf = b"\x00\x01t".to_vec();
+            buf.extend_from_slice(props);
+            decode_packet(Bytes::from(buf), 0x30, false)
+        }
+
+        fn publish_props(pkt: Result<Packet, DecodeError>) -> PublishProperties {
+            match pkt {
+                Ok(Packet::Publish(pkt)) => pkt.properties,
+                pkt => panic!("unexpected result: {:?}", pkt),
+            }
+        }
+
+        // message expiry interval, four byte integer
+        let props = publish_props(decode_publish(b"\x05\x02\xff\xff\xff\xff"));
+        assert_eq!(props.message_expiry_interval, NonZeroU32::new(u32::MAX));
+        let props = publish_props(decode_publish(b"\x05\x02\x00\x00\x00\x01"));
+        assert_eq!(props.message_expiry_interval, NonZeroU32::new(1));
+        assert_eq!(
+            decode_publish(b"\x05\x02\x00\x00\x00\x00"),
+            Err(DecodeError::MalformedPacket)
+        );
+        assert_eq!(decode_publish(b"\x04\x02\xff\xff\xff"), Err(DecodeError::InvalidLength));
+
+        // topic alias, two byte integer
+        let props = publish_props(decode_publish(b"\x03\x23\xff\xff"));
+        assert_eq!(props.topic_alias, NonZeroU16::new(u16::MAX));
+        assert_eq!(decode_publish(b"\x03\x23\x00\x00"), Err(DecodeError::MalformedPacket));
+        assert_eq!(decode_publish(b"\x02\x23\xff"), Err(DecodeError::InvalidLength));
+
+        // subscription identifier, variable byte integer
+        let props = publish_props(decode_publish(b"\x05\x0b\xff\xff\xff\x7f"));
+        assert_eq!(props.subscription_ids, Some(vec![NonZeroU32::new(268_435_455).unwrap()]));
+        let props = publish_props(decode_publish(b"\x02\x0b\x01"));
+        assert_eq!(props.subscription_ids, Some(vec![NonZeroU32::new(1).unwrap()]));
+        assert_eq!(decode_publish(b"\x02\x0b\x00"), Err(DecodeError::MalformedPacket));
+        assert_eq!(
+            decode_publish(b"\x06\x0b\xff\xff\xff\xff\x01"),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(decode_publish(b"\x02\x0b\xff"), Err(DecodeError::MalformedPacket));
+
+        // subscribe packet subscription identifier
+        assert!(decode_packet(
+            Bytes::from_static(b"\x12\x34\x05\x0b\xff\xff\xff\x7f"),
+            0x82,
+            false
+        )
+        .is_ok());
+        assert_eq!(
+            decode_packet(
+                Bytes::from_static(b"\x12\x34\x06\x0b\xff\xff\xff\xff\x01"),
+                0x82,
+                false
+            ),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(
+            decode_packet(Bytes::from_static(b"\x12\x34\x02\x0b\x00"), 0x82, false),
+            Err(DecodeError::MalformedPacket)
+        );
+    }
+
     #[test]
     fn test_decode_subscribe_packets() {
         let p = Packet::Subscribe(Subscribe {
@@ -311,9 +439,10 @@ mod tests {
 
         assert_eq!(
             Packet::Unsubscribe(
-                Unsubscribe::decode(&mut Bytes::from_static(
-                    b"\x12\x34\x00\x00\x04test\x00\x06filter"
-                ))
+                Unsubscribe::decode(
+                    &mut Bytes::from_static(b"\x12\x34\x00\x00\x04test\x00\x06filter"),
+                    false
+                )
                 .unwrap()
             ),
             p.clone()
@@ -331,6 +460,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_decode_auth_packets() {
+        assert_decode_packet(b"\xf0\x00", Packet::Auth(Auth::default()));
+        assert_decode_packet(
+            b"\xf0\x0a\x18\x08\x15\x00\x01m\x16\x00\x01d",
+            Packet::Auth(Auth {
+                reason_code: AuthReasonCode::ContinueAuth,
+                auth_method: Some(ByteString::from_static("m")),
+                auth_data: Some(Bytes::from_static(b"d")),
+                ..Auth::default()
+            }),
+        );
+
+        // round trip with all properties
+        for reason_code in
+            [AuthReasonCode::Success, AuthReasonCode::ContinueAuth, AuthReasonCode::ReAuth]
+        {
+            let pkt = Packet::Auth(Auth {
+                reason_code,
+                auth_method: Some(ByteString::from_static("SCRAM-SHA-1")),
+                auth_data: Some(Bytes::from_static(b"data")),
+                reason_string: Some(ByteString::from_static("reason")),
+                user_properties: vec![(
+                    ByteString::from_static("key"),
+                    ByteString::from_static("value"),
+                )],
+            });
+            let mut buf = BytesMut::new();
+            ntex::codec::Encoder::encode(&Codec::new(), pkt.clone(), &mut buf).unwrap();
+            assert_eq!(ntex::codec::Decoder::decode(&Codec::new(), &mut buf), Ok(Some(pkt)));
+        }
+    }
+
     #[test]
     fn test_decode_ping_packets() {
         assert_decode_packet(b"\xc0\x00", Packet::PingRequest);
@@ -30,6 +30,7 @@ impl EncodeLtd for Packet {
             Packet::PingRequest | Packet::PingResponse => 0,
             Packet::Disconnect(disconnect) => disconnect.encoded_size(limit),
             Packet::Auth(auth) => auth.encoded_size(limit),
+            Packet::Custom(_, body) => body.len(),
         }
     }
 
@@ -113,6 +114,12 @@ impl EncodeLtd for Packet {
                 write_variable_length(check_size, buf);
                 auth.encode(buf, check_size)
             }
+            Packet::Custom(first_byte, body) => {
+                buf.put_u8(*first_byte);
+                write_variable_length(check_size, buf);
+                buf.put_slice(body);
+                Ok(())
+            }
         }
     }
 }
@@ -2,8 +2,8 @@ use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::convert::TryInto;
 
 use crate::error::{DecodeError, EncodeError};
-use crate::utils::{self, Decode, Property};
-use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
+use crate::utils::{self, Property};
+use crate::v5::codec::{encode::*, property_type as pt, UserProperties};
 
 /// AUTH message
 #[derive(Debug, PartialEq, Clone)]
@@ -25,7 +25,7 @@ prim_enum! {
 }
 
 impl Auth {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         if src.has_remaining() {
             ensure!(src.remaining() > 1, DecodeError::InvalidLength);
             let reason_code = src.get_u8().try_into()?;
@@ -41,8 +41,11 @@ impl Auth {
                     match prop_src.get_u8() {
                         pt::AUTH_METHOD => auth_method.read_value(prop_src)?,
                         pt::AUTH_DATA => auth_data.read_value(prop_src)?,
-                        pt::REASON_STRING => reason_string.read_value(prop_src)?,
-                        pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
+                        pt::REASON_STRING => {
+                            utils::read_string_property(&mut reason_string, prop_src, lenient)?
+                        }
+                        pt::USER => user_properties
+                            .push(utils::decode_user_property(prop_src, lenient)?),
                         _ => return Err(DecodeError::MalformedPacket),
                     }
                 }
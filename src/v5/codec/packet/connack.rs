@@ -3,8 +3,8 @@ use std::{convert::TryInto, num::NonZeroU16};
 
 use crate::error::{DecodeError, EncodeError};
 use crate::types::{ConnectAckFlags, QoS};
-use crate::utils::{self, Decode, Encode, Property};
-use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
+use crate::utils::{self, Encode, Property};
+use crate::v5::codec::{encode::*, property_type as pt, UserProperties};
 
 /// Connect acknowledgment packet
 #[derive(Debug, PartialEq, Clone)]
@@ -102,7 +102,7 @@ impl ConnectAckReason {
 }
 
 impl ConnectAck {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         ensure!(src.remaining() >= 2, DecodeError::InvalidLength);
         let flags = ConnectAckFlags::from_bits(src.get_u8())
             .ok_or(DecodeError::ConnAckReservedFlagSet)?;
@@ -141,8 +141,12 @@ impl ConnectAck {
                 pt::MAX_PACKET_SIZE => max_packet_size.read_value(prop_src)?,
                 pt::ASSND_CLIENT_ID => assigned_client_id.read_value(prop_src)?,
                 pt::TOPIC_ALIAS_MAX => topic_alias_max.read_value(prop_src)?,
-                pt::REASON_STRING => reason_string.read_value(prop_src)?,
-                pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
+                pt::REASON_STRING => {
+                    utils::read_string_property(&mut reason_string, prop_src, lenient)?
+                }
+                pt::USER => {
+                    user_properties.push(utils::decode_user_property(prop_src, lenient)?)
+                }
                 pt::WILDCARD_SUB_AVAIL => wildcard_sub_avail.read_value(prop_src)?,
                 pt::SUB_IDS_AVAIL => sub_ids_avail.read_value(prop_src)?,
                 pt::SHARED_SUB_AVAIL => shared_sub_avail.read_value(prop_src)?,
@@ -154,6 +158,7 @@ impl ConnectAck {
                 _ => return Err(DecodeError::MalformedPacket),
             }
         }
+        ensure!(max_packet_size != Some(0), DecodeError::MalformedPacket); // zero max packet size is not allowed
         ensure!(!src.has_remaining(), DecodeError::InvalidLength);
 
         Ok(ConnectAck {
@@ -5,7 +5,7 @@ use std::num::{NonZeroU16, NonZeroU32};
 use crate::error::{DecodeError, EncodeError};
 use crate::types::{ConnectFlags, QoS, MQTT, MQTT_LEVEL_5, WILL_QOS_SHIFT};
 use crate::utils::{self, Decode, Encode, Property};
-use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
+use crate::v5::codec::{encode::*, property_type as pt, UserProperties};
 
 #[derive(Debug, PartialEq, Clone)]
 /// Connect packet content
@@ -103,7 +103,7 @@ impl Connect {
         prop_len
     }
 
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         ensure!(src.remaining() >= 10, DecodeError::InvalidLength);
         let len = src.get_u16();
 
@@ -137,22 +137,20 @@ impl Connect {
                 pt::REQ_RESP_INFO => request_response_info.read_value(prop_src)?,
                 pt::RECEIVE_MAX => receive_max.read_value(prop_src)?,
                 pt::TOPIC_ALIAS_MAX => topic_alias_max.read_value(prop_src)?,
-                pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
+                pt::USER => {
+                    user_properties.push(utils::decode_user_property(prop_src, lenient)?)
+                }
                 pt::MAX_PACKET_SIZE => max_packet_size.read_value(prop_src)?,
                 _ => return Err(DecodeError::MalformedPacket),
             }
         }
 
+        // empty client id is allowed regardless of clean start flag,
+        // server assigns client id [MQTT-3.1.3-6]
         let client_id = ByteString::decode(src)?;
 
-        ensure!(
-            // todo: [MQTT-3.1.3-8]?
-            !client_id.is_empty() || flags.contains(ConnectFlags::CLEAN_START),
-            DecodeError::InvalidClientId
-        );
-
         let last_will = if flags.contains(ConnectFlags::WILL) {
-            Some(decode_last_will(src, flags)?)
+            Some(decode_last_will(src, flags, lenient)?)
         } else {
             None
         };
@@ -212,7 +210,11 @@ impl Default for Connect {
     }
 }
 
-fn decode_last_will(src: &mut Bytes, flags: ConnectFlags) -> Result<LastWill, DecodeError> {
+fn decode_last_will(
+    src: &mut Bytes,
+    flags: ConnectFlags,
+    lenient: bool,
+) -> Result<LastWill, DecodeError> {
     let mut will_delay_interval_sec = None;
     let mut correlation_data = None;
     let mut message_expiry_interval = None;
@@ -226,10 +228,12 @@ fn decode_last_will(src: &mut Bytes, flags: ConnectFlags) -> Result<LastWill, De
             pt::WILL_DELAY_INT => will_delay_interval_sec.read_value(prop_src)?,
             pt::CORR_DATA => correlation_data.read_value(prop_src)?,
             pt::MSG_EXPIRY_INT => message_expiry_interval.read_value(prop_src)?,
-            pt::CONTENT_TYPE => content_type.read_value(prop_src)?,
+            pt::CONTENT_TYPE => {
+                utils::read_string_property(&mut content_type, prop_src, lenient)?
+            }
             pt::UTF8_PAYLOAD => is_utf8_payload.read_value(prop_src)?,
             pt::RESP_TOPIC => response_topic.read_value(prop_src)?,
-            pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
+            pt::USER => user_properties.push(utils::decode_user_property(prop_src, lenient)?),
             _ => return Err(DecodeError::MalformedPacket),
         }
     }
@@ -319,6 +323,13 @@ impl EncodeLtd for Connect {
         if let Some(will) = self.last_will.as_ref() {
             let prop_len = will.properties_len();
             utils::write_variable_length(prop_len as u32, buf); // safe: whole message size is checked for max already
+            encode_property(&will.will_delay_interval_sec, pt::WILL_DELAY_INT, buf)?;
+            encode_property(&will.correlation_data, pt::CORR_DATA, buf)?;
+            encode_property(&will.message_expiry_interval, pt::MSG_EXPIRY_INT, buf)?;
+            encode_property(&will.content_type, pt::CONTENT_TYPE, buf)?;
+            encode_property(&will.is_utf8_payload, pt::UTF8_PAYLOAD, buf)?;
+            encode_property(&will.response_topic, pt::RESP_TOPIC, buf)?;
+            will.user_properties.encode(buf)?;
 
             will.topic.encode(buf)?;
             will.message.encode(buf)?;
@@ -2,8 +2,8 @@ use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::convert::TryInto;
 
 use crate::error::{DecodeError, EncodeError};
-use crate::utils::{self, Decode, Property};
-use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
+use crate::utils::{self, Property};
+use crate::v5::codec::{encode::*, property_type as pt, UserProperties};
 
 /// DISCONNECT message
 #[derive(Debug, PartialEq, Clone)]
@@ -63,7 +63,7 @@ impl Disconnect {
         }
     }
 
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         if src.has_remaining() {
             let reason_code = src.get_u8().try_into()?;
 
@@ -76,8 +76,12 @@ impl Disconnect {
             while prop_src.has_remaining() {
                 match prop_src.get_u8() {
                     pt::SESS_EXPIRY_INT => session_expiry_interval_secs.read_value(prop_src)?,
-                    pt::REASON_STRING => reason_string.read_value(prop_src)?,
-                    pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
+                    pt::REASON_STRING => {
+                        utils::read_string_property(&mut reason_string, prop_src, lenient)?
+                    }
+                    pt::USER => {
+                        user_properties.push(utils::decode_user_property(prop_src, lenient)?)
+                    }
                     pt::SERVER_REF => server_reference.read_value(prop_src)?,
                     _ => return Err(DecodeError::MalformedPacket),
                 }
@@ -6,7 +6,9 @@ pub use crate::types::{ConnectAckFlags, ConnectFlags, QoS};
 use super::{encode::*, property_type as pt, UserProperties};
 use crate::error::{DecodeError, EncodeError};
 use crate::types::packet_type;
-use crate::utils::{take_properties, write_variable_length, Decode, Property};
+use crate::utils::{
+    decode_user_property, read_string_property, take_properties, write_variable_length,
+};
 
 mod auth;
 mod connack;
@@ -59,6 +61,11 @@ pub enum Packet {
     Disconnect(Disconnect),
     /// Auth exchange
     Auth(Auth),
+    #[from(ignore)]
+    /// Packet with reserved packet type, first byte of fixed header and packet body
+    ///
+    /// Decoded only if custom packets are enabled for codec.
+    Custom(u8, Bytes),
 }
 
 impl Packet {
@@ -79,6 +86,7 @@ impl Packet {
             Packet::PingResponse => packet_type::PINGRESP,
             Packet::Disconnect(_) => packet_type::DISCONNECT,
             Packet::Auth(_) => packet_type::AUTH,
+            Packet::Custom(first_byte, _) => *first_byte,
         }
     }
 }
@@ -153,6 +161,7 @@ mod ack_props {
     /// Parses ACK properties (User and Reason String properties) from `src`
     pub(crate) fn decode(
         src: &mut Bytes,
+        lenient: bool,
     ) -> Result<(UserProperties, Option<ByteString>), DecodeError> {
         let prop_src = &mut take_properties(src)?;
         let mut reason_string = None;
@@ -160,8 +169,10 @@ mod ack_props {
         while prop_src.has_remaining() {
             let prop_id = prop_src.get_u8();
             match prop_id {
-                pt::REASON_STRING => reason_string.read_value(prop_src)?,
-                pt::USER => user_props.push(<(ByteString, ByteString)>::decode(prop_src)?),
+                pt::REASON_STRING => {
+                    read_string_property(&mut reason_string, prop_src, lenient)?
+                }
+                pt::USER => user_props.push(decode_user_property(prop_src, lenient)?),
                 _ => return Err(DecodeError::MalformedPacket),
             }
         }
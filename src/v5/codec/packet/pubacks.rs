@@ -52,11 +52,11 @@ prim_enum! {
 }
 
 impl PublishAck {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
         let (reason_code, properties, reason_string) = if src.has_remaining() {
             let reason_code = src.get_u8().try_into()?;
-            let (properties, reason_string) = ack_props::decode(src)?;
+            let (properties, reason_string) = ack_props::decode(src, lenient)?;
             ensure!(!src.has_remaining(), DecodeError::InvalidLength); // no bytes should be left
             (reason_code, properties, reason_string)
         } else {
@@ -79,11 +79,11 @@ impl Default for PublishAck {
 }
 
 impl PublishAck2 {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
         let (reason_code, properties, reason_string) = if src.has_remaining() {
             let reason_code = src.get_u8().try_into()?;
-            let (properties, reason_string) = ack_props::decode(src)?;
+            let (properties, reason_string) = ack_props::decode(src, lenient)?;
             ensure!(!src.has_remaining(), DecodeError::InvalidLength); // no bytes should be left
             (reason_code, properties, reason_string)
         } else {
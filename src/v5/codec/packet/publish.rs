@@ -48,7 +48,11 @@ pub struct PublishProperties {
 }
 
 impl Publish {
-    pub(crate) fn decode(mut src: Bytes, packet_flags: u8) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(
+        mut src: Bytes,
+        packet_flags: u8,
+        lenient: bool,
+    ) -> Result<Self, DecodeError> {
         let topic = ByteString::decode(&mut src)?;
         let qos = QoS::try_from((packet_flags & 0b0110) >> 1)?;
         let packet_id = if qos == QoS::AtMostOnce {
@@ -57,7 +61,11 @@ impl Publish {
             Some(NonZeroU16::decode(&mut src)?) // packet id = 0 encountered
         };
 
-        let properties = parse_publish_properties(&mut src)?;
+        let properties = parse_publish_properties(&mut src, lenient)?;
+        // empty topic is allowed only with topic alias
+        if topic.is_empty() && properties.topic_alias.is_none() {
+            return Err(DecodeError::EmptyTopic);
+        }
         let payload = src;
 
         Ok(Self {
@@ -72,7 +80,10 @@ impl Publish {
     }
 }
 
-fn parse_publish_properties(src: &mut Bytes) -> Result<PublishProperties, DecodeError> {
+fn parse_publish_properties(
+    src: &mut Bytes,
+    lenient: bool,
+) -> Result<PublishProperties, DecodeError> {
     let prop_src = &mut utils::take_properties(src)?;
 
     let mut message_expiry_interval = None;
@@ -88,7 +99,9 @@ fn parse_publish_properties(src: &mut Bytes) -> Result<PublishProperties, Decode
         match prop_src.get_u8() {
             pt::UTF8_PAYLOAD => is_utf8_payload.read_value(prop_src)?,
             pt::MSG_EXPIRY_INT => message_expiry_interval.read_value(prop_src)?,
-            pt::CONTENT_TYPE => content_type.read_value(prop_src)?,
+            pt::CONTENT_TYPE => {
+                utils::read_string_property(&mut content_type, prop_src, lenient)?
+            }
             pt::RESP_TOPIC => response_topic.read_value(prop_src)?,
             pt::CORR_DATA => correlation_data.read_value(prop_src)?,
             pt::SUB_ID => {
@@ -97,8 +110,14 @@ fn parse_publish_properties(src: &mut Bytes) -> Result<PublishProperties, Decode
                     .get_or_insert_with(Vec::new)
                     .push(NonZeroU32::new(id).ok_or(DecodeError::MalformedPacket)?);
             }
-            pt::TOPIC_ALIAS => topic_alias.read_value(prop_src)?,
-            pt::USER => user_props.push(<(ByteString, ByteString)>::decode(prop_src)?),
+            pt::TOPIC_ALIAS => {
+                ensure!(topic_alias.is_none(), DecodeError::MalformedPacket); // property is set twice
+                topic_alias = Some(
+                    NonZeroU16::new(u16::decode(prop_src)?)
+                        .ok_or(DecodeError::InvalidTopicAlias)?, // topic alias 0 is not permitted
+                );
+            }
+            pt::USER => user_props.push(utils::decode_user_property(prop_src, lenient)?),
             _ => return Err(DecodeError::MalformedPacket),
         }
     }
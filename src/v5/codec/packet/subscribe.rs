@@ -6,7 +6,7 @@ use super::ack_props;
 use crate::error::{DecodeError, EncodeError};
 use crate::types::QoS;
 use crate::utils::{self, write_variable_length, Decode, Encode};
-use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
+use crate::v5::codec::{encode::*, property_type as pt, UserProperties};
 
 /// Represents SUBSCRIBE packet
 #[derive(Debug, PartialEq, Clone)]
@@ -98,7 +98,17 @@ prim_enum! {
 }
 
 impl Subscribe {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
+        Self::decode_limited(src, 0, lenient)
+    }
+
+    /// Decode packet, fails with `MaxSizeExceeded` if packet contains
+    /// more than `max_filters` topic filters. `0` means unlimited.
+    pub(crate) fn decode_limited(
+        src: &mut Bytes,
+        max_filters: usize,
+        lenient: bool,
+    ) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
         let prop_src = &mut utils::take_properties(src)?;
         let mut sub_id = None;
@@ -111,13 +121,19 @@ impl Subscribe {
                     let val = utils::decode_variable_length_cursor(prop_src)?;
                     sub_id = Some(NonZeroU32::new(val).ok_or(DecodeError::MalformedPacket)?);
                 }
-                pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
+                pt::USER => {
+                    user_properties.push(utils::decode_user_property(prop_src, lenient)?)
+                }
                 _ => return Err(DecodeError::MalformedPacket),
             }
         }
 
         let mut topic_filters = Vec::new();
         while src.has_remaining() {
+            ensure!(
+                max_filters == 0 || topic_filters.len() < max_filters,
+                DecodeError::MaxSizeExceeded
+            );
             let topic = ByteString::decode(src)?;
             let opts = SubscriptionOptions::decode(src)?;
             topic_filters.push((topic, opts));
@@ -128,9 +144,9 @@ impl Subscribe {
 }
 
 impl SubscribeAck {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
-        let (properties, reason_string) = ack_props::decode(src)?;
+        let (properties, reason_string) = ack_props::decode(src, lenient)?;
         let mut status = Vec::with_capacity(src.remaining());
         for code in src.as_ref().iter().copied() {
             status.push(code.try_into()?);
@@ -140,7 +156,7 @@ impl SubscribeAck {
 }
 
 impl Unsubscribe {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
 
         let prop_src = &mut utils::take_properties(src)?;
@@ -148,7 +164,9 @@ impl Unsubscribe {
         while prop_src.has_remaining() {
             let prop_id = prop_src.get_u8();
             match prop_id {
-                pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
+                pt::USER => {
+                    user_properties.push(utils::decode_user_property(prop_src, lenient)?)
+                }
                 _ => return Err(DecodeError::MalformedPacket),
             }
         }
@@ -163,9 +181,9 @@ impl Unsubscribe {
 }
 
 impl UnsubscribeAck {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(src: &mut Bytes, lenient: bool) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
-        let (properties, reason_string) = ack_props::decode(src)?;
+        let (properties, reason_string) = ack_props::decode(src, lenient)?;
         let mut status = Vec::with_capacity(src.remaining());
         for code in src.as_ref().iter().copied() {
             status.push(code.try_into()?);
@@ -320,7 +338,7 @@ mod tests {
         let size = ack.encoded_size(99999);
         let mut buf = BytesMut::with_capacity(size);
         ack.encode(&mut buf, size as u32).unwrap();
-        assert_eq!(ack, SubscribeAck::decode(&mut buf.freeze()).unwrap());
+        assert_eq!(ack, SubscribeAck::decode(&mut buf.freeze(), false).unwrap());
 
         let ack = SubscribeAck {
             packet_id: NonZeroU16::new(1).unwrap(),
@@ -331,7 +349,7 @@ mod tests {
         let size = ack.encoded_size(99999);
         let mut buf = BytesMut::with_capacity(size);
         ack.encode(&mut buf, size as u32).unwrap();
-        assert_eq!(ack, SubscribeAck::decode(&mut buf.freeze()).unwrap());
+        assert_eq!(ack, SubscribeAck::decode(&mut buf.freeze(), false).unwrap());
 
         let ack = UnsubscribeAck {
             packet_id: NonZeroU16::new(1).unwrap(),
@@ -342,7 +360,7 @@ mod tests {
         let mut buf = BytesMut::new();
         let size = ack.encoded_size(99999);
         ack.encode(&mut buf, size as u32).unwrap();
-        assert_eq!(ack, UnsubscribeAck::decode(&mut buf.freeze()).unwrap());
+        assert_eq!(ack, UnsubscribeAck::decode(&mut buf.freeze(), false).unwrap());
 
         let ack = UnsubscribeAck {
             packet_id: NonZeroU16::new(1).unwrap(),
@@ -353,6 +371,6 @@ mod tests {
         let size = ack.encoded_size(99999);
         let mut buf = BytesMut::with_capacity(size);
         ack.encode(&mut buf, size as u32).unwrap();
-        assert_eq!(ack, UnsubscribeAck::decode(&mut buf.freeze()).unwrap());
+        assert_eq!(ack, UnsubscribeAck::decode(&mut buf.freeze(), false).unwrap());
     }
 }
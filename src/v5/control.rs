@@ -1,9 +1,9 @@
-use std::{io, marker::PhantomData};
+use std::{io, marker::PhantomData, num::NonZeroU32};
 
-use ntex::util::ByteString;
+use ntex::util::{ByteString, Bytes};
 
 use super::codec::{self, DisconnectReasonCode, QoS, UserProperties};
-use crate::error;
+use crate::{error, topic::Topic};
 
 /// Control plain messages
 #[derive(Debug)]
@@ -26,6 +26,18 @@ pub enum ControlMessage<E> {
     ProtocolError(ProtocolError),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Server is going to disconnect the client
+    ///
+    /// Message is sent for every server initiated disconnect: exceeded
+    /// subscribe rate limit, and `ProtocolError` or `Error` messages handled
+    /// with DISCONNECT packet (receive maximum exceeded, topic alias errors,
+    /// keep-alive timeout, etc). Control service could change DISCONNECT
+    /// packet sent to the client.
+    ServerDisconnecting(ServerDisconnecting),
+    /// Packet with reserved packet type from a client
+    ///
+    /// Message is sent only if custom packets are enabled for server.
+    CustomPacket(CustomPacket),
 }
 
 /// Control message handling result
@@ -35,6 +47,26 @@ pub struct ControlResult {
     pub(crate) disconnect: bool,
 }
 
+/// Order of SUBACK/UNSUBACK and control service processing
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AckOrdering {
+    /// Ack is sent immediately after packet is received, before control
+    /// service processes subscribe or unsubscribe message.
+    ///
+    /// All topic filters are acknowledged with requested qos, result of
+    /// control service is ignored.
+    Before,
+    /// Ack is sent after control service completes, publishes sent by
+    /// control service during processing precede ack.
+    After,
+}
+
+impl Default for AckOrdering {
+    fn default() -> Self {
+        AckOrdering::After
+    }
+}
+
 impl<E> ControlMessage<E> {
     /// Create a new `ControlMessage` from AUTH packet.
     #[doc(hidden)]
@@ -63,11 +95,16 @@ impl<E> ControlMessage<E> {
     /// Create a new `ControlMessage` from DISCONNECT packet.
     #[doc(hidden)]
     pub fn remote_disconnect(pkt: codec::Disconnect) -> Self {
-        ControlMessage::Disconnect(Disconnect(pkt))
+        let session_expiry = pkt.session_expiry_interval_secs.unwrap_or(0);
+        ControlMessage::Disconnect(Disconnect(pkt, session_expiry))
+    }
+
+    pub(super) fn session_disconnect(pkt: codec::Disconnect, session_expiry: u32) -> Self {
+        ControlMessage::Disconnect(Disconnect(pkt, session_expiry))
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn closed(is_error: bool, reason: Option<DisconnectReasonCode>) -> Self {
+        ControlMessage::Closed(Closed::new(is_error).with_reason(reason))
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -82,6 +119,14 @@ impl<E> ControlMessage<E> {
         ControlMessage::ProtocolError(ProtocolError::new(err))
     }
 
+    pub(super) fn server_disconnecting(pkt: codec::Disconnect) -> Self {
+        ControlMessage::ServerDisconnecting(ServerDisconnecting(pkt))
+    }
+
+    pub(super) fn custom_packet(first_byte: u8, payload: Bytes) -> Self {
+        ControlMessage::CustomPacket(CustomPacket(first_byte, payload))
+    }
+
     /// Disconnects the client by sending DISCONNECT packet
     /// with `NormalDisconnection` reason code.
     pub fn disconnect(&self) -> ControlResult {
@@ -126,7 +171,7 @@ impl Ping {
 }
 
 #[derive(Debug)]
-pub struct Disconnect(pub(crate) codec::Disconnect);
+pub struct Disconnect(pub(crate) codec::Disconnect, pub(crate) u32);
 
 impl Disconnect {
     /// Returns reference to disconnect packet
@@ -134,6 +179,21 @@ impl Disconnect {
         &self.0
     }
 
+    /// Session expiry interval in seconds
+    ///
+    /// Interval negotiated during handshake, or overridden by DISCONNECT packet.
+    pub fn session_expiry_interval_secs(&self) -> u32 {
+        self.1
+    }
+
+    /// Check if session state must be discarded
+    ///
+    /// Session state must be discarded if session expiry interval is 0,
+    /// otherwise session state should persist for the expiry interval.
+    pub fn is_session_discarded(&self) -> bool {
+        self.1 == 0
+    }
+
     /// Ack disconnect message
     pub fn ack(self) -> ControlResult {
         ControlResult { packet: None, disconnect: true }
@@ -141,10 +201,17 @@ impl Disconnect {
 }
 
 /// Subscribe message
+///
+/// With default `AckOrdering::After` ordering SUBACK is sent only after
+/// control service's future resolves, so control service could persist
+/// subscriptions asynchronously and fail individual topic filters with
+/// `Subscription::fail()` if persisting fails.
 #[derive(Debug)]
 pub struct Subscribe {
     packet: codec::Subscribe,
     result: codec::SubscribeAck,
+    // filters rejected before control service
+    rejected: Vec<bool>,
 }
 
 impl Subscribe {
@@ -162,7 +229,50 @@ impl Subscribe {
             reason_string: None,
         };
 
-        Self { packet, result }
+        Self { packet, result, rejected: Vec::new() }
+    }
+
+    /// Reject filters with more than `max` levels
+    ///
+    /// Rejected filters get `Topic Filter invalid` reason code and
+    /// are skipped by iterator.
+    pub(super) fn reject_filter_levels(&mut self, max: usize) {
+        for (idx, (filter, _)) in self.packet.topic_filters.iter().enumerate() {
+            if Topic::validate_filter_levels(filter, max).is_err() {
+                if self.rejected.is_empty() {
+                    self.rejected.resize(self.packet.topic_filters.len(), false);
+                }
+                self.rejected[idx] = true;
+                self.result.status[idx] = codec::SubscribeAckReason::TopicFilterInvalid;
+            }
+        }
+    }
+
+    /// Ack with requested qos for all filters except rejected ones
+    pub(super) fn granted(&self) -> codec::SubscribeAck {
+        let status = self
+            .packet
+            .topic_filters
+            .iter()
+            .enumerate()
+            .map(|(idx, (_, opts))| {
+                if self.rejected.get(idx).copied().unwrap_or(false) {
+                    return codec::SubscribeAckReason::TopicFilterInvalid;
+                }
+                match opts.qos {
+                    QoS::AtMostOnce => codec::SubscribeAckReason::GrantedQos0,
+                    QoS::AtLeastOnce => codec::SubscribeAckReason::GrantedQos1,
+                    QoS::ExactlyOnce => codec::SubscribeAckReason::GrantedQos2,
+                }
+            })
+            .collect();
+
+        codec::SubscribeAck {
+            packet_id: self.packet.packet_id,
+            status,
+            properties: codec::UserProperties::new(),
+            reason_string: None,
+        }
     }
 
     #[inline]
@@ -190,13 +300,33 @@ impl Subscribe {
 
     #[inline]
     /// Ack Subscribe packet
-    pub fn ack(self) -> ControlResult {
+    pub fn ack(mut self) -> ControlResult {
+        // duplicated filters get status of the last entry
+        let filters = &self.packet.topic_filters;
+        for idx in 0..filters.len() {
+            if let Some(last) = filters.iter().rposition(|(t, _)| t == &filters[idx].0) {
+                self.result.status[idx] = self.result.status[last];
+            }
+        }
+
         ControlResult {
             packet: Some(codec::Packet::SubscribeAck(self.result)),
             disconnect: false,
         }
     }
 
+    #[inline]
+    /// Subscription identifier
+    pub fn id(&self) -> Option<NonZeroU32> {
+        self.packet.id
+    }
+
+    #[inline]
+    /// User properties of subscribe packet
+    pub fn properties(&self) -> &UserProperties {
+        &self.packet.user_properties
+    }
+
     /// Returns reference to subscribe packet
     pub fn packet(&self) -> &codec::Subscribe {
         &self.packet
@@ -223,6 +353,18 @@ impl<'a> SubscribeIter<'a> {
     fn next_unsafe(&mut self) -> Option<Subscription<'a>> {
         let subs = unsafe { &mut *self.subs };
 
+        // skip duplicated filters, last entry wins, and rejected filters
+        while self.entry < subs.packet.topic_filters.len() {
+            let filters = &subs.packet.topic_filters;
+            if filters[self.entry + 1..].iter().any(|(t, _)| t == &filters[self.entry].0)
+                || subs.rejected.get(self.entry).copied().unwrap_or(false)
+            {
+                self.entry += 1;
+            } else {
+                break;
+            }
+        }
+
         if self.entry < subs.packet.topic_filters.len() {
             let s = Subscription {
                 topic: &subs.packet.topic_filters[self.entry].0,
@@ -261,6 +403,12 @@ impl<'a> Subscription<'a> {
         self.topic
     }
 
+    #[inline]
+    /// requested qos for current topic
+    pub fn qos(&self) -> QoS {
+        self.options.qos
+    }
+
     #[inline]
     /// subscription options for current topic
     pub fn options(&self) -> &codec::SubscriptionOptions {
@@ -437,11 +585,17 @@ impl<'a> UnsubscribeItem<'a> {
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    reason: Option<DisconnectReasonCode>,
 }
 
 impl Closed {
     pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+        Self { is_error, reason: None }
+    }
+
+    pub(crate) fn with_reason(mut self, reason: Option<DisconnectReasonCode>) -> Self {
+        self.reason = reason;
+        self
     }
 
     /// Returns error state on connection close
@@ -449,6 +603,24 @@ impl Closed {
         self.is_error
     }
 
+    /// Check if connection is closed cleanly
+    ///
+    /// Connection is closed cleanly if peer sent DISCONNECT packet with
+    /// normal disconnection reason code. Connection is not clean if it is lost,
+    /// or DISCONNECT packet has `DisconnectWithWillMessage` or error reason code,
+    /// will message of the session must be published in these cases.
+    pub fn is_clean(&self) -> bool {
+        match self.reason {
+            Some(DisconnectReasonCode::DisconnectWithWillMessage) | None => false,
+            Some(reason) => u8::from(reason) < 0x80,
+        }
+    }
+
+    /// Reason code of DISCONNECT packet sent by peer, `None` if connection is lost
+    pub fn reason_code(&self) -> Option<DisconnectReasonCode> {
+        self.reason
+    }
+
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
@@ -472,7 +644,7 @@ impl<E> Error<E> {
                 server_reference: None,
                 reason_string: None,
                 user_properties: UserProperties::default(),
-                reason_code: DisconnectReasonCode::ImplementationSpecificError,
+                reason_code: DisconnectReasonCode::UnspecifiedError,
             },
         }
     }
@@ -483,6 +655,16 @@ impl<E> Error<E> {
         &self.err
     }
 
+    #[inline]
+    /// Returns reason code of disconnect packet
+    pub fn reason_code(&self) -> DisconnectReasonCode {
+        self.pkt.reason_code
+    }
+
+    pub(super) fn set_reason_code(&mut self, reason: DisconnectReasonCode) {
+        self.pkt.reason_code = reason;
+    }
+
     #[inline]
     /// Set reason string for disconnect packet
     pub fn reason_string(mut self, reason: ByteString) -> Self {
@@ -541,12 +723,24 @@ impl ProtocolError {
                 reason_string: None,
                 user_properties: UserProperties::default(),
                 reason_code: match err {
-                    error::ProtocolError::Decode(error::DecodeError::InvalidLength) => {
-                        DisconnectReasonCode::MalformedPacket
-                    }
-                    error::ProtocolError::Decode(error::DecodeError::MaxSizeExceeded) => {
-                        DisconnectReasonCode::PacketTooLarge
-                    }
+                    error::ProtocolError::Decode(ref err)
+                    | error::ProtocolError::DecodeInput(ref err, _) => match err {
+                        error::DecodeError::InvalidLength
+                        | error::DecodeError::MalformedPacket
+                        | error::DecodeError::Utf8Error => {
+                            DisconnectReasonCode::MalformedPacket
+                        }
+                        error::DecodeError::MaxSizeExceeded => {
+                            DisconnectReasonCode::PacketTooLarge
+                        }
+                        error::DecodeError::EmptyTopic
+                        | error::DecodeError::InvalidTopicAlias
+                        | error::DecodeError::InvalidProtocol
+                        | error::DecodeError::UnsupportedProtocolLevel => {
+                            DisconnectReasonCode::ProtocolError
+                        }
+                        _ => DisconnectReasonCode::ImplementationSpecificError,
+                    },
                     error::ProtocolError::Unexpected(_, _) => {
                         DisconnectReasonCode::ProtocolError
                     }
@@ -556,9 +750,13 @@ impl ProtocolError {
                     error::ProtocolError::KeepAliveTimeout => {
                         DisconnectReasonCode::KeepAliveTimeout
                     }
-                    error::ProtocolError::UnknownTopicAlias => {
+                    error::ProtocolError::UnknownTopicAlias
+                    | error::ProtocolError::MaxTopicAlias => {
                         DisconnectReasonCode::TopicAliasInvalid
                     }
+                    error::ProtocolError::TopicNameInvalid => {
+                        DisconnectReasonCode::TopicNameInvalid
+                    }
                     error::ProtocolError::Encode(_) => {
                         DisconnectReasonCode::ImplementationSpecificError
                     }
@@ -644,3 +842,96 @@ impl PeerGone {
         ControlResult { packet: None, disconnect: true }
     }
 }
+
+/// Server initiated disconnect
+#[derive(Debug)]
+pub struct ServerDisconnecting(codec::Disconnect);
+
+impl ServerDisconnecting {
+    #[inline]
+    /// Returns reference to disconnect packet
+    pub fn packet(&self) -> &codec::Disconnect {
+        &self.0
+    }
+
+    #[inline]
+    /// Set reason code for disconnect packet
+    pub fn reason_code(mut self, reason: DisconnectReasonCode) -> Self {
+        self.0.reason_code = reason;
+        self
+    }
+
+    #[inline]
+    /// Set reason string for disconnect packet
+    pub fn reason_string(mut self, reason: ByteString) -> Self {
+        self.0.reason_string = Some(reason);
+        self
+    }
+
+    #[inline]
+    /// Ack server disconnect, return disconnect packet and close connection.
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: Some(codec::Packet::Disconnect(self.0)), disconnect: true }
+    }
+}
+
+/// Packet with reserved packet type
+#[derive(Debug)]
+pub struct CustomPacket(u8, Bytes);
+
+impl CustomPacket {
+    #[inline]
+    /// Returns first byte of fixed header, packet type and flags
+    pub fn first_byte(&self) -> u8 {
+        self.0
+    }
+
+    #[inline]
+    /// Returns packet body
+    pub fn payload(&self) -> &Bytes {
+        &self.1
+    }
+
+    #[inline]
+    /// Ack custom packet, connection stays open
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_error_reason_code() {
+        let err = ProtocolError::new(error::ProtocolError::Decode(
+            error::DecodeError::MalformedPacket,
+        ));
+        assert_eq!(err.pkt.reason_code, DisconnectReasonCode::MalformedPacket);
+
+        let err =
+            ProtocolError::new(error::ProtocolError::Decode(error::DecodeError::InvalidLength));
+        assert_eq!(err.pkt.reason_code, DisconnectReasonCode::MalformedPacket);
+
+        let err =
+            ProtocolError::new(error::ProtocolError::Decode(error::DecodeError::EmptyTopic));
+        assert_eq!(err.pkt.reason_code, DisconnectReasonCode::ProtocolError);
+
+        let err = ProtocolError::new(error::ProtocolError::Decode(
+            error::DecodeError::InvalidTopicAlias,
+        ));
+        assert_eq!(err.pkt.reason_code, DisconnectReasonCode::ProtocolError);
+
+        let err = ProtocolError::new(error::ProtocolError::Decode(
+            error::DecodeError::UnsupportedProtocolLevel,
+        ));
+        assert_eq!(err.pkt.reason_code, DisconnectReasonCode::ProtocolError);
+
+        let err = ProtocolError::new(error::ProtocolError::DecodeInput(
+            error::DecodeError::MalformedPacket,
+            "4000".to_string(),
+        ));
+        assert_eq!(err.pkt.reason_code, DisconnectReasonCode::MalformedPacket);
+    }
+}
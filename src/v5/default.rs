@@ -83,6 +83,11 @@ impl<S, E: fmt::Debug> Service<ControlMessage<E>> for DefaultControlService<S, E
         match pkt {
             ControlMessage::Ping(pkt) => Ready::Ok(pkt.ack()),
             ControlMessage::Disconnect(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::ServerDisconnecting(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::Error(err) => {
+                log::warn!("MQTT5 Control service is not configured, error: {:?}", err);
+                Ready::Ok(err.ack_with(|_, pkt| pkt))
+            }
             _ => {
                 log::warn!("MQTT5 Control service is not configured, pkt: {:?}", pkt);
                 Ready::Ok(pkt.disconnect_with(super::codec::Disconnect::new(
@@ -1,25 +1,40 @@
-use std::cell::RefCell;
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, convert::TryFrom};
+use std::{future::Future, marker, num};
+use std::{pin::Pin, rc::Rc, time::Instant};
 
+use ntex::channel::oneshot;
 use ntex::io::DispatchItem;
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
+use ntex::time::Millis;
 use ntex::util::{
-    buffer::BufferService, inflight::InFlightService, join, Either, HashSet, Ready,
+    buffer::BufferService, inflight::InFlightService, join, ByteString, Either, HashMap,
+    HashSet, Ready,
 };
 
 use crate::error::{MqttError, ProtocolError};
+use crate::time::{self, Delay, TimeSource};
+use crate::{topic::Topic, types::packet_type};
 
-use super::control::{ControlMessage, ControlResult};
-use super::publish::{Publish, PublishAck};
+use super::control::{AckOrdering, ControlMessage, ControlResult, Subscribe};
+use super::lifecycle::ConnectionTracker;
+use super::publish::{
+    IdempotencyCheck, IdempotencyGuard, Publish, PublishAck, PublishOrdering, QosCeilingPolicy,
+};
 use super::shared::{Ack, MqttShared};
 use super::sink::MqttSink;
+use super::stats::{ConnectionGuard, InflightGuard};
 use super::{codec, Session};
 
+/// Disconnect reason code mapping for service errors
+pub(super) type ErrorReason<E> = Option<Rc<dyn Fn(&E) -> codec::DisconnectReasonCode>>;
+
 /// mqtt3 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
+    sub_rate_limit: SubRateLimit,
+    error_reason: ErrorReason<E>,
 ) -> impl ServiceFactory<
     DispatchItem<Rc<MqttShared>>,
     Session<St>,
@@ -39,6 +54,7 @@ where
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
 
         let (max_receive, max_topic_alias) = cfg.params();
+        let error_reason = error_reason.clone();
 
         async move {
             let (publish, control) = fut.await;
@@ -51,13 +67,14 @@ where
                 16,
                 || MqttError::<E>::Disconnected(None),
                 // limit number of in-flight messages
-                InFlightService::new(1, control),
+                InFlightService::new(1, ErrorReasonService { service: control, error_reason }),
             );
 
             Ok(Dispatcher::<_, _, E>::new(
                 cfg.sink().clone(),
                 max_receive as usize,
                 max_topic_alias,
+                sub_rate_limit,
                 publish,
                 control,
             ))
@@ -68,10 +85,17 @@ where
 /// Mqtt protocol dispatcher
 pub(crate) struct Dispatcher<T, C: Service<ControlMessage<E>>, E> {
     sink: MqttSink,
-    publish: T,
+    publish: Rc<T>,
+    ordering: Option<Rc<OrderingGate>>,
     shutdown: RefCell<Option<Pin<Box<C::Future>>>>,
     max_receive: usize,
     max_topic_alias: u16,
+    sub_limit: Option<RateLimiter>,
+    _stats: Option<ConnectionGuard>,
+    _conn: Option<ConnectionTracker>,
+    session: usize,
+    // reason code of received DISCONNECT packet
+    disconnected: Cell<Option<codec::DisconnectReasonCode>>,
     inner: Rc<Inner<C>>,
     _t: marker::PhantomData<E>,
 }
@@ -84,7 +108,171 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<num::NonZeroU16>,
-    aliases: HashSet<num::NonZeroU16>,
+    // qos2 packet ids, publish is being processed
+    qos2: HashSet<num::NonZeroU16>,
+    // qos2 packet ids, PUBREC is sent, waiting for PUBREL
+    pending_rel: HashSet<num::NonZeroU16>,
+    aliases: HashMap<num::NonZeroU16, ByteString>,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+/// Rate limit for subscribe and unsubscribe packets
+pub(super) struct SubRateLimit {
+    pub(super) per_sec: u16,
+    pub(super) burst: u16,
+    pub(super) disconnect: bool,
+}
+
+/// Serializes publish service calls with the same ordering key
+struct OrderingGate {
+    ordering: PublishOrdering,
+    // keys of in-process calls and senders of calls waiting for their turn
+    busy: RefCell<HashMap<ByteString, VecDeque<oneshot::Sender<()>>>>,
+}
+
+impl OrderingGate {
+    fn new(ordering: PublishOrdering) -> Self {
+        Self { ordering, busy: RefCell::new(HashMap::default()) }
+    }
+
+    fn key(&self, topic: &ByteString) -> ByteString {
+        match self.ordering {
+            PublishOrdering::Global => ByteString::from_static(""),
+            PublishOrdering::PerTopic => topic.clone(),
+        }
+    }
+
+    /// Take turn for the key, returns receiver if call with the same key is in process
+    fn acquire(&self, key: &ByteString) -> Option<oneshot::Receiver<()>> {
+        let mut busy = self.busy.borrow_mut();
+        if let Some(waiters) = busy.get_mut(key) {
+            let (tx, rx) = oneshot::channel();
+            waiters.push_back(tx);
+            Some(rx)
+        } else {
+            busy.insert(key.clone(), VecDeque::new());
+            None
+        }
+    }
+
+    /// Pass turn to next waiting call
+    fn release(&self, key: &ByteString) {
+        let mut busy = self.busy.borrow_mut();
+        if let Some(waiters) = busy.get_mut(key) {
+            while let Some(tx) = waiters.pop_front() {
+                if tx.send(()).is_ok() {
+                    return;
+                }
+            }
+            busy.remove(key);
+        }
+    }
+}
+
+/// Turn of publish service call
+struct OrderingGuard(Rc<OrderingGate>, ByteString);
+
+impl Drop for OrderingGuard {
+    fn drop(&mut self) {
+        self.0.release(&self.1);
+    }
+}
+
+/// Token bucket, tokens are measured in 1/1000 of a packet
+pub(super) struct RateLimiter {
+    cfg: SubRateLimit,
+    // tokens per second, could be adjusted by adaptive pacing
+    per_sec: Cell<u16>,
+    tokens: Cell<i64>,
+    updated: Cell<Instant>,
+    delay: RefCell<Option<Delay>>,
+    time: Option<Rc<dyn TimeSource>>,
+}
+
+impl RateLimiter {
+    pub(super) fn new(cfg: SubRateLimit, time: Option<Rc<dyn TimeSource>>) -> Self {
+        Self {
+            cfg,
+            per_sec: Cell::new(cfg.per_sec),
+            tokens: Cell::new(i64::from(cfg.burst) * 1000),
+            updated: Cell::new(time::now(time.as_ref())),
+            delay: RefCell::new(None),
+            time,
+        }
+    }
+
+    /// Number of tokens per second
+    pub(super) fn rate(&self) -> u16 {
+        self.per_sec.get()
+    }
+
+    /// Change number of tokens per second, available tokens are kept
+    pub(super) fn set_rate(&self, per_sec: u16) {
+        // tokens accumulated so far are counted at previous rate
+        self.refill();
+        self.per_sec.set(per_sec);
+    }
+
+    /// Take one token, returns delay if there are no available tokens
+    pub(super) fn acquire(&self) -> Result<(), Millis> {
+        let tokens = self.refill() - 1000;
+        self.tokens.set(tokens);
+
+        if tokens >= 0 {
+            Ok(())
+        } else {
+            Err(self.delay_for(-tokens))
+        }
+    }
+
+    /// Check if token is available, token is not taken
+    pub(super) fn check(&self) -> Result<(), Millis> {
+        let tokens = self.refill();
+        if tokens >= 1000 {
+            Ok(())
+        } else {
+            Err(self.delay_for(1000 - tokens))
+        }
+    }
+
+    fn refill(&self) -> i64 {
+        let now = time::now(self.time.as_ref());
+        let elapsed = now.duration_since(self.updated.get()).as_millis() as i64;
+        self.updated.set(now);
+
+        let max = i64::from(self.cfg.burst) * 1000;
+        let tokens =
+            std::cmp::min(self.tokens.get() + elapsed * i64::from(self.per_sec.get()), max);
+        self.tokens.set(tokens);
+        tokens
+    }
+
+    fn delay_for(&self, missing: i64) -> Millis {
+        let rate = i64::from(self.per_sec.get());
+        Millis(((missing + rate - 1) / rate) as u32)
+    }
+
+    /// Pause packets processing
+    fn throttle(&self, delay: Millis) {
+        *self.delay.borrow_mut() = Some(time::sleep(self.time.as_ref(), delay.into()));
+    }
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut delay = self.delay.borrow_mut();
+        if let Some(ref mut fut) = *delay {
+            if Pin::new(fut).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *delay = None;
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<T, C: Service<ControlMessage<E>>, E> Drop for Dispatcher<T, C, E> {
+    fn drop(&mut self) {
+        self.sink.session_end(self.session);
+    }
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -98,26 +286,62 @@ where
         sink: MqttSink,
         max_receive: usize,
         max_topic_alias: u16,
+        sub_rate_limit: SubRateLimit,
         publish: T,
         control: C,
     ) -> Self {
+        let stats = sink.stats().map(|stats| stats.connection());
+        let session = sink.session_start();
+        let conn = sink.connection_start();
+        let ordering = sink.publish_ordering().map(|o| Rc::new(OrderingGate::new(o)));
+
         Self {
-            publish,
+            publish: Rc::new(publish),
+            ordering,
             max_receive,
             max_topic_alias,
+            sub_limit: if sub_rate_limit.per_sec != 0 {
+                Some(RateLimiter::new(sub_rate_limit, sink.time()))
+            } else {
+                None
+            },
             sink: sink.clone(),
+            _stats: stats,
+            _conn: conn,
+            session,
+            disconnected: Cell::new(None),
             shutdown: RefCell::new(None),
             inner: Rc::new(Inner {
                 control,
                 sink,
                 info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
+                    aliases: HashMap::default(),
                     inflight: HashSet::default(),
+                    qos2: HashSet::default(),
+                    pending_rel: HashSet::default(),
                 }),
             }),
             _t: marker::PhantomData,
         }
     }
+
+    /// Check subscribe rate limit, returns disconnect packet if connection must be closed
+    fn check_sub_rate_limit(&self) -> Result<(), codec::Disconnect> {
+        if let Some(ref limit) = self.sub_limit {
+            if let Err(delay) = limit.acquire() {
+                if limit.cfg.disconnect {
+                    log::trace!("Subscribe rate limit is exceeded, disconnecting");
+                    return Err(codec::Disconnect {
+                        reason_code: codec::DisconnectReasonCode::QuotaExceeded,
+                        ..Default::default()
+                    });
+                }
+                log::trace!("Subscribe rate limit is exceeded, pause for {:?}", delay);
+                limit.throttle(delay);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<T, C, E> Service<DispatchItem<Rc<MqttShared>>> for Dispatcher<T, C, E>
@@ -135,6 +359,32 @@ where
     >;
 
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // queued publishes
+        self.sink.poll_queue(cx);
+        // expired subscriptions
+        self.sink.poll_subscriptions(cx);
+
+        // server write budget, dispatcher is woken up when buffers get flushed
+        if self.sink.poll_write_budget(cx).is_pending() {
+            log::trace!("Write budget is exceeded, pause processing");
+            return Poll::Pending;
+        }
+
+        // disconnect requested via session handle
+        if let Some(reason_code) = self.sink.poll_disconnect(cx) {
+            log::trace!("Session disconnect is requested: {:?}", reason_code);
+            self.sink
+                .close_with_reason(codec::Disconnect { reason_code, ..Default::default() });
+        }
+
+        // subscribe rate limit
+        if let Some(ref limit) = self.sub_limit {
+            if limit.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        // not ready publish service pauses io read task
         let res1 = self.publish.poll_ready(cx).map_err(|e| MqttError::Service(e.into()))?;
         let res2 = self.inner.control.poll_ready(cx)?;
 
@@ -149,8 +399,11 @@ where
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
             self.inner.sink.drop_sink();
-            *shutdown =
-                Some(Box::pin(self.inner.control.call(ControlMessage::closed(is_error))));
+            *shutdown = Some(Box::pin(
+                self.inner
+                    .control
+                    .call(ControlMessage::closed(is_error, self.disconnected.get())),
+            ));
         }
 
         let res0 = shutdown.as_mut().expect("guard above").as_mut().poll(cx);
@@ -166,15 +419,47 @@ where
     fn call(&self, request: DispatchItem<Rc<MqttShared>>) -> Self::Future {
         log::trace!("Dispatch v5 packet: {:#?}", request);
 
+        // [MQTT-3.14.4-1] peer must not send packets after DISCONNECT
+        if self.disconnected.get().is_some() {
+            if let DispatchItem::Item(ref pkt) = request {
+                log::debug!("Packet is received after DISCONNECT, ignore: {:?}", pkt);
+                return Either::Right(Either::Left(Ready::Ok(None)));
+            }
+        }
+
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
+                let received_at = self.sink.now();
+
+                if Topic::validate_name(&publish.topic).is_err() {
+                    log::trace!(
+                        "MQTT-3.3.2-2: Publish topic contains wildcards {:?}",
+                        publish.topic
+                    );
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::TopicNameInvalid),
+                        &self.inner,
+                    )));
+                }
+
+                let stats = self.sink.stats().map(|stats| stats.message_in());
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos2 = publish.qos == codec::QoS::ExactlyOnce;
 
-                {
+                let idempotency = {
                     let mut inner = info.info.borrow_mut();
 
                     if let Some(pid) = packet_id {
+                        // qos2 exchange is in progress, treat publish as retransmission
+                        if qos2 && inner.pending_rel.contains(&pid) {
+                            self.sink.send(codec::Packet::PublishReceived(codec::PublishAck {
+                                packet_id: pid,
+                                ..Default::default()
+                            }));
+                            return Either::Right(Either::Left(Ready::Ok(None)));
+                        }
+
                         // check for receive maximum
                         if self.max_receive != 0 && inner.inflight.len() >= self.max_receive {
                             log::trace!(
@@ -190,8 +475,43 @@ where
                             )));
                         }
 
+                        // check for max concurrent qos2 exchanges
+                        let max_qos2 = self.sink.max_inflight_qos2();
+                        if qos2
+                            && max_qos2 != 0
+                            && !inner.qos2.contains(&pid)
+                            && inner.qos2.len() + inner.pending_rel.len() >= max_qos2
+                        {
+                            log::trace!(
+                                "Max inflight qos2 exceeded: max: {} inflight: {}",
+                                max_qos2,
+                                inner.qos2.len() + inner.pending_rel.len()
+                            );
+                            return Either::Right(Either::Right(ControlResponse::new(
+                                ControlMessage::proto_error(
+                                    ProtocolError::ReceiveMaximumExceeded,
+                                ),
+                                &self.inner,
+                            )));
+                        }
+
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
+                            // new publish reuses packet id of in-process publish
+                            if !publish.dup {
+                                log::trace!("Packet id of non-dup publish is in use: {}", pid);
+                                return Either::Right(Either::Right(ControlResponse::new(
+                                    ControlMessage::proto_error(ProtocolError::Unexpected(
+                                        packet_type::PUBLISH_START,
+                                        "MQTT-2.2.1-3: Packet id of new publish is in use",
+                                    )),
+                                    &self.inner,
+                                )));
+                            }
+                            // qos2 publish is still in process, PUBREC is not sent yet
+                            if qos2 {
+                                return Either::Right(Either::Left(Ready::Ok(None)));
+                            }
                             self.sink.send(codec::Packet::PublishAck(codec::PublishAck {
                                 packet_id: pid,
                                 reason_code: codec::PublishAckReason::PacketIdentifierInUse,
@@ -199,13 +519,19 @@ where
                             }));
                             return Either::Right(Either::Left(Ready::Ok(None)));
                         }
+                        if qos2 {
+                            inner.qos2.insert(pid);
+                        }
                     }
 
                     // handle topic aliases
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            // resolve topic from previously recorded alias
+                            if let Some(topic) = inner.aliases.get(&alias) {
+                                publish.topic = topic.clone();
+                            } else {
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -222,17 +548,104 @@ where
                             }
 
                             // record new alias
-                            inner.aliases.insert(alias);
+                            inner.aliases.insert(alias, publish.topic.clone());
+                            self.sink.set_topic_aliases(inner.aliases.len());
+                        }
+                    }
+
+                    // per-topic qos ceiling
+                    if let Some((max_qos, policy)) = self.sink.qos_ceiling(&publish.topic) {
+                        if publish.qos as u8 > max_qos as u8 {
+                            match policy {
+                                QosCeilingPolicy::Downgrade => publish.qos = max_qos,
+                                QosCeilingPolicy::Reject => {
+                                    log::trace!(
+                                        "Publish QoS {:?} exceeds topic {:?} QoS ceiling",
+                                        publish.qos,
+                                        publish.topic
+                                    );
+                                    if let Some(pid) = packet_id {
+                                        inner.inflight.remove(&pid);
+                                        inner.qos2.remove(&pid);
+                                        let ack = codec::PublishAck {
+                                            packet_id: pid,
+                                            reason_code: codec::PublishAckReason::ImplementationSpecificError,
+                                            ..Default::default()
+                                        };
+                                        self.sink.send(if qos2 {
+                                            codec::Packet::PublishReceived(ack)
+                                        } else {
+                                            codec::Packet::PublishAck(ack)
+                                        });
+                                    }
+                                    return Either::Right(Either::Left(Ready::Ok(None)));
+                                }
+                            }
                         }
                     }
-                }
+
+                    // publish with already received idempotency key is acked without processing
+                    match self.sink.check_idempotency(&publish) {
+                        IdempotencyCheck::NoKey => None,
+                        IdempotencyCheck::Recorded(guard) => Some(guard),
+                        IdempotencyCheck::Duplicate => {
+                            log::trace!(
+                                "Publish with duplicated idempotency key: {:?}",
+                                publish.topic
+                            );
+                            if let Some(pid) = packet_id {
+                                inner.inflight.remove(&pid);
+                                inner.qos2.remove(&pid);
+                                let ack =
+                                    codec::PublishAck { packet_id: pid, ..Default::default() };
+                                if qos2 {
+                                    inner.pending_rel.insert(pid);
+                                    self.sink.send(codec::Packet::PublishReceived(ack));
+                                } else {
+                                    self.sink.send(codec::Packet::PublishAck(ack));
+                                }
+                            }
+                            return Either::Right(Either::Left(Ready::Ok(None)));
+                        }
+                    }
+                };
+
+                self.sink.decode_payload(&mut publish);
+
+                // wait for completion of previous publish with the same ordering key
+                let mut ordering = None;
+                let state = if let Some(ref gate) = self.ordering {
+                    let key = gate.key(&publish.topic);
+                    if let Some(rx) = gate.acquire(&key) {
+                        PublishResponseState::Wait {
+                            rx,
+                            publish: Some(Publish::with_received_at(publish, received_at)),
+                            service: self.publish.clone(),
+                            gate: gate.clone(),
+                            key,
+                        }
+                    } else {
+                        ordering = Some(OrderingGuard(gate.clone(), key));
+                        PublishResponseState::Publish {
+                            fut: self
+                                .publish
+                                .call(Publish::with_received_at(publish, received_at)),
+                        }
+                    }
+                } else {
+                    PublishResponseState::Publish {
+                        fut: self.publish.call(Publish::with_received_at(publish, received_at)),
+                    }
+                };
 
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
+                    qos2,
                     inner: info,
-                    state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
-                    },
+                    ordering,
+                    idempotency,
+                    _stats: stats,
+                    state,
                 })
             }
             DispatchItem::Item(codec::Packet::PublishAck(packet)) => {
@@ -245,16 +658,65 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived(packet)) => {
+                if let Err(err) = self.sink.pkt_ack(Ack::Receive(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete(packet)) => {
+                if let Err(err) = self.sink.pkt_ack(Ack::Complete(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease(pkt)) => {
+                let reason_code =
+                    if self.inner.info.borrow_mut().pending_rel.remove(&pkt.packet_id) {
+                        codec::PublishAck2Reason::Success
+                    } else {
+                        codec::PublishAck2Reason::PacketIdNotFound
+                    };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        packet_id: pkt.packet_id,
+                        reason_code,
+                        properties: codec::UserProperties::default(),
+                        reason_string: None,
+                    },
+                )))))
+            }
             DispatchItem::Item(codec::Packet::Auth(pkt)) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::auth(pkt), &self.inner),
             )),
             DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::ping(), &self.inner),
             )),
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::remote_disconnect(pkt), &self.inner),
-            )),
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                self.disconnected.set(Some(pkt.reason_code));
+                let session_expiry =
+                    self.sink.update_session_expiry(pkt.session_expiry_interval_secs);
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::session_disconnect(pkt, session_expiry),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(codec::Packet::Subscribe(pkt)) => {
+                if let Err(pkt) = self.check_sub_rate_limit() {
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::server_disconnecting(pkt),
+                        &self.inner,
+                    )));
+                }
+
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
                     // duplicated packet id
@@ -271,12 +733,38 @@ where
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
                 let id = pkt.packet_id;
+                let mut subscribe = Subscribe::new(pkt);
+                let max_levels = self.sink.max_filter_levels();
+                if max_levels != 0 {
+                    subscribe.reject_filter_levels(max_levels);
+                }
+
+                // send ack before control service processing
+                if self.sink.ack_ordering() == AckOrdering::Before {
+                    self.inner.info.borrow_mut().inflight.remove(&id);
+                    let ack = subscribe.granted();
+                    self.sink.track_subscribe(granted(&subscribe.packet().topic_filters, &ack));
+                    self.sink.send(codec::Packet::SubscribeAck(ack));
+                    return Either::Right(Either::Right(
+                        ControlResponse::new(ControlMessage::Subscribe(subscribe), &self.inner)
+                            .acked(),
+                    ));
+                }
+                let filters = subscribe.packet().topic_filters.clone();
                 Either::Right(Either::Right(
-                    ControlResponse::new(ControlMessage::subscribe(pkt), &self.inner)
-                        .packet_id(id),
+                    ControlResponse::new(ControlMessage::Subscribe(subscribe), &self.inner)
+                        .packet_id(id)
+                        .filters(filters),
                 ))
             }
             DispatchItem::Item(codec::Packet::Unsubscribe(pkt)) => {
+                if let Err(pkt) = self.check_sub_rate_limit() {
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::server_disconnecting(pkt),
+                        &self.inner,
+                    )));
+                }
+
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
                     // duplicated packet id
@@ -293,11 +781,62 @@ where
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
                 let id = pkt.packet_id;
+                self.sink.unsubscribed(&pkt.topic_filters);
+
+                // send ack before control service processing
+                if self.sink.ack_ordering() == AckOrdering::Before {
+                    self.inner.info.borrow_mut().inflight.remove(&id);
+                    self.sink.send(codec::Packet::UnsubscribeAck(codec::UnsubscribeAck {
+                        packet_id: id,
+                        status: pkt
+                            .topic_filters
+                            .iter()
+                            .map(|_| codec::UnsubscribeAckReason::Success)
+                            .collect(),
+                        properties: codec::UserProperties::new(),
+                        reason_string: None,
+                    }));
+                    return Either::Right(Either::Right(
+                        ControlResponse::new(ControlMessage::unsubscribe(pkt), &self.inner)
+                            .acked(),
+                    ));
+                }
                 Either::Right(Either::Right(
                     ControlResponse::new(ControlMessage::unsubscribe(pkt), &self.inner)
                         .packet_id(id),
                 ))
             }
+            DispatchItem::Item(pkt @ codec::Packet::Connect(_)) => {
+                log::trace!("MQTT-3.1.0-2: CONNECT packet is received after handshake");
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::proto_error(ProtocolError::Unexpected(
+                        pkt.packet_type(),
+                        "MQTT-3.1.0-2: Second CONNECT packet",
+                    )),
+                    &self.inner,
+                )))
+            }
+            DispatchItem::Item(pkt @ codec::Packet::PingResponse) => {
+                if self.sink.strict_packet_direction() {
+                    log::trace!("PINGRESP packet is received from client");
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::Unexpected(
+                            pkt.packet_type(),
+                            "PINGRESP packet is sent by server only",
+                        )),
+                        &self.inner,
+                    )))
+                } else {
+                    log::trace!("Ignore PINGRESP packet from client");
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::Custom(first_byte, payload)) => {
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::custom_packet(first_byte, payload),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
             DispatchItem::EncoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
@@ -313,7 +852,7 @@ where
             }
             DispatchItem::DecoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Decode(err)),
+                    ControlMessage::proto_error(self.sink.decode_error(err)),
                     &self.inner,
                 )))
             }
@@ -333,13 +872,25 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
+        qos2: bool,
         inner: Rc<Inner<C>>,
+        ordering: Option<OrderingGuard>,
+        // recorded idempotency key, kept only if publish is processed successfully
+        idempotency: Option<IdempotencyGuard>,
+        _stats: Option<InflightGuard>,
     }
 }
 
 pin_project_lite::pin_project! {
     #[project = PublishResponseStateProject]
     enum PublishResponseState<T: Service<Publish>, C: Service<ControlMessage<E>>, E> {
+        Wait {
+            #[pin] rx: oneshot::Receiver<()>,
+            publish: Option<Publish>,
+            service: Rc<T>,
+            gate: Rc<OrderingGate>,
+            key: ByteString,
+        },
         Publish { #[pin] fut: T::Future },
         Control { #[pin] fut: ControlResponse<C, E> },
     }
@@ -358,8 +909,22 @@ where
         let mut this = self.as_mut().project();
 
         match this.state.as_mut().project() {
+            PublishResponseStateProject::Wait { rx, publish, service, gate, key } => {
+                if rx.poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                *this.ordering = Some(OrderingGuard(gate.clone(), key.clone()));
+                let fut = service.call(publish.take().unwrap());
+                this.state.set(PublishResponseState::Publish { fut });
+                self.poll(cx)
+            }
             PublishResponseStateProject::Publish { fut } => {
-                let ack = match fut.poll(cx) {
+                let res = fut.poll(cx);
+                if res.is_ready() {
+                    // pass turn to next publish with the same ordering key
+                    this.ordering.take();
+                }
+                let ack = match res {
                     Poll::Ready(Ok(ack)) => ack,
                     Poll::Ready(Err(e)) => {
                         if *this.packet_id != 0 {
@@ -387,15 +952,30 @@ where
                     }
                     Poll::Pending => return Poll::Pending,
                 };
+                if let Some(guard) = this.idempotency.take() {
+                    if u8::from(ack.reason_code) < 0x80 {
+                        guard.confirm();
+                    }
+                }
                 if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
-                    this.inner.info.borrow_mut().inflight.remove(&id);
+                    let mut info = this.inner.info.borrow_mut();
+                    info.inflight.remove(&id);
+                    info.qos2.remove(&id);
                     let ack = codec::PublishAck {
                         packet_id: id,
                         reason_code: ack.reason_code,
                         reason_string: ack.reason_string,
                         properties: ack.properties,
                     };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    if *this.qos2 {
+                        // successful PUBREC, wait for PUBREL
+                        if u8::from(ack.reason_code) < 0x80 {
+                            info.pending_rel.insert(id);
+                        }
+                        Poll::Ready(Ok(Some(codec::Packet::PublishReceived(ack))))
+                    } else {
+                        Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    }
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -413,7 +993,12 @@ pin_project_lite::pin_project! {
         fut: C::Future,
         inner: Rc<Inner<C>>,
         error: bool,
+        // server disconnect is reported to control service
+        disconnecting: bool,
+        acked: bool,
         packet_id: u16,
+        // subscribed filters, tracked after subscribe ack
+        filters: Vec<(ByteString, codec::SubscriptionOptions)>,
         _t: marker::PhantomData<E>,
     }
 }
@@ -425,15 +1010,24 @@ where
     #[allow(clippy::match_like_matches_macro)]
     fn new(pkt: ControlMessage<E>, inner: &Rc<Inner<C>>) -> Self {
         let error = match pkt {
-            ControlMessage::Error(_) | ControlMessage::ProtocolError(_) => true,
+            ControlMessage::Error(_)
+            | ControlMessage::ProtocolError(_)
+            | ControlMessage::ServerDisconnecting(_) => true,
+            _ => false,
+        };
+        let disconnecting = match pkt {
+            ControlMessage::ServerDisconnecting(_) => true,
             _ => false,
         };
 
         Self {
             error,
+            disconnecting,
             fut: inner.control.call(pkt),
             inner: inner.clone(),
+            acked: false,
             packet_id: 0,
+            filters: Vec::new(),
             _t: marker::PhantomData,
         }
     }
@@ -442,6 +1036,17 @@ where
         self.packet_id = id.get();
         self
     }
+
+    fn filters(mut self, filters: Vec<(ByteString, codec::SubscriptionOptions)>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Ack is already sent, ignore ack packet of control service
+    fn acked(mut self) -> Self {
+        self.acked = true;
+        self
+    }
 }
 
 impl<C, E> Future for ControlResponse<C, E>
@@ -456,7 +1061,12 @@ where
         let result = match this.fut.poll(cx) {
             Poll::Ready(Ok(result)) => {
                 if let Some(id) = num::NonZeroU16::new(self.packet_id) {
-                    self.inner.info.borrow_mut().inflight.remove(&id);
+                    let mut info = self.inner.info.borrow_mut();
+                    info.inflight.remove(&id);
+                    info.qos2.remove(&id);
+                }
+                if let Some(codec::Packet::SubscribeAck(ref ack)) = result.packet {
+                    self.inner.sink.track_subscribe(granted(&self.filters, ack));
                 }
                 result
             }
@@ -481,8 +1091,20 @@ where
         };
 
         if self.error {
-            if let Some(pkt) = result.packet {
-                self.inner.sink.send(pkt)
+            match result.packet {
+                // server initiated disconnect, control service could change disconnect packet
+                Some(codec::Packet::Disconnect(pkt))
+                    if result.disconnect && !self.disconnecting =>
+                {
+                    let fut =
+                        self.inner.control.call(ControlMessage::server_disconnecting(pkt));
+                    let this = self.as_mut().project();
+                    *this.disconnecting = true;
+                    this.fut.set(fut);
+                    return self.poll(cx);
+                }
+                Some(pkt) => self.inner.sink.send(pkt),
+                None => (),
             }
             if result.disconnect {
                 self.inner.sink.drop_sink();
@@ -492,7 +1114,63 @@ where
             if result.disconnect {
                 self.inner.sink.drop_sink();
             }
+            if self.acked {
+                match result.packet {
+                    Some(codec::Packet::SubscribeAck(_))
+                    | Some(codec::Packet::UnsubscribeAck(_)) => return Poll::Ready(Ok(None)),
+                    _ => (),
+                }
+            }
             Poll::Ready(Ok(result.packet))
         }
     }
 }
+
+/// Topic filters granted by subscribe ack
+fn granted<'a>(
+    filters: &'a [(ByteString, codec::SubscriptionOptions)],
+    ack: &'a codec::SubscribeAck,
+) -> impl Iterator<Item = &'a ByteString> {
+    // reason codes 0x80 or greater indicate failure
+    filters
+        .iter()
+        .zip(ack.status.iter())
+        .filter(|(_, s)| u8::from(**s) < 0x80)
+        .map(|(f, _)| &f.0)
+}
+
+/// Set disconnect reason code of service errors
+struct ErrorReasonService<S, E> {
+    service: S,
+    error_reason: ErrorReason<E>,
+}
+
+impl<S, E> Service<ControlMessage<E>> for ErrorReasonService<S, E>
+where
+    S: Service<ControlMessage<E>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    #[inline]
+    fn call(&self, mut msg: ControlMessage<E>) -> Self::Future {
+        if let Some(ref error_reason) = self.error_reason {
+            if let ControlMessage::Error(ref mut err) = msg {
+                let reason = (*error_reason)(err.get_ref());
+                err.set_reason_code(reason);
+            }
+        }
+        self.service.call(msg)
+    }
+}
@@ -0,0 +1,38 @@
+use std::{convert::TryFrom, fmt};
+
+use ntex::service::{fn_factory_with_config, fn_service, ServiceFactory};
+use ntex::util::{ByteString, Ready};
+
+use super::default::DefaultControlService;
+use super::{Handshake, HandshakeAck, MqttServer, Publish, PublishAck, Session};
+
+/// Create echo server
+///
+/// Server accepts any connection and sends every received publish back
+/// to the client on `echo/{topic}` topic. Echoed messages are sent with QoS 0.
+/// Useful for smoke tests and for trying out clients.
+#[allow(clippy::type_complexity)]
+pub fn echo<E>() -> MqttServer<
+    (),
+    impl ServiceFactory<Handshake, Response = HandshakeAck<()>, Error = E, InitError = ()>,
+    DefaultControlService<(), E>,
+    impl ServiceFactory<Publish, Session<()>, Response = PublishAck, Error = E, InitError = E>,
+>
+where
+    E: fmt::Debug + 'static,
+    PublishAck: TryFrom<E, Error = E>,
+{
+    MqttServer::new(fn_service(|hs: Handshake| Ready::Ok::<_, E>(hs.ack(())))).publish(
+        fn_factory_with_config(|session: Session<()>| {
+            Ready::Ok::<_, E>(fn_service(move |p: Publish| {
+                let topic = ByteString::from(format!("echo/{}", p.publish_topic()));
+                if let Err(e) =
+                    session.sink().publish(topic, p.payload().clone()).send_at_most_once()
+                {
+                    log::trace!("Cannot send echo publish: {:?}", e);
+                }
+                Ready::Ok::<_, E>(p.ack())
+            }))
+        }),
+    )
+}
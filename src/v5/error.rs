@@ -58,3 +58,37 @@ pub enum PublishQos1Error {
     #[display(fmt = "Peer disconnected")]
     Disconnected,
 }
+
+#[derive(Debug, Display, PartialEq)]
+pub enum PublishQos2Error {
+    /// Negative PUBREC from peer, exchange is terminated
+    #[display(fmt = "Negative publish received: {:?}", _0)]
+    Fail(codec::PublishAck),
+    /// Negative PUBCOMP from peer
+    #[display(fmt = "Negative publish complete: {:?}", _0)]
+    Complete(codec::PublishAck2),
+    /// Encoder error
+    Encode(EncodeError),
+    /// Provided packet id is in use
+    #[display(fmt = "Provided packet id is in use")]
+    PacketIdInUse(u16),
+    /// Peer disconnected
+    #[display(fmt = "Peer disconnected")]
+    Disconnected,
+}
+
+/// Errors which can occur during one-shot subscription, see `MqttSink::take_one()`
+#[derive(Debug, Display, From)]
+pub enum TakeOneError {
+    /// Subscription is rejected by server
+    #[display(fmt = "Subscription is rejected: {:?}", _0)]
+    Subscribe(codec::SubscribeAckReason),
+    /// No matching publish is received within timeout
+    #[display(fmt = "Timeout")]
+    Timeout,
+    /// Subscribe or unsubscribe packet cannot be sent
+    #[display(fmt = "Send packet error: {}", _0)]
+    Send(SendPacketError),
+}
+
+impl std::error::Error for TakeOneError {}
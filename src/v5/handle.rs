@@ -0,0 +1,46 @@
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use super::codec::DisconnectReasonCode;
+
+/// Handle for disconnecting session
+///
+/// Handle could be sent to other threads, connection is closed
+/// by connection's dispatcher.
+#[derive(Clone, Debug)]
+pub struct SessionHandle(Arc<Mutex<HandleState>>);
+
+#[derive(Debug, Default)]
+struct HandleState {
+    reason: Option<DisconnectReasonCode>,
+    waker: Option<Waker>,
+}
+
+impl SessionHandle {
+    pub(super) fn new() -> Self {
+        SessionHandle(Arc::new(Mutex::new(HandleState::default())))
+    }
+
+    /// Disconnect session
+    ///
+    /// DISCONNECT packet with provided reason code is sent to the client
+    /// and connection gets closed.
+    pub fn disconnect(&self, reason: DisconnectReasonCode) {
+        let mut state = self.0.lock().unwrap();
+        state.reason = Some(reason);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Check if disconnect is requested
+    pub(super) fn poll_disconnect(&self, cx: &mut Context<'_>) -> Poll<DisconnectReasonCode> {
+        let mut state = self.0.lock().unwrap();
+        if let Some(reason) = state.reason.take() {
+            Poll::Ready(reason)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
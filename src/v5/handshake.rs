@@ -1,7 +1,8 @@
-use ntex::io::IoBoxed;
+use ntex::{io::IoBoxed, util::ByteString, util::Bytes, util::Either};
 use std::{fmt, num::NonZeroU16, rc::Rc};
 
-use super::{codec, shared::MqttShared, sink::MqttSink};
+use super::{codec, handle::SessionHandle, shared::MqttShared, sink::MqttSink};
+use crate::error::{MqttError, ProtocolError};
 
 /// Handshake message
 pub struct Handshake {
@@ -35,6 +36,61 @@ impl Handshake {
         &mut self.pkt
     }
 
+    #[inline]
+    /// Returns will message and will properties
+    pub fn last_will(&self) -> Option<&codec::LastWill> {
+        self.pkt.last_will.as_ref()
+    }
+
+    #[inline]
+    /// Returns retain flag of will message, `false` if will is not set
+    pub fn will_retain(&self) -> bool {
+        self.pkt.last_will.as_ref().map(|will| will.retain).unwrap_or(false)
+    }
+
+    #[inline]
+    /// Returns authentication method of CONNECT packet
+    pub fn auth_method(&self) -> Option<&str> {
+        self.pkt.auth_method.as_deref()
+    }
+
+    #[inline]
+    /// Returns authentication data of CONNECT packet
+    pub fn auth_data(&self) -> Option<&[u8]> {
+        self.pkt.auth_data.as_deref()
+    }
+
+    #[inline]
+    /// Returns will message payload
+    ///
+    /// Payload is not copied, it refers to the memory of received CONNECT packet.
+    pub fn will_payload(&self) -> Option<&Bytes> {
+        self.pkt.last_will.as_ref().map(|will| &will.message)
+    }
+
+    /// Send AUTH packet to the client and wait for client's AUTH response
+    ///
+    /// Used for driving enhanced authentication exchange, client continues
+    /// exchange with `ContinueAuth` reason code. Handshake could call this
+    /// method multiple times before ack.
+    pub async fn auth(&self, pkt: codec::Auth) -> Result<codec::Auth, MqttError<()>> {
+        self.io.send(codec::Packet::Auth(pkt), &self.shared.codec).await?;
+
+        match self.io.recv(&self.shared.codec).await {
+            Ok(Some(codec::Packet::Auth(pkt))) => Ok(pkt),
+            Ok(Some(pkt)) => {
+                log::trace!("Unexpected packet during auth exchange: {:?}", pkt);
+                Err(MqttError::Protocol(ProtocolError::Unexpected(
+                    pkt.packet_type(),
+                    "AUTH packet is expected",
+                )))
+            }
+            Ok(None) => Err(MqttError::Disconnected(None)),
+            Err(Either::Left(err)) => Err(MqttError::Protocol(self.shared.decode_error(err))),
+            Err(Either::Right(err)) => Err(MqttError::Disconnected(Some(err))),
+        }
+    }
+
     #[inline]
     pub fn io(&self) -> &IoBoxed {
         &self.io
@@ -46,6 +102,12 @@ impl Handshake {
         MqttSink::new(self.shared.clone())
     }
 
+    #[inline]
+    /// Returns handle for disconnecting session
+    pub fn session_handle(&self) -> SessionHandle {
+        self.shared.handle.borrow_mut().get_or_insert_with(SessionHandle::new).clone()
+    }
+
     #[inline]
     /// Ack handshake message and set state
     pub fn ack<St>(self, st: St) -> HandshakeAck<St> {
@@ -60,15 +122,27 @@ impl Handshake {
         if self.max_receive != 0 {
             packet.receive_max = Some(NonZeroU16::new(self.max_receive).unwrap());
         }
+        if let Some(ref defaults) = *self.shared.pool.connack_defaults.borrow() {
+            defaults.apply(&mut packet);
+        }
 
         let Handshake { io, shared, pkt, .. } = self;
+        let response_info_requested = pkt.request_response_info;
         // [MQTT-3.1.2-22]
         let keepalive = if pkt.keep_alive != 0 {
             (pkt.keep_alive >> 1).checked_add(pkt.keep_alive).unwrap_or(u16::MAX)
         } else {
             30
         };
-        HandshakeAck { io, shared, keepalive, packet, session: Some(st) }
+        HandshakeAck {
+            io,
+            shared,
+            keepalive,
+            packet,
+            session: Some(st),
+            keepalive_disabled: false,
+            response_info_requested,
+        }
     }
 
     #[inline]
@@ -79,6 +153,8 @@ impl Handshake {
             shared: self.shared,
             session: None,
             keepalive: 30,
+            keepalive_disabled: false,
+            response_info_requested: false,
             packet: codec::ConnectAck { reason_code, ..codec::ConnectAck::default() },
         }
     }
@@ -92,6 +168,8 @@ impl Handshake {
             session: None,
             packet: ack,
             keepalive: 30,
+            keepalive_disabled: false,
+            response_info_requested: false,
         }
     }
 }
@@ -109,6 +187,8 @@ pub struct HandshakeAck<St> {
     pub(crate) shared: Rc<MqttShared>,
     pub(crate) packet: codec::ConnectAck,
     pub(crate) keepalive: u16,
+    pub(crate) keepalive_disabled: bool,
+    pub(crate) response_info_requested: bool,
 }
 
 impl<St> HandshakeAck<St> {
@@ -126,6 +206,29 @@ impl<St> HandshakeAck<St> {
         self
     }
 
+    #[inline]
+    /// Disable keep-alive timer of the connection.
+    ///
+    /// Idle connection is not closed, advertised `server_keepalive_sec`
+    /// property of `ConnectAck` response packet is not affected.
+    /// Useful for bridges that manage connection liveness.
+    pub fn disable_keepalive(mut self) -> Self {
+        self.keepalive_disabled = true;
+        self
+    }
+
+    #[inline]
+    /// Set `Response Information` property of `ConnectAck` response packet.
+    ///
+    /// Property is sent only if client requested response information
+    /// with `Request Response Information` property of CONNECT packet.
+    pub fn response_information(mut self, info: ByteString) -> Self {
+        if self.response_info_requested {
+            self.packet.response_info = Some(info);
+        }
+        self
+    }
+
     /// Access to ConnectAck packet
     #[inline]
     pub fn with(mut self, f: impl FnOnce(&mut codec::ConnectAck)) -> Self {
@@ -0,0 +1,256 @@
+use std::sync::{atomic::AtomicUsize, atomic::Ordering, Arc, Mutex};
+use std::{cell::Cell, cell::RefCell, cmp, rc::Rc, time::Duration, time::Instant};
+
+use ntex::util::{ByteString, HashMap};
+
+use super::codec;
+use crate::time::{self, TimeSource};
+
+pub(super) type OnSessionEnd = Rc<dyn Fn(&SessionInfo)>;
+pub(super) type OnConnectionChange = Rc<dyn Fn(ConnectionEvent)>;
+
+/// Information about destroyed session
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    client_id: ByteString,
+    expired: bool,
+}
+
+impl SessionInfo {
+    /// Client id of the session
+    pub fn client_id(&self) -> &ByteString {
+        &self.client_id
+    }
+
+    /// Check if session is destroyed because session expiry interval elapsed
+    ///
+    /// Returns `false` if session is ended by connection close with
+    /// session expiry interval set to 0.
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+}
+
+/// Sessions lifecycle tracking
+#[derive(Default)]
+pub(super) struct Sessions {
+    pub(super) on_end: RefCell<Option<OnSessionEnd>>,
+    // client id -> generation of latest connection
+    active: RefCell<HashMap<ByteString, usize>>,
+    generation: Cell<usize>,
+    // client id -> un-acked publishes of closed connection, re-sent on session resume
+    inflight: RefCell<HashMap<ByteString, (usize, Vec<codec::Publish>)>>,
+    stored: Cell<usize>,
+}
+
+impl Sessions {
+    pub(super) fn set_on_end(&self, f: OnSessionEnd) {
+        *self.on_end.borrow_mut() = Some(f);
+    }
+
+    /// Register connection for session, returns connection generation
+    pub(super) fn start(&self, client_id: &ByteString) -> usize {
+        if self.on_end.borrow().is_none() {
+            return 0;
+        }
+
+        let generation = self.generation.get() + 1;
+        self.generation.set(generation);
+        self.active.borrow_mut().insert(client_id.clone(), generation);
+        generation
+    }
+
+    /// Store un-acked publishes of closed connection until session expires
+    pub(super) fn store_inflight(
+        self: &Rc<Self>,
+        client_id: ByteString,
+        packets: Vec<codec::Publish>,
+        expiry: u32,
+        time: Option<Rc<dyn TimeSource>>,
+    ) {
+        if packets.is_empty() || expiry == 0 {
+            return;
+        }
+
+        let stamp = self.stored.get() + 1;
+        self.stored.set(stamp);
+        self.inflight.borrow_mut().insert(client_id.clone(), (stamp, packets));
+
+        if expiry != u32::MAX {
+            let sessions = self.clone();
+            let delay = time::sleep(time.as_ref(), Duration::from_secs(u64::from(expiry)));
+            ntex::rt::spawn(async move {
+                delay.await;
+                let mut inflight = sessions.inflight.borrow_mut();
+                if inflight.get(&client_id).map(|(s, _)| *s) == Some(stamp) {
+                    inflight.remove(&client_id);
+                }
+            });
+        }
+    }
+
+    /// Take un-acked publishes of previous connection of the session
+    pub(super) fn take_inflight(&self, client_id: &ByteString) -> Vec<codec::Publish> {
+        self.inflight.borrow_mut().remove(client_id).map(|(_, p)| p).unwrap_or_default()
+    }
+
+    /// Connection of the session is closed
+    pub(super) fn end(
+        self: &Rc<Self>,
+        client_id: ByteString,
+        generation: usize,
+        expiry: u32,
+        time: Option<Rc<dyn TimeSource>>,
+    ) {
+        let on_end = if let Some(ref f) = *self.on_end.borrow() {
+            f.clone()
+        } else {
+            return;
+        };
+
+        // session is taken over by new connection
+        if self.active.borrow().get(&client_id) != Some(&generation) {
+            return;
+        }
+
+        if expiry == 0 {
+            self.active.borrow_mut().remove(&client_id);
+            (*on_end)(&SessionInfo { client_id, expired: false });
+        } else if expiry != u32::MAX {
+            let sessions = self.clone();
+            let delay = time::sleep(time.as_ref(), Duration::from_secs(u64::from(expiry)));
+            ntex::rt::spawn(async move {
+                delay.await;
+                if sessions.active.borrow().get(&client_id) == Some(&generation) {
+                    sessions.active.borrow_mut().remove(&client_id);
+                    (*on_end)(&SessionInfo { client_id, expired: true });
+                }
+            });
+        }
+    }
+}
+
+/// Change of number of active connections
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// Connection is established, carries new number of connections
+    Connected(usize),
+    /// Connection is closed, carries new number of connections
+    Disconnected(usize),
+}
+
+impl ConnectionEvent {
+    /// Number of active connections after the change
+    pub fn count(&self) -> usize {
+        match self {
+            ConnectionEvent::Connected(count) | ConnectionEvent::Disconnected(count) => *count,
+        }
+    }
+}
+
+/// Number of active connections
+///
+/// Cloned handle refers to the same counter, so the same handle could be
+/// used by all server workers.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionCounter(Arc<AtomicUsize>);
+
+impl ConnectionCounter {
+    /// Create new counter
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of active connections
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Rate limit for connection handshakes
+///
+/// Token bucket with `burst` capacity that is refilled at `per_sec` rate.
+/// Cloned handle refers to the same bucket, so the same handle could be
+/// used by all server workers.
+#[derive(Clone, Debug)]
+pub struct AcceptRateLimit(Arc<Mutex<AcceptBucket>>);
+
+#[derive(Debug)]
+struct AcceptBucket {
+    per_sec: u16,
+    burst: u16,
+    // tokens are measured in 1/1000 of a handshake
+    tokens: i64,
+    updated: Instant,
+}
+
+impl AcceptRateLimit {
+    /// Create new rate limit
+    ///
+    /// `burst` handshakes could be accepted at once, after that handshakes
+    /// are accepted at `per_sec` rate.
+    pub fn new(per_sec: u16, burst: u16) -> Self {
+        AcceptRateLimit(Arc::new(Mutex::new(AcceptBucket {
+            per_sec,
+            burst,
+            tokens: i64::from(burst) * 1000,
+            updated: Instant::now(),
+        })))
+    }
+
+    /// Take one token, returns `false` if rate limit is exceeded
+    pub(super) fn acquire(&self) -> bool {
+        let mut bucket = self.0.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_micros() as i64;
+        bucket.updated = now;
+
+        let max = i64::from(bucket.burst) * 1000;
+        let refill = elapsed * i64::from(bucket.per_sec) / 1000;
+        bucket.tokens = cmp::min(max, bucket.tokens + refill);
+        if bucket.tokens >= 1000 {
+            bucket.tokens -= 1000;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Connections count tracking
+#[derive(Default)]
+pub(super) struct Connections {
+    pub(super) on_change: RefCell<Option<OnConnectionChange>>,
+    pub(super) counter: RefCell<ConnectionCounter>,
+}
+
+impl Connections {
+    pub(super) fn set_on_change(&self, f: OnConnectionChange) {
+        *self.on_change.borrow_mut() = Some(f);
+    }
+
+    pub(super) fn set_counter(&self, counter: ConnectionCounter) {
+        *self.counter.borrow_mut() = counter;
+    }
+
+    /// Register new connection
+    pub(super) fn connected(self: &Rc<Self>) -> Option<ConnectionTracker> {
+        let on_change = self.on_change.borrow().clone()?;
+        let count = self.counter.borrow().0.fetch_add(1, Ordering::SeqCst) + 1;
+        (*on_change)(ConnectionEvent::Connected(count));
+        Some(ConnectionTracker(self.clone()))
+    }
+}
+
+/// Active connection marker, notifies about disconnect on drop
+pub(super) struct ConnectionTracker(Rc<Connections>);
+
+impl Drop for ConnectionTracker {
+    fn drop(&mut self) {
+        let count = self.0.counter.borrow().0.fetch_sub(1, Ordering::SeqCst) - 1;
+        let on_change = self.0.on_change.borrow().clone();
+        if let Some(on_change) = on_change {
+            (*on_change)(ConnectionEvent::Disconnected(count));
+        }
+    }
+}
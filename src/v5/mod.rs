@@ -1,28 +1,45 @@
 //! MQTT5 Client/Server framework
 
+mod budget;
 pub mod client;
 pub mod codec;
 pub mod control;
 mod default;
 mod dispatcher;
+mod echo;
 pub mod error;
+mod handle;
 mod handshake;
+mod lifecycle;
+mod offline;
+mod payload;
 mod publish;
 mod router;
 mod selector;
 mod server;
 mod shared;
 mod sink;
+mod stats;
 
 pub type Session<St> = crate::Session<MqttSink, St>;
 
-pub use self::control::{ControlMessage, ControlResult};
+pub use self::budget::WriteBudget;
+pub use self::control::{AckOrdering, ControlMessage, ControlResult};
+pub use self::echo::echo;
+pub use self::handle::SessionHandle;
 pub use self::handshake::{Handshake, HandshakeAck};
-pub use self::publish::{Publish, PublishAck};
-pub use self::router::Router;
+pub use self::lifecycle::{AcceptRateLimit, ConnectionCounter, ConnectionEvent, SessionInfo};
+pub use self::offline::OfflineQueue;
+pub use self::payload::PayloadCodec;
+pub use self::publish::{Publish, PublishAck, PublishOrdering, QosCeilingPolicy};
+pub use self::router::{RouteInfo, Router};
 pub use self::selector::Selector;
-pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
+pub use self::server::{ConnackProps, MqttServer, ServerLimits};
+pub use self::sink::{
+    FlushPolicy, MqttSink, OverflowPolicy, PubAck, PublishBuilder, PublishOptions,
+    SubscribeBuilder, UnsubscribeBuilder, UnsubscribePolicy,
+};
+pub use self::stats::ServerStats;
 
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+
+use super::codec;
+use crate::types::QoS;
+
+/// Publishes queue of offline session
+///
+/// Queue stores publishes while client is disconnected, stored publishes
+/// get re-sent with `MqttSink::publish_pkt()` after client re-connects.
+#[derive(Debug, Default)]
+pub struct OfflineQueue {
+    queue: VecDeque<codec::Publish>,
+    coalesce: bool,
+}
+
+impl OfflineQueue {
+    /// Create empty queue
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep only newest QoS0 and QoS1 publish per topic.
+    ///
+    /// QoS2 publishes are never coalesced.
+    ///
+    /// By default coalescing is disabled.
+    pub fn coalesce_by_topic(mut self, val: bool) -> Self {
+        self.coalesce = val;
+        self
+    }
+
+    /// Number of stored publishes
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Check if queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Store publish
+    pub fn push(&mut self, pkt: codec::Publish) {
+        if self.coalesce && pkt.qos != QoS::ExactlyOnce {
+            self.queue.retain(|p| p.qos == QoS::ExactlyOnce || p.topic != pkt.topic);
+        }
+        self.queue.push_back(pkt);
+    }
+
+    /// Take stored publishes in replay order
+    pub fn drain(&mut self) -> impl Iterator<Item = codec::Publish> + '_ {
+        self.queue.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::{ByteString, Bytes};
+
+    use super::*;
+
+    fn publish(topic: &'static str, payload: &'static [u8], qos: QoS) -> codec::Publish {
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos,
+            topic: ByteString::from_static(topic),
+            packet_id: None,
+            payload: Bytes::from_static(payload),
+            properties: codec::PublishProperties::default(),
+        }
+    }
+
+    fn replay(queue: &mut OfflineQueue) -> Vec<(String, Bytes)> {
+        queue.drain().map(|p| (p.topic.to_string(), p.payload)).collect()
+    }
+
+    #[test]
+    fn test_offline_queue() {
+        let mut queue = OfflineQueue::new();
+        queue.push(publish("t1", b"1", QoS::AtLeastOnce));
+        queue.push(publish("t1", b"2", QoS::AtLeastOnce));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(replay(&mut queue).len(), 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_coalesce_by_topic() {
+        let mut queue = OfflineQueue::new().coalesce_by_topic(true);
+        queue.push(publish("t1", b"1", QoS::AtMostOnce));
+        queue.push(publish("t2", b"1", QoS::AtLeastOnce));
+        queue.push(publish("t1", b"2", QoS::AtLeastOnce));
+        queue.push(publish("t2", b"2", QoS::AtLeastOnce));
+        queue.push(publish("t1", b"3", QoS::AtMostOnce));
+        assert_eq!(
+            replay(&mut queue),
+            vec![
+                ("t2".to_string(), Bytes::from_static(b"2")),
+                ("t1".to_string(), Bytes::from_static(b"3"))
+            ]
+        );
+
+        // qos2 publishes are not coalesced
+        queue.push(publish("t1", b"1", QoS::ExactlyOnce));
+        queue.push(publish("t1", b"2", QoS::ExactlyOnce));
+        queue.push(publish("t1", b"3", QoS::AtLeastOnce));
+        queue.push(publish("t1", b"4", QoS::AtLeastOnce));
+        assert_eq!(
+            replay(&mut queue),
+            vec![
+                ("t1".to_string(), Bytes::from_static(b"1")),
+                ("t1".to_string(), Bytes::from_static(b"2")),
+                ("t1".to_string(), Bytes::from_static(b"4"))
+            ]
+        );
+    }
+}
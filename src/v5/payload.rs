@@ -0,0 +1,14 @@
+use ntex::util::{ByteString, Bytes};
+
+/// Publish payload transformation
+///
+/// Payload codec allows to transparently transform payloads, for example
+/// to decompress or decrypt. Received payloads get decoded before publish
+/// handler is called, payloads sent via `MqttSink` get encoded.
+pub trait PayloadCodec {
+    /// Decode payload of received publish packet
+    fn decode(&self, topic: &ByteString, payload: Bytes) -> Bytes;
+
+    /// Encode payload of publish packet before sending
+    fn encode(&self, topic: &ByteString, payload: Bytes) -> Bytes;
+}
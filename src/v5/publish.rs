@@ -1,16 +1,140 @@
-use std::{mem, num::NonZeroU16};
+use std::time::{Duration, Instant};
+use std::{cell::RefCell, collections::VecDeque, mem, num::NonZeroU16, rc::Rc};
 
 use ntex::router::Path;
-use ntex::util::{ByteString, Bytes};
+use ntex::util::{ByteString, Bytes, HashMap};
 use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
 
 use super::codec;
+use crate::types::{packet_type, QoS};
+
+pub(super) type QosCeiling = (Rc<dyn Fn(&str) -> QoS>, QosCeilingPolicy);
+
+/// Recently seen idempotency keys of inbound publishes
+pub(super) struct Idempotency {
+    property: ByteString,
+    window: Duration,
+    // client id and idempotency key, and instant key is recorded at
+    keys: RefCell<HashMap<(ByteString, ByteString), Instant>>,
+    // keys in order of arrival
+    order: RefCell<VecDeque<(Instant, (ByteString, ByteString))>>,
+}
+
+/// Result of idempotency key check
+pub(super) enum IdempotencyCheck {
+    /// Publish does not have idempotency key
+    NoKey,
+    /// Publish with the same key is received within window
+    Duplicate,
+    /// Key is recorded until publish is processed
+    Recorded(IdempotencyGuard),
+}
+
+impl Idempotency {
+    pub(super) fn new(property: ByteString, window: Duration) -> Self {
+        Self {
+            property,
+            window,
+            keys: RefCell::new(HashMap::default()),
+            order: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    /// Check if publish with the same idempotency key is received
+    /// from the same client within window, record key otherwise
+    ///
+    /// Recorded key is removed on guard drop, unless publish is processed
+    /// successfully and guard is confirmed.
+    pub(super) fn check(
+        self: &Rc<Self>,
+        client_id: &ByteString,
+        pkt: &codec::Publish,
+        now: Instant,
+    ) -> IdempotencyCheck {
+        let value = if let Some((_, value)) =
+            pkt.properties.user_properties.iter().find(|(k, _)| *k == self.property)
+        {
+            value
+        } else {
+            return IdempotencyCheck::NoKey;
+        };
+
+        let mut keys = self.keys.borrow_mut();
+        let mut order = self.order.borrow_mut();
+
+        // remove expired keys
+        while let Some((created, _)) = order.front() {
+            if now.saturating_duration_since(*created) < self.window {
+                break;
+            }
+            if let Some((created, key)) = order.pop_front() {
+                // key could be removed and recorded again
+                if keys.get(&key) == Some(&created) {
+                    keys.remove(&key);
+                }
+            }
+        }
+
+        let key = (client_id.clone(), value.clone());
+        if keys.contains_key(&key) {
+            IdempotencyCheck::Duplicate
+        } else {
+            keys.insert(key.clone(), now);
+            order.push_back((now, key.clone()));
+            IdempotencyCheck::Recorded(IdempotencyGuard { store: self.clone(), key: Some(key) })
+        }
+    }
+}
+
+/// Recorded idempotency key of publish in process
+pub(super) struct IdempotencyGuard {
+    store: Rc<Idempotency>,
+    key: Option<(ByteString, ByteString)>,
+}
+
+impl IdempotencyGuard {
+    /// Keep key, publish is processed successfully
+    pub(super) fn confirm(mut self) {
+        self.key.take();
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        // publish is failed or dropped, retry must be processed
+        if let Some(key) = self.key.take() {
+            self.store.keys.borrow_mut().remove(&key);
+        }
+    }
+}
+
+/// Handling of publishes with QoS above topic QoS ceiling
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QosCeilingPolicy {
+    /// Pass publish to publish service with QoS lowered to the ceiling.
+    ///
+    /// Publish is acknowledged according to QoS of received packet.
+    Downgrade,
+    /// Reject publish with `Implementation specific error` reason code
+    Reject,
+}
+
+/// Order of publish service calls
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PublishOrdering {
+    /// Publish service is called after previous call completes
+    Global,
+    /// Calls for the same topic are serialized, calls for different
+    /// topics could run concurrently
+    PerTopic,
+}
 
 /// Publish message
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    received_at: Instant,
 }
 
 impl Publish {
@@ -18,7 +142,20 @@ impl Publish {
     /// packet
     #[doc(hidden)]
     pub fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+        Self::with_received_at(publish, Instant::now())
+    }
+
+    /// Create a new `Publish` message from a PUBLISH packet received at `received_at`
+    pub(crate) fn with_received_at(publish: codec::Publish, received_at: Instant) -> Self {
+        Self { topic: Path::new(publish.topic.clone()), publish, received_at }
+    }
+
+    #[inline]
+    /// Instant when PUBLISH packet is received by connection dispatcher.
+    ///
+    /// Server connections take instant from time source of the server.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
     }
 
     #[inline]
@@ -32,12 +169,32 @@ impl Publish {
         self.publish.retain
     }
 
+    #[inline]
+    /// retained publish with empty payload, clears retained message of the topic.
+    pub fn is_retain_clear(&self) -> bool {
+        self.publish.retain && self.publish.payload.is_empty()
+    }
+
     #[inline]
     /// the level of assurance for delivery of an Application Message.
     pub fn qos(&self) -> codec::QoS {
         self.publish.qos
     }
 
+    #[inline]
+    /// raw fixed header byte of the publish packet, includes packet type,
+    /// DUP, QoS and RETAIN bits.
+    pub fn fixed_header_byte(&self) -> u8 {
+        let mut byte = packet_type::PUBLISH_START | (u8::from(self.publish.qos) << 1);
+        if self.publish.dup {
+            byte |= 0b1000;
+        }
+        if self.publish.retain {
+            byte |= 0b0001;
+        }
+        byte
+    }
+
     #[inline]
     /// the information channel to which payload data is published.
     pub fn publish_topic(&self) -> &str {
@@ -148,3 +305,134 @@ impl PublishAck {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::{codec::Decoder, util::BytesMut};
+
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Publish {
+        let mut buf = BytesMut::from(bytes);
+        match codec::Codec::new().decode(&mut buf).unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => Publish::new(pkt),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_fixed_header() {
+        let p = decode(b"\x30\x05\x00\x02/a\x00");
+        assert!(!p.dup());
+        assert!(!p.retain());
+        assert_eq!(p.qos(), codec::QoS::AtMostOnce);
+        assert_eq!(p.fixed_header_byte(), 0b0011_0000);
+
+        let p = decode(b"\x3b\x07\x00\x02/a\x00\x01\x00");
+        assert!(p.dup());
+        assert!(p.retain());
+        assert_eq!(p.qos(), codec::QoS::AtLeastOnce);
+        assert_eq!(p.fixed_header_byte(), 0b0011_1011);
+
+        let p = decode(b"\x34\x07\x00\x02/a\x00\x01\x00");
+        assert!(!p.dup());
+        assert!(!p.retain());
+        assert_eq!(p.qos(), codec::QoS::ExactlyOnce);
+        assert_eq!(p.fixed_header_byte(), 0b0011_0100);
+
+        let p = decode(b"\x38\x05\x00\x02/a\x00");
+        assert!(p.dup());
+        assert_eq!(p.fixed_header_byte(), 0b0011_1000);
+    }
+
+    #[test]
+    fn test_idempotency() {
+        let pkt = |key: &'static str| codec::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("topic"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+            properties: codec::PublishProperties {
+                user_properties: vec![(
+                    ByteString::from_static("idem"),
+                    ByteString::from_static(key),
+                )],
+                ..Default::default()
+            },
+        };
+        let client1 = ByteString::from_static("client1");
+        let client2 = ByteString::from_static("client2");
+
+        // confirm recorded keys
+        let check = |idempotency: &Rc<Idempotency>, client_id, pkt| match idempotency.check(
+            client_id,
+            &pkt,
+            Instant::now(),
+        ) {
+            IdempotencyCheck::NoKey => "nokey",
+            IdempotencyCheck::Duplicate => "duplicate",
+            IdempotencyCheck::Recorded(guard) => {
+                guard.confirm();
+                "recorded"
+            }
+        };
+
+        let idempotency =
+            Rc::new(Idempotency::new(ByteString::from_static("idem"), Duration::from_secs(10)));
+        assert_eq!(check(&idempotency, &client1, pkt("k1")), "recorded");
+        assert_eq!(check(&idempotency, &client1, pkt("k1")), "duplicate");
+        assert_eq!(check(&idempotency, &client1, pkt("k2")), "recorded");
+        assert_eq!(check(&idempotency, &client2, pkt("k1")), "recorded");
+
+        // publish without key
+        let mut p = pkt("k1");
+        p.properties.user_properties.clear();
+        assert_eq!(check(&idempotency, &client1, p.clone()), "nokey");
+        assert_eq!(check(&idempotency, &client1, p), "nokey");
+
+        // key of failed publish is removed, retry is processed
+        let guard = idempotency.check(&client1, &pkt("k3"), Instant::now());
+        assert_eq!(check(&idempotency, &client1, pkt("k3")), "duplicate");
+        drop(guard);
+        assert_eq!(check(&idempotency, &client1, pkt("k3")), "recorded");
+        assert_eq!(check(&idempotency, &client1, pkt("k3")), "duplicate");
+
+        // keys expire after window
+        let idempotency =
+            Rc::new(Idempotency::new(ByteString::from_static("idem"), Duration::ZERO));
+        assert_eq!(check(&idempotency, &client1, pkt("k1")), "recorded");
+        assert_eq!(check(&idempotency, &client1, pkt("k1")), "recorded");
+
+        // window is measured with provided time
+        let idempotency =
+            Rc::new(Idempotency::new(ByteString::from_static("idem"), Duration::from_secs(10)));
+        let now = Instant::now();
+        let check_at = |now| match idempotency.check(&client1, &pkt("k1"), now) {
+            IdempotencyCheck::NoKey => "nokey",
+            IdempotencyCheck::Duplicate => "duplicate",
+            IdempotencyCheck::Recorded(guard) => {
+                guard.confirm();
+                "recorded"
+            }
+        };
+        assert_eq!(check_at(now), "recorded");
+        assert_eq!(check_at(now + Duration::from_secs(9)), "duplicate");
+        assert_eq!(check_at(now + Duration::from_secs(10)), "recorded");
+    }
+
+    #[test]
+    fn test_retain_clear() {
+        let p = decode(b"\x31\x05\x00\x02/a\x00");
+        assert!(p.payload().is_empty());
+        assert!(p.is_retain_clear());
+
+        let p = decode(b"\x31\x06\x00\x02/a\x00x");
+        assert!(!p.is_retain_clear());
+
+        let p = decode(b"\x30\x05\x00\x02/a\x00");
+        assert!(p.payload().is_empty());
+        assert!(!p.is_retain_clear());
+    }
+}
@@ -1,23 +1,50 @@
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{cell::Cell, cell::RefCell, future::Future, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::router::{IntoPattern, Path, RouterBuilder};
 use ntex::service::boxed::{self, BoxService, BoxServiceFactory};
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
 use ntex::task::LocalWaker;
+use ntex::time::Millis;
 use ntex::util::{ByteString, HashMap};
 
 use super::publish::{Publish, PublishAck};
 
 type Handler<S, E> = BoxServiceFactory<S, Publish, PublishAck, E, E>;
 type HandlerService<E> = BoxService<Publish, PublishAck, E>;
+type OnRoute = Rc<dyn Fn(&str, Option<RouteInfo<'_>>)>;
+
+/// Route match information
+pub struct RouteInfo<'a> {
+    /// Index of matched resource, in order of registration
+    pub index: usize,
+    /// Matched topic with captured params
+    pub path: &'a Path<ByteString>,
+}
 
 /// Router - structure that follows the builder pattern
 /// for building publish packet router instances for mqtt server.
 pub struct Router<S, Err> {
     router: RouterBuilder<usize>,
+    matchers: Vec<ntex::router::Router<usize>>,
     handlers: Vec<Handler<S, Err>>,
     default: Handler<S, Err>,
+    multi_match: bool,
+    on_route: Option<OnRoute>,
+    breaker: Option<BreakerConfig>,
+}
+
+#[derive(Copy, Clone)]
+struct BreakerConfig {
+    failures: usize,
+    cooldown: Duration,
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: usize,
+    open_until: Option<Instant>,
 }
 
 impl<S, Err> Router<S, Err>
@@ -35,11 +62,63 @@ where
     {
         Router {
             router: ntex::router::Router::build(),
+            matchers: Vec::new(),
             handlers: Vec::new(),
             default: boxed::factory(default_service.into_factory()),
+            multi_match: false,
+            on_route: None,
+            breaker: None,
         }
     }
 
+    /// Deliver publish packet to all matching resources.
+    ///
+    /// If enabled, publish packet is delivered to every resource that matches
+    /// packet's topic. Publish ack of the first matched resource is used as
+    /// a response, it is sent once all handlers complete.
+    ///
+    /// By default publish packet is delivered to first matched resource only.
+    pub fn multi_match(mut self, val: bool) -> Self {
+        self.multi_match = val;
+        self
+    }
+
+    /// Set callback for route matches.
+    ///
+    /// Callback is called for every publish packet with publish topic and
+    /// matched resource info, or `None` if packet is handled by default service.
+    /// This is a diagnostic aid.
+    pub fn on_route<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str, Option<RouteInfo<'_>>) + 'static,
+    {
+        self.on_route = Some(Rc::new(f));
+        self
+    }
+
+    /// Set circuit breaker for resource handlers.
+    ///
+    /// After `failures` consecutive handler errors, resource stops receiving
+    /// publish packets for `cooldown` period, such packets are delivered to
+    /// the default service instead. Once cooldown period elapses, next packet
+    /// is delivered to resource again, single failure re-opens the breaker.
+    /// Breaker state is tracked per connection. Cooldown period is measured
+    /// with receive time of publish packets, see `Publish::received_at()`,
+    /// so it follows time source of the server.
+    ///
+    /// By default circuit breaker is disabled.
+    pub fn circuit_breaker(mut self, failures: usize, cooldown: Millis) -> Self {
+        self.breaker = if failures == 0 {
+            None
+        } else {
+            Some(BreakerConfig {
+                failures,
+                cooldown: Duration::from_millis(u64::from(cooldown.0)),
+            })
+        };
+        self
+    }
+
     /// Configure mqtt resource for a specific topic.
     pub fn resource<T, F, U: 'static>(mut self, address: T, service: F) -> Self
     where
@@ -48,10 +127,68 @@ where
         U: ServiceFactory<Publish, S, Response = PublishAck, Error = Err>,
         Err: From<U::InitError>,
     {
-        self.router.path(address, self.handlers.len());
-        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self.add_resource(
+            address,
+            boxed::factory(service.into_factory().map_init_err(Err::from)),
+        );
         self
     }
+
+    /// Configure mqtt resource for a specific topic with delivery confirmation.
+    ///
+    /// `on_confirm` callback is called with publish topic and handler's ack
+    /// every time handler successfully completes processing of a publish
+    /// packet, regardless of packet's QoS. This allows to build application
+    /// level delivery guarantees for QoS0 publishes.
+    pub fn resource_confirmed<T, F, U: 'static, C>(
+        mut self,
+        address: T,
+        service: F,
+        on_confirm: C,
+    ) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U, Publish, S>,
+        U: ServiceFactory<Publish, S, Response = PublishAck, Error = Err>,
+        Err: From<U::InitError>,
+        C: Fn(&ByteString, &PublishAck) + 'static,
+    {
+        self.add_resource(
+            address,
+            boxed::factory(ConfirmedFactory {
+                factory: service.into_factory().map_init_err(Err::from),
+                on_confirm: Rc::new(on_confirm),
+            }),
+        );
+        self
+    }
+
+    /// Returns resources that match publish topic.
+    ///
+    /// Iterator yields index of matched resource and matched topic with
+    /// captured params, in order of registration. Publish packet is delivered
+    /// to the first matched resource, unless `multi_match` is enabled.
+    /// This is read-only introspection, no handlers get called.
+    pub fn matches<'a>(
+        &'a self,
+        topic: &str,
+    ) -> impl Iterator<Item = (usize, Path<ByteString>)> + 'a {
+        let topic = ByteString::from(topic);
+        self.matchers.iter().filter_map(move |matcher| {
+            let mut path = Path::new(topic.clone());
+            matcher.recognize(&mut path).map(|(idx, _)| (*idx, path))
+        })
+    }
+
+    fn add_resource<T: IntoPattern>(&mut self, address: T, handler: Handler<S, Err>) {
+        let idx = self.handlers.len();
+        let mut matcher = ntex::router::Router::build();
+        matcher.path(address.patterns(), idx);
+
+        self.router.path(address, idx);
+        self.matchers.push(matcher.finish());
+        self.handlers.push(handler);
+    }
 }
 
 impl<S, Err> IntoServiceFactory<RouterFactory<S, Err>, Publish, S> for Router<S, Err>
@@ -62,16 +199,22 @@ where
     fn into_factory(self) -> RouterFactory<S, Err> {
         RouterFactory {
             router: self.router.finish(),
+            matchers: if self.multi_match { Some(Rc::new(self.matchers)) } else { None },
             handlers: Rc::new(self.handlers),
             default: self.default,
+            on_route: self.on_route,
+            breaker: self.breaker,
         }
     }
 }
 
 pub struct RouterFactory<S, Err> {
     router: ntex::router::Router<usize>,
+    matchers: Option<Rc<Vec<ntex::router::Router<usize>>>>,
     handlers: Rc<Vec<Handler<S, Err>>>,
     default: Handler<S, Err>,
+    on_route: Option<OnRoute>,
+    breaker: Option<BreakerConfig>,
 }
 
 impl<S, Err> ServiceFactory<Publish, S> for RouterFactory<S, Err>
@@ -87,20 +230,28 @@ where
 
     fn new_service(&self, session: S) -> Self::Future {
         let router = self.router.clone();
+        let matchers = self.matchers.clone();
         let factories = self.handlers.clone();
+        let on_route = self.on_route.clone();
+        let breaker = self.breaker;
         let default_fut = self.default.new_service(session.clone());
 
         Box::pin(async move {
             let default = default_fut.await?;
             let handlers = (0..factories.len()).map(|_| None).collect();
+            let breakers = (0..factories.len()).map(|_| Breaker::default()).collect();
 
             Ok(RouterService {
                 router,
+                matchers,
                 default,
+                on_route,
+                breaker,
                 inner: Rc::new(Inner {
                     session,
                     factories,
                     handlers: RefCell::new(handlers),
+                    breakers: RefCell::new(breakers),
                     creating: Cell::new(false),
                     aliases: RefCell::new(HashMap::default()),
                     waker: LocalWaker::new(),
@@ -113,19 +264,63 @@ where
 pub struct RouterService<S, Err> {
     inner: Rc<Inner<S, Err>>,
     router: ntex::router::Router<usize>,
+    matchers: Option<Rc<Vec<ntex::router::Router<usize>>>>,
     default: HandlerService<Err>,
+    on_route: Option<OnRoute>,
+    breaker: Option<BreakerConfig>,
 }
 
 struct Inner<S, Err> {
     session: S,
     handlers: RefCell<Vec<Option<HandlerService<Err>>>>,
+    breakers: RefCell<Vec<Breaker>>,
     factories: Rc<Vec<Handler<S, Err>>>,
     aliases: RefCell<HashMap<NonZeroU16, (usize, Path<ByteString>)>>,
     waker: LocalWaker,
     creating: Cell<bool>,
 }
 
+impl<S, Err> Inner<S, Err> {
+    fn is_open(&self, idx: usize, cfg: BreakerConfig, now: Instant) -> bool {
+        let mut breakers = self.breakers.borrow_mut();
+        let breaker = &mut breakers[idx];
+        if let Some(until) = breaker.open_until {
+            if now < until {
+                return true;
+            }
+            // half-open, next failure opens breaker
+            breaker.open_until = None;
+            breaker.failures = cfg.failures - 1;
+        }
+        false
+    }
+
+    fn report_result(&self, idx: usize, success: bool, cfg: BreakerConfig, now: Instant) {
+        let mut breakers = self.breakers.borrow_mut();
+        let breaker = &mut breakers[idx];
+        if success {
+            breaker.failures = 0;
+        } else {
+            breaker.failures += 1;
+            if breaker.failures >= cfg.failures {
+                log::trace!("Circuit breaker is open for resource {}", idx);
+                breaker.failures = 0;
+                breaker.open_until = Some(now + cfg.cooldown);
+            }
+        }
+    }
+}
+
 impl<S: Clone + 'static, Err: 'static> RouterService<S, Err> {
+    fn report_route(&self, index: Option<usize>, req: &Publish) {
+        if let Some(ref on_route) = self.on_route {
+            (on_route)(
+                req.publish_topic(),
+                index.map(|index| RouteInfo { index, path: req.topic() }),
+            );
+        }
+    }
+
     fn create_handler(
         &self,
         idx: usize,
@@ -149,6 +344,74 @@ impl<S: Clone + 'static, Err: 'static> RouterService<S, Err> {
             fut.await
         })
     }
+
+    fn call_handler(
+        &self,
+        idx: usize,
+        req: Publish,
+    ) -> Pin<Box<dyn Future<Output = Result<PublishAck, Err>>>> {
+        if let Some(hnd) = &self.inner.handlers.borrow()[idx] {
+            hnd.call(req)
+        } else {
+            self.create_handler(idx, req)
+        }
+    }
+
+    fn call_resource(
+        &self,
+        idx: usize,
+        req: Publish,
+    ) -> Result<Pin<Box<dyn Future<Output = Result<PublishAck, Err>>>>, Publish> {
+        if let Some(cfg) = self.breaker {
+            let received_at = req.received_at();
+            if self.inner.is_open(idx, cfg, received_at) {
+                return Err(req);
+            }
+            let inner = self.inner.clone();
+            let fut = self.call_handler(idx, req);
+            Ok(Box::pin(async move {
+                let res = fut.await;
+                inner.report_result(idx, res.is_ok(), cfg, received_at);
+                res
+            }))
+        } else {
+            Ok(self.call_handler(idx, req))
+        }
+    }
+
+    fn call_all(
+        &self,
+        matchers: &[ntex::router::Router<usize>],
+        req: Publish,
+    ) -> Result<Pin<Box<dyn Future<Output = Result<PublishAck, Err>>>>, Publish> {
+        let mut futs = Vec::new();
+        for matcher in matchers {
+            let mut topic = Path::new(req.topic().get_ref().clone());
+            if let Some((idx, _)) = matcher.recognize(&mut topic) {
+                let mut pkt =
+                    Publish::with_received_at(req.packet().clone(), req.received_at());
+                *pkt.topic_mut() = topic;
+                if let Ok(fut) = self.call_resource(*idx, pkt) {
+                    futs.push(fut);
+                }
+            }
+        }
+
+        if futs.is_empty() {
+            Err(req)
+        } else {
+            Ok(Box::pin(async move {
+                let mut ack = None;
+                for fut in futs {
+                    let res = fut.await?;
+                    if ack.is_none() {
+                        ack = Some(res);
+                    }
+                }
+                Ok(ack.unwrap())
+            }))
+        }
+    }
 }
 
 impl<S: Clone + 'static, Err: 'static> Service<Publish> for RouterService<S, Err> {
@@ -188,27 +451,102 @@ impl<S: Clone + 'static, Err: 'static> Service<Publish> for RouterService<S, Err
                 if let Some(alias) = req.packet().properties.topic_alias {
                     self.inner.aliases.borrow_mut().insert(alias, (*idx, req.topic().clone()));
                 }
-                if let Some(hnd) = &self.inner.handlers.borrow()[*idx] {
-                    return hnd.call(req);
+                self.report_route(Some(*idx), &req);
+                if let Some(ref matchers) = self.matchers {
+                    match self.call_all(matchers, req) {
+                        Ok(fut) => return fut,
+                        Err(r) => req = r,
+                    }
                 } else {
-                    return self.create_handler(*idx, req);
+                    match self.call_resource(*idx, req) {
+                        Ok(fut) => return fut,
+                        Err(r) => req = r,
+                    }
                 }
             }
         }
         // handle publish with topic alias
         else if let Some(ref alias) = req.packet().properties.topic_alias {
-            let aliases = self.inner.aliases.borrow();
-            if let Some(item) = aliases.get(alias) {
-                *req.topic_mut() = item.1.clone();
-                if let Some(hnd) = &self.inner.handlers.borrow()[item.0] {
-                    return hnd.call(req);
+            let item = self.inner.aliases.borrow().get(alias).cloned();
+            if let Some(item) = item {
+                *req.topic_mut() = item.1;
+                self.report_route(Some(item.0), &req);
+                if let Some(ref matchers) = self.matchers {
+                    match self.call_all(matchers, req) {
+                        Ok(fut) => return fut,
+                        Err(r) => req = r,
+                    }
                 } else {
-                    return self.create_handler(item.0, req);
+                    match self.call_resource(item.0, req) {
+                        Ok(fut) => return fut,
+                        Err(r) => req = r,
+                    }
                 }
             } else {
                 log::error!("Unknown topic alias: {:?}", alias);
             }
         }
+        self.report_route(None, &req);
         self.default.call(req)
     }
 }
+
+struct ConfirmedFactory<F> {
+    factory: F,
+    on_confirm: Rc<dyn Fn(&ByteString, &PublishAck)>,
+}
+
+impl<F, S> ServiceFactory<Publish, S> for ConfirmedFactory<F>
+where
+    F: ServiceFactory<Publish, S, Response = PublishAck>,
+    F::Service: 'static,
+{
+    type Response = PublishAck;
+    type Error = F::Error;
+    type InitError = F::InitError;
+    type Service = ConfirmedService<F::Service>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Service, Self::InitError>>>>;
+
+    fn new_service(&self, session: S) -> Self::Future {
+        let fut = self.factory.new_service(session);
+        let on_confirm = self.on_confirm.clone();
+
+        Box::pin(async move { Ok(ConfirmedService { service: fut.await?, on_confirm }) })
+    }
+}
+
+struct ConfirmedService<T> {
+    service: T,
+    on_confirm: Rc<dyn Fn(&ByteString, &PublishAck)>,
+}
+
+impl<T> Service<Publish> for ConfirmedService<T>
+where
+    T: Service<Publish, Response = PublishAck> + 'static,
+{
+    type Response = PublishAck;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: Publish) -> Self::Future {
+        let topic = req.topic().get_ref().clone();
+        let fut = self.service.call(req);
+        let on_confirm = self.on_confirm.clone();
+
+        Box::pin(async move {
+            let ack = fut.await?;
+            (*on_confirm)(&topic, &ack);
+            Ok(ack)
+        })
+    }
+}
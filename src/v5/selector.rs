@@ -12,6 +12,7 @@ use crate::error::{MqttError, ProtocolError};
 use super::control::{ControlMessage, ControlResult};
 use super::handshake::{Handshake, HandshakeAck};
 use super::publish::{Publish, PublishAck};
+use super::server::recv_connect;
 use super::shared::{MqttShared, MqttSinkPool};
 use super::{codec as mqtt, MqttServer, Session};
 
@@ -98,6 +99,56 @@ where
         P::Error: fmt::Debug,
         PublishAck: TryFrom<P::Error, Error = C::Error>,
     {
+        if let Some(budget) = server.pool.write_budget.borrow_mut().take() {
+            *self.pool.write_budget.borrow_mut() = Some(budget);
+        }
+        if let Some(stats) = server.pool.stats.borrow_mut().take() {
+            *self.pool.stats.borrow_mut() = Some(stats);
+        }
+        if let Some(f) = server.pool.sessions.on_end.borrow_mut().take() {
+            self.pool.sessions.set_on_end(f);
+        }
+        if let Some(f) = server.pool.conn_events.on_change.borrow_mut().take() {
+            self.pool.conn_events.set_on_change(f);
+            self.pool.conn_events.set_counter(server.pool.conn_events.counter.borrow().clone());
+        }
+        if let Some(ceiling) = server.pool.qos_ceiling.borrow_mut().take() {
+            *self.pool.qos_ceiling.borrow_mut() = Some(ceiling);
+        }
+        if let Some(idempotency) = server.pool.idempotency.borrow_mut().take() {
+            *self.pool.idempotency.borrow_mut() = Some(idempotency);
+        }
+        if let Some(f) = server.pool.connect_validator.borrow_mut().take() {
+            *self.pool.connect_validator.borrow_mut() = Some(f);
+        }
+        if let Some(limit) = server.pool.accept_limit.borrow_mut().take() {
+            *self.pool.accept_limit.borrow_mut() = Some(limit);
+        }
+        if let Some(codec) = server.pool.payload_codec.borrow_mut().take() {
+            *self.pool.payload_codec.borrow_mut() = Some(codec);
+        }
+        self.pool.ack_ordering.set(server.pool.ack_ordering.get());
+        self.pool.max_sub_filters.set(server.pool.max_sub_filters.get());
+        self.pool.lenient_utf8.set(server.pool.lenient_utf8.get());
+        self.pool.max_filter_levels.set(server.pool.max_filter_levels.get());
+        self.pool.subscription_ttl.set(server.pool.subscription_ttl.get());
+        self.pool.unsubscribe_policy.set(server.pool.unsubscribe_policy.get());
+        self.pool.max_qos2.set(server.pool.max_qos2.get());
+        self.pool.decode_capture.set(server.pool.decode_capture.get());
+        self.pool.max_connect_size.set(server.pool.max_connect_size.get());
+        self.pool.strict_direction.set(server.pool.strict_direction.get());
+        *self.pool.require_tls.borrow_mut() = server.pool.require_tls.borrow().clone();
+        self.pool.retained_limit.set(server.pool.retained_limit.get());
+        self.pool.retained_rate.set(server.pool.retained_rate.get());
+        if let Some(props) = server.pool.connack_defaults.borrow_mut().take() {
+            *self.pool.connack_defaults.borrow_mut() = Some(props);
+        }
+        self.pool.custom_packets.set(server.pool.custom_packets.get());
+        self.pool.publish_ordering.set(server.pool.publish_ordering.get());
+        *self.pool.time.borrow_mut() = server.pool.time.borrow().clone();
+        if let Some(pool) = server.pool.io_pool.get() {
+            self.pool.io_pool.set(Some(pool));
+        }
         server.pool = self.pool.clone();
         self.servers.push(boxed::factory(server.finish_selector(check)));
         self
@@ -253,17 +304,7 @@ where
         let delay = self.handshake_timeout.map(sleep);
         Box::pin(async move {
             // read first packet
-            let packet = io
-                .recv(&shared.codec)
-                .await
-                .map_err(|err| {
-                    log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::from(err)
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Disconnected(None)
-                })?;
+            let packet = recv_connect(&io, &shared).await?;
 
             let connect = match packet {
                 mqtt::Packet::Connect(connect) => connect,
@@ -322,17 +363,7 @@ where
 
         Box::pin(async move {
             // read first packet
-            let packet = io
-                .recv(&shared.codec)
-                .await
-                .map_err(|err| {
-                    // log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::from(err)
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Disconnected(None)
-                })?;
+            let packet = recv_connect(&io, &shared).await?;
 
             let connect = match packet {
                 mqtt::Packet::Connect(connect) => connect,
@@ -1,24 +1,120 @@
+use std::num::NonZeroU16;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{convert::TryFrom, fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc};
 
 use ntex::io::{DispatchItem, IoBoxed};
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
 use ntex::time::{timeout_checked, Millis, Seconds};
-use ntex::util::Either;
+use ntex::util::{ByteString, Either, PoolId};
 
-use crate::error::{MqttError, ProtocolError};
-use crate::{io::Dispatcher, service, types::QoS};
+use crate::error::{DecodeError, MqttError, ProtocolError};
+use crate::{
+    io::is_tls, io::Dispatcher, service, service::ServerHandle, time::TimeSource, types::QoS,
+};
+use crate::{session::Negotiated, types::MqttVersion};
 
-use super::control::{ControlMessage, ControlResult};
+use super::budget::WriteBudget;
+use super::control::{AckOrdering, ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
+use super::dispatcher::{factory, ErrorReason, SubRateLimit};
 use super::handshake::{Handshake, HandshakeAck};
-use super::publish::{Publish, PublishAck};
+use super::lifecycle::{AcceptRateLimit, ConnectionCounter, ConnectionEvent, SessionInfo};
+use super::payload::PayloadCodec;
+use super::publish::{Idempotency, Publish, PublishAck, PublishOrdering, QosCeilingPolicy};
 use super::selector::SelectItem;
 use super::shared::{MqttShared, MqttSinkPool};
-use super::{codec as mqtt, dispatcher::factory, MqttSink, Session};
+use super::sink::UnsubscribePolicy;
+use super::stats::ServerStats;
+use super::{codec as mqtt, MqttSink, Session};
+
+/// Server limits
+///
+/// Limits are applied at once with `MqttServer::limits()`, individual setters
+/// called afterwards override provided values. Max number of connections
+/// is configured by `ntex::server::ServerBuilder::maxconn()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Max inbound packet size, `0` means unlimited
+    pub max_size: u32,
+    /// Number of in-flight inbound publish packets, `0` means unlimited
+    pub receive_max: u16,
+    /// Number of topic aliases
+    pub max_topic_alias: u16,
+    /// Max qos of inbound publish packets
+    pub max_qos: Option<QoS>,
+    /// Max number of topic filters in SUBSCRIBE packet, `0` means unlimited
+    pub max_subscribe_filters: usize,
+    /// Max number of concurrent inbound qos2 exchanges, `0` means unlimited
+    pub max_inflight_qos2: u16,
+    /// Subscribe and unsubscribe packets per second, `0` disables rate limit
+    pub sub_rate_limit: u16,
+    /// Number of subscribe and unsubscribe packets allowed at once
+    pub sub_rate_burst: u16,
+    /// Handshake timeout, `0` disables timeout
+    pub handshake_timeout: Seconds,
+    /// Disconnect timeout, `0` disables timeout
+    pub disconnect_timeout: Seconds,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 0,
+            receive_max: 15,
+            max_topic_alias: 32,
+            max_qos: None,
+            max_subscribe_filters: 0,
+            max_inflight_qos2: 0,
+            sub_rate_limit: 0,
+            sub_rate_burst: 0,
+            handshake_timeout: Seconds::ZERO,
+            disconnect_timeout: Seconds(3),
+        }
+    }
+}
+
+/// Server-wide defaults of CONNACK properties
+///
+/// Defaults are applied to every successful CONNACK, they override values
+/// derived from server limits. Changes made with `HandshakeAck::with()`
+/// override defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnackProps {
+    /// Max packet size the server is willing to accept
+    pub max_packet_size: Option<u32>,
+    /// Number of in-flight inbound publish packets
+    pub receive_max: Option<NonZeroU16>,
+    /// Number of topic aliases
+    pub topic_alias_max: Option<u16>,
+    /// Max qos supported by the server
+    pub max_qos: Option<QoS>,
+    /// Retained messages support
+    pub retain_available: Option<bool>,
+}
+
+impl ConnackProps {
+    pub(super) fn apply(&self, packet: &mut mqtt::ConnectAck) {
+        if let Some(size) = self.max_packet_size {
+            packet.max_packet_size = Some(size);
+        }
+        if let Some(num) = self.receive_max {
+            packet.receive_max = Some(num);
+        }
+        if let Some(num) = self.topic_alias_max {
+            packet.topic_alias_max = num;
+        }
+        if let Some(qos) = self.max_qos {
+            packet.max_qos = Some(qos);
+        }
+        if let Some(val) = self.retain_available {
+            packet.retain_available = Some(val);
+        }
+    }
+}
 
 /// Mqtt Server
-pub struct MqttServer<St, C, Cn, P> {
+pub struct MqttServer<St, C: ServiceFactory<Handshake>, Cn, P> {
     handshake: C,
     srv_control: Cn,
     srv_publish: P,
@@ -27,7 +123,11 @@ pub struct MqttServer<St, C, Cn, P> {
     max_qos: Option<QoS>,
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
+    handle: Option<ServerHandle>,
     max_topic_alias: u16,
+    sub_rate_limit: SubRateLimit,
+    error_reason: ErrorReason<C::Error>,
+    time: Option<Rc<dyn TimeSource>>,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -52,7 +152,11 @@ where
             max_qos: None,
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
+            handle: None,
             max_topic_alias: 32,
+            sub_rate_limit: SubRateLimit::default(),
+            error_reason: None,
+            time: None,
             pool: Rc::new(MqttSinkPool::default()),
             _t: PhantomData,
         }
@@ -68,6 +172,23 @@ where
         + 'static,
     P: ServiceFactory<Publish, Session<St>, Response = PublishAck> + 'static,
 {
+    /// Set all server limits at once.
+    ///
+    /// Setters called afterwards override provided values.
+    pub fn limits(mut self, limits: ServerLimits) -> Self {
+        self.max_size = limits.max_size;
+        self.max_receive = limits.receive_max;
+        self.max_topic_alias = limits.max_topic_alias;
+        self.max_qos = limits.max_qos;
+        self.sub_rate_limit.per_sec = limits.sub_rate_limit;
+        self.sub_rate_limit.burst = limits.sub_rate_burst;
+        self.handshake_timeout = limits.handshake_timeout;
+        self.disconnect_timeout = limits.disconnect_timeout;
+        self.pool.max_sub_filters.set(limits.max_subscribe_filters);
+        self.pool.max_qos2.set(limits.max_inflight_qos2);
+        self
+    }
+
     /// Set handshake timeout.
     ///
     /// Handshake includes `connect` packet and response `connect-ack`.
@@ -90,6 +211,14 @@ where
         self
     }
 
+    /// Set handle for adjusting settings of running server.
+    ///
+    /// Disconnect timeout of the handle overrides `disconnect_timeout()` value.
+    pub fn server_handle(mut self, handle: ServerHandle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -99,6 +228,82 @@ where
         self
     }
 
+    /// Set max inbound CONNECT packet size.
+    ///
+    /// Size is checked as soon as fixed header is received, oversized CONNECT
+    /// packet is rejected with `packet too large` reason code before it is buffered.
+    /// If max size is set to `0`, size is limited by max inbound frame size only.
+    /// By default max size is set to `0`
+    pub fn max_connect_size(self, size: u32) -> Self {
+        self.pool.max_connect_size.set(size);
+        self
+    }
+
+    /// Accept packets with reserved packet types
+    ///
+    /// Some brokers use reserved packet types for protocol extensions. Such packets
+    /// are delivered to control service as `ControlMessage::CustomPacket` instead of
+    /// closing connection with decode error.
+    /// By default custom packets are disabled.
+    pub fn custom_packets(self, val: bool) -> Self {
+        self.pool.custom_packets.set(val);
+        self
+    }
+
+    /// Treat packets that only server sends as protocol violation
+    ///
+    /// In strict mode PINGRESP packet from client closes connection with
+    /// protocol error, otherwise such packet is ignored.
+    /// By default strict mode is disabled.
+    pub fn strict_packet_direction(self, val: bool) -> Self {
+        self.pool.strict_direction.set(val);
+        self
+    }
+
+    /// Reject connections without tls session
+    ///
+    /// Connection without tls session is rejected with `not authorized`
+    /// reason code before handshake service is called. Tls session is detected
+    /// with protocol query, tls filters of `ntex-tls` answer it for every
+    /// established session. Use `require_tls_with()` for other tls filters.
+    /// By default plain connections are accepted.
+    pub fn require_tls(self, val: bool) -> Self {
+        *self.pool.require_tls.borrow_mut() = if val { Some(Rc::new(is_tls)) } else { None };
+        self
+    }
+
+    /// Reject connections that fail custom tls session check
+    ///
+    /// Check is called before handshake service, connection is rejected
+    /// the same way as with `require_tls()` if check returns `false`.
+    pub fn require_tls_with<F>(self, f: F) -> Self
+    where
+        F: Fn(&IoBoxed) -> bool + 'static,
+    {
+        *self.pool.require_tls.borrow_mut() = Some(Rc::new(f));
+        self
+    }
+
+    /// Set max number of retained messages sent immediately per subscribe
+    ///
+    /// Limit applies to `MqttSink::replay_retained()`, remaining retained
+    /// messages are streamed with `retained_replay_rate()` rate or dropped
+    /// if rate is not set. If limit is set to `0`, all messages are sent immediately.
+    /// By default limit is set to `0`
+    pub fn retained_replay_limit(self, limit: usize) -> Self {
+        self.pool.retained_limit.set(limit);
+        self
+    }
+
+    /// Set rate of retained messages that exceed replay limit
+    ///
+    /// At most `per_sec` messages per second are sent after immediate burst.
+    /// By default rate is set to `0`, messages that exceed limit are dropped.
+    pub fn retained_replay_rate(self, per_sec: u16) -> Self {
+        self.pool.retained_rate.set(per_sec);
+        self
+    }
+
     /// Set `receive max`
     ///
     /// Number of in-flight publish packets. By default receive max is set to 15 packets.
@@ -124,6 +329,322 @@ where
         self
     }
 
+    /// Set defaults of CONNACK properties.
+    ///
+    /// Defaults are applied to every successful CONNACK, per-connection
+    /// changes made with `HandshakeAck::with()` override them.
+    pub fn connack_defaults(self, props: ConnackProps) -> Self {
+        *self.pool.connack_defaults.borrow_mut() = Some(props);
+        self
+    }
+
+    /// Set rate limit for subscribe and unsubscribe packets.
+    ///
+    /// Client could send `burst` packets at once, after that packets get processed
+    /// at `per_sec` rate, connection's read side is paused until next packet is
+    /// allowed. To disable rate limit set `per_sec` to 0.
+    ///
+    /// By default rate limit is disabled.
+    pub fn sub_rate_limit(mut self, per_sec: u16, burst: u16) -> Self {
+        self.sub_rate_limit.per_sec = per_sec;
+        self.sub_rate_limit.burst = burst;
+        self
+    }
+
+    /// Disconnect client if subscribe rate limit is exceeded.
+    ///
+    /// If enabled, server sends `DISCONNECT` packet with `Quota exceeded`
+    /// reason code instead of pausing connection.
+    ///
+    /// By default is disabled.
+    pub fn sub_rate_limit_disconnect(mut self, val: bool) -> Self {
+        self.sub_rate_limit.disconnect = val;
+        self
+    }
+
+    /// Set rate limit for connection handshakes.
+    ///
+    /// Handshakes that exceed the rate limit are rejected with `Server busy`
+    /// CONNACK before handshake service is called. Each server worker builds
+    /// its own `MqttServer`, use the same handle for all workers to limit
+    /// total rate of the server.
+    ///
+    /// By default rate limit is disabled.
+    pub fn accept_rate_limit(self, limit: AcceptRateLimit) -> Self {
+        *self.pool.accept_limit.borrow_mut() = Some(limit);
+        self
+    }
+
+    /// Set io buffer params of connection dispatcher.
+    ///
+    /// Read buffer is allocated with `read_hw` capacity and reading pauses once
+    /// buffer reaches it, buffer is re-allocated if available space drops below
+    /// `read_lw`. Write buffer is allocated with `write_hw` capacity and is
+    /// shrunk back below `write_lw` after flush.
+    ///
+    /// Memory pool params are process wide, so connection buffers are allocated
+    /// from provided `pool`. Pool should be dedicated to this server, other
+    /// users of the pool get the same params.
+    ///
+    /// By default connections use server's memory pool with unchanged params.
+    pub fn io_buffer_params(
+        self,
+        pool: PoolId,
+        read_hw: u16,
+        read_lw: u16,
+        write_hw: u16,
+        write_lw: u16,
+    ) -> Self {
+        let pool = pool
+            .pool_ref()
+            .set_read_params(read_hw, read_lw)
+            .set_write_params(write_hw, write_lw);
+        self.pool.io_pool.set(Some(pool));
+        self
+    }
+
+    /// Set global write budget.
+    ///
+    /// Budget is shared by all connections that use the same handle, the same
+    /// handle could be used by all server workers. If total size of connections
+    /// write buffers exceeds budget, processing of incoming packets is paused.
+    /// Paused connections resume when write buffer of any connection gets flushed
+    /// and total size drops to budget.
+    ///
+    /// By default write budget is disabled.
+    pub fn global_write_budget(self, budget: WriteBudget) -> Self {
+        *self.pool.write_budget.borrow_mut() = Some(budget);
+        self
+    }
+
+    /// Set disconnect reason code for service errors.
+    ///
+    /// Function is called when publish or control service fails, returned
+    /// reason code is used for DISCONNECT packet. Error is still passed to
+    /// control service, which could override it. Mapping could be defined via
+    /// `From<&Error> for DisconnectReasonCode` conversion.
+    ///
+    /// By default `Unspecified error` reason code is used.
+    pub fn error_reason<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&C::Error) -> mqtt::DisconnectReasonCode + 'static,
+    {
+        self.error_reason = Some(Rc::new(f));
+        self
+    }
+
+    /// Set callback for session end.
+    ///
+    /// Callback is called when session is destroyed, either immediately after
+    /// connection close if session expiry interval is 0, or once session expiry
+    /// interval elapses. Session is not destroyed if client re-connects with
+    /// the same client id before expiry.
+    pub fn on_session_end<F>(self, f: F) -> Self
+    where
+        F: Fn(&SessionInfo) + 'static,
+    {
+        self.pool.sessions.set_on_end(Rc::new(f));
+        self
+    }
+
+    /// Set callback for connection count changes.
+    ///
+    /// Callback is called on every established and closed connection
+    /// with the new number of active connections. Each server worker creates
+    /// its own `MqttServer`, use `connection_counter()` with the same handle
+    /// for all workers to receive the total number of connections.
+    pub fn on_connection_change<F>(self, f: F) -> Self
+    where
+        F: Fn(ConnectionEvent) + 'static,
+    {
+        self.pool.conn_events.set_on_change(Rc::new(f));
+        self
+    }
+
+    /// Set shared connections counter.
+    ///
+    /// Counter is used for `on_connection_change()` events, the same handle
+    /// could be used by all server workers.
+    pub fn connection_counter(self, counter: ConnectionCounter) -> Self {
+        self.pool.conn_events.set_counter(counter);
+        self
+    }
+
+    /// Set server statistics handle.
+    ///
+    /// Server updates counters of provided handle, the same handle could be
+    /// shared by all server workers to aggregate statistics.
+    pub fn stats(self, stats: ServerStats) -> Self {
+        *self.pool.stats.borrow_mut() = Some(stats);
+        self
+    }
+
+    /// Set publish payload codec.
+    ///
+    /// Payloads of received publish packets get decoded before publish
+    /// handler is called, payloads of publish packets sent via `MqttSink`
+    /// get encoded.
+    pub fn payload_codec<T: PayloadCodec + 'static>(self, codec: T) -> Self {
+        *self.pool.payload_codec.borrow_mut() = Some(Rc::new(codec));
+        self
+    }
+
+    /// Set max number of concurrent inbound qos2 exchanges.
+    ///
+    /// Exchange lasts from received PUBLISH until PUBREL packet. Connection is
+    /// closed with `Receive Maximum exceeded` reason code if limit is exceeded.
+    /// If max number is set to `0`, number is limited by receive maximum only.
+    /// By default max number is set to `0`
+    pub fn max_inflight_qos2(self, max: u16) -> Self {
+        self.pool.max_qos2.set(max);
+        self
+    }
+
+    /// Set max number of topic filters in SUBSCRIBE packet.
+    ///
+    /// SUBSCRIBE packet with more filters gets rejected during decoding,
+    /// connection is closed with `Packet too large` reason code.
+    /// If max number is set to `0`, number of filters is unlimited.
+    /// By default max number is set to `0`
+    pub fn max_subscribe_filters(self, max: usize) -> Self {
+        self.pool.max_sub_filters.set(max);
+        self
+    }
+
+    /// Accept reason strings, content types and user properties with invalid utf-8 sequences.
+    ///
+    /// In strict mode packet with invalid utf-8 string (topic, reason string,
+    /// content type, user property, etc) gets rejected, connection is closed
+    /// with `Malformed Packet` reason code. In lenient mode invalid sequences
+    /// of reason strings, content types and user properties get replaced with `U+FFFD`.
+    /// Topic names, response topics, client ids and other strings are always checked,
+    /// replaced sequences would change topic of delivery.
+    /// By default strict mode is used
+    pub fn lenient_utf8(self, val: bool) -> Self {
+        self.pool.lenient_utf8.set(val);
+        self
+    }
+
+    /// Set max number of levels in topic filter of SUBSCRIBE packet.
+    ///
+    /// Filters with more levels are rejected with `Topic Filter invalid`
+    /// reason code, control service does not receive such filters.
+    /// If max number is set to `0`, number of levels is unlimited.
+    /// By default max number is set to `0`
+    pub fn max_filter_levels(self, max: usize) -> Self {
+        self.pool.max_filter_levels.set(max);
+        self
+    }
+
+    /// Set ttl of subscriptions.
+    ///
+    /// Subscriptions expire after ttl elapses, expired subscription is treated
+    /// as if client unsubscribed. Connection dispatcher removes it once ttl elapses,
+    /// `MqttSink::is_subscribed()` does not match it anymore and queued publishes
+    /// are handled according to unsubscribe policy. Re-subscribing renews ttl.
+    /// This is not part of MQTT specification.
+    /// By default ttl is not set.
+    pub fn subscription_ttl(self, ttl: Seconds) -> Self {
+        self.pool.subscription_ttl.set(ttl);
+        self
+    }
+
+    /// Set per-topic QoS ceiling for received publishes.
+    ///
+    /// Function returns max QoS for publish topic, publish with higher QoS
+    /// is downgraded or rejected according to the policy.
+    pub fn topic_qos_ceiling<F>(self, policy: QosCeilingPolicy, f: F) -> Self
+    where
+        F: Fn(&str) -> QoS + 'static,
+    {
+        *self.pool.qos_ceiling.borrow_mut() = Some((Rc::new(f), policy));
+        self
+    }
+
+    /// Set handling of queued publishes on unsubscribe.
+    ///
+    /// By default queued publishes are sent.
+    pub fn unsubscribe_policy(self, policy: UnsubscribePolicy) -> Self {
+        self.pool.unsubscribe_policy.set(policy);
+        self
+    }
+
+    /// Deduplicate received publishes by idempotency key.
+    ///
+    /// Idempotency key is a value of `property` user property of publish.
+    /// Publish with the key already received from the same client within
+    /// `window` is acknowledged with `Success` reason code, but is not passed
+    /// to publish service. Key is kept only if publish service acks publish
+    /// successfully, retry of failed or interrupted publish is processed again.
+    ///
+    /// Keys are stored per worker and shared across client connections
+    /// of the worker. Duplicate is detected after reconnect only if
+    /// new connection is accepted by the same worker.
+    /// By default publishes are not deduplicated.
+    pub fn idempotency(self, property: ByteString, window: Seconds) -> Self {
+        *self.pool.idempotency.borrow_mut() =
+            Some(Rc::new(Idempotency::new(property, Duration::from_secs(u64::from(window.0)))));
+        self
+    }
+
+    /// Set connect validator.
+    ///
+    /// Validator is called with CONNECT packet before handshake service, it is
+    /// intended for cheap checks like client id format. If validator returns
+    /// error, CONNACK packet with returned reason code is sent to the client
+    /// and connection gets closed, handshake service is not called.
+    pub fn connect_validator<F>(self, f: F) -> Self
+    where
+        F: Fn(&mqtt::Connect) -> Result<(), mqtt::ConnectAckReason> + 'static,
+    {
+        *self.pool.connect_validator.borrow_mut() = Some(Rc::new(f));
+        self
+    }
+
+    /// Set max number of captured bytes of malformed packet.
+    ///
+    /// Hex dump of the beginning of packet that failed to decode is attached
+    /// to protocol error, see `ProtocolError::decode_input()`.
+    /// If max number is set to `0`, packets are not captured.
+    /// By default max number is set to `0`
+    pub fn decode_error_capture(self, size: usize) -> Self {
+        self.pool.decode_capture.set(size);
+        self
+    }
+
+    /// Set order of publish service calls.
+    ///
+    /// Publish acks are sent in order of received publishes regardless
+    /// of ordering. By default publish service calls are not serialized.
+    pub fn publish_ordering(self, ordering: PublishOrdering) -> Self {
+        self.pool.publish_ordering.set(Some(ordering));
+        self
+    }
+
+    /// Set order of SUBACK/UNSUBACK packets and control service processing.
+    ///
+    /// By default ack is sent after control service completes, so
+    /// retained messages sent by control service precede SUBACK.
+    pub fn suback_ordering(self, ordering: AckOrdering) -> Self {
+        self.pool.ack_ordering.set(ordering);
+        self
+    }
+
+    /// Set time source for timers of connections.
+    ///
+    /// Time source drives keep-alive timer, sink timers (publish pacing and
+    /// coalescing, subscription ttl, retained messages streaming), session
+    /// expiry, rate limits, idempotency window and publish receive time.
+    /// Handshake timeout and disconnect timeout are driven by ntex's timer.
+    ///
+    /// By default all timers are driven by ntex's timer.
+    pub fn time_source<T: TimeSource + 'static>(mut self, time: T) -> Self {
+        let time: Rc<dyn TimeSource> = Rc::new(time);
+        *self.pool.time.borrow_mut() = Some(time.clone());
+        self.time = Some(time);
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max number of buffered
@@ -145,6 +666,10 @@ where
             max_qos: self.max_qos,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            handle: self.handle,
+            sub_rate_limit: self.sub_rate_limit,
+            error_reason: self.error_reason,
+            time: self.time,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -169,6 +694,10 @@ where
             max_qos: self.max_qos,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            handle: self.handle,
+            sub_rate_limit: self.sub_rate_limit,
+            error_reason: self.error_reason,
+            time: self.time,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -221,9 +750,11 @@ where
                 pool: self.pool,
                 _t: PhantomData,
             },
-            factory(self.srv_publish, self.srv_control),
+            factory(self.srv_publish, self.srv_control, self.sub_rate_limit, self.error_reason),
             self.disconnect_timeout,
         )
+        .time_source(self.time)
+        .server_handle(self.handle)
     }
 
     /// Set service to handle publish packets and create mqtt server factory
@@ -243,12 +774,19 @@ where
         ServerSelector::<St, _, _, _, _> {
             check: Rc::new(check),
             connect: self.handshake,
-            handler: Rc::new(factory(self.srv_publish, self.srv_control)),
+            handler: Rc::new(factory(
+                self.srv_publish,
+                self.srv_control,
+                self.sub_rate_limit,
+                self.error_reason,
+            )),
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             disconnect_timeout: self.disconnect_timeout,
+            handle: self.handle,
+            time: self.time,
             _t: PhantomData,
         }
     }
@@ -345,17 +883,7 @@ where
 
         let f = async move {
             // read first packet
-            let packet = io
-                .recv(&shared.codec)
-                .await
-                .map_err(|err| {
-                    log::trace!("Error is received during mqtt handshake: {:?}", err);
-                    MqttError::from(err)
-                })?
-                .ok_or_else(|| {
-                    log::trace!("Server mqtt is disconnected during handshake");
-                    MqttError::Disconnected(None)
-                })?;
+            let packet = recv_connect(&io, &shared).await?;
 
             match packet {
                 mqtt::Packet::Connect(connect) => {
@@ -364,8 +892,14 @@ where
                         shared.codec.set_max_outbound_size(size.get());
                     }
                     shared.cap.set(connect.receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+                    shared
+                        .session_expiry
+                        .set(connect.session_expiry_interval_secs.unwrap_or(0));
+                    *shared.client_id.borrow_mut() = connect.client_id.clone();
 
                     let keep_alive = connect.keep_alive;
+                    let client_limits = client_limits(&connect);
+                    validate_connect(&io, &shared, &connect).await?;
 
                     // authenticate mqtt connection
                     let mut ack = service
@@ -387,6 +921,13 @@ where
 
                             max_topic_alias = ack.packet.topic_alias_max;
 
+                            if let Some(expiry) = ack.packet.session_expiry_interval_secs {
+                                shared.session_expiry.set(expiry);
+                            }
+                            if let Some(ref client_id) = ack.packet.assigned_client_id {
+                                *shared.client_id.borrow_mut() = client_id.clone();
+                            }
+
                             if ack.packet.max_qos.is_none() {
                                 ack.packet.max_qos = max_qos;
                             }
@@ -405,23 +946,39 @@ where
                                 ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
                             }
 
+                            let effective_keep_alive =
+                                ack.packet.server_keepalive_sec.unwrap_or(keep_alive);
+                            let negotiated = negotiated(&ack.packet, client_limits);
+                            let present = ack.packet.session_present;
                             ack.io
-                                .send(
+                                .encode(
                                     mqtt::Packet::ConnectAck(Box::new(ack.packet)),
                                     &shared.codec,
                                 )
-                                .await?;
+                                .map_err(|e| MqttError::Protocol(ProtocolError::Encode(e)))?;
+                            MqttSink::new(shared.clone()).session_resume(present);
+                            ack.io.flush(true).await?;
 
+                            let session = Session::new_v5(
+                                session,
+                                MqttSink::new(shared.clone()),
+                                max_receive,
+                                max_topic_alias,
+                            );
+                            session.set_keepalive(keep_alive, effective_keep_alive);
+                            session.set_negotiated(negotiated);
+                            if let Some(pool) = shared.pool.io_pool.get() {
+                                ack.io.set_memory_pool(pool);
+                            }
                             Ok((
                                 ack.io,
-                                shared.clone(),
-                                Session::new_v5(
-                                    session,
-                                    MqttSink::new(shared),
-                                    max_receive,
-                                    max_topic_alias,
-                                ),
-                                Seconds(ack.keepalive),
+                                shared,
+                                session,
+                                if ack.keepalive_disabled {
+                                    Seconds::ZERO
+                                } else {
+                                    Seconds(ack.keepalive)
+                                },
                             ))
                         }
                         None => {
@@ -458,6 +1015,101 @@ where
     }
 }
 
+/// Max packet size, receive maximum and topic alias maximum of the client
+fn client_limits(connect: &mqtt::Connect) -> (Option<u32>, u16, u16) {
+    (
+        connect.max_packet_size.map(|v| v.get()),
+        connect.receive_max.map(|v| v.get()).unwrap_or(u16::MAX),
+        connect.topic_alias_max,
+    )
+}
+
+/// Protocol features negotiated by CONNECT and CONNACK packets
+fn negotiated(ack: &mqtt::ConnectAck, client: (Option<u32>, u16, u16)) -> Negotiated {
+    Negotiated {
+        max_packet_size: ack.max_packet_size,
+        client_max_packet_size: client.0,
+        receive_max: ack.receive_max.map(|v| v.get()).unwrap_or(0),
+        client_receive_max: client.1,
+        topic_alias_max: ack.topic_alias_max,
+        client_topic_alias_max: client.2,
+        max_qos: ack.max_qos.unwrap_or(QoS::ExactlyOnce),
+        retain_available: ack.retain_available.unwrap_or(true),
+        wildcard_subscription_available: ack.wildcard_subscription_available.unwrap_or(true),
+        shared_subscription_available: ack.shared_subscription_available.unwrap_or(true),
+        ..Negotiated::new(MqttVersion::V5)
+    }
+}
+
+/// Read first packet of the connection
+///
+/// Oversized CONNECT packet is rejected with `packet too large` reason code.
+pub(super) async fn recv_connect<E>(
+    io: &IoBoxed,
+    shared: &MqttShared,
+) -> Result<mqtt::Packet, MqttError<E>> {
+    match io.recv(&shared.codec).await {
+        Ok(Some(packet)) => Ok(packet),
+        Ok(None) => {
+            log::trace!("Server mqtt is disconnected during handshake");
+            Err(MqttError::Disconnected(None))
+        }
+        Err(Either::Left(DecodeError::MaxSizeExceeded)) => {
+            log::trace!("Connect packet exceeds max size");
+            io.send(
+                mqtt::Packet::ConnectAck(Box::new(mqtt::ConnectAck {
+                    reason_code: mqtt::ConnectAckReason::PacketTooLarge,
+                    ..mqtt::ConnectAck::default()
+                })),
+                &shared.codec,
+            )
+            .await?;
+            let _ = io.shutdown().await;
+            Err(MqttError::Protocol(ProtocolError::Decode(DecodeError::MaxSizeExceeded)))
+        }
+        Err(Either::Left(err)) => {
+            log::trace!("Error is received during mqtt handshake: {:?}", err);
+            Err(MqttError::Protocol(shared.decode_error(err)))
+        }
+        Err(Either::Right(err)) => {
+            log::trace!("Error is received during mqtt handshake: {:?}", err);
+            Err(MqttError::from(err))
+        }
+    }
+}
+
+/// Check tls session, run connect validator and check accept rate limit
+///
+/// Validation failure is reported to the client with CONNACK packet.
+async fn validate_connect<E>(
+    io: &IoBoxed,
+    shared: &MqttShared,
+    connect: &mqtt::Connect,
+) -> Result<(), MqttError<E>> {
+    let result = if !shared.check_tls(io) {
+        log::trace!("Connection without tls session is rejected");
+        Err(mqtt::ConnectAckReason::NotAuthorized)
+    } else {
+        shared.validate_connect(connect).and_then(|_| shared.check_accept_rate())
+    };
+    if let Err(reason_code) = result {
+        log::trace!("Connect validation failed: {:?}", reason_code);
+
+        io.send(
+            mqtt::Packet::ConnectAck(Box::new(mqtt::ConnectAck {
+                reason_code,
+                ..mqtt::ConnectAck::default()
+            })),
+            &shared.codec,
+        )
+        .await?;
+        let _ = io.shutdown().await;
+        Err(MqttError::Disconnected(None))
+    } else {
+        Ok(())
+    }
+}
+
 pub(crate) struct ServerSelector<St, C, T, F, R> {
     connect: C,
     handler: Rc<T>,
@@ -466,7 +1118,9 @@ pub(crate) struct ServerSelector<St, C, T, F, R> {
     max_receive: u16,
     max_qos: Option<QoS>,
     disconnect_timeout: Seconds,
+    handle: Option<ServerHandle>,
     max_topic_alias: u16,
+    time: Option<Rc<dyn TimeSource>>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -500,6 +1154,8 @@ where
         let max_qos = self.max_qos;
         let max_topic_alias = self.max_topic_alias;
         let disconnect_timeout = self.disconnect_timeout;
+        let handle = self.handle.clone();
+        let time = self.time.clone();
 
         // create connect service and then create service impl
         Box::pin(async move {
@@ -511,6 +1167,8 @@ where
                 max_qos,
                 max_topic_alias,
                 disconnect_timeout,
+                handle,
+                time,
                 connect: Rc::new(fut.await?),
                 _t: PhantomData,
             })
@@ -526,7 +1184,9 @@ pub(crate) struct ServerSelectorImpl<St, C, T, F, R> {
     max_receive: u16,
     max_qos: Option<QoS>,
     disconnect_timeout: Seconds,
+    handle: Option<ServerHandle>,
     max_topic_alias: u16,
+    time: Option<Rc<dyn TimeSource>>,
     _t: PhantomData<(St, R)>,
 }
 
@@ -566,7 +1226,12 @@ where
         let check = self.check.clone();
         let connect = self.connect.clone();
         let handler = self.handler.clone();
-        let timeout = self.disconnect_timeout;
+        let timeout = self
+            .handle
+            .as_ref()
+            .map(|h| h.disconnect_timeout())
+            .unwrap_or(self.disconnect_timeout);
+        let time = self.time.clone();
         let max_qos = self.max_qos;
         let max_size = self.max_size;
         let mut max_receive = self.max_receive;
@@ -595,8 +1260,14 @@ where
                 hnd.shared
                     .cap
                     .set(hnd.packet().receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+                hnd.shared
+                    .session_expiry
+                    .set(hnd.packet().session_expiry_interval_secs.unwrap_or(0));
+                *hnd.shared.client_id.borrow_mut() = hnd.packet().client_id.clone();
 
                 let keep_alive = hnd.packet().keep_alive;
+                let client_limits = client_limits(hnd.packet());
+                validate_connect(hnd.io(), &hnd.shared, hnd.packet()).await?;
                 hnd.max_size = max_size;
                 hnd.max_receive = max_receive;
                 hnd.max_topic_alias = max_topic_alias;
@@ -625,6 +1296,13 @@ where
 
                         max_topic_alias = ack.packet.topic_alias_max;
 
+                        if let Some(expiry) = ack.packet.session_expiry_interval_secs {
+                            shared.session_expiry.set(expiry);
+                        }
+                        if let Some(ref client_id) = ack.packet.assigned_client_id {
+                            *shared.client_id.borrow_mut() = client_id.clone();
+                        }
+
                         if ack.packet.max_qos.is_none() {
                             ack.packet.max_qos = max_qos;
                         }
@@ -643,9 +1321,18 @@ where
                             ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
                         }
 
+                        let effective_keep_alive =
+                            ack.packet.server_keepalive_sec.unwrap_or(keep_alive);
+                        let negotiated = negotiated(&ack.packet, client_limits);
+                        let present = ack.packet.session_present;
                         ack.io
-                            .send(mqtt::Packet::ConnectAck(Box::new(ack.packet)), &shared.codec)
-                            .await?;
+                            .encode(
+                                mqtt::Packet::ConnectAck(Box::new(ack.packet)),
+                                &shared.codec,
+                            )
+                            .map_err(|e| MqttError::Protocol(ProtocolError::Encode(e)))?;
+                        MqttSink::new(shared.clone()).session_resume(present);
+                        ack.io.flush(true).await?;
 
                         let session = Session::new_v5(
                             session,
@@ -653,12 +1340,25 @@ where
                             max_receive,
                             max_topic_alias,
                         );
+                        session.set_keepalive(keep_alive, effective_keep_alive);
+                        session.set_negotiated(negotiated);
                         let handler = handler.new_service(session).await?;
                         log::trace!("Connection handler is created, starting dispatcher");
 
+                        let keepalive = if ack.keepalive_disabled {
+                            Seconds::ZERO
+                        } else {
+                            Seconds(ack.keepalive)
+                        };
+                        if let Some(pool) = shared.pool.io_pool.get() {
+                            ack.io.set_memory_pool(pool);
+                        }
+                        let check = shared.flush_check();
                         Dispatcher::new(ack.io, shared, handler)
-                            .keepalive_timeout(Seconds(ack.keepalive))
+                            .flush_wakeup(check)
+                            .keepalive_timeout(keepalive)
                             .disconnect_timeout(timeout)
+                            .time_source(time)
                             .await?;
                         Ok(Either::Right(()))
                     }
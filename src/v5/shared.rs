@@ -1,32 +1,144 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc};
+use std::time::{Duration, Instant};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, convert::TryFrom, rc::Rc};
 
-use ntex::channel::pool;
+use ntex::channel::{oneshot, pool};
 use ntex::codec::{Decoder, Encoder};
-use ntex::io::IoRef;
-use ntex::util::{BytesMut, HashMap, PoolId, PoolRef};
+use ntex::io::{IoBoxed, IoRef};
+use ntex::task::LocalWaker;
+use ntex::time::{Millis, Seconds};
+use ntex::util::{ByteString, BytesMut, HashMap, PoolId, PoolRef};
 
-use super::codec;
-use crate::{error, types::packet_type};
+use super::budget::WriteBudget;
+use super::dispatcher::RateLimiter;
+use super::handle::SessionHandle;
+use super::publish::{Idempotency, Publish, PublishOrdering, QosCeiling};
+use super::server::ConnackProps;
+use super::sink::{FlushPolicy, OverflowPolicy, Queued, UnsubscribePolicy};
+use super::stats::ServerStats;
+use super::{
+    codec,
+    control::AckOrdering,
+    lifecycle::{AcceptRateLimit, Connections, Sessions},
+    payload::PayloadCodec,
+};
+use crate::time::{self, Delay, TimeSource};
+use crate::{error, io::TlsCheck, topic::Topic, types::packet_type};
 
 pub struct MqttShared {
     pub(super) io: IoRef,
     pub(super) cap: Cell<usize>,
+    // size of write buffer accounted in pool's write budget
+    write_used: Cell<usize>,
     queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    pub(super) delay_id_reuse: Cell<bool>,
+    pub(super) overflow: Cell<OverflowPolicy>,
+    pub(super) draining: Cell<bool>,
+    // dispatcher task, writes queued publishes
+    pub(super) queue_task: LocalWaker,
+    pub(super) queue_timer: RefCell<Option<Delay>>,
+    // expiry of tracked subscriptions
+    pub(super) sweep_timer: RefCell<Option<Delay>>,
+    pub(super) flush: Cell<FlushPolicy>,
+    pub(super) flushing: Cell<bool>,
+    pub(super) pacing: RefCell<Option<RateLimiter>>,
+    pub(super) adaptive: RefCell<Option<AdaptivePacing>>,
+    pub(super) on_available: RefCell<Option<Rc<dyn Fn()>>>,
+    pub(super) session_expiry: Cell<u32>,
+    pub(super) topic_aliases: Cell<usize>,
+    pub(super) client_id: RefCell<ByteString>,
+    pub(super) handle: RefCell<Option<SessionHandle>>,
+    // subscribed filters and their creation time, tracked if subscription ttl is set
+    pub(super) subscriptions: RefCell<HashMap<ByteString, (Topic, Instant)>>,
+    // one-shot subscriptions of client, see `MqttSink::take_one()`
+    pub(super) one_shot: RefCell<Vec<(Topic, oneshot::Sender<Publish>)>>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) codec: codec::Codec,
 }
 
+/// Adaptive pacing state, pacing rate follows publish ack latency
+pub(super) struct AdaptivePacing {
+    max_per_sec: u16,
+    target: u32,
+    // smoothed PUBLISH to PUBACK latency in millis
+    pub(super) latency: Cell<Option<u32>>,
+    // send instants of in-flight QoS1 publishes
+    sent: RefCell<HashMap<u16, Instant>>,
+}
+
+impl AdaptivePacing {
+    pub(super) fn new(max_per_sec: u16, target: Millis) -> Self {
+        Self {
+            max_per_sec,
+            target: target.0,
+            latency: Cell::new(None),
+            sent: Default::default(),
+        }
+    }
+
+    /// Pacing rate for current latency
+    pub(super) fn rate(&self) -> u16 {
+        match self.latency.get() {
+            Some(latency) if latency > self.target => {
+                let rate =
+                    u64::from(self.max_per_sec) * u64::from(self.target) / u64::from(latency);
+                u16::try_from(rate).unwrap_or(self.max_per_sec).max(1)
+            }
+            _ => self.max_per_sec,
+        }
+    }
+}
+
 pub(super) struct MqttSharedQueues {
     pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
     pub(super) inflight_order: VecDeque<u16>,
+    // packet ids of dropped packets and acks still expected from peer
+    pub(super) abandoned: HashMap<u16, AckType>,
+    // publishes waiting for write buffer flush or receive credit, in enqueue order
+    pub(super) queue: VecDeque<Queued>,
+    // written publishes waiting for PUBACK or PUBREC, re-sent on session resume
+    pub(super) unacked: VecDeque<codec::Publish>,
+    // coalesced qos0 publishes and size of their topics and payloads
+    pub(super) coalesced: Vec<codec::Publish>,
+    pub(super) coalesced_size: usize,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
+    pub(super) sub_waiters: Vec<pool::Sender<()>>,
 }
 
+pub(super) type ConnectValidator =
+    Rc<dyn Fn(&codec::Connect) -> Result<(), codec::ConnectAckReason>>;
+
 pub(super) struct MqttSinkPool {
     pub(super) queue: pool::Pool<Ack>,
     pub(super) waiters: pool::Pool<()>,
     pub(super) pool: Cell<PoolRef>,
+    pub(super) write_budget: RefCell<Option<WriteBudget>>,
+    pub(super) stats: RefCell<Option<ServerStats>>,
+    pub(super) payload_codec: RefCell<Option<Rc<dyn PayloadCodec>>>,
+    pub(super) ack_ordering: Cell<AckOrdering>,
+    pub(super) max_sub_filters: Cell<usize>,
+    pub(super) lenient_utf8: Cell<bool>,
+    pub(super) max_filter_levels: Cell<usize>,
+    pub(super) subscription_ttl: Cell<Seconds>,
+    pub(super) unsubscribe_policy: Cell<UnsubscribePolicy>,
+    pub(super) max_qos2: Cell<u16>,
+    pub(super) decode_capture: Cell<usize>,
+    pub(super) max_connect_size: Cell<u32>,
+    pub(super) strict_direction: Cell<bool>,
+    pub(super) require_tls: RefCell<Option<TlsCheck>>,
+    pub(super) retained_limit: Cell<usize>,
+    pub(super) retained_rate: Cell<u16>,
+    pub(super) connack_defaults: RefCell<Option<ConnackProps>>,
+    pub(super) custom_packets: Cell<bool>,
+    pub(super) qos_ceiling: RefCell<Option<QosCeiling>>,
+    pub(super) idempotency: RefCell<Option<Rc<Idempotency>>>,
+    pub(super) publish_ordering: Cell<Option<PublishOrdering>>,
+    pub(super) connect_validator: RefCell<Option<ConnectValidator>>,
+    pub(super) accept_limit: RefCell<Option<AcceptRateLimit>>,
+    pub(super) io_pool: Cell<Option<PoolRef>>,
+    pub(super) time: RefCell<Option<Rc<dyn TimeSource>>>,
+    pub(super) sessions: Rc<Sessions>,
+    pub(super) conn_events: Rc<Connections>,
 }
 
 impl Default for MqttSinkPool {
@@ -35,10 +147,80 @@ impl Default for MqttSinkPool {
             queue: pool::new(),
             waiters: pool::new(),
             pool: Cell::new(PoolId::P5.pool_ref()),
+            write_budget: RefCell::new(None),
+            stats: RefCell::new(None),
+            payload_codec: RefCell::new(None),
+            ack_ordering: Cell::new(AckOrdering::default()),
+            max_sub_filters: Cell::new(0),
+            lenient_utf8: Cell::new(false),
+            max_filter_levels: Cell::new(0),
+            subscription_ttl: Cell::new(Seconds::ZERO),
+            unsubscribe_policy: Cell::new(UnsubscribePolicy::default()),
+            max_qos2: Cell::new(0),
+            decode_capture: Cell::new(0),
+            max_connect_size: Cell::new(0),
+            strict_direction: Cell::new(false),
+            require_tls: RefCell::new(None),
+            retained_limit: Cell::new(0),
+            retained_rate: Cell::new(0),
+            connack_defaults: RefCell::new(None),
+            custom_packets: Cell::new(false),
+            qos_ceiling: RefCell::new(None),
+            idempotency: RefCell::new(None),
+            publish_ordering: Cell::new(None),
+            connect_validator: RefCell::new(None),
+            accept_limit: RefCell::new(None),
+            io_pool: Cell::new(None),
+            time: RefCell::new(None),
+            sessions: Rc::new(Sessions::default()),
+            conn_events: Rc::new(Connections::default()),
         }
     }
 }
 
+impl MqttSinkPool {
+    /// Decode payload of received publish packet
+    pub(super) fn decode_payload(&self, pkt: &mut codec::Publish) {
+        if let Some(ref payload_codec) = *self.payload_codec.borrow() {
+            let payload = std::mem::take(&mut pkt.payload);
+            pkt.payload = payload_codec.decode(&pkt.topic, payload);
+        }
+    }
+
+    /// Encode payload of publish packet before sending
+    pub(super) fn encode_payload(&self, pkt: &mut codec::Publish) {
+        if let Some(ref payload_codec) = *self.payload_codec.borrow() {
+            let payload = std::mem::take(&mut pkt.payload);
+            pkt.payload = payload_codec.encode(&pkt.topic, payload);
+        }
+    }
+
+    /// Count sent publish packet
+    pub(super) fn message_out(&self) {
+        if let Some(ref stats) = *self.stats.borrow() {
+            stats.message_out();
+        }
+    }
+}
+
+impl MqttSharedQueues {
+    /// Check if packet id is in use, including ids of dropped packets
+    /// which are not acked by peer yet
+    pub(super) fn is_reserved(&self, idx: u16) -> bool {
+        self.inflight.contains_key(&idx) || self.abandoned.contains_key(&idx)
+    }
+
+    /// Ack type expected for packet id
+    pub(super) fn ack_type(&self, idx: u16) -> Option<AckType> {
+        self.inflight.get(&idx).map(|(_, tp)| *tp).or_else(|| self.abandoned.get(&idx).copied())
+    }
+
+    /// Check if there are in-flight subscribe packets
+    pub(super) fn has_subscribes(&self) -> bool {
+        self.inflight.values().any(|(_, tp)| *tp == AckType::Subscribe)
+    }
+}
+
 impl MqttShared {
     pub(super) fn new(
         io: IoRef,
@@ -46,17 +228,208 @@ impl MqttShared {
         cap: usize,
         pool: Rc<MqttSinkPool>,
     ) -> Self {
+        codec.set_max_subscribe_filters(pool.max_sub_filters.get());
+        codec.set_decode_capture_size(pool.decode_capture.get());
+        codec.set_max_connect_size(pool.max_connect_size.get());
+        codec.set_lenient_utf8(pool.lenient_utf8.get());
+        codec.set_custom_packets(pool.custom_packets.get());
         Self {
             io,
             pool,
             codec,
             cap: Cell::new(cap),
+            write_used: Cell::new(0),
             queues: RefCell::new(MqttSharedQueues {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
+                abandoned: HashMap::default(),
+                queue: VecDeque::new(),
+                unacked: VecDeque::new(),
+                coalesced: Vec::new(),
+                coalesced_size: 0,
                 waiters: VecDeque::new(),
+                sub_waiters: Vec::new(),
             }),
             inflight_idx: Cell::new(0),
+            delay_id_reuse: Cell::new(true),
+            overflow: Cell::new(OverflowPolicy::default()),
+            draining: Cell::new(false),
+            queue_task: LocalWaker::new(),
+            queue_timer: RefCell::new(None),
+            sweep_timer: RefCell::new(None),
+            flush: Cell::new(FlushPolicy::default()),
+            flushing: Cell::new(false),
+            pacing: RefCell::new(None),
+            adaptive: RefCell::new(None),
+            on_available: RefCell::new(None),
+            session_expiry: Cell::new(0),
+            topic_aliases: Cell::new(0),
+            client_id: RefCell::new(ByteString::default()),
+            handle: RefCell::new(None),
+            subscriptions: RefCell::new(HashMap::default()),
+            one_shot: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Time source of server, ntex's timer is used if not set
+    pub(super) fn time(&self) -> Option<Rc<dyn TimeSource>> {
+        self.pool.time.borrow().clone()
+    }
+
+    /// Current time of server's time source
+    pub(super) fn now(&self) -> Instant {
+        time::now(self.pool.time.borrow().as_ref())
+    }
+
+    /// Sleep future of server's time source
+    pub(super) fn sleep(&self, dur: Duration) -> Delay {
+        time::sleep(self.pool.time.borrow().as_ref(), dur)
+    }
+
+    /// Encode packet to write buffer
+    pub(super) fn encode_packet(&self, pkt: codec::Packet) -> Result<(), error::EncodeError> {
+        self.io.encode(pkt, self)
+    }
+
+    /// Update write budget with current size of write buffer
+    pub(super) fn sync_write_budget(&self) {
+        if self.pool.write_budget.borrow().is_some() {
+            self.account_write(self.io.with_write_buf(|buf| buf.len()).unwrap_or(0));
+        }
+    }
+
+    fn account_write(&self, size: usize) {
+        if let Some(ref budget) = *self.pool.write_budget.borrow() {
+            budget.update(self.write_used.replace(size), size);
+        }
+    }
+
+    /// Protocol error for decode error, with captured packet bytes if available
+    pub(super) fn decode_error(&self, err: error::DecodeError) -> error::ProtocolError {
+        if let Some(dump) = self.codec.take_decode_input() {
+            error::ProtocolError::DecodeInput(err, dump)
+        } else {
+            error::ProtocolError::Decode(err)
+        }
+    }
+
+    /// Check if publishes of all QoS levels are kept in enqueue order
+    pub(super) fn is_ordered(&self) -> bool {
+        self.overflow.get() != OverflowPolicy::Unbounded || self.is_paced()
+    }
+
+    /// Check if dispatcher must be woken up on write buffer flush
+    ///
+    /// Queued QoS0 publishes wait for write buffer flush if overflow policy is set.
+    /// Write budget gets updated on flush, so paused connections could resume.
+    pub(super) fn wants_flush(&self) -> bool {
+        self.write_used.get() != 0
+            || (self.overflow.get() != OverflowPolicy::Unbounded
+                && self.queues.borrow().queue.front().map_or(false, |item| item.is_qos0()))
+    }
+
+    /// Write buffer flush check for connection dispatcher
+    pub(super) fn flush_check(self: &Rc<Self>) -> Rc<dyn Fn() -> bool> {
+        let shared = self.clone();
+        Rc::new(move || shared.wants_flush())
+    }
+
+    /// Check if QoS1 or QoS2 publish must wait in queue for its turn
+    pub(super) fn must_queue(&self) -> bool {
+        let queues = self.queues.borrow();
+        self.cap.get() <= queues.inflight.len() + queues.abandoned.len()
+            || !queues.queue.is_empty()
+            || self.pacing_delay().is_some()
+    }
+
+    /// Check if outbound publishes are paced
+    pub(super) fn is_paced(&self) -> bool {
+        self.pacing.borrow().is_some()
+    }
+
+    /// Delay of next pacing token if publish must wait for its turn
+    pub(super) fn pacing_delay(&self) -> Option<Millis> {
+        self.pacing.borrow().as_ref().and_then(|limiter| limiter.check().err())
+    }
+
+    /// Take pacing token of written publish
+    pub(super) fn take_pacing_token(&self) {
+        if let Some(ref limiter) = *self.pacing.borrow() {
+            let _ = limiter.acquire();
+        }
+    }
+
+    /// Publish is received by peer, it is not re-sent on session resume
+    pub(super) fn publish_delivered(&self, idx: u16) {
+        self.with_queues(|q| {
+            q.unacked.retain(|pkt| pkt.packet_id.map(|i| i.get()) != Some(idx))
+        });
+    }
+
+    /// Store send instant of QoS1 publish if adaptive pacing is enabled
+    pub(super) fn publish_sent(&self, idx: u16) {
+        if let Some(ref adaptive) = *self.adaptive.borrow() {
+            adaptive.sent.borrow_mut().insert(idx, self.now());
+        }
+    }
+
+    /// Update ack latency and pacing rate with PUBACK of QoS1 publish
+    pub(super) fn publish_acked(&self, idx: u16) {
+        let adaptive = self.adaptive.borrow();
+        let adaptive = if let Some(ref adaptive) = *adaptive {
+            adaptive
+        } else {
+            return;
+        };
+        let sent = if let Some(sent) = adaptive.sent.borrow_mut().remove(&idx) {
+            sent
+        } else {
+            return;
+        };
+
+        let elapsed = self.now().saturating_duration_since(sent);
+        let sample = u32::try_from(elapsed.as_millis()).unwrap_or(u32::MAX);
+        let latency = match adaptive.latency.get() {
+            // same smoothing as tcp srtt
+            Some(latency) => ((u64::from(latency) * 7 + u64::from(sample)) / 8) as u32,
+            None => sample,
+        };
+        adaptive.latency.set(Some(latency));
+
+        let rate = adaptive.rate();
+        if let Some(ref limiter) = *self.pacing.borrow() {
+            if limiter.rate() != rate {
+                log::trace!("Ack latency is {}ms, pacing rate is {}/s", latency, rate);
+                limiter.set_rate(rate);
+            }
+        }
+    }
+
+    /// Check tls session of connection, if server requires tls
+    pub(super) fn check_tls(&self, io: &IoBoxed) -> bool {
+        self.pool.require_tls.borrow().as_ref().map_or(true, |f| (*f)(io))
+    }
+
+    /// Run connect validator for CONNECT packet
+    pub(super) fn validate_connect(
+        &self,
+        connect: &codec::Connect,
+    ) -> Result<(), codec::ConnectAckReason> {
+        if let Some(ref f) = *self.pool.connect_validator.borrow() {
+            (*f)(connect)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take accept rate limit token
+    pub(super) fn check_accept_rate(&self) -> Result<(), codec::ConnectAckReason> {
+        match *self.pool.accept_limit.borrow() {
+            Some(ref limit) if !limit.acquire() => {
+                log::trace!("Accept rate limit is exceeded");
+                Err(codec::ConnectAckReason::ServerBusy)
+            }
+            _ => Ok(()),
         }
     }
 
@@ -66,10 +439,101 @@ impl MqttShared {
     }
 
     pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+        let queues = self.queues.borrow();
+        self.cap.get() > queues.inflight.len() + queues.abandoned.len()
+    }
+
+    /// Release packet id of dropped publish future
+    ///
+    /// If packet is sent already, ack from peer is still expected and
+    /// it gets ignored once received.
+    pub(super) fn release_id(&self, idx: u16, sent: bool) {
+        self.with_queues(|queues| {
+            let canceled = queues.inflight.get(&idx).map(|(tx, _)| tx.is_canceled());
+            if canceled != Some(true) {
+                return;
+            }
+            log::trace!("Packet with id {} is dropped before ack", idx);
+            let tp = match queues.inflight.remove(&idx) {
+                Some((_, tp)) => tp,
+                None => return,
+            };
+            if tp == AckType::Subscribe && !queues.has_subscribes() {
+                // wake up subscriptions barrier
+                for tx in queues.sub_waiters.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+
+            if sent {
+                // packet id stays reserved until peer acks it
+                queues.abandoned.insert(idx, tp);
+            } else {
+                if let Some(pos) = queues.inflight_order.iter().position(|i| *i == idx) {
+                    // errored publish
+                    queues.inflight_order[pos] = 0;
+                }
+                self.credit_released(queues);
+            }
+        })
+    }
+
+    /// Hand released credit to queued publishes first, then to waiters
+    pub(super) fn credit_released(&self, queues: &mut MqttSharedQueues) {
+        if queues.queue.iter().any(|item| !item.is_qos0()) {
+            self.queue_task.wake();
+            return;
+        }
+        while let Some(tx) = queues.waiters.pop_front() {
+            if tx.send(()).is_ok() {
+                break;
+            }
+        }
+    }
+
+    /// Send PUBREL for PUBREC with success reason code
+    ///
+    /// Returns `false` if packet is not a successful PUBREC.
+    pub(super) fn release_publish(&self, pkt: &Ack) -> bool {
+        if let Ack::Receive(ref pkt) = pkt {
+            if u8::from(pkt.reason_code) < 0x80 {
+                let rel = codec::PublishAck2 {
+                    packet_id: pkt.packet_id,
+                    reason_code: codec::PublishAck2Reason::Success,
+                    properties: codec::UserProperties::default(),
+                    reason_string: None,
+                };
+                if let Err(err) = self.encode_packet(codec::Packet::PublishRelease(rel)) {
+                    log::error!("Cannot send PUBREL packet: {:?}", err);
+                }
+                return true;
+            }
+        }
+        false
     }
 
     pub(super) fn next_id(&self) -> u16 {
+        // use lowest free packet id
+        if !self.delay_id_reuse.get() {
+            let queues = self.queues.borrow();
+            if let Some(idx) = (1..=u16::MAX).find(|idx| !queues.is_reserved(*idx)) {
+                self.inflight_idx.set(idx);
+                return idx;
+            }
+        }
+
+        // skip ids of in-flight and dropped packets, e.g. re-sent publishes of resumed session
+        let mut idx = self.next_idx();
+        for _ in 0..u16::MAX {
+            if !self.queues.borrow().is_reserved(idx) {
+                break;
+            }
+            idx = self.next_idx();
+        }
+        idx
+    }
+
+    fn next_idx(&self) -> u16 {
         let idx = self.inflight_idx.get() + 1;
         self.inflight_idx.set(idx);
         if idx == u16::max_value() {
@@ -88,7 +552,15 @@ impl Encoder for MqttShared {
 
     #[inline]
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.codec.encode(item, dst)
+        let result = self.codec.encode(item, dst);
+        self.account_write(dst.len());
+        result
+    }
+}
+
+impl Drop for MqttShared {
+    fn drop(&mut self) {
+        self.account_write(0);
     }
 }
 
@@ -102,15 +574,19 @@ impl Decoder for MqttShared {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub(super) enum AckType {
     Publish,
+    Receive,
+    Complete,
     Subscribe,
     Unsubscribe,
 }
 
 pub(super) enum Ack {
     Publish(codec::PublishAck),
+    Receive(codec::PublishAck),
+    Complete(codec::PublishAck2),
     Subscribe(codec::SubscribeAck),
     Unsubscribe(codec::UnsubscribeAck),
 }
@@ -119,6 +595,8 @@ impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) => packet_type::PUBCOMP,
             Ack::Subscribe(_) => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
         }
@@ -127,6 +605,8 @@ impl Ack {
     pub(super) fn packet_id(&self) -> u16 {
         match self {
             Ack::Publish(ref pkt) => pkt.packet_id.get(),
+            Ack::Receive(ref pkt) => pkt.packet_id.get(),
+            Ack::Complete(ref pkt) => pkt.packet_id.get(),
             Ack::Subscribe(ref pkt) => pkt.packet_id.get(),
             Ack::Unsubscribe(ref pkt) => pkt.packet_id.get(),
         }
@@ -140,6 +620,14 @@ impl Ack {
         }
     }
 
+    pub(super) fn complete(self) -> codec::PublishAck2 {
+        if let Ack::Complete(pkt) = self {
+            pkt
+        } else {
+            panic!()
+        }
+    }
+
     pub(super) fn subscribe(self) -> codec::SubscribeAck {
         if let Ack::Subscribe(pkt) = self {
             pkt
@@ -159,6 +647,8 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
+            (Ack::Complete(_), AckType::Complete) => true,
             (Ack::Subscribe(_), AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -170,6 +660,8 @@ impl AckType {
     pub(super) fn name(&self) -> &'static str {
         match self {
             AckType::Publish => "PublishAck",
+            AckType::Receive => "PublishReceived",
+            AckType::Complete => "PublishComplete",
             AckType::Subscribe => "SubscribeAck",
             AckType::Unsubscribe => "UnsubscribeAck",
         }
@@ -1,12 +1,27 @@
 use std::future::{ready, Future};
-use std::{fmt, num::NonZeroU16, num::NonZeroU32, rc::Rc};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::{collections::VecDeque, fmt, num::NonZeroU16, num::NonZeroU32, pin::Pin, rc::Rc};
 
-use ntex::util::{ByteString, Bytes, Either, Ready};
+use ntex::channel::{oneshot, pool, Canceled};
+use ntex::time::{Millis, Seconds};
+use ntex::util::{ByteString, Bytes, Either, PoolRef, Ready};
 
 use super::codec;
-use super::error::{ProtocolError, PublishQos1Error, SendPacketError};
-use super::shared::{Ack, AckType, MqttShared};
-use crate::types::QoS;
+use super::control::AckOrdering;
+use super::dispatcher::{RateLimiter, SubRateLimit};
+use super::error::{
+    DecodeError, ProtocolError, PublishQos1Error, PublishQos2Error, SendPacketError,
+    TakeOneError,
+};
+use super::lifecycle::ConnectionTracker;
+use super::publish::{IdempotencyCheck, Publish, PublishOrdering, QosCeilingPolicy};
+use super::shared::{Ack, AckType, AdaptivePacing, MqttShared};
+use super::stats::ServerStats;
+use crate::{error::EncodeError, time::TimeSource, topic::Topic, types::QoS, utils::select};
+
+/// Max delay of coalesced QoS0 publishes
+const COALESCE_DELAY: Millis = Millis(5);
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -26,10 +41,164 @@ impl MqttSink {
         !self.0.io.is_closed()
     }
 
+    /// Number of bytes queued in connection's write buffer
+    ///
+    /// Write buffer is flushed to the peer by io write task, large value
+    /// indicates that peer does not keep up with sent packets.
+    pub fn buffered_bytes(&self) -> usize {
+        self.0.io.with_write_buf(|buf| buf.len()).unwrap_or(0)
+    }
+
     /// Get client's receive credit
     pub fn credit(&self) -> usize {
         let cap = self.0.cap.get();
-        cap - self.0.with_queues(|q| q.inflight.len())
+        cap.saturating_sub(self.0.with_queues(|q| q.inflight.len() + q.abandoned.len()))
+    }
+
+    /// Memory pool of the connection's io buffers
+    ///
+    /// Read and write buffer params are configured with
+    /// `MqttServer::io_buffer_params()`.
+    pub fn memory_pool(&self) -> PoolRef {
+        self.0.io.memory_pool()
+    }
+
+    /// Number of topic aliases established by the peer
+    ///
+    /// Number is bounded by `Topic Alias Maximum` of the connection.
+    pub fn topic_aliases(&self) -> usize {
+        self.0.topic_aliases.get()
+    }
+
+    /// Check if subscription of this session accepts publish received from `publisher`
+    ///
+    /// Returns `false` if subscription has `No Local` option set and publish
+    /// is received from the same session [MQTT-3.8.3-3].
+    pub fn accepts(&self, publisher: &MqttSink, options: &codec::SubscriptionOptions) -> bool {
+        !(options.no_local && Rc::ptr_eq(&self.0, &publisher.0))
+    }
+
+    /// Set packet id allocation policy.
+    ///
+    /// If enabled, freed packet id is not reused until whole packet id space
+    /// wraps. Otherwise lowest available packet id is used.
+    ///
+    /// By default packet id reuse delay is enabled.
+    pub fn delay_id_reuse(&self, val: bool) {
+        self.0.delay_id_reuse.set(val);
+    }
+
+    /// Set overflow policy for QoS0 publishes.
+    ///
+    /// While policy is set, publishes of all QoS levels are written in enqueue
+    /// order, QoS1 and QoS2 publishes waiting for receive credit hold back
+    /// QoS0 publishes enqueued after them. Limit applies to QoS0 publishes only.
+    ///
+    /// By default all publishes are written to connection write buffer.
+    pub fn overflow_policy(&self, policy: OverflowPolicy) {
+        self.0.overflow.set(policy);
+    }
+
+    /// Set flush policy for publishes.
+    ///
+    /// By default all publishes are written to connection immediately.
+    pub fn qos_flush_policy(&self, policy: FlushPolicy) {
+        self.0.flush.set(policy);
+    }
+
+    /// Pace outbound publishes of the session.
+    ///
+    /// At most `per_sec` new publishes leave per second, excess publishes of all
+    /// QoS levels wait in sink queue in enqueue order. Queued QoS0 publishes are
+    /// limited by overflow policy. Acks are not paced. `0` disables pacing.
+    ///
+    /// By default pacing is disabled.
+    pub fn pacing(&self, per_sec: u16) {
+        *self.0.adaptive.borrow_mut() = None;
+        *self.0.pacing.borrow_mut() = if per_sec == 0 {
+            None
+        } else {
+            let limit = SubRateLimit { per_sec, burst: 1, disconnect: false };
+            Some(RateLimiter::new(limit, self.0.time()))
+        };
+        // queued publishes could be written at new rate
+        self.0.queue_task.wake();
+    }
+
+    /// Pace outbound publishes of the session by client's ack latency.
+    ///
+    /// Latency between QoS1 PUBLISH and its PUBACK is measured and smoothed.
+    /// While latency stays within `target` at most `max_per_sec` publishes leave
+    /// per second, above `target` the rate decreases in proportion to latency,
+    /// down to one publish per second. Rate recovers once client acks faster.
+    /// `0` disables pacing.
+    ///
+    /// By default pacing is disabled.
+    pub fn adaptive_pacing(&self, max_per_sec: u16, target: Millis) {
+        self.pacing(max_per_sec);
+        if max_per_sec != 0 {
+            *self.0.adaptive.borrow_mut() = Some(AdaptivePacing::new(max_per_sec, target));
+        }
+    }
+
+    /// Current pacing rate, publishes per second
+    pub fn pacing_rate(&self) -> Option<u16> {
+        self.0.pacing.borrow().as_ref().map(|limiter| limiter.rate())
+    }
+
+    /// Smoothed PUBLISH to PUBACK latency, measured if adaptive pacing is enabled
+    pub fn ack_latency(&self) -> Option<Duration> {
+        self.0
+            .adaptive
+            .borrow()
+            .as_ref()
+            .and_then(|adaptive| adaptive.latency.get())
+            .map(|latency| Duration::from_millis(u64::from(latency)))
+    }
+
+    /// Send retained messages matched by new subscription
+    ///
+    /// At most `MqttServer::retained_replay_limit()` messages are sent immediately,
+    /// remaining messages are streamed with `MqttServer::retained_replay_rate()`
+    /// rate or dropped if rate is not set. Messages are sent with their QoS,
+    /// delivery errors are logged. Returns number of messages sent immediately.
+    pub fn replay_retained<I>(&self, packets: I) -> usize
+    where
+        I: IntoIterator<Item = codec::Publish>,
+    {
+        let limit = match self.0.pool.retained_limit.get() {
+            0 => usize::MAX,
+            limit => limit,
+        };
+        let mut packets = packets.into_iter();
+        let mut sent = 0;
+        for packet in packets.by_ref().take(limit) {
+            send_retained(&self.0, packet);
+            sent += 1;
+        }
+
+        let rest: VecDeque<_> = packets.collect();
+        if !rest.is_empty() {
+            let rate = self.0.pool.retained_rate.get();
+            if rate == 0 {
+                log::trace!("Retained replay limit is reached, drop {} messages", rest.len());
+            } else {
+                ntex::rt::spawn(stream_retained(self.0.clone(), rest, rate));
+            }
+        }
+        sent
+    }
+
+    /// Set callback for freed slots of in-flight window
+    ///
+    /// Callback is called once per freed slot, when PUBACK or PUBCOMP
+    /// is received for QoS1 or QoS2 publish. Subscribe acks and acks for
+    /// dropped publishes do not call it.
+    pub fn on_inflight_available<F>(&self, f: F)
+    where
+        F: Fn() + 'static,
+    {
+        *self.0.on_available.borrow_mut() = Some(Rc::new(f));
     }
 
     /// Get notification when packet could be send to the peer.
@@ -39,7 +208,7 @@ impl MqttSink {
         if !self.0.io.is_closed() {
             self.0
                 .with_queues(|q| {
-                    if q.inflight.len() >= self.0.cap.get() {
+                    if q.inflight.len() + q.abandoned.len() >= self.0.cap.get() {
                         let (tx, rx) = self.0.pool.waiters.channel();
                         q.waiters.push_back(tx);
                         return Some(rx);
@@ -53,97 +222,492 @@ impl MqttSink {
         }
     }
 
+    /// Get notification when all in-flight subscribe packets get acknowledged.
+    ///
+    /// Result indicates if connection is alive
+    pub fn wait_subscriptions_ready(&self) -> impl Future<Output = bool> {
+        if !self.0.io.is_closed() {
+            self.0
+                .with_queues(|q| {
+                    if q.has_subscribes() {
+                        let (tx, rx) = self.0.pool.waiters.channel();
+                        q.sub_waiters.push(tx);
+                        return Some(rx);
+                    }
+                    None
+                })
+                .map(|rx| Either::Right(async move { rx.await.is_ok() }))
+                .unwrap_or_else(|| Either::Left(ready(true)))
+        } else {
+            Either::Left(ready(false))
+        }
+    }
+
+    /// Apply session expiry interval override from DISCONNECT packet
+    pub(super) fn update_session_expiry(&self, val: Option<u32>) -> u32 {
+        if let Some(expiry) = val {
+            // MQTT-3.14.2-2: session expiry cannot be set if it was 0 in CONNECT
+            if self.0.session_expiry.get() == 0 && expiry != 0 {
+                log::trace!("Ignore session expiry interval override: {}", expiry);
+            } else {
+                self.0.session_expiry.set(expiry);
+            }
+        }
+        self.0.session_expiry.get()
+    }
+
+    /// Decode payload of received publish packet
+    pub(super) fn decode_payload(&self, pkt: &mut codec::Publish) {
+        self.0.pool.decode_payload(pkt);
+    }
+
+    /// Register connection for session lifecycle tracking
+    pub(super) fn session_start(&self) -> usize {
+        self.0.pool.sessions.start(&self.0.client_id.borrow())
+    }
+
+    /// Max number of concurrent inbound qos2 exchanges
+    pub(super) fn max_inflight_qos2(&self) -> usize {
+        self.0.pool.max_qos2.get() as usize
+    }
+
+    /// Protocol error for decode error
+    pub(super) fn decode_error(&self, err: DecodeError) -> ProtocolError {
+        self.0.decode_error(err)
+    }
+
+    /// Order of publish service calls
+    pub(super) fn publish_ordering(&self) -> Option<PublishOrdering> {
+        self.0.pool.publish_ordering.get()
+    }
+
+    /// Max QoS and policy for received publish topic
+    pub(super) fn qos_ceiling(&self, topic: &str) -> Option<(QoS, QosCeilingPolicy)> {
+        self.0.pool.qos_ceiling.borrow().as_ref().map(|(f, policy)| ((*f)(topic), *policy))
+    }
+
+    /// Check if publish with the same idempotency key is already received
+    pub(super) fn check_idempotency(&self, pkt: &codec::Publish) -> IdempotencyCheck {
+        if let Some(ref idempotency) = *self.0.pool.idempotency.borrow() {
+            idempotency.check(&self.0.client_id.borrow(), pkt, self.0.now())
+        } else {
+            IdempotencyCheck::NoKey
+        }
+    }
+
+    /// Check if session disconnect is requested via session handle
+    pub(super) fn poll_disconnect(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Option<codec::DisconnectReasonCode> {
+        match *self.0.handle.borrow() {
+            Some(ref handle) => match handle.poll_disconnect(cx) {
+                Poll::Ready(reason) => Some(reason),
+                Poll::Pending => None,
+            },
+            None => None,
+        }
+    }
+
+    /// Register connection for connection count tracking
+    pub(super) fn connection_start(&self) -> Option<ConnectionTracker> {
+        self.0.pool.conn_events.connected()
+    }
+
+    /// Connection of the session is closed
+    pub(super) fn session_end(&self, generation: usize) {
+        // un-acked publishes are re-sent once session resumes
+        let expiry = self.0.session_expiry.get();
+        if expiry != 0 {
+            let client_id = self.0.client_id.borrow().clone();
+            let packets = self.0.with_queues(|q| Vec::from(std::mem::take(&mut q.unacked)));
+            self.0.pool.sessions.store_inflight(client_id, packets, expiry, self.0.time());
+        }
+
+        if generation != 0 {
+            let client_id = self.0.client_id.borrow().clone();
+            let expiry = self.0.session_expiry.get();
+            self.0.pool.sessions.end(client_id, generation, expiry, self.0.time());
+        }
+    }
+
+    /// Re-send un-acked publishes of previous connection of the session
+    ///
+    /// Called once CONNACK is written, publishes are re-sent with `dup` flag
+    /// before any new publish of the session. Stored publishes are discarded
+    /// if session is not present.
+    pub(super) fn session_resume(&self, present: bool) {
+        let client_id = self.0.client_id.borrow().clone();
+        let packets = self.0.pool.sessions.take_inflight(&client_id);
+        if !present {
+            return;
+        }
+
+        for mut packet in packets {
+            packet.dup = true;
+            // ack is not awaited, packet id stays reserved until peer acks it
+            if let Err(WriteError::Encode(err)) = write_publish(&self.0, packet) {
+                log::trace!("Cannot re-send publish: {:?}", err);
+            }
+        }
+    }
+
+    /// Take one-shot subscription that matches publish topic
+    pub(super) fn take_one_sender(&self, topic: &str) -> Option<oneshot::Sender<Publish>> {
+        let mut one_shot = self.0.one_shot.borrow_mut();
+        // receivers of timed out subscriptions are dropped
+        one_shot.retain(|(_, tx)| !tx.is_canceled());
+        let idx = one_shot.iter().position(|(t, _)| t.matches_str(topic))?;
+        Some(one_shot.remove(idx).1)
+    }
+
+    /// Update number of inbound topic aliases
+    pub(super) fn set_topic_aliases(&self, val: usize) {
+        self.0.topic_aliases.set(val);
+    }
+
+    /// Check if packets of wrong direction are treated as protocol violation
+    pub(super) fn strict_packet_direction(&self) -> bool {
+        self.0.pool.strict_direction.get()
+    }
+
+    /// Max number of levels of subscription filter, `0` means unlimited
+    pub(super) fn max_filter_levels(&self) -> usize {
+        self.0.pool.max_filter_levels.get()
+    }
+
+    /// Track granted subscriptions
+    pub(super) fn track_subscribe<'a, I>(&self, filters: I)
+    where
+        I: IntoIterator<Item = &'a ByteString>,
+    {
+        let now = self.0.now();
+        let mut subs = self.0.subscriptions.borrow_mut();
+        for filter in filters {
+            if let Some(topic) = filter_topic(filter) {
+                subs.insert(filter.clone(), (topic, now));
+            }
+        }
+        if self.0.pool.subscription_ttl.get().non_zero() {
+            // dispatcher arms expiry timer
+            self.0.queue_task.wake();
+        }
+    }
+
+    /// Stop tracking unsubscribed filters, purge queued publishes
+    /// according to unsubscribe policy
+    pub(super) fn unsubscribed(&self, filters: &[ByteString]) {
+        {
+            let mut subs = self.0.subscriptions.borrow_mut();
+            if !subs.is_empty() {
+                filters.iter().for_each(|f| {
+                    subs.remove(f);
+                });
+            }
+        }
+        self.purge_unsubscribed(filters);
+    }
+
+    /// Drop queued QoS0 publishes of unsubscribed filters if policy is `Purge`
+    fn purge_unsubscribed(&self, filters: &[ByteString]) {
+        if self.0.pool.unsubscribe_policy.get() == UnsubscribePolicy::Purge {
+            let subs = self.0.subscriptions.borrow();
+            let topics: Vec<Topic> = filters.iter().filter_map(|f| filter_topic(f)).collect();
+            // publish is purged if it does not match any of remaining subscriptions
+            let purge = |pkt: &codec::Publish| {
+                topics.iter().any(|t| t.matches_str(&pkt.topic))
+                    && !subs.values().any(|(t, _)| t.matches_str(&pkt.topic))
+            };
+            self.0.with_queues(|q| {
+                q.queue.retain(|item| !item.is_qos0() || !purge(&item.packet));
+                q.coalesced.retain(|pkt| !purge(pkt));
+                q.coalesced_size =
+                    q.coalesced.iter().map(|pkt| pkt.topic.len() + pkt.payload.len()).sum();
+            });
+        }
+    }
+
+    /// Remove expired subscriptions, expired subscription is handled as unsubscribed.
+    ///
+    /// Returns time until next subscription expires.
+    fn sweep_subscriptions(&self) -> Option<Millis> {
+        let ttl = self.0.pool.subscription_ttl.get();
+        if !ttl.non_zero() {
+            return None;
+        }
+        let ttl = Duration::from_secs(u64::from(ttl.0));
+        let now = self.0.now();
+
+        let mut expired = Vec::new();
+        let mut next = None;
+        self.0.subscriptions.borrow_mut().retain(|filter, (_, created)| {
+            let elapsed = now.saturating_duration_since(*created);
+            if elapsed < ttl {
+                next = Some(next.map_or(ttl - elapsed, |d: Duration| d.min(ttl - elapsed)));
+                true
+            } else {
+                expired.push(filter.clone());
+                false
+            }
+        });
+        if !expired.is_empty() {
+            self.purge_unsubscribed(&expired);
+        }
+        next.map(|d| Millis(d.as_millis() as u32 + 1))
+    }
+
+    /// Remove expired subscriptions, called by dispatcher on readiness check
+    pub(super) fn poll_subscriptions(&self, cx: &mut Context<'_>) {
+        if !self.0.pool.subscription_ttl.get().non_zero() {
+            return;
+        }
+        self.0.queue_task.register(cx.waker());
+
+        let mut timer = self.0.sweep_timer.borrow_mut();
+        if let Some(ref mut fut) = *timer {
+            if Pin::new(fut).poll(cx).is_pending() {
+                return;
+            }
+            *timer = None;
+        }
+        if let Some(delay) = self.sweep_subscriptions() {
+            let mut fut = self.0.sleep(delay.into());
+            let _ = Pin::new(&mut fut).poll(cx);
+            *timer = Some(fut);
+        }
+    }
+
+    /// Check if topic matches any of active subscriptions of the session
+    ///
+    /// Expired subscriptions do not match, see `MqttServer::subscription_ttl()`.
+    /// Shared subscription `$share/{group}/{filter}` matches topics of `filter`.
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.sweep_subscriptions();
+        self.0.subscriptions.borrow().values().any(|(t, _)| t.matches_str(topic))
+    }
+
+    /// Active subscriptions of the session
+    ///
+    /// Expired subscriptions are not included, see `MqttServer::subscription_ttl()`.
+    pub fn subscriptions(&self) -> Vec<ByteString> {
+        self.sweep_subscriptions();
+        self.0.subscriptions.borrow().keys().cloned().collect()
+    }
+
+    /// Order of subscribe acks and control service processing
+    pub(super) fn ack_ordering(&self) -> AckOrdering {
+        self.0.pool.ack_ordering.get()
+    }
+
+    /// Time source of server
+    pub(super) fn time(&self) -> Option<Rc<dyn TimeSource>> {
+        self.0.time()
+    }
+
+    /// Current time of server's time source
+    pub(super) fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    /// Server statistics
+    pub(super) fn stats(&self) -> Option<ServerStats> {
+        self.0.pool.stats.borrow().clone()
+    }
+
+    /// Write queued publishes, called by dispatcher on readiness check
+    pub(super) fn poll_queue(&self, cx: &mut Context<'_>) {
+        self.0.queue_task.register(cx.waker());
+
+        let mut timer = self.0.queue_timer.borrow_mut();
+        if let Some(ref mut fut) = *timer {
+            if Pin::new(fut).poll(cx).is_pending() {
+                return;
+            }
+            *timer = None;
+        }
+        if let Some(delay) = drain_queue(&self.0) {
+            let mut fut = self.0.sleep(delay.into());
+            let _ = Pin::new(&mut fut).poll(cx);
+            *timer = Some(fut);
+        }
+    }
+
+    /// Check server write budget, pending while total size of write buffers exceeds budget
+    pub(super) fn poll_write_budget(&self, cx: &mut Context<'_>) -> Poll<()> {
+        // write buffer of this connection could be flushed since last check
+        self.0.sync_write_budget();
+        if let Some(ref budget) = *self.0.pool.write_budget.borrow() {
+            budget.poll_ready(cx)
+        } else {
+            Poll::Ready(())
+        }
+    }
+
     /// Close mqtt connection with default Disconnect message
     pub fn close(&self) {
         if self.is_open() {
-            let _ = self
-                .0
-                .io
-                .encode(codec::Packet::Disconnect(codec::Disconnect::default()), &self.0.codec);
+            flush_coalesced(&self.0);
+            flush_queue(&self.0);
+            let _ =
+                self.0.encode_packet(codec::Packet::Disconnect(codec::Disconnect::default()));
             self.0.io.close();
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
+            q.abandoned.clear();
+            q.queue.clear();
+            q.coalesced.clear();
+            q.coalesced_size = 0;
             q.waiters.clear();
+            q.sub_waiters.clear();
         });
     }
 
     /// Close mqtt connection
     pub fn close_with_reason(&self, pkt: codec::Disconnect) {
         if self.is_open() {
-            let _ = self.0.io.encode(codec::Packet::Disconnect(pkt), &self.0.codec);
+            flush_coalesced(&self.0);
+            flush_queue(&self.0);
+            let _ = self.0.encode_packet(codec::Packet::Disconnect(pkt));
             self.0.io.close();
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
+            q.abandoned.clear();
+            q.queue.clear();
+            q.coalesced.clear();
+            q.coalesced_size = 0;
             q.waiters.clear();
+            q.sub_waiters.clear();
         });
     }
 
     pub(super) fn send(&self, pkt: codec::Packet) {
-        let _ = self.0.io.encode(pkt, &self.0.codec);
+        let _ = self.0.encode_packet(pkt);
     }
 
     /// Send ping
     pub(super) fn ping(&self) -> bool {
-        self.0.io.encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
+        self.0.encode_packet(codec::Packet::PingRequest).is_ok()
     }
 
     /// Close mqtt connection, dont send disconnect message
     pub(super) fn drop_sink(&self) {
         self.0.with_queues(|q| {
             q.waiters.clear();
+            q.sub_waiters.clear();
             q.inflight.clear();
+            q.abandoned.clear();
+            q.queue.clear();
+            q.coalesced.clear();
+            q.coalesced_size = 0;
         });
         self.0.io.close();
     }
 
     pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
-        self.0.with_queues(|queues| loop {
-            // check ack order
-            if let Some(idx) = queues.inflight_order.pop_front() {
-                // errored publish
-                if idx == 0 {
-                    continue;
-                }
+        match pkt {
+            Ack::Publish(ref ack) => {
+                self.0.publish_acked(ack.packet_id.get());
+                self.0.publish_delivered(ack.packet_id.get());
+            }
+            Ack::Receive(ref ack) => self.0.publish_delivered(ack.packet_id.get()),
+            _ => (),
+        }
+
+        let freed = self.0.with_queues(|queues| {
+            let idx = pkt.packet_id();
+
+            // errored publishes
+            while queues.inflight_order.front() == Some(&0) {
+                queues.inflight_order.pop_front();
+            }
 
-                if idx != pkt.packet_id() {
+            // check ack order, acks arrive in order packets are sent.
+            // PUBCOMP could arrive after acks of packets sent later
+            let pos = if let Ack::Complete(_) = pkt {
+                queues.inflight_order.iter().position(|i| *i == idx)
+            } else {
+                queues.inflight_order.iter().position(|i| {
+                    *i != 0 && queues.ack_type(*i) != Some(AckType::Complete)
+                })
+            };
+            let pos = match pos {
+                Some(pos) if queues.inflight_order[pos] == idx => pos,
+                Some(pos) => {
                     log::trace!(
                         "MQTT protocol error, packet_id order does not match, expected {}, got: {}",
-                        idx,
-                        pkt.packet_id()
+                        queues.inflight_order[pos],
+                        idx
                     );
-                } else {
-                    // get publish ack channel
-                    log::trace!("Ack packet with id: {}", pkt.packet_id());
-                    let idx = pkt.packet_id();
-                    if let Some((tx, tp)) = queues.inflight.remove(&idx) {
-                        // cleanup ack queue
-                        if !pkt.is_match(tp) {
-                            log::trace!("MQTT protocol error, unexpeted packet");
-                            return Err(ProtocolError::Unexpected(
-                                pkt.packet_type(),
-                                tp.name(),
-                            ));
-                        }
-                        let _ = tx.send(pkt);
-
-                        // wake up queued request (receive max limit)
-                        while let Some(tx) = queues.waiters.pop_front() {
-                            if tx.send(()).is_ok() {
-                                break;
-                            }
-                        }
-                        return Ok(());
-                    } else {
-                        log::error!("In-flight state inconsistency")
-                    }
+                    return Err(ProtocolError::PacketIdMismatch);
+                }
+                None => {
+                    log::trace!("Unexpected ack packet with id: {}", idx);
+                    return Err(ProtocolError::PacketIdMismatch);
                 }
+            };
+            log::trace!("Ack packet with id: {}", idx);
+
+            let tp = if let Some(tp) = queues.ack_type(idx) {
+                tp
             } else {
-                log::trace!("Unexpected PublishAck packet");
+                log::error!("In-flight state inconsistency");
+                return Err(ProtocolError::PacketIdMismatch);
+            };
+            if !pkt.is_match(tp) {
+                log::trace!("MQTT protocol error, unexpeted packet");
+                return Err(ProtocolError::Unexpected(pkt.packet_type(), tp.name()));
             }
-            return Err(ProtocolError::PacketIdMismatch);
-        })
+
+            // successful PUBREC, packet keeps its id and position until PUBCOMP
+            if self.0.release_publish(&pkt) {
+                if let Some(entry) = queues.inflight.get_mut(&idx) {
+                    entry.1 = AckType::Complete;
+                } else {
+                    // qos2 exchange of dropped publish must be completed
+                    queues.abandoned.insert(idx, AckType::Complete);
+                }
+                return Ok(false);
+            }
+            queues.inflight_order.remove(pos);
+
+            // ack for dropped packet
+            if queues.abandoned.remove(&idx).is_some() {
+                log::trace!("Ignore ack for dropped packet with id: {}", idx);
+                self.0.credit_released(queues);
+                return Ok(false);
+            }
+
+            let (tx, _) = queues.inflight.remove(&idx).expect("checked above");
+            let freed = tp == AckType::Publish || tp == AckType::Complete;
+            let _ = tx.send(pkt);
+
+            // wake up subscriptions barrier
+            if tp == AckType::Subscribe && !queues.has_subscribes() {
+                for tx in queues.sub_waiters.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+
+            // wake up queued request (receive max limit)
+            self.0.credit_released(queues);
+            Ok(freed)
+        })?;
+
+        // queued publishes take released credit first
+        if drain_queue(&self.0).is_some() {
+            self.0.queue_task.wake();
+        }
+
+        // callback is called outside of queues borrow, it could send publishes
+        if freed {
+            let f = self.0.on_available.borrow().clone();
+            if let Some(f) = f {
+                f();
+            }
+        }
+        Ok(())
     }
 
     /// Create publish packet builder
@@ -165,6 +729,68 @@ impl MqttSink {
         }
     }
 
+    /// Create publish packet builder with publish options
+    ///
+    /// All options are applied at once, builder could be used to set
+    /// remaining packet fields.
+    pub fn publish_with<U>(
+        &self,
+        topic: U,
+        payload: Bytes,
+        options: PublishOptions,
+    ) -> PublishBuilder
+    where
+        ByteString: From<U>,
+    {
+        let mut builder = self.publish(topic, payload);
+        builder.packet.retain = options.retain;
+        builder.packet.properties = options.properties;
+        builder
+    }
+
+    /// Create publish packet builder for will message
+    ///
+    /// Topic, payload, retain flag and publish properties of the will are applied,
+    /// will message should be sent with QoS of the will. Server does not publish
+    /// will messages itself, control service could publish will to subscribers
+    /// on `ControlMessage::Closed` if connection is not closed cleanly.
+    pub fn publish_will(&self, will: &codec::LastWill) -> PublishBuilder {
+        let mut builder = self.publish(will.topic.clone(), will.message.clone());
+        builder.packet.retain = will.retain;
+
+        let props = &mut builder.packet.properties;
+        props.message_expiry_interval = will.message_expiry_interval;
+        props.content_type = will.content_type.clone();
+        props.correlation_data = will.correlation_data.clone();
+        props.response_topic = will.response_topic.clone();
+        props.is_utf8_payload = will.is_utf8_payload;
+        props.user_properties = will.user_properties.clone();
+        builder
+    }
+
+    /// Create publish packet builder from existing packet
+    ///
+    /// Could be used for re-sending stored publishes of resumed session,
+    /// packet id of the packet is preserved. Un-acked publishes should be
+    /// re-sent with `dup` flag before newly queued publishes.
+    pub fn publish_pkt(&self, packet: codec::Publish) -> PublishBuilder {
+        PublishBuilder { packet, shared: self.0.clone() }
+    }
+
+    /// Send publish packet with QoS 1
+    ///
+    /// Returned future resolves with PUBACK reason code and properties.
+    pub fn publish_qos1<U>(
+        &self,
+        topic: U,
+        payload: Bytes,
+    ) -> impl Future<Output = Result<PubAck, PublishQos1Error>>
+    where
+        ByteString: From<U>,
+    {
+        self.publish(topic, payload).send_at_least_once_ack()
+    }
+
     /// Create subscribe packet builder
     pub fn subscribe(&self, id: Option<NonZeroU32>) -> SubscribeBuilder {
         SubscribeBuilder {
@@ -191,6 +817,59 @@ impl MqttSink {
             shared: self.0.clone(),
         }
     }
+
+    /// Subscribe to topic filter and wait for first matching publish
+    ///
+    /// Matching publish is acknowledged and is not passed to publish handler,
+    /// other publishes are handled as usual. Topic filter is unsubscribed after
+    /// publish is received or timeout is elapsed. Packets are sent and received
+    /// by client dispatcher, so client must be started.
+    pub async fn take_one(
+        &self,
+        filter: ByteString,
+        timeout: Seconds,
+    ) -> Result<Publish, TakeOneError> {
+        let topic = filter.parse::<Topic>().map_err(|_| {
+            TakeOneError::Subscribe(codec::SubscribeAckReason::TopicFilterInvalid)
+        })?;
+
+        // register before subscribe, matching publish could precede SUBACK
+        let (tx, rx) = oneshot::channel();
+        self.0.one_shot.borrow_mut().push((topic, tx));
+
+        let subscribe = self.subscribe(None).topic_filter(
+            filter.clone(),
+            codec::SubscriptionOptions {
+                qos: QoS::ExactlyOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            },
+        );
+        let fut = async move {
+            let ack = subscribe.send().await?;
+            // reason codes 0x80 or greater indicate failure
+            if let Some(reason) = ack.status.into_iter().find(|r| u8::from(*r) >= 0x80) {
+                return Err(TakeOneError::Subscribe(reason));
+            }
+            rx.await.map_err(|_| TakeOneError::Send(SendPacketError::Disconnected))
+        };
+
+        let result = if timeout.non_zero() {
+            match select(fut, self.0.sleep(timeout.into())).await {
+                Either::Left(Ok(publish)) => Ok(publish),
+                Either::Left(Err(err)) => return Err(err),
+                Either::Right(_) => Err(TakeOneError::Timeout),
+            }
+        } else {
+            fut.await
+        };
+
+        // packet id of timed out SUBSCRIBE stays reserved until SUBACK is received,
+        // UNSUBSCRIBE gets new packet id
+        self.unsubscribe().topic_filter(filter).send().await?;
+        result
+    }
 }
 
 impl fmt::Debug for MqttSink {
@@ -199,6 +878,85 @@ impl fmt::Debug for MqttSink {
     }
 }
 
+/// Publish packet options
+///
+/// Options are applied with `MqttSink::publish_with()`.
+#[derive(Clone, Debug, Default)]
+pub struct PublishOptions {
+    retain: bool,
+    properties: codec::PublishProperties,
+}
+
+impl PublishOptions {
+    /// Create empty publish options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set retain flag
+    pub fn retain(mut self) -> Self {
+        self.retain = true;
+        self
+    }
+
+    /// Set topic alias
+    pub fn topic_alias(mut self, alias: NonZeroU16) -> Self {
+        self.properties.topic_alias = Some(alias);
+        self
+    }
+
+    /// Set content type of the payload
+    pub fn content_type<U>(mut self, val: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.properties.content_type = Some(val.into());
+        self
+    }
+
+    /// Set payload format indicator, `true` for UTF-8 encoded payload
+    pub fn payload_format(mut self, is_utf8: bool) -> Self {
+        self.properties.is_utf8_payload = Some(is_utf8);
+        self
+    }
+
+    /// Set message expiry interval in seconds
+    pub fn message_expiry(mut self, secs: NonZeroU32) -> Self {
+        self.properties.message_expiry_interval = Some(secs);
+        self
+    }
+
+    /// Set response topic
+    pub fn response_topic<U>(mut self, val: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.properties.response_topic = Some(val.into());
+        self
+    }
+
+    /// Set correlation data
+    pub fn correlation_data(mut self, val: Bytes) -> Self {
+        self.properties.correlation_data = Some(val);
+        self
+    }
+
+    /// Add user property
+    pub fn user_property<K, V>(mut self, key: K, val: V) -> Self
+    where
+        ByteString: From<K> + From<V>,
+    {
+        self.properties.user_properties.push((key.into(), val.into()));
+        self
+    }
+
+    /// Add subscription identifier
+    pub fn subscription_id(mut self, id: NonZeroU32) -> Self {
+        self.properties.subscription_ids.get_or_insert_with(Vec::new).push(id);
+        self
+    }
+}
+
 pub struct PublishBuilder {
     shared: Rc<MqttShared>,
     packet: codec::Publish,
@@ -219,6 +977,9 @@ impl PublishBuilder {
     }
 
     /// This might be re-delivery of an earlier attempt to send the Packet.
+    ///
+    /// DUP flag is managed by sink, method is available with `testing` feature.
+    #[cfg(feature = "testing")]
     pub fn dup(mut self, val: bool) -> Self {
         self.packet.dup = val;
         self
@@ -230,6 +991,18 @@ impl PublishBuilder {
         self
     }
 
+    /// Apply subscription options of the receiving subscriber
+    ///
+    /// Retain flag is cleared unless subscription has `Retain As Published`
+    /// option set. Should be used for forwarded application messages,
+    /// retained messages sent as a result of new subscription keep retain flag.
+    pub fn subscription(mut self, options: &codec::SubscriptionOptions) -> Self {
+        if !options.retain_as_published {
+            self.packet.retain = false;
+        }
+        self
+    }
+
     /// Set publish packet properties
     pub fn properties<F>(mut self, f: F) -> Self
     where
@@ -249,94 +1022,574 @@ impl PublishBuilder {
 
     /// Send publish packet with QoS 0
     pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
-        let packet = self.packet;
+        let mut packet = self.packet;
+        self.shared.pool.encode_payload(&mut packet);
 
         if !self.shared.io.is_closed() {
+            // slow peer, write buffer is not flushed yet or pacing delays publish
+            if self.shared.is_ordered()
+                && (self.shared.with_queues(|q| !q.queue.is_empty())
+                    || (self.shared.overflow.get() != OverflowPolicy::Unbounded
+                        && self
+                            .shared
+                            .io
+                            .with_write_buf(|buf| !buf.is_empty())
+                            .unwrap_or(false))
+                    || self.shared.pacing_delay().is_some())
+            {
+                queue_qos0(&self.shared, packet);
+                return Ok(());
+            }
+
+            self.shared.take_pacing_token();
+            if let FlushPolicy::CoalesceQos0(threshold) = self.shared.flush.get() {
+                coalesce_qos0(&self.shared, packet, threshold);
+                return Ok(());
+            }
+
             log::trace!("Publish (QoS-0) to {:?}", packet.topic);
             self.shared
-                .io
-                .encode(codec::Packet::Publish(packet), &self.shared.codec)
+                .encode_packet(codec::Packet::Publish(packet))
                 .map_err(SendPacketError::Encode)
-                .map(|_| ())
+                .map(|_| self.shared.pool.message_out())
         } else {
             log::error!("Mqtt sink is disconnected");
             Err(SendPacketError::Disconnected)
         }
     }
 
-    /// Send publish packet with QoS 1
-    pub fn send_at_least_once(
-        self,
-    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
-        let shared = self.shared;
-        let mut packet = self.packet;
-        packet.qos = QoS::AtLeastOnce;
+    /// Send publish packet with QoS 1
+    pub fn send_at_least_once(
+        self,
+    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
+        let fut = self.send_qos1();
+        async move {
+            fut.await.and_then(|pkt| match pkt.reason_code {
+                codec::PublishAckReason::Success => Ok(pkt),
+                _ => Err(PublishQos1Error::Fail(pkt)),
+            })
+        }
+    }
+
+    /// Send publish packet with QoS 1 and return PUBACK reason code and properties
+    ///
+    /// Unlike `send_at_least_once()`, positive reason codes other than `Success`
+    /// (e.g. `NoMatchingSubscribers`) are not treated as errors.
+    pub fn send_at_least_once_ack(
+        self,
+    ) -> impl Future<Output = Result<PubAck, PublishQos1Error>> {
+        let fut = self.send_qos1();
+        async move {
+            fut.await.and_then(|pkt| {
+                if u8::from(pkt.reason_code) < 0x80 {
+                    Ok(PubAck(pkt))
+                } else {
+                    Err(PublishQos1Error::Fail(pkt))
+                }
+            })
+        }
+    }
+
+    fn send_qos1(self) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
+        let shared = self.shared;
+        let mut packet = self.packet;
+        packet.qos = QoS::AtLeastOnce;
+        shared.pool.encode_payload(&mut packet);
+
+        if !shared.io.is_closed() {
+            // handle client receive maximum and pacing, publish waits for its turn in queue
+            if shared.must_queue() {
+                let rx = queue_publish(&shared, packet);
+                return Either::Left(Either::Right(async move {
+                    let rx = match rx.await {
+                        Ok(res) => res?,
+                        Err(_) => return Err(PublishQos1Error::Disconnected),
+                    };
+                    rx.await
+                        .map(|pkt| pkt.publish())
+                        .map_err(|_| PublishQos1Error::Disconnected)
+                }));
+            }
+            Either::Right(Self::send_at_least_once_inner(packet, shared))
+        } else {
+            Either::Left(Either::Left(Ready::Err(PublishQos1Error::Disconnected)))
+        }
+    }
+
+    fn send_at_least_once_inner(
+        packet: codec::Publish,
+        shared: Rc<MqttShared>,
+    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
+        match write_publish(&shared, packet) {
+            // wait ack from peer
+            Ok(rx) => Either::Right(async move {
+                rx.await.map(|pkt| pkt.publish()).map_err(|_| PublishQos1Error::Disconnected)
+            }),
+            Err(err) => Either::Left(Ready::Err(err.into())),
+        }
+    }
+
+    /// Send publish packet with QoS 2
+    ///
+    /// Future resolves with PUBCOMP packet. If peer responds with PUBREC
+    /// with failure reason code, exchange is terminated without PUBREL and
+    /// future resolves with `PublishQos2Error::Fail`.
+    pub fn send_exactly_once(
+        self,
+    ) -> impl Future<Output = Result<codec::PublishAck2, PublishQos2Error>> {
+        let shared = self.shared;
+        let mut packet = self.packet;
+        packet.qos = QoS::ExactlyOnce;
+        shared.pool.encode_payload(&mut packet);
+
+        async move {
+            if shared.io.is_closed() {
+                return Err(PublishQos2Error::Disconnected);
+            }
+
+            // handle client receive maximum and pacing, publish waits for its turn in queue
+            let rx = if shared.must_queue() {
+                match queue_publish(&shared, packet).await {
+                    Ok(res) => res?,
+                    Err(_) => return Err(PublishQos2Error::Disconnected),
+                }
+            } else {
+                write_publish(&shared, packet)?
+            };
+
+            // PUBREL is sent on successful PUBREC, even if this future is dropped.
+            // failure reason code of PUBREC terminates exchange
+            match rx.await.map_err(|_| PublishQos2Error::Disconnected)? {
+                Ack::Receive(pkt) => Err(PublishQos2Error::Fail(pkt)),
+                ack => {
+                    let pkt = ack.complete();
+                    match pkt.reason_code {
+                        codec::PublishAck2Reason::Success => Ok(pkt),
+                        _ => Err(PublishQos2Error::Complete(pkt)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Ack receiver
+///
+/// Releases packet id if publish, subscribe or unsubscribe future is dropped
+/// before ack is received.
+pub(super) struct AckReceiver {
+    idx: u16,
+    rx: Option<pool::Receiver<Ack>>,
+    sent: bool,
+    shared: Rc<MqttShared>,
+}
+
+impl AckReceiver {
+    fn register(shared: &Rc<MqttShared>, idx: u16, tp: AckType) -> Option<AckReceiver> {
+        shared.with_queues(|queues| {
+            if queues.is_reserved(idx) {
+                return None;
+            }
+            let (tx, rx) = shared.pool.queue.channel();
+            queues.inflight.insert(idx, (tx, tp));
+            queues.inflight_order.push_back(idx);
+            Some(AckReceiver { idx, rx: Some(rx), sent: false, shared: shared.clone() })
+        })
+    }
+}
+
+impl Future for AckReceiver {
+    type Output = Result<Ack, Canceled>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let res = match self.rx.as_mut() {
+            Some(rx) => match Pin::new(rx).poll(cx) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            },
+            None => Err(Canceled),
+        };
+        self.rx = None;
+        Poll::Ready(res)
+    }
+}
+
+impl Drop for AckReceiver {
+    fn drop(&mut self) {
+        if let Some(rx) = self.rx.take() {
+            drop(rx);
+            self.shared.release_id(self.idx, self.sent);
+        }
+    }
+}
+
+/// Overflow policy for QoS0 publishes sent to slow peer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Write all publishes to connection write buffer
+    Unbounded,
+    /// Queue QoS0 publishes while write buffer is not flushed,
+    /// drop new publish if queue size reaches limit
+    DropNewest(usize),
+    /// Queue QoS0 publishes while write buffer is not flushed,
+    /// drop oldest queued publish if queue size reaches limit
+    DropOldest(usize),
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        OverflowPolicy::Unbounded
+    }
+}
+
+/// Handling of queued publishes on unsubscribe
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnsubscribePolicy {
+    /// Queued publishes are sent
+    Finish,
+    /// Queued QoS0 publishes that match unsubscribed topic filters are dropped,
+    /// unless they match remaining subscriptions of the session.
+    /// In-flight QoS1 and QoS2 publishes are not affected.
+    Purge,
+}
+
+impl Default for UnsubscribePolicy {
+    fn default() -> Self {
+        UnsubscribePolicy::Finish
+    }
+}
+
+/// Topic of subscription filter, shared subscription `$share/{group}/{filter}`
+/// matches topics of `filter`
+fn filter_topic(filter: &str) -> Option<Topic> {
+    let filter = match filter.strip_prefix("$share/") {
+        Some(shared) => shared.splitn(2, '/').nth(1)?,
+        None => filter,
+    };
+    filter.parse().ok()
+}
+
+/// Publish waiting in sink queue
+pub(super) struct Queued {
+    packet: codec::Publish,
+    // QoS1 and QoS2 publishes get receiver of their ack once written
+    tx: Option<oneshot::Sender<Result<AckReceiver, WriteError>>>,
+}
+
+impl Queued {
+    pub(super) fn is_qos0(&self) -> bool {
+        self.tx.is_none()
+    }
+}
+
+/// Error of QoS1 and QoS2 publish write
+enum WriteError {
+    PacketIdInUse(u16),
+    Encode(EncodeError),
+}
+
+impl From<WriteError> for PublishQos1Error {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::PacketIdInUse(idx) => PublishQos1Error::PacketIdInUse(idx),
+            WriteError::Encode(err) => PublishQos1Error::Encode(err),
+        }
+    }
+}
+
+impl From<WriteError> for PublishQos2Error {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::PacketIdInUse(idx) => PublishQos2Error::PacketIdInUse(idx),
+            WriteError::Encode(err) => PublishQos2Error::Encode(err),
+        }
+    }
+}
+
+/// Write QoS1 or QoS2 publish, returns receiver of its ack
+fn write_publish(
+    shared: &Rc<MqttShared>,
+    mut packet: codec::Publish,
+) -> Result<AckReceiver, WriteError> {
+    // packet id
+    let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
+    if idx == 0 {
+        idx = shared.next_id();
+        packet.packet_id = NonZeroU16::new(idx);
+    }
+
+    // publish ack channel
+    let tp = if packet.qos == QoS::AtLeastOnce { AckType::Publish } else { AckType::Receive };
+    let mut rx =
+        AckReceiver::register(shared, idx, tp).ok_or(WriteError::PacketIdInUse(idx))?;
+
+    // publish is re-sent on session resume until peer receives it
+    let stored = if shared.session_expiry.get() != 0 { Some(packet.clone()) } else { None };
+
+    // send publish to peer
+    log::trace!("Publish ({:?}) to {:#?}", packet.qos, packet);
+    flush_coalesced(shared);
+    shared.take_pacing_token();
+    shared.encode_packet(codec::Packet::Publish(packet)).map_err(WriteError::Encode)?;
+    if let Some(packet) = stored {
+        shared.with_queues(|q| q.unacked.push_back(packet));
+    }
+    shared.pool.message_out();
+    if tp == AckType::Publish {
+        shared.publish_sent(idx);
+    }
+    rx.sent = true;
+    Ok(rx)
+}
+
+/// Queue QoS0 publish according to overflow policy
+fn queue_qos0(shared: &Rc<MqttShared>, packet: codec::Publish) {
+    let policy = shared.overflow.get();
+    shared.with_queues(|q| {
+        let queued = || q.queue.iter().filter(|item| item.is_qos0()).count();
+        match policy {
+            OverflowPolicy::DropNewest(limit) if queued() >= limit => {
+                log::trace!("Publish queue is full, drop publish to {:?}", packet.topic);
+                return;
+            }
+            OverflowPolicy::DropOldest(limit) if queued() >= limit => {
+                if let Some(pos) = q.queue.iter().position(|item| item.is_qos0()) {
+                    let item = q.queue.remove(pos).expect("checked above");
+                    log::trace!(
+                        "Publish queue is full, drop publish to {:?}",
+                        item.packet.topic
+                    );
+                }
+                if limit == 0 {
+                    return;
+                }
+            }
+            _ => (),
+        }
+        q.queue.push_back(Queued { packet, tx: None });
+    });
+    shared.queue_task.wake();
+}
+
+/// Queue QoS1 or QoS2 publish, it is written at its turn
+fn queue_publish(
+    shared: &Rc<MqttShared>,
+    packet: codec::Publish,
+) -> oneshot::Receiver<Result<AckReceiver, WriteError>> {
+    let (tx, rx) = oneshot::channel();
+    shared.with_queues(|q| q.queue.push_back(Queued { packet, tx: Some(tx) }));
+    drain_queue(shared);
+    rx
+}
+
+/// Write queued publishes in enqueue order
+///
+/// QoS0 publishes wait for write buffer flush if overflow policy is set,
+/// QoS1 and QoS2 publishes wait for receive credit, all publishes wait for
+/// pacing token. Returns delay of next check if queue waits for pacing token.
+pub(super) fn drain_queue(shared: &Rc<MqttShared>) -> Option<Millis> {
+    // queue is drained already, write could release packet id and drain again
+    if shared.draining.replace(true) {
+        return None;
+    }
+
+    let flushed = shared.io.with_write_buf(|buf| buf.is_empty()).unwrap_or(true);
+    let overflow = shared.overflow.get() != OverflowPolicy::Unbounded;
+    let mut delay = None;
+    loop {
+        if shared.io.is_closed() {
+            shared.with_queues(|q| q.queue.clear());
+            break;
+        }
+
+        let item = shared.with_queues(|q| {
+            let front = q.queue.front()?;
+            if front.is_qos0() {
+                if overflow && !flushed {
+                    // dispatcher is woken up on write buffer flush
+                    return None;
+                }
+            } else if q.inflight.len() + q.abandoned.len() >= shared.cap.get() {
+                return None;
+            }
+            if let Some(d) = shared.pacing_delay() {
+                delay = Some(d);
+                return None;
+            }
+            q.queue.pop_front()
+        });
+
+        match item {
+            Some(Queued { packet, tx: None }) => {
+                shared.take_pacing_token();
+                write_qos0(shared, packet);
+            }
+            Some(Queued { packet, tx: Some(tx) }) => {
+                // publish future is dropped
+                if !tx.is_canceled() {
+                    let _ = tx.send(write_publish(shared, packet));
+                }
+            }
+            None => break,
+        }
+    }
+    shared.draining.set(false);
+    delay
+}
+
+/// Write queued publishes before connection close
+///
+/// Write buffer flush and pacing token are not awaited, publishes
+/// waiting for receive credit are dropped.
+fn flush_queue(shared: &Rc<MqttShared>) {
+    loop {
+        let item = shared.with_queues(|q| {
+            let front = q.queue.front()?;
+            if !front.is_qos0() && q.inflight.len() + q.abandoned.len() >= shared.cap.get() {
+                return None;
+            }
+            q.queue.pop_front()
+        });
+
+        match item {
+            Some(Queued { packet, tx: None }) => write_qos0(shared, packet),
+            Some(Queued { packet, tx: Some(tx) }) => {
+                if !tx.is_canceled() {
+                    let _ = tx.send(write_publish(shared, packet));
+                }
+            }
+            None => break,
+        }
+    }
+}
+
+/// Flush policy for publishes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Write all publishes to connection immediately
+    Immediate,
+    /// Coalesce QoS0 publishes until total size of topics and payloads
+    /// reaches threshold, QoS1 and QoS2 publishes are written immediately
+    CoalesceQos0(usize),
+}
 
-        if !shared.io.is_closed() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy::Immediate
+    }
+}
 
-                return Either::Left(Either::Right(async move {
-                    if rx.await.is_err() {
-                        return Err(PublishQos1Error::Disconnected);
-                    }
-                    Self::send_at_least_once_inner(packet, shared).await
-                }));
+/// Coalesce QoS0 publish according to flush policy
+fn coalesce_qos0(shared: &Rc<MqttShared>, packet: codec::Publish, threshold: usize) {
+    let size = shared.with_queues(|q| {
+        q.coalesced_size += packet.topic.len() + packet.payload.len();
+        q.coalesced.push(packet);
+        q.coalesced_size
+    });
+
+    if size >= threshold {
+        flush_coalesced(shared);
+    } else if !shared.flushing.get() {
+        shared.flushing.set(true);
+        let shared = shared.clone();
+        ntex::rt::spawn(async move {
+            shared.sleep(COALESCE_DELAY.into()).await;
+            flush_coalesced(&shared);
+            shared.flushing.set(false);
+        });
+    }
+}
+
+/// Write coalesced QoS0 publishes
+fn flush_coalesced(shared: &MqttShared) {
+    let packets = shared.with_queues(|q| {
+        q.coalesced_size = 0;
+        std::mem::take(&mut q.coalesced)
+    });
+    for packet in packets {
+        write_qos0(shared, packet);
+    }
+}
+
+/// Send retained message with its QoS
+fn send_retained(shared: &Rc<MqttShared>, packet: codec::Publish) {
+    let builder = PublishBuilder { packet, shared: shared.clone() };
+    match builder.packet.qos {
+        QoS::AtMostOnce => {
+            if let Err(e) = builder.send_at_most_once() {
+                log::trace!("Cannot send retained message: {:?}", e);
             }
-            Either::Right(Self::send_at_least_once_inner(packet, shared))
-        } else {
-            Either::Left(Either::Left(Ready::Err(PublishQos1Error::Disconnected)))
+        }
+        QoS::AtLeastOnce => {
+            let fut = builder.send_at_least_once();
+            ntex::rt::spawn(async move {
+                if let Err(e) = fut.await {
+                    log::trace!("Cannot send retained message: {:?}", e);
+                }
+            });
+        }
+        QoS::ExactlyOnce => {
+            let fut = builder.send_exactly_once();
+            ntex::rt::spawn(async move {
+                if let Err(e) = fut.await {
+                    log::trace!("Cannot send retained message: {:?}", e);
+                }
+            });
         }
     }
+}
 
-    fn send_at_least_once_inner(
-        mut packet: codec::Publish,
-        shared: Rc<MqttShared>,
-    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
-        // packet id
-        let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
-        if idx == 0 {
-            idx = shared.next_id();
-            packet.packet_id = NonZeroU16::new(idx);
+/// Send retained messages that exceed replay limit at configured rate
+async fn stream_retained(
+    shared: Rc<MqttShared>,
+    mut packets: VecDeque<codec::Publish>,
+    per_sec: u16,
+) {
+    let interval = Millis(1000 / u32::from(per_sec));
+    while let Some(packet) = packets.pop_front() {
+        shared.sleep(interval.into()).await;
+        if shared.io.is_closed() {
+            break;
         }
+        send_retained(&shared, packet);
+    }
+}
 
-        let rx = shared.with_queues(|queues| {
-            // publish ack channel
-            let (tx, rx) = shared.pool.queue.channel();
+fn write_qos0(shared: &MqttShared, packet: codec::Publish) {
+    log::trace!("Publish (QoS-0) to {:?}", packet.topic);
+    if shared.encode_packet(codec::Packet::Publish(packet)).is_ok() {
+        shared.pool.message_out();
+    }
+}
 
-            if queues.inflight.contains_key(&idx) {
-                return Err(PublishQos1Error::PacketIdInUse(idx));
-            }
-            queues.inflight.insert(idx, (tx, AckType::Publish));
-            queues.inflight_order.push_back(idx);
-            Ok(rx)
-        });
+/// Publish acknowledgment for QoS 1 publish
+#[derive(Debug, Clone)]
+pub struct PubAck(codec::PublishAck);
 
-        let rx = match rx {
-            Ok(rx) => rx,
-            Err(e) => return Either::Left(Ready::Err(e)),
-        };
+impl PubAck {
+    /// Packet id of acknowledged publish
+    pub fn packet_id(&self) -> NonZeroU16 {
+        self.0.packet_id
+    }
 
-        // send publish to client
-        log::trace!("Publish (QoS1) to {:#?}", packet);
-
-        match shared.io.encode(codec::Packet::Publish(packet), &shared.codec) {
-            Ok(_) => {
-                // wait ack from peer
-                Either::Right(async move {
-                    rx.await.map_err(|_| PublishQos1Error::Disconnected).and_then(|pkt| {
-                        let pkt = pkt.publish();
-                        match pkt.reason_code {
-                            codec::PublishAckReason::Success => Ok(pkt),
-                            _ => Err(PublishQos1Error::Fail(pkt)),
-                        }
-                    })
-                })
-            }
-            Err(err) => Either::Left(Ready::Err(PublishQos1Error::Encode(err))),
-        }
+    /// PUBACK reason code
+    pub fn reason_code(&self) -> codec::PublishAckReason {
+        self.0.reason_code
+    }
+
+    /// Reason string
+    pub fn reason_string(&self) -> Option<&ByteString> {
+        self.0.reason_string.as_ref()
+    }
+
+    /// User properties
+    pub fn properties(&self) -> &codec::UserProperties {
+        &self.0.properties
+    }
+
+    /// Returns PUBACK packet
+    pub fn into_inner(self) -> codec::PublishAck {
+        self.0
     }
 }
 
@@ -394,23 +1647,15 @@ impl SubscribeBuilder {
             // allocate packet id
             let idx = if self.id == 0 { shared.next_id() } else { self.id };
             packet.packet_id = NonZeroU16::new(idx).unwrap();
-            let rx = shared.with_queues(|queues| {
-                // ack channel
-                let (tx, rx) = shared.pool.queue.channel();
-
-                if queues.inflight.contains_key(&idx) {
-                    return Err(SendPacketError::PacketIdInUse(idx));
-                }
-                queues.inflight.insert(idx, (tx, AckType::Subscribe));
-                queues.inflight_order.push_back(idx);
-                Ok(rx)
-            })?;
+            let mut rx = AckReceiver::register(&shared, idx, AckType::Subscribe)
+                .ok_or(SendPacketError::PacketIdInUse(idx))?;
 
             // send subscribe to client
             log::trace!("Sending subscribe packet {:#?}", packet);
 
-            match shared.io.encode(codec::Packet::Subscribe(packet), &shared.codec) {
+            match shared.encode_packet(codec::Packet::Subscribe(packet)) {
                 Ok(_) => {
+                    rx.sent = true;
                     // wait ack from peer
                     rx.await
                         .map_err(|_| SendPacketError::Disconnected)
@@ -473,24 +1718,16 @@ impl UnsubscribeBuilder {
             }
             // allocate packet id
             let idx = if self.id == 0 { shared.next_id() } else { self.id };
-            let rx = shared.with_queues(|queues| {
-                // ack channel
-                let (tx, rx) = shared.pool.queue.channel();
-
-                if queues.inflight.contains_key(&idx) {
-                    return Err(SendPacketError::PacketIdInUse(idx));
-                }
-                queues.inflight.insert(idx, (tx, AckType::Unsubscribe));
-                queues.inflight_order.push_back(idx);
-                Ok(rx)
-            })?;
+            let mut rx = AckReceiver::register(&shared, idx, AckType::Unsubscribe)
+                .ok_or(SendPacketError::PacketIdInUse(idx))?;
             packet.packet_id = NonZeroU16::new(idx).unwrap();
 
             // send unsubscribe to client
             log::trace!("Sending unsubscribe packet {:#?}", packet);
 
-            match shared.io.encode(codec::Packet::Unsubscribe(packet), &shared.codec) {
+            match shared.encode_packet(codec::Packet::Unsubscribe(packet)) {
                 Ok(_) => {
+                    rx.sent = true;
                     // wait ack from peer
                     rx.await
                         .map_err(|_| SendPacketError::Disconnected)
@@ -503,3 +1740,970 @@ impl UnsubscribeBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::codec::Decoder;
+    use ntex::time::sleep;
+    use ntex::util::BytesMut;
+    use ntex::{io::Io, testing};
+
+    use super::*;
+    use crate::types::packet_type;
+    use crate::v5::shared::MqttSinkPool;
+
+    fn create_sink(io: &Io) -> MqttSink {
+        MqttSink::new(Rc::new(MqttShared::new(
+            io.get_ref(),
+            codec::Codec::default(),
+            16,
+            Default::default(),
+        )))
+    }
+
+    /// Write queued publishes, as dispatcher does
+    fn drive_queue(io: Io, sink: &MqttSink) {
+        let sink = sink.clone();
+        ntex::rt::spawn(ntex::util::poll_fn(move |cx| {
+            sink.poll_queue(cx);
+            // connection dispatcher is woken up on write buffer flush
+            if io.with_write_buf(|buf| !buf.is_empty()).unwrap_or(false) {
+                let _ = io.poll_flush(cx, true);
+            }
+            Poll::<()>::Pending
+        }));
+    }
+
+    #[ntex::test]
+    async fn test_io_buffer_capacity() {
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        io.set_memory_pool(
+            ntex::util::PoolId::P6
+                .pool_ref()
+                .set_read_params(32 * 1024, 4096)
+                .set_write_params(40 * 1024, 2048),
+        );
+        let sink = create_sink(&io);
+
+        sink.publish("topic", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+        let (len, capacity) = io.with_write_buf(|buf| (buf.len(), buf.capacity())).unwrap();
+        assert!(len > 0);
+        assert!(capacity >= 40 * 1024);
+        assert_eq!(sink.memory_pool().read_params_high(), 32 * 1024);
+    }
+
+    #[ntex::test]
+    async fn test_retain_as_published() {
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+
+        let mut options = codec::SubscriptionOptions {
+            qos: QoS::AtMostOnce,
+            no_local: false,
+            retain_as_published: true,
+            retain_handling: codec::RetainHandling::AtSubscribe,
+        };
+        let builder = sink.publish("topic", Bytes::new()).retain().subscription(&options);
+        assert!(builder.packet.retain);
+
+        options.retain_as_published = false;
+        let builder = sink.publish("topic", Bytes::new()).retain().subscription(&options);
+        assert!(!builder.packet.retain);
+
+        let builder = sink.publish("topic", Bytes::new()).subscription(&options);
+        assert!(!builder.packet.retain);
+    }
+
+    #[ntex::test]
+    async fn test_delay_id_reuse() {
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+
+        let id = sink.0.next_id();
+        let (tx, _rx) = sink.0.pool.queue.channel();
+        sink.0.with_queues(|q| q.inflight.insert(id, (tx, AckType::Publish)));
+        sink.0.with_queues(|q| q.inflight.remove(&id));
+        assert_ne!(sink.0.next_id(), id);
+
+        sink.0.inflight_idx.set(u16::MAX - 1);
+        assert_eq!(sink.0.next_id(), u16::MAX);
+        assert_eq!(sink.0.next_id(), 1);
+
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.delay_id_reuse(false);
+
+        let id = sink.0.next_id();
+        let (tx, _rx) = sink.0.pool.queue.channel();
+        sink.0.with_queues(|q| q.inflight.insert(id, (tx, AckType::Publish)));
+        assert_eq!(sink.0.next_id(), id + 1);
+        sink.0.with_queues(|q| q.inflight.remove(&id));
+        assert_eq!(sink.0.next_id(), id);
+    }
+
+    #[ntex::test]
+    async fn test_publish_drop_releases_id() {
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.delay_id_reuse(false);
+
+        let ack = |id| {
+            Ack::Publish(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+            })
+        };
+
+        // drop publish future before ack
+        let fut = sink.publish("topic", Bytes::new()).send_at_least_once();
+        assert!(sink.0.with_queues(|q| q.inflight.contains_key(&1)));
+        drop(fut);
+        assert!(sink.0.with_queues(|q| q.inflight.is_empty() && q.abandoned.contains_key(&1)));
+
+        // packet id is not reused until peer acks it
+        let fut = sink.publish("topic", Bytes::new()).send_at_least_once();
+        assert!(sink.0.with_queues(|q| q.inflight.contains_key(&2)));
+
+        // ack for dropped publish is ignored and releases packet id
+        assert!(sink.pkt_ack(ack(1)).is_ok());
+        assert!(sink.0.with_queues(|q| q.inflight.contains_key(&2) && q.abandoned.is_empty()));
+
+        assert!(sink.pkt_ack(ack(2)).is_ok());
+        assert_eq!(fut.await.unwrap().packet_id, NonZeroU16::new(2).unwrap());
+        assert!(sink.0.with_queues(|q| q.inflight.is_empty() && q.abandoned.is_empty()));
+        assert_eq!(sink.0.next_id(), 1);
+    }
+
+    #[ntex::test]
+    async fn test_abandoned_publish_holds_credit() {
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.0.cap.set(1);
+
+        // dropped publish keeps its slot until peer acks it
+        let fut = sink.publish("topic", Bytes::new()).send_at_least_once();
+        drop(fut);
+        assert!(sink.0.with_queues(|q| q.inflight.is_empty() && q.abandoned.contains_key(&1)));
+        assert_eq!(sink.credit(), 0);
+        let mut ready = Box::pin(sink.ready());
+        assert!(ntex::util::lazy(|cx| ready.as_mut().poll(cx)).await.is_pending());
+
+        let ack = Ack::Publish(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        });
+        assert!(sink.pkt_ack(ack).is_ok());
+        assert_eq!(sink.credit(), 1);
+        assert!(ready.await);
+    }
+
+    #[ntex::test]
+    async fn test_qos2_drop_completes_exchange() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.delay_id_reuse(false);
+
+        let pubrec = Ack::Receive(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        });
+        let pubcomp = Ack::Complete(codec::PublishAck2 {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        });
+
+        // drop publish future before PUBREC
+        let fut = sink.publish("topic", Bytes::new()).send_exactly_once();
+        match crate::utils::select(fut, sleep(Millis(10))).await {
+            Either::Right(_) => (),
+            Either::Left(_) => panic!("publish must be pending"),
+        }
+        assert!(sink.0.with_queues(|q| q.abandoned.contains_key(&1)));
+
+        // PUBREL is sent for PUBREC, packet id stays reserved until PUBCOMP
+        assert!(sink.pkt_ack(pubrec).is_ok());
+        assert!(sink.0.with_queues(|q| q.abandoned.get(&1) == Some(&AckType::Complete)));
+        assert_eq!(sink.0.next_id(), 2);
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let mut packets = Vec::new();
+        while packets.len() < 2 {
+            buf.extend_from_slice(&client.read().await.unwrap());
+            while let Some(pkt) = codec.decode(&mut buf).unwrap() {
+                packets.push(pkt.packet_type());
+            }
+        }
+        assert_eq!(packets, vec![packet_type::PUBLISH_START, packet_type::PUBREL]);
+
+        assert!(sink.pkt_ack(pubcomp).is_ok());
+        assert!(sink.0.with_queues(|q| q.abandoned.is_empty()));
+        assert_eq!(sink.0.next_id(), 1);
+    }
+
+    #[ntex::test]
+    async fn test_interleaved_qos_acks() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.delay_id_reuse(false);
+
+        let id = |id| NonZeroU16::new(id).unwrap();
+        let puback = |id| {
+            Ack::Publish(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+            })
+        };
+        let pubrec = |id| {
+            Ack::Receive(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+            })
+        };
+        let pubcomp = |id| {
+            Ack::Complete(codec::PublishAck2 {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAck2Reason::Success,
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+            })
+        };
+
+        let f1 = ntex::rt::spawn(sink.publish("t1", Bytes::new()).send_exactly_once());
+        sleep(Millis(10)).await;
+        let f2 = sink.publish("t2", Bytes::new()).send_at_least_once();
+        let f3 = ntex::rt::spawn(sink.publish("t3", Bytes::new()).send_exactly_once());
+        sleep(Millis(10)).await;
+
+        // packet id stays reserved until PUBCOMP
+        assert!(sink.pkt_ack(pubrec(1)).is_ok());
+        assert!(sink.0.with_queues(|q| q.is_reserved(1)));
+
+        // acks of later publishes arrive before PUBCOMP
+        assert!(sink.pkt_ack(puback(2)).is_ok());
+        assert!(sink.pkt_ack(pubrec(3)).is_ok());
+        assert!(sink.pkt_ack(pubcomp(3)).is_ok());
+        assert!(sink.pkt_ack(pubcomp(1)).is_ok());
+
+        assert_eq!(f1.await.unwrap().unwrap().packet_id, id(1));
+        assert_eq!(f2.await.unwrap().packet_id, id(2));
+        assert_eq!(f3.await.unwrap().unwrap().packet_id, id(3));
+        assert!(sink.0.with_queues(|q| q.inflight.is_empty() && q.inflight_order.is_empty()));
+
+        // PUBACKs still must arrive in order
+        let _f4 = sink.publish("t4", Bytes::new()).send_at_least_once();
+        let _f5 = sink.publish("t5", Bytes::new()).send_at_least_once();
+        assert!(sink.pkt_ack(puback(2)).is_err());
+    }
+
+    #[ntex::test]
+    async fn test_on_inflight_available() {
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.0.cap.set(2);
+
+        let freed = Rc::new(std::cell::Cell::new(0));
+        let freed2 = freed.clone();
+        sink.on_inflight_available(move || freed2.set(freed2.get() + 1));
+
+        let ack = |id| {
+            Ack::Publish(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+            })
+        };
+
+        // saturate in-flight window
+        let fut1 = sink.publish("topic", Bytes::new()).send_at_least_once();
+        let fut2 = sink.publish("topic", Bytes::new()).send_at_least_once();
+        assert_eq!(sink.credit(), 0);
+        assert_eq!(freed.get(), 0);
+
+        assert!(sink.pkt_ack(ack(1)).is_ok());
+        assert_eq!(sink.credit(), 1);
+        assert_eq!(freed.get(), 1);
+        assert!(fut1.await.is_ok());
+
+        assert!(sink.pkt_ack(ack(2)).is_ok());
+        assert_eq!(freed.get(), 2);
+        assert!(fut2.await.is_ok());
+
+        // unexpected ack does not free slot
+        assert!(sink.pkt_ack(ack(3)).is_err());
+        assert_eq!(freed.get(), 2);
+
+        // acks of dropped publish and of subscribe do not free slot
+        let fut = sink.publish("topic", Bytes::new()).send_at_least_once();
+        drop(fut);
+        assert!(sink.pkt_ack(ack(3)).is_ok());
+        assert_eq!(freed.get(), 2);
+
+        let options = codec::SubscriptionOptions {
+            qos: QoS::AtMostOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: codec::RetainHandling::AtSubscribe,
+        };
+        let fut = ntex::rt::spawn(
+            sink.subscribe(None).topic_filter(ByteString::from_static("topic"), options).send(),
+        );
+        sleep(Millis(10)).await;
+        let suback = Ack::Subscribe(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(4).unwrap(),
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+            status: vec![codec::SubscribeAckReason::GrantedQos0],
+        });
+        assert!(sink.pkt_ack(suback).is_ok());
+        assert!(fut.await.unwrap().is_ok());
+        assert_eq!(freed.get(), 2);
+    }
+
+    #[ntex::test]
+    async fn test_subscribe_drop_releases_id() {
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.delay_id_reuse(false);
+
+        let suback = |id| {
+            Ack::Subscribe(codec::SubscribeAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+                status: vec![codec::SubscribeAckReason::GrantedQos0],
+            })
+        };
+        let subscribe = || {
+            let options = codec::SubscriptionOptions {
+                qos: QoS::AtMostOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            };
+            sink.subscribe(None).topic_filter(ByteString::from_static("topic"), options).send()
+        };
+
+        // drop subscribe future before suback
+        match crate::utils::select(subscribe(), sleep(Millis(10))).await {
+            Either::Right(_) => (),
+            Either::Left(_) => panic!("subscribe must be pending"),
+        }
+        assert!(sink.0.with_queues(|q| q.inflight.is_empty() && q.abandoned.contains_key(&1)));
+        assert!(sink.wait_subscriptions_ready().await);
+
+        // suback for dropped subscribe is ignored, packet id is reusable
+        assert!(sink.pkt_ack(suback(1)).is_ok());
+        assert!(sink.0.with_queues(|q| q.inflight.is_empty() && q.abandoned.is_empty()));
+
+        let fut = ntex::rt::spawn(subscribe());
+        sleep(Millis(10)).await;
+        assert!(sink.0.with_queues(|q| q.inflight.contains_key(&1)));
+        assert!(sink.pkt_ack(suback(1)).is_ok());
+        assert_eq!(fut.await.unwrap().unwrap().packet_id, NonZeroU16::new(1).unwrap());
+        assert!(sink.0.with_queues(|q| q.inflight.is_empty() && q.abandoned.is_empty()));
+    }
+
+    #[ntex::test]
+    async fn test_overflow_policy() {
+        let cases = vec![
+            (OverflowPolicy::DropNewest(2), vec!["t1", "t2"]),
+            (OverflowPolicy::DropOldest(2), vec!["t2", "t3"]),
+        ];
+
+        for (policy, expected) in cases {
+            let (client, server) = testing::Io::create();
+            client.remote_buffer_cap(0);
+            let io = Io::new(server);
+            let sink = create_sink(&io);
+            sink.overflow_policy(policy);
+
+            for topic in &["t0", "t1", "t2", "t3"] {
+                sink.publish(*topic, Bytes::new()).send_at_most_once().unwrap();
+            }
+            let queued: Vec<_> = sink
+                .0
+                .with_queues(|q| q.queue.iter().map(|i| i.packet.topic.to_string()).collect());
+            assert_eq!(queued, expected);
+
+            // qos1 publish is queued after queued qos0 publishes, limit does not apply
+            let _fut = sink.publish("t4", Bytes::new()).send_at_least_once();
+            let queued: Vec<_> = sink
+                .0
+                .with_queues(|q| q.queue.iter().map(|i| i.packet.topic.to_string()).collect());
+            assert_eq!(queued, [expected, vec!["t4"]].concat());
+            assert!(sink.0.with_queues(|q| q.inflight.is_empty()));
+        }
+    }
+
+    #[ntex::test]
+    async fn test_unsubscribe_policy() {
+        let cases = vec![
+            (UnsubscribePolicy::Finish, vec![], vec!["t/1", "x/1", "t/2"]),
+            (UnsubscribePolicy::Purge, vec![], vec!["x/1"]),
+            (UnsubscribePolicy::Purge, vec!["t/1"], vec!["t/1", "x/1"]),
+        ];
+
+        for (policy, remaining, expected) in cases {
+            let (client, server) = testing::Io::create();
+            client.remote_buffer_cap(0);
+            let io = Io::new(server);
+            let pool = Rc::new(MqttSinkPool::default());
+            pool.unsubscribe_policy.set(policy);
+            let sink = MqttSink::new(Rc::new(MqttShared::new(
+                io.get_ref(),
+                codec::Codec::default(),
+                16,
+                pool,
+            )));
+            sink.overflow_policy(OverflowPolicy::DropNewest(16));
+
+            let mut filters = vec![ByteString::from_static("t/#")];
+            filters.extend(remaining.into_iter().map(ByteString::from_static));
+            sink.track_subscribe(&filters);
+
+            for topic in &["t/0", "t/1", "x/1", "t/2"] {
+                sink.publish(*topic, Bytes::new()).send_at_most_once().unwrap();
+            }
+            sink.unsubscribed(&filters[..1]);
+            let queued: Vec<_> = sink
+                .0
+                .with_queues(|q| q.queue.iter().map(|i| i.packet.topic.to_string()).collect());
+            assert_eq!(queued, expected);
+        }
+    }
+
+    #[ntex::test]
+    async fn test_unsubscribe_finish() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.overflow_policy(OverflowPolicy::DropNewest(16));
+        sink.track_subscribe(&vec![ByteString::from_static("t/#")]);
+
+        // write buffer is not flushed yet, "t/1" and "t/2" get queued
+        for topic in &["t/0", "t/1", "t/2"] {
+            sink.publish(*topic, Bytes::new()).send_at_most_once().unwrap();
+        }
+        sink.unsubscribed(&[ByteString::from_static("t/#")]);
+        assert!(!sink.is_subscribed("t/1"));
+        assert_eq!(sink.0.with_queues(|q| q.queue.len()), 2);
+
+        // queued publishes reach peer
+        drive_queue(io, &sink);
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let mut topics = Vec::new();
+        while topics.len() < 3 {
+            buf.extend_from_slice(&client.read().await.unwrap());
+            while let Some(codec::Packet::Publish(pkt)) = codec.decode(&mut buf).unwrap() {
+                topics.push(pkt.topic.to_string());
+            }
+        }
+        assert_eq!(topics, vec!["t/0", "t/1", "t/2"]);
+    }
+
+    #[ntex::test]
+    async fn test_close_flushes_queue() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.overflow_policy(OverflowPolicy::DropNewest(16));
+
+        // write buffer is not flushed yet, "t1" and "t2" get queued
+        for topic in &["t0", "t1", "t2"] {
+            sink.publish(*topic, Bytes::new()).send_at_most_once().unwrap();
+        }
+        assert_eq!(sink.0.with_queues(|q| q.queue.len()), 2);
+
+        // queued publishes are written before DISCONNECT
+        sink.close();
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let mut packets = Vec::new();
+        while !packets.iter().any(|p| p == "disconnect") {
+            buf.extend_from_slice(&client.read().await.unwrap());
+            while let Some(pkt) = codec.decode(&mut buf).unwrap() {
+                match pkt {
+                    codec::Packet::Publish(pkt) => packets.push(pkt.topic.to_string()),
+                    codec::Packet::Disconnect(_) => packets.push("disconnect".to_string()),
+                    pkt => panic!("Unexpected packet {:?}", pkt),
+                }
+            }
+        }
+        assert_eq!(packets, vec!["t0", "t1", "t2", "disconnect"]);
+    }
+
+    #[ntex::test]
+    async fn test_subscription_expiry() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(0);
+        let io = Io::new(server);
+        let pool = Rc::new(MqttSinkPool::default());
+        pool.unsubscribe_policy.set(UnsubscribePolicy::Purge);
+        pool.subscription_ttl.set(ntex::time::Seconds(1));
+        let sink = MqttSink::new(Rc::new(MqttShared::new(
+            io.get_ref(),
+            codec::Codec::default(),
+            16,
+            pool,
+        )));
+        sink.overflow_policy(OverflowPolicy::DropNewest(16));
+
+        let filters =
+            vec![ByteString::from_static("t/#"), ByteString::from_static("$share/g/s/#")];
+        sink.track_subscribe(&filters);
+        assert!(sink.is_subscribed("s/1"));
+        assert!(!sink.is_subscribed("g/s/1"));
+        sink.publish("t/1", Bytes::new()).send_at_most_once().unwrap();
+        sink.publish("s/1", Bytes::new()).send_at_most_once().unwrap();
+
+        // dispatcher removes expired subscriptions
+        let s = sink.clone();
+        ntex::rt::spawn(ntex::util::poll_fn(move |cx| {
+            s.poll_subscriptions(cx);
+            Poll::<()>::Pending
+        }));
+        sleep(Millis(500)).await;
+        assert_eq!(sink.0.subscriptions.borrow().len(), 2);
+        assert_eq!(sink.0.with_queues(|q| q.queue.len()), 2);
+
+        sleep(Millis(700)).await;
+        assert!(sink.0.subscriptions.borrow().is_empty());
+        // queued publishes are purged as on unsubscribe
+        assert_eq!(sink.0.with_queues(|q| q.queue.len()), 0);
+    }
+
+    #[ntex::test]
+    async fn test_resume_order() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+
+        // un-acked publish of previous connection
+        let inflight = codec::Publish {
+            dup: true,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("inflight"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+            properties: codec::PublishProperties::default(),
+        };
+        let _f1 = sink.publish_pkt(inflight).send_at_least_once();
+        let _f2 = sink.publish("queued1", Bytes::new()).send_at_least_once();
+        let _f3 = sink.publish("queued2", Bytes::new()).send_at_least_once();
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let mut packets = Vec::new();
+        while packets.len() < 3 {
+            buf.extend_from_slice(&client.read().await.unwrap());
+            while let Some(codec::Packet::Publish(pkt)) = codec.decode(&mut buf).unwrap() {
+                packets.push((pkt.topic.to_string(), pkt.dup, pkt.packet_id.unwrap().get()));
+            }
+        }
+        assert_eq!(
+            packets,
+            vec![
+                ("inflight".to_string(), true, 1),
+                ("queued1".to_string(), false, 2),
+                ("queued2".to_string(), false, 3)
+            ]
+        );
+    }
+
+    #[ntex::test]
+    async fn test_publish_dup() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+
+        let _f1 = sink.publish("t1", Bytes::new()).send_at_least_once();
+        let mut builder = sink.publish("t2", Bytes::new());
+        builder.packet.dup = true;
+        let _f2 = builder.send_at_least_once();
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let mut packets = Vec::new();
+        while packets.len() < 2 {
+            let data = client.read().await.unwrap();
+            buf.extend_from_slice(&data);
+            while !buf.is_empty() {
+                // publish, qos1 and dup flag of fixed header
+                let first_byte = buf[0];
+                match codec.decode(&mut buf).unwrap() {
+                    Some(codec::Packet::Publish(pkt)) => packets.push((first_byte, pkt.dup)),
+                    _ => break,
+                }
+            }
+        }
+        assert_eq!(packets, vec![(0b0011_0010, false), (0b0011_1010, true)]);
+    }
+
+    #[ntex::test]
+    async fn test_mixed_qos_order() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.overflow_policy(OverflowPolicy::DropNewest(16));
+        drive_queue(io, &sink);
+
+        // write buffer is not flushed yet, "t2", "t3" and "t4" get queued
+        sink.publish("t1", Bytes::new()).send_at_most_once().unwrap();
+        sink.publish("t2", Bytes::new()).send_at_most_once().unwrap();
+        let _f = sink.publish("t3", Bytes::new()).send_at_least_once();
+        sink.publish("t4", Bytes::new()).send_at_most_once().unwrap();
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let mut packets = Vec::new();
+        while packets.len() < 4 {
+            buf.extend_from_slice(&client.read().await.unwrap());
+            while let Some(codec::Packet::Publish(pkt)) = codec.decode(&mut buf).unwrap() {
+                packets.push((pkt.topic.to_string(), pkt.qos));
+            }
+        }
+        assert_eq!(
+            packets,
+            vec![
+                ("t1".to_string(), QoS::AtMostOnce),
+                ("t2".to_string(), QoS::AtMostOnce),
+                ("t3".to_string(), QoS::AtLeastOnce),
+                ("t4".to_string(), QoS::AtMostOnce)
+            ]
+        );
+    }
+
+    #[ntex::test]
+    async fn test_mixed_qos_order_no_credit() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = MqttSink::new(Rc::new(MqttShared::new(
+            io.get_ref(),
+            codec::Codec::default(),
+            1,
+            Default::default(),
+        )));
+        sink.overflow_policy(OverflowPolicy::DropNewest(16));
+        drive_queue(io, &sink);
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let mut packets = Vec::new();
+
+        // "t2" waits for credit, "t3" waits for "t2"
+        let f1 = sink.publish("t1", Bytes::new()).send_at_least_once();
+        let _f2 = sink.publish("t2", Bytes::new()).send_at_least_once();
+        sink.publish("t3", Bytes::new()).send_at_most_once().unwrap();
+
+        buf.extend_from_slice(&client.read().await.unwrap());
+        while let Some(codec::Packet::Publish(pkt)) = codec.decode(&mut buf).unwrap() {
+            packets.push((pkt.topic.to_string(), pkt.qos));
+        }
+        sleep(Millis(50)).await;
+        assert!(client.read_any().is_empty());
+        assert_eq!(packets, vec![("t1".to_string(), QoS::AtLeastOnce)]);
+        assert_eq!(sink.0.with_queues(|q| q.queue.len()), 2);
+
+        let ack = Ack::Publish(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        });
+        assert!(sink.pkt_ack(ack).is_ok());
+        assert!(f1.await.is_ok());
+
+        while packets.len() < 3 {
+            buf.extend_from_slice(&client.read().await.unwrap());
+            while let Some(codec::Packet::Publish(pkt)) = codec.decode(&mut buf).unwrap() {
+                packets.push((pkt.topic.to_string(), pkt.qos));
+            }
+        }
+        assert_eq!(
+            packets,
+            vec![
+                ("t1".to_string(), QoS::AtLeastOnce),
+                ("t2".to_string(), QoS::AtLeastOnce),
+                ("t3".to_string(), QoS::AtMostOnce)
+            ]
+        );
+    }
+
+    #[ntex::test]
+    async fn test_publish_will() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+
+        let will = codec::LastWill {
+            qos: QoS::AtMostOnce,
+            retain: true,
+            topic: ByteString::from_static("will"),
+            message: Bytes::from_static(b"gone"),
+            will_delay_interval_sec: None,
+            correlation_data: None,
+            message_expiry_interval: NonZeroU32::new(60),
+            content_type: Some(ByteString::from_static("text/plain")),
+            user_properties: Vec::new(),
+            is_utf8_payload: Some(true),
+            response_topic: None,
+        };
+        sink.publish_will(&will).send_at_most_once().unwrap();
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&client.read().await.unwrap());
+        let pkt = match codec.decode(&mut buf).unwrap() {
+            Some(codec::Packet::Publish(pkt)) => pkt,
+            _ => panic!("expected publish packet"),
+        };
+        assert!(pkt.retain);
+        assert_eq!(pkt.topic, "will");
+        assert_eq!(pkt.payload, Bytes::from_static(b"gone"));
+        assert_eq!(pkt.properties.message_expiry_interval, NonZeroU32::new(60));
+        assert_eq!(pkt.properties.content_type, Some(ByteString::from_static("text/plain")));
+        assert_eq!(pkt.properties.is_utf8_payload, Some(true));
+    }
+
+    #[ntex::test]
+    async fn test_publish_options() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+
+        let options = PublishOptions::new()
+            .retain()
+            .topic_alias(NonZeroU16::new(3).unwrap())
+            .content_type("application/json")
+            .payload_format(true)
+            .message_expiry(NonZeroU32::new(60).unwrap())
+            .response_topic("response")
+            .correlation_data(Bytes::from_static(b"corr"))
+            .user_property("key", "val")
+            .subscription_id(NonZeroU32::new(1).unwrap())
+            .subscription_id(NonZeroU32::new(7).unwrap());
+        sink.publish_with("topic", Bytes::from_static(b"{}"), options)
+            .send_at_most_once()
+            .unwrap();
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&client.read().await.unwrap());
+        let pkt = match codec.decode(&mut buf).unwrap() {
+            Some(codec::Packet::Publish(pkt)) => pkt,
+            _ => panic!("expected publish packet"),
+        };
+        assert!(pkt.retain);
+        assert_eq!(pkt.topic, "topic");
+        assert_eq!(pkt.payload, Bytes::from_static(b"{}"));
+        assert_eq!(
+            pkt.properties,
+            codec::PublishProperties {
+                topic_alias: NonZeroU16::new(3),
+                correlation_data: Some(Bytes::from_static(b"corr")),
+                message_expiry_interval: NonZeroU32::new(60),
+                content_type: Some(ByteString::from_static("application/json")),
+                user_properties: vec![(
+                    ByteString::from_static("key"),
+                    ByteString::from_static("val")
+                )],
+                is_utf8_payload: Some(true),
+                response_topic: Some(ByteString::from_static("response")),
+                subscription_ids: Some(vec![
+                    NonZeroU32::new(1).unwrap(),
+                    NonZeroU32::new(7).unwrap()
+                ]),
+            }
+        );
+    }
+
+    #[ntex::test]
+    async fn test_buffered_bytes() {
+        let (client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        assert_eq!(sink.buffered_bytes(), 0);
+
+        sink.publish("t1", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+        let len = sink.buffered_bytes();
+        assert!(len > 0);
+        sink.publish("t2", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+        assert!(sink.buffered_bytes() > len);
+
+        // buffer is flushed
+        client.remote_buffer_cap(1024);
+        sleep(Millis(50)).await;
+        assert_eq!(sink.buffered_bytes(), 0);
+    }
+
+    #[ntex::test]
+    async fn test_qos_flush_policy() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.qos_flush_policy(FlushPolicy::CoalesceQos0(16));
+        let write_buf = || io.with_write_buf(|buf| buf.len()).unwrap();
+
+        // qos0 publishes are coalesced
+        sink.publish("t1", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+        sink.publish("t2", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+        assert_eq!(sink.0.with_queues(|q| q.coalesced.len()), 2);
+        assert_eq!(write_buf(), 0);
+
+        // qos1 publish flushes immediately
+        let _fut = sink.publish("t3", Bytes::from_static(b"data")).send_at_least_once();
+        assert_eq!(sink.0.with_queues(|q| q.coalesced.len()), 0);
+        assert!(write_buf() > 0);
+
+        // threshold is reached
+        sink.publish("t4", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+        assert_eq!(sink.0.with_queues(|q| q.coalesced.len()), 1);
+        sink.publish("t5", Bytes::from_static(b"large data")).send_at_most_once().unwrap();
+        assert_eq!(sink.0.with_queues(|q| q.coalesced.len()), 0);
+
+        // coalesced publishes are flushed after delay
+        sink.publish("t6", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+        sleep(Millis(50)).await;
+        assert_eq!(sink.0.with_queues(|q| q.coalesced.len()), 0);
+    }
+
+    #[ntex::test]
+    async fn test_pacing_overflow() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.pacing(20);
+        sink.overflow_policy(OverflowPolicy::DropNewest(2));
+        drive_queue(io, &sink);
+
+        // "t0" takes pacing token, policy limits queued qos0 publishes
+        for topic in &["t0", "t1", "t2", "t3"] {
+            sink.publish(*topic, Bytes::new()).send_at_most_once().unwrap();
+        }
+        let f4 = sink.publish("t4", Bytes::new()).send_at_least_once();
+        let queued: Vec<_> = sink
+            .0
+            .with_queues(|q| q.queue.iter().map(|i| i.packet.topic.to_string()).collect());
+        assert_eq!(queued, vec!["t1", "t2", "t4"]);
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let mut packets = Vec::new();
+        while packets.len() < 4 {
+            buf.extend_from_slice(&client.read().await.unwrap());
+            while let Some(codec::Packet::Publish(pkt)) = codec.decode(&mut buf).unwrap() {
+                packets.push((pkt.topic.to_string(), Instant::now()));
+            }
+        }
+        let topics: Vec<_> = packets.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(topics, vec!["t0", "t1", "t2", "t4"]);
+        assert!(packets[3].1 - packets[0].1 >= Duration::from_millis(140));
+
+        let ack = Ack::Publish(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        });
+        assert!(sink.pkt_ack(ack).is_ok());
+        assert!(f4.await.is_ok());
+    }
+
+    #[ntex::test]
+    async fn test_adaptive_pacing() {
+        let (client, server) = testing::Io::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.adaptive_pacing(100, Millis(20));
+        drive_queue(io, &sink);
+        assert_eq!(sink.pacing_rate(), Some(100));
+        assert_eq!(sink.ack_latency(), None);
+
+        let ack = |id| {
+            Ack::Publish(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+            })
+        };
+
+        // client acks slower and slower
+        let mut rates = Vec::new();
+        for (id, delay) in [(1, 10), (2, 200), (3, 400)] {
+            let fut = sink.publish("topic", Bytes::new()).send_at_least_once();
+            ntex::rt::spawn(async move {
+                let _ = fut.await;
+            });
+            sleep(Millis(delay)).await;
+            assert!(sink.pkt_ack(ack(id)).is_ok());
+            rates.push(sink.pacing_rate().unwrap());
+        }
+        assert_eq!(rates[0], 100);
+        assert!(rates[1] < rates[0]);
+        assert!(rates[2] < rates[1]);
+        assert!(sink.ack_latency().unwrap() > Duration::from_millis(20));
+
+        // fixed pacing disables adaptive pacing
+        sink.pacing(10);
+        assert_eq!(sink.pacing_rate(), Some(10));
+        assert_eq!(sink.ack_latency(), None);
+    }
+
+    #[ntex::test]
+    async fn test_adaptive_pacing_rate() {
+        // max rate and target product exceeds u32
+        let adaptive = AdaptivePacing::new(1000, Millis(5_000_000));
+        adaptive.latency.set(Some(6_000_000));
+        assert_eq!(adaptive.rate(), 833);
+
+        // rate change does not refill tokens
+        let (_client, server) = testing::Io::create();
+        let io = Io::new(server);
+        let sink = create_sink(&io);
+        sink.pacing(1);
+        sink.0.take_pacing_token();
+        sink.0.pacing.borrow().as_ref().unwrap().set_rate(2);
+        assert_eq!(sink.pacing_rate(), Some(2));
+        assert!(sink.0.pacing_delay().is_some());
+    }
+}
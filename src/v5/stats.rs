@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Aggregated server statistics
+///
+/// Counters are shared by all connections of the server. Cloned handle refers
+/// to the same counters, so the same handle could be used by all server workers.
+#[derive(Clone, Debug, Default)]
+pub struct ServerStats(Arc<StatsInner>);
+
+#[derive(Debug, Default)]
+struct StatsInner {
+    connections: AtomicUsize,
+    total_connections: AtomicUsize,
+    messages_in: AtomicUsize,
+    messages_out: AtomicUsize,
+    inflight: AtomicUsize,
+}
+
+impl ServerStats {
+    /// Create new statistics handle
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of active connections
+    pub fn connections(&self) -> usize {
+        self.0.connections.load(Ordering::Relaxed)
+    }
+
+    /// Total number of established connections
+    pub fn total_connections(&self) -> usize {
+        self.0.total_connections.load(Ordering::Relaxed)
+    }
+
+    /// Total number of received publish packets
+    pub fn messages_in(&self) -> usize {
+        self.0.messages_in.load(Ordering::Relaxed)
+    }
+
+    /// Total number of sent publish packets
+    pub fn messages_out(&self) -> usize {
+        self.0.messages_out.load(Ordering::Relaxed)
+    }
+
+    /// Number of received publish packets that are being processed
+    pub fn inflight(&self) -> usize {
+        self.0.inflight.load(Ordering::Relaxed)
+    }
+
+    /// Render statistics in Prometheus text exposition format
+    #[cfg(feature = "metrics")]
+    pub fn render_prometheus(&self) -> String {
+        use std::fmt::Write;
+
+        let metrics = [
+            ("connections", "gauge", "Number of active connections", self.connections()),
+            (
+                "connections_total",
+                "counter",
+                "Total number of established connections",
+                self.total_connections(),
+            ),
+            (
+                "messages_received_total",
+                "counter",
+                "Total number of received publish packets",
+                self.messages_in(),
+            ),
+            (
+                "messages_sent_total",
+                "counter",
+                "Total number of sent publish packets",
+                self.messages_out(),
+            ),
+            (
+                "messages_inflight",
+                "gauge",
+                "Number of received publish packets that are being processed",
+                self.inflight(),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, tp, help, value) in metrics.iter() {
+            let _ = writeln!(out, "# HELP ntex_mqtt_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE ntex_mqtt_{} {}", name, tp);
+            let _ = writeln!(out, "ntex_mqtt_{} {}", name, value);
+        }
+        out
+    }
+
+    pub(super) fn connection(&self) -> ConnectionGuard {
+        self.0.connections.fetch_add(1, Ordering::Relaxed);
+        self.0.total_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    pub(super) fn message_in(&self) -> InflightGuard {
+        self.0.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.0.inflight.fetch_add(1, Ordering::Relaxed);
+        InflightGuard(self.clone())
+    }
+
+    pub(super) fn message_out(&self) {
+        self.0.messages_out.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Active connection marker
+pub(super) struct ConnectionGuard(ServerStats);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        (self.0).0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// In-process publish marker
+pub(super) struct InflightGuard(ServerStats);
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        (self.0).0.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = ServerStats::new();
+        let stats2 = stats.clone();
+
+        let conn = stats.connection();
+        let msg = stats2.message_in();
+        stats2.message_out();
+        assert_eq!(stats.connections(), 1);
+        assert_eq!(stats.total_connections(), 1);
+        assert_eq!(stats.messages_in(), 1);
+        assert_eq!(stats.messages_out(), 1);
+        assert_eq!(stats.inflight(), 1);
+
+        drop(msg);
+        drop(conn);
+        assert_eq!(stats.connections(), 0);
+        assert_eq!(stats.total_connections(), 1);
+        assert_eq!(stats.inflight(), 0);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_render_prometheus() {
+        let stats = ServerStats::new();
+        let _conn = stats.connection();
+        stats.message_out();
+
+        let out = stats.render_prometheus();
+        assert!(out.contains("# TYPE ntex_mqtt_connections gauge\nntex_mqtt_connections 1\n"));
+        assert!(out.contains(
+            "# TYPE ntex_mqtt_connections_total counter\nntex_mqtt_connections_total 1\n"
+        ));
+        assert!(out.contains("ntex_mqtt_messages_received_total 0\n"));
+        assert!(out.contains("ntex_mqtt_messages_sent_total 1\n"));
+        assert!(out.contains("ntex_mqtt_messages_inflight 0\n"));
+    }
+}
@@ -0,0 +1,111 @@
+//! MQTT over WebSocket helpers
+//!
+//! WebSocket handshake is handled by http server. Upgrade handler must
+//! negotiate `mqtt` subprotocol and refuse connection if client does not
+//! offer it, negotiated subprotocol is sent back in `Sec-WebSocket-Protocol`
+//! response header. `accept()` could be used as upgrade handler, upgraded io
+//! is passed to mqtt server service afterwards.
+use ntex::http::{body::BodySize, h1, header, Request, RequestHead};
+use ntex::http::{Response, ResponseBuilder, ResponseError};
+use ntex::io::{Filter, Io};
+use ntex::ws;
+
+use crate::error::MqttError;
+
+/// WebSocket subprotocol of MQTT [MQTT-6.0.0-3]
+pub const SUBPROTOCOL: &str = "mqtt";
+
+/// Negotiate subprotocol from `Sec-WebSocket-Protocol` request header value
+///
+/// Returns `mqtt` if client offers it, `None` if connection must be refused.
+pub fn negotiate_subprotocol(header: &str) -> Option<&'static str> {
+    header.split(',').map(|p| p.trim()).find(|p| *p == SUBPROTOCOL).map(|_| SUBPROTOCOL)
+}
+
+/// Verify WebSocket handshake request and negotiate `mqtt` subprotocol
+///
+/// Returns handshake response with negotiated subprotocol, or error response
+/// if request is not valid WebSocket handshake or `mqtt` is not offered.
+pub fn handshake(req: &RequestHead) -> Result<ResponseBuilder, Response> {
+    let mut res = ws::handshake(req).map_err(|e| e.error_response())?;
+
+    let protocol = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|hdr| hdr.to_str().ok())
+        .and_then(negotiate_subprotocol);
+    if let Some(protocol) = protocol {
+        res.header(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+        Ok(res)
+    } else {
+        log::trace!("WebSocket handshake without mqtt subprotocol");
+        Err(Response::BadRequest().finish())
+    }
+}
+
+/// Accept WebSocket connection of http upgrade handler
+///
+/// Sends handshake response and switches io to WebSocket transport.
+/// Connection is refused if handshake is not valid or client does not
+/// offer `mqtt` subprotocol.
+pub async fn accept<F, E>(
+    req: Request,
+    io: Io<F>,
+    codec: h1::Codec,
+) -> Result<Io<ws::WsTransport<F>>, MqttError<E>>
+where
+    F: Filter,
+{
+    match handshake(req.head()) {
+        Ok(mut res) => {
+            io.encode(h1::Message::Item((res.finish().drop_body(), BodySize::None)), &codec)?;
+            Ok(io.add_filter(ws::WsTransportFactory::new(ws::Codec::default())).await?)
+        }
+        Err(res) => {
+            io.send(h1::Message::Item((res.drop_body(), BodySize::None)), &codec).await?;
+            Err(MqttError::ServerError("WebSocket handshake error"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::{codec::Decoder, testing, util::BytesMut};
+
+    use super::*;
+
+    #[test]
+    fn test_negotiate_subprotocol() {
+        assert_eq!(negotiate_subprotocol("mqtt"), Some("mqtt"));
+        assert_eq!(negotiate_subprotocol("mqttv3.1, mqtt"), Some("mqtt"));
+        assert_eq!(negotiate_subprotocol("chat"), None);
+        assert_eq!(negotiate_subprotocol("mqttv3.1"), None);
+        assert_eq!(negotiate_subprotocol(""), None);
+    }
+
+    #[ntex::test]
+    async fn test_accept() {
+        for (protocol, status) in &[("mqtt", "HTTP/1.1 101"), ("chat", "HTTP/1.1 400")] {
+            let (client, server) = testing::Io::create();
+            client.remote_buffer_cap(1024);
+
+            let codec = h1::Codec::default();
+            let mut buf = BytesMut::from(format!(
+                "GET /mqtt HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Protocol: {}\r\n\r\n",
+                protocol
+            ));
+            let (req, _) = codec.decode(&mut buf).unwrap().unwrap();
+
+            let res = accept::<_, ()>(req, Io::new(server), codec).await;
+            assert_eq!(res.is_ok(), *protocol == "mqtt");
+
+            let data = client.read().await.unwrap();
+            let data = String::from_utf8_lossy(&data).to_lowercase();
+            assert!(data.starts_with(&status.to_lowercase()));
+            assert_eq!(data.contains("sec-websocket-protocol: mqtt"), *protocol == "mqtt");
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::{convert::TryFrom, sync::Arc, sync::Mutex, time::Duration, time::Instant};
+
+use ntex::service::{fn_factory_with_config, fn_service};
+use ntex::{time::sleep, time::Millis, util::Ready};
+use ntex_mqtt::testing::{assert_response, with_latency, Harness, Latency};
+use ntex_mqtt::{v3, v5};
+
+struct St;
+
+#[derive(Debug)]
+struct TestError;
+
+impl TryFrom<TestError> for v5::PublishAck {
+    type Error = TestError;
+
+    fn try_from(err: TestError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+fn v3_server() -> impl ntex::service::ServiceFactory<
+    ntex::io::IoBoxed,
+    InitError = (),
+    Error = ntex_mqtt::MqttError<()>,
+> {
+    v3::MqttServer::new(|con: v3::Handshake| Ready::Ok::<_, ()>(con.ack(St, false)))
+        .publish(|_| Ready::Ok(()))
+        .control(|msg| match msg {
+            v3::ControlMessage::Subscribe(mut msg) => {
+                for mut sub in &mut msg {
+                    let qos = sub.qos();
+                    sub.subscribe(qos);
+                }
+                Ready::Ok::<_, ()>(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+}
+
+// CONNECT, protocol level 4, clean session, keep alive 60, client id "u"
+const V3_CONNECT: &[u8] = b"\x10\x0d\x00\x04MQTT\x04\x02\x00\x3c\x00\x01u";
+
+#[ntex::test]
+async fn test_connect_connack() {
+    assert_response(v3_server(), V3_CONNECT, b"\x20\x02\x00\x00").await;
+}
+
+#[ntex::test]
+async fn test_subscribe_suback() {
+    let harness = Harness::new(v3_server()).await;
+    harness.assert_response(V3_CONNECT, b"\x20\x02\x00\x00").await;
+
+    // SUBSCRIBE, packet id 1, "a/b" with qos 1
+    harness.assert_response(b"\x82\x08\x00\x01\x00\x03a/b\x01", b"\x90\x03\x00\x01\x01").await;
+}
+
+#[ntex::test]
+async fn test_malformed_packet_disconnect() {
+    let harness = Harness::new(
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish(),
+    )
+    .await;
+
+    // CONNECT, protocol level 5, clean start, keep alive 60, client id "u"
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // PUBACK with packet id 0, DISCONNECT with `Malformed Packet` reason code
+    harness.assert_response(b"\x40\x02\x00\x00", b"\xe0\x02\x81\x00").await;
+}
+
+#[ntex::test]
+async fn test_subscribe_too_many_filters() {
+    let harness = Harness::new(
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .max_subscribe_filters(2)
+            .finish(),
+    )
+    .await;
+
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // SUBSCRIBE with three filters, DISCONNECT with `Packet too large` reason code
+    harness
+        .assert_response(
+            b"\x82\x12\x00\x01\x00\x00\x02t1\x01\x00\x02t2\x01\x00\x02t3\x01",
+            b"\xe0\x02\x95\x00",
+        )
+        .await;
+}
+
+#[ntex::test]
+async fn test_packets_after_disconnect() {
+    let publishes = Arc::new(AtomicUsize::new(0));
+    let publishes2 = publishes.clone();
+    let harness = Harness::new(
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(move |p: v5::Publish| {
+                publishes2.fetch_add(1, Relaxed);
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish(),
+    )
+    .await;
+
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // DISCONNECT followed by qos0 PUBLISH in the same buffer
+    harness.send(b"\xe0\x00\x30\x05\x00\x01t\x00x");
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(publishes.load(Relaxed), 0);
+}
+
+#[ntex::test]
+async fn test_packets_after_disconnect_v3() {
+    let publishes = Arc::new(AtomicUsize::new(0));
+    let publishes2 = publishes.clone();
+    let harness = Harness::new(
+        v3::MqttServer::new(|con: v3::Handshake| Ready::Ok::<_, ()>(con.ack(St, false)))
+            .publish(move |_: v3::Publish| {
+                publishes2.fetch_add(1, Relaxed);
+                Ready::Ok::<_, ()>(())
+            })
+            .finish(),
+    )
+    .await;
+    harness.assert_response(V3_CONNECT, b"\x20\x02\x00\x00").await;
+
+    // DISCONNECT followed by qos0 PUBLISH in the same buffer
+    harness.send(b"\xe0\x00\x30\x04\x00\x01tx");
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(publishes.load(Relaxed), 0);
+}
+
+#[ntex::test]
+async fn test_disable_keepalive() {
+    let harness = Harness::new(
+        v5::MqttServer::new(|con: v5::Handshake| {
+            Ready::Ok::<_, TestError>(con.ack(St).disable_keepalive())
+        })
+        .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish(),
+    )
+    .await;
+
+    // CONNECT with keep alive 1 second
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x01\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // idle connection is not closed
+    sleep(Duration::from_millis(2500)).await;
+    assert!(harness.recv().await.is_none());
+    harness.assert_response(b"\xc0\x00", b"\xd0\x00").await;
+}
+
+#[ntex::test]
+async fn test_topic_alias_max() {
+    let aliases = Arc::new(Mutex::new(Vec::new()));
+    let aliases2 = aliases.clone();
+    let harness = Harness::new(
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .max_topic_alias(2)
+            .publish(fn_factory_with_config(move |session: v5::Session<St>| {
+                let aliases = aliases2.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: v5::Publish| {
+                    aliases.lock().unwrap().push(session.sink().topic_aliases());
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish(),
+    )
+    .await;
+
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // qos0 PUBLISH packets with topic alias 1 and 2
+    harness.send(b"\x30\x07\x00\x01a\x03\x23\x00\x01");
+    harness.send(b"\x30\x07\x00\x01b\x03\x23\x00\x02");
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(*aliases.lock().unwrap(), vec![1, 2]);
+
+    // topic alias 3 exceeds max, DISCONNECT with `Topic Alias invalid` reason code
+    harness.assert_response(b"\x30\x07\x00\x01c\x03\x23\x00\x03", b"\xe0\x02\x94\x00").await;
+    assert_eq!(aliases.lock().unwrap().len(), 2);
+}
+
+#[ntex::test]
+async fn test_empty_client_id_persistent_session_v3() {
+    let handshakes = Arc::new(AtomicUsize::new(0));
+    let handshakes2 = handshakes.clone();
+    let harness = Harness::new(
+        v3::MqttServer::new(move |con: v3::Handshake| {
+            handshakes2.fetch_add(1, Relaxed);
+            Ready::Ok::<_, ()>(con.ack(St, false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish(),
+    )
+    .await;
+
+    // CONNECT without clean session flag and empty client id,
+    // CONNACK with `identifier rejected` return code
+    harness
+        .assert_response(b"\x10\x0c\x00\x04MQTT\x04\x00\x00\x3c\x00\x00", b"\x20\x02\x00\x02")
+        .await;
+    assert_eq!(handshakes.load(Relaxed), 0);
+}
+
+#[ntex::test]
+async fn test_empty_client_id_persistent_session_v5() {
+    let client_id = Arc::new(Mutex::new(None));
+    let client_id2 = client_id.clone();
+    let harness = Harness::new(
+        v5::MqttServer::new(move |con: v5::Handshake| {
+            *client_id2.lock().unwrap() = Some(con.packet().client_id.clone());
+            Ready::Ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish(),
+    )
+    .await;
+
+    // CONNECT without clean start flag, session expiry interval 60, empty client id
+    harness.send(b"\x10\x12\x00\x04MQTT\x05\x00\x00\x3c\x05\x11\x00\x00\x00\x3c\x00\x00");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+    // session present flag and `success` reason code
+    assert_eq!(&ack[2..4], b"\x00\x00");
+    assert_eq!(client_id.lock().unwrap().as_deref(), Some(""));
+}
+
+#[ntex::test]
+async fn test_with_latency() {
+    let harness = with_latency(v3_server(), Latency::Fixed(Millis(300))).await;
+
+    let start = Instant::now();
+    harness.assert_response(V3_CONNECT, b"\x20\x02\x00\x00").await;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(800), "{:?}", elapsed);
+
+    // PINGREQ, PINGRESP
+    let start = Instant::now();
+    harness.assert_response(b"\xc0\x00", b"\xd0\x00").await;
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
+
+#[ntex::test]
+async fn test_connect_after_handshake() {
+    let server = || {
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    };
+
+    // CONNECT with protocol level 4, DISCONNECT with `Protocol Error` reason code
+    let harness = Harness::new(server()).await;
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+    harness.assert_response(V3_CONNECT, b"\xe0\x02\x82\x00").await;
+
+    // second CONNECT with protocol level 5
+    let harness = Harness::new(server()).await;
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+    harness
+        .assert_response(
+            b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u",
+            b"\xe0\x02\x82\x00",
+        )
+        .await;
+}
+
+#[ntex::test]
+async fn test_connect_after_handshake_v3() {
+    let harness = Harness::new(v3_server()).await;
+    harness.assert_response(V3_CONNECT, b"\x20\x02\x00\x00").await;
+
+    // CONNECT with protocol level 5 closes connection
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    sleep(Duration::from_millis(100)).await;
+    harness.send(b"\xc0\x00");
+    assert!(harness.recv().await.is_none());
+}
+
+#[ntex::test]
+async fn test_decode_error_capture() {
+    let captured = Arc::new(Mutex::new(None));
+    let captured2 = captured.clone();
+    let harness = Harness::new(
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .decode_error_capture(4)
+            .control(move |msg| match msg {
+                v5::ControlMessage::ProtocolError(msg) => {
+                    *captured2.lock().unwrap() = msg.get_ref().decode_input().map(String::from);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                v5::ControlMessage::ServerDisconnecting(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish(),
+    )
+    .await;
+
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // PUBACK with packet id 0, only first 4 bytes are captured
+    harness.assert_response(b"\x40\x06\x00\x00\x00\x00\x00\x00", b"\xe0\x02\x81\x00").await;
+    assert_eq!(captured.lock().unwrap().as_deref(), Some("40000000"));
+}
+
+#[ntex::test]
+async fn test_pingresp_from_client() {
+    let server = |strict| {
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .strict_packet_direction(strict)
+            .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    };
+
+    // PINGRESP is ignored, connection is alive
+    let harness = Harness::new(server(false)).await;
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+    harness.send(b"\xd0\x00");
+    harness.assert_response(b"\xc0\x00", b"\xd0\x00").await;
+
+    // DISCONNECT with `Protocol Error` reason code
+    let harness = Harness::new(server(true)).await;
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+    harness.assert_response(b"\xd0\x00", b"\xe0\x02\x82\x00").await;
+}
+
+#[ntex::test]
+async fn test_pingresp_from_client_v3() {
+    let server = |strict| {
+        v3::MqttServer::new(|con: v3::Handshake| Ready::Ok::<_, ()>(con.ack(St, false)))
+            .strict_packet_direction(strict)
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    };
+
+    // PINGRESP is ignored, connection is alive
+    let harness = Harness::new(server(false)).await;
+    harness.assert_response(V3_CONNECT, b"\x20\x02\x00\x00").await;
+    harness.send(b"\xd0\x00");
+    harness.assert_response(b"\xc0\x00", b"\xd0\x00").await;
+
+    // connection is closed
+    let harness = Harness::new(server(true)).await;
+    harness.assert_response(V3_CONNECT, b"\x20\x02\x00\x00").await;
+    harness.send(b"\xd0\x00");
+    sleep(Duration::from_millis(100)).await;
+    harness.send(b"\xc0\x00");
+    assert!(harness.recv().await.is_none());
+}
+
+#[ntex::test]
+async fn test_publish_topic_wildcards() {
+    let server = || {
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    };
+
+    // DISCONNECT with `Topic Name invalid` reason code
+    for publish in [&b"\x30\x08\x00\x05a/+/b\x00"[..], &b"\x30\x06\x00\x03a/#\x00"[..]] {
+        let harness = Harness::new(server()).await;
+        harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+        let ack = harness.recv().await.unwrap();
+        assert_eq!(ack[0], 0x20);
+        harness.assert_response(publish, b"\xe0\x02\x90\x00").await;
+    }
+}
+
+#[ntex::test]
+async fn test_publish_topic_wildcards_v3() {
+    // connection is closed
+    for publish in [&b"\x30\x07\x00\x05a/+/b"[..], &b"\x30\x05\x00\x03a/#"[..]] {
+        let harness = Harness::new(v3_server()).await;
+        harness.assert_response(V3_CONNECT, b"\x20\x02\x00\x00").await;
+        harness.send(publish);
+        sleep(Duration::from_millis(100)).await;
+        harness.send(b"\xc0\x00");
+        assert!(harness.recv().await.is_none());
+    }
+}
+
+#[ntex::test]
+async fn test_publish_topic_alias() {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+    let server = move || {
+        let topics = topics2.clone();
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(move |p: v5::Publish| {
+                topics.lock().unwrap().push(p.publish_topic().to_string());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    };
+
+    // topic alias 0, DISCONNECT with `Protocol Error` reason code
+    let harness = Harness::new(server()).await;
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+    harness.assert_response(b"\x30\x0a\x00\x04test\x03\x23\x00\x00", b"\xe0\x02\x82\x00").await;
+    assert!(topics.lock().unwrap().is_empty());
+
+    let harness = Harness::new(server()).await;
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // PUBLISH qos 1 with topic and alias 1 establishes mapping
+    harness
+        .assert_response(
+            b"\x32\x0c\x00\x04test\x00\x01\x03\x23\x00\x01",
+            b"\x40\x04\x00\x01\x00\x00",
+        )
+        .await;
+    // PUBLISH qos 1 with empty topic and alias 1 resolves mapping
+    harness
+        .assert_response(
+            b"\x32\x08\x00\x00\x00\x02\x03\x23\x00\x01",
+            b"\x40\x04\x00\x02\x00\x00",
+        )
+        .await;
+    // PUBLISH with new topic overwrites mapping
+    harness
+        .assert_response(
+            b"\x32\x0d\x00\x05other\x00\x03\x03\x23\x00\x01",
+            b"\x40\x04\x00\x03\x00\x00",
+        )
+        .await;
+    harness
+        .assert_response(
+            b"\x32\x08\x00\x00\x00\x04\x03\x23\x00\x01",
+            b"\x40\x04\x00\x04\x00\x00",
+        )
+        .await;
+    assert_eq!(*topics.lock().unwrap(), vec!["test", "test", "other", "other"]);
+}
+
+#[ntex::test]
+async fn test_publish_empty_payload() {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+    let harness = Harness::new(
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(move |p: v5::Publish| {
+                publishes2.lock().unwrap().push((p.payload().clone(), p.is_retain_clear()));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish(),
+    )
+    .await;
+
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // PUBLISH qos 0 with empty payload, PUBLISH qos 0 retained with empty payload
+    harness.send(b"\x30\x07\x00\x04test\x00");
+    harness.send(b"\x31\x07\x00\x04test\x00");
+    harness.assert_response(b"\xc0\x00", b"\xd0\x00").await;
+    assert_eq!(
+        *publishes.lock().unwrap(),
+        vec![(ntex::util::Bytes::new(), false), (ntex::util::Bytes::new(), true)]
+    );
+}
+
+#[ntex::test]
+async fn test_publish_empty_payload_v3() {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+    let harness = Harness::new(
+        v3::MqttServer::new(|con: v3::Handshake| Ready::Ok::<_, ()>(con.ack(St, false)))
+            .publish(move |p: v3::Publish| {
+                publishes2.lock().unwrap().push((p.payload().clone(), p.is_retain_clear()));
+                Ready::Ok::<_, ()>(())
+            })
+            .finish(),
+    )
+    .await;
+    harness.assert_response(V3_CONNECT, b"\x20\x02\x00\x00").await;
+
+    // PUBLISH qos 0 with empty payload, PUBLISH qos 0 retained with empty payload
+    harness.send(b"\x30\x06\x00\x04test");
+    harness.send(b"\x31\x06\x00\x04test");
+    harness.assert_response(b"\xc0\x00", b"\xd0\x00").await;
+    assert_eq!(
+        *publishes.lock().unwrap(),
+        vec![(ntex::util::Bytes::new(), false), (ntex::util::Bytes::new(), true)]
+    );
+}
+
+#[ntex::test]
+async fn test_subscribe_max_filter_levels() {
+    let filters = Arc::new(Mutex::new(Vec::new()));
+    let filters2 = filters.clone();
+    let harness = Harness::new(
+        v5::MqttServer::new(|con: v5::Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                v5::ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in msg.iter_mut() {
+                        filters2.lock().unwrap().push(sub.topic().to_string());
+                        sub.confirm(v5::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .max_filter_levels(2)
+            .finish(),
+    )
+    .await;
+
+    harness.send(b"\x10\x0e\x00\x04MQTT\x05\x02\x00\x3c\x00\x00\x01u");
+    let ack = harness.recv().await.unwrap();
+    assert_eq!(ack[0], 0x20);
+
+    // SUBSCRIBE with "a/b" and "a/b/c" filters, SUBACK with `Topic Filter invalid`
+    // reason code for filter that exceeds levels limit
+    harness
+        .assert_response(
+            b"\x82\x11\x00\x01\x00\x00\x03a/b\x01\x00\x05a/b/c\x01",
+            b"\x90\x05\x00\x01\x00\x01\x8f",
+        )
+        .await;
+    assert_eq!(*filters.lock().unwrap(), vec!["a/b"]);
+}
@@ -1,13 +1,17 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{num::NonZeroU16, time::Duration};
+use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc, Mutex};
+use std::{
+    num::NonZeroU16,
+    time::{Duration, Instant},
+};
 
+use ntex::codec::BytesCodec;
 use ntex::service::{Service, ServiceFactory};
 use ntex::time::{sleep, Millis, Seconds};
 use ntex::util::{join_all, ByteString, Bytes, Ready};
 use ntex::{server, service::pipeline_factory};
 
 use ntex_mqtt::v3::{
-    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
+    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
 };
 
 struct St;
@@ -34,13 +38,73 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
 
     sink.close();
     Ok(())
 }
 
+#[ntex::test]
+async fn test_publish_received_at() -> std::io::Result<()> {
+    let times = Arc::new(Mutex::new(Vec::new()));
+    let times2 = times.clone();
+
+    let srv = server::test_server(move || {
+        let times = times2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let times = times.clone();
+                async move {
+                    sleep(Millis(20)).await;
+                    times.lock().unwrap().push((p.received_at(), Instant::now()));
+                    Ok::<_, ()>(())
+                }
+            })
+            .finish()
+    });
+
+    let start = Instant::now();
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    let times = times.lock().unwrap();
+    assert_eq!(times.len(), 1);
+    let (received_at, handled_at) = times[0];
+    assert!(received_at >= start);
+    assert!(handled_at.duration_since(received_at) >= Duration::from_millis(20));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connect_ack_timeout() -> std::io::Result<()> {
+    // server never sends CONNACK
+    let srv = server::test_server(|| {
+        ntex::service::fn_service(|io: ntex::io::Io| async move {
+            let codec = codec::Codec::default();
+            let _ = io.recv(&codec).await;
+            std::future::pending::<()>().await;
+            Ok::<_, ()>(())
+        })
+    });
+
+    // connect future completes only by handshake timeout
+    let connect =
+        client::MqttConnector::new(srv.addr()).client_id("user").handshake_timeout(Seconds(1));
+    let res = ntex::time::timeout(Millis(30_000), connect.connect()).await;
+    assert!(matches!(res, Ok(Err(error::ClientError::HandshakeTimeout))));
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_connect_fail() -> std::io::Result<()> {
     // bad user name or password
@@ -98,6 +162,37 @@ async fn test_connect_fail() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_connect_fail_return_codes() -> std::io::Result<()> {
+    let codes = vec![
+        codec::ConnectAckReason::UnacceptableProtocolVersion,
+        codec::ConnectAckReason::IdentifierRejected,
+        codec::ConnectAckReason::ServiceUnavailable,
+        codec::ConnectAckReason::BadUserNameOrPassword,
+        codec::ConnectAckReason::NotAuthorized,
+    ];
+
+    for code in codes {
+        let srv = server::test_server(move || {
+            MqttServer::new(move |conn: Handshake| Ready::Ok::<_, ()>(conn.failed::<St>(code)))
+                .publish(|_t| Ready::Ok(()))
+                .finish()
+        });
+
+        let io = srv.connect().await.unwrap();
+        io.send(
+            codec::Packet::Connect(codec::Connect::default().client_id("user").into()),
+            &codec::Codec::default(),
+        )
+        .await
+        .unwrap();
+        let ack = io.recv(&BytesCodec).await.unwrap().unwrap();
+        assert_eq!(ack.as_ref(), &[0x20, 0x02, 0x00, u8::from(code)][..]);
+    }
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_ping() -> std::io::Result<()> {
     let ping = Arc::new(AtomicBool::new(false));
@@ -274,7 +369,7 @@ async fn test_disconnect() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_err());
 
     Ok(())
@@ -416,3 +511,207 @@ async fn test_large_publish_openssl() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_subscribe_duplicated_topics() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    let mut count = 0;
+                    for mut sub in &mut msg {
+                        count += 1;
+                        if &**sub.topic() == "a/b" {
+                            assert_eq!(sub.qos(), codec::QoS::ExactlyOnce);
+                        }
+                        let qos = sub.qos();
+                        sub.subscribe(qos);
+                    }
+                    assert_eq!(count, 2);
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![
+                (ByteString::from("a/b"), codec::QoS::AtMostOnce),
+                (ByteString::from("c/d"), codec::QoS::AtLeastOnce),
+                (ByteString::from("a/b"), codec::QoS::ExactlyOnce),
+            ],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![
+                codec::SubscribeReturnCode::Success(codec::QoS::ExactlyOnce),
+                codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+                codec::SubscribeReturnCode::Success(codec::QoS::ExactlyOnce),
+            ],
+        }
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_connect_size() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).max_connect_size(64).publish(|_t| Ready::Ok(())).finish()
+    });
+
+    // only fixed header of 1MB connect packet is sent
+    let io = srv.connect().await.unwrap();
+    io.send(Bytes::from_static(b"\x10\x80\x80\x40"), &BytesCodec).await.unwrap();
+
+    // connection is closed without waiting for packet body
+    let res = ntex::time::timeout(Duration::from_millis(500), io.recv(&BytesCodec)).await;
+    assert!(matches!(res, Ok(Ok(None)) | Ok(Err(_))));
+
+    // small connect packet is accepted
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+        }
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_session_present() -> std::io::Result<()> {
+    for present in [true, false] {
+        let srv = server::test_server(move || {
+            MqttServer::new(move |conn: Handshake| Ready::Ok::<_, ()>(conn.ack(St, present)))
+                .publish(|_t| Ready::Ok(()))
+                .finish()
+        });
+
+        let mut connector = client::MqttConnector::new(srv.addr()).client_id("user");
+        if !present {
+            connector = connector.clean_session();
+        }
+        let client = connector.connect().await.unwrap();
+        assert_eq!(client.session_present(), present);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_closed_is_clean() -> std::io::Result<()> {
+    let closed = Arc::new(Mutex::new(Vec::new()));
+    let closed2 = closed.clone();
+
+    let srv = server::test_server(move || {
+        let closed = closed2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::Disconnect(msg) => Ready::Ok(msg.ack()),
+                ControlMessage::Closed(msg) => {
+                    closed.lock().unwrap().push(msg.is_clean());
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // client sends DISCONNECT
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::Disconnect, &codec).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(closed.lock().unwrap().pop(), Some(true));
+
+    // connection is dropped
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    drop(io);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(closed.lock().unwrap().pop(), Some(false));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_require_tls() -> std::io::Result<()> {
+    // plain connection is closed
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).require_tls(true).publish(|_| Ready::Ok(())).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    assert!(!matches!(io.recv(&codec).await, Ok(Some(_))));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_require_tls_openssl() -> std::io::Result<()> {
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+    // tls connection without client certificate is accepted
+    let srv = server::test_server(move || {
+        pipeline_factory(server::openssl::Acceptor::new(ssl_acceptor()).map_err(|_| ()))
+            .and_then(
+                MqttServer::new(handshake)
+                    .require_tls(true)
+                    .publish(|_| Ready::Ok(()))
+                    .finish()
+                    .map_err(|_| ())
+                    .map_init_err(|_| ()),
+            )
+    });
+
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    let con = ntex::connect::openssl::Connector::new(builder.build());
+    let addr = format!("127.0.0.1:{}", srv.addr().port());
+    let io = con.call(addr.into()).await.unwrap();
+
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    assert_eq!(
+        io.recv(&codec).await.unwrap().unwrap(),
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted
+        }
+    );
+    Ok(())
+}
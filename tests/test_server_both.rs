@@ -1,9 +1,12 @@
 use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
 
 use ntex::server;
+use ntex::service::{fn_factory_with_config, fn_service};
 use ntex::util::{ByteString, Bytes, Ready};
 
-use ntex_mqtt::{v3, v5, MqttServer};
+use ntex_mqtt::types::QoS;
+use ntex_mqtt::{any_publish, v3, v5, AnyPublish, MqttServer, MqttVersion};
 
 struct St;
 
@@ -45,7 +48,7 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
     sink.close();
 
@@ -56,9 +59,154 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
     sink.close();
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_protocol_version() -> std::io::Result<()> {
+    let versions = Arc::new(Mutex::new(Vec::new()));
+    let versions2 = versions.clone();
+
+    let srv = server::test_server(move || {
+        let v3_versions = versions2.clone();
+        let v5_versions = versions2.clone();
+        MqttServer::new()
+            .v3(v3::MqttServer::new(|con: v3::Handshake| {
+                Ready::Ok::<_, TestError>(con.ack(St, false))
+            })
+            .publish(fn_factory_with_config(move |session: v3::Session<St>| {
+                v3_versions.lock().unwrap().push(session.protocol_version());
+                Ready::Ok::<_, TestError>(fn_service(|_: v3::Publish| {
+                    Ready::Ok::<_, TestError>(())
+                }))
+            })))
+            .v5(v5::MqttServer::new(|con: v5::Handshake| {
+                Ready::Ok::<_, TestError>(con.ack(St))
+            })
+            .publish(fn_factory_with_config(move |session: v5::Session<St>| {
+                v5_versions.lock().unwrap().push(session.protocol_version());
+                Ready::Ok::<_, TestError>(fn_service(|p: v5::Publish| {
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            })))
+    });
+
+    // connect to v3 server
+    let client =
+        v3::client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    // connect to v5 server
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    assert_eq!(*versions.lock().unwrap(), vec![MqttVersion::V3, MqttVersion::V5]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_any_publish() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        // same handler is used by both routers
+        let received = received2.clone();
+        let handler = move |p: AnyPublish| {
+            received.lock().unwrap().push((
+                p.version(),
+                p.publish_topic().to_string(),
+                p.payload().clone(),
+                p.qos(),
+                p.retain(),
+            ));
+            Ready::Ok::<_, TestError>(())
+        };
+
+        MqttServer::new()
+            .v3(v3::MqttServer::new(|con: v3::Handshake| {
+                Ready::Ok::<_, TestError>(con.ack(St, false))
+            })
+            .publish(
+                v3::Router::new(fn_factory_with_config(|_: v3::Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|_: v3::Publish| {
+                        Ready::Ok::<_, TestError>(())
+                    }))
+                }))
+                .resource("test", any_publish(fn_service(handler.clone()))),
+            ))
+            .v5(v5::MqttServer::new(|con: v5::Handshake| {
+                Ready::Ok::<_, TestError>(con.ack(St))
+            })
+            .publish(
+                v5::Router::new(fn_factory_with_config(|_: v5::Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|p: v5::Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }))
+                .resource("test", any_publish(fn_service(handler))),
+            ))
+    });
+
+    // connect to v3 server
+    let client =
+        v3::client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from_static(b"v3"))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    sink.close();
+
+    // connect to v5 server
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from_static(b"v5"))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    sink.close();
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![
+            (
+                MqttVersion::V3,
+                "test".to_string(),
+                Bytes::from_static(b"v3"),
+                QoS::AtLeastOnce,
+                false
+            ),
+            (
+                MqttVersion::V5,
+                "test".to_string(),
+                Bytes::from_static(b"v5"),
+                QoS::AtLeastOnce,
+                false
+            ),
+        ]
+    );
+
+    Ok(())
+}
@@ -1,13 +1,21 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{convert::TryFrom, num::NonZeroU16, time::Duration};
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use std::{convert::TryFrom, future::Future, num::NonZeroU16, pin::Pin, rc::Rc};
 
-use ntex::util::{ByteString, Bytes, Ready};
-use ntex::{server, service::fn_service, time::sleep};
+use ntex::service::{fn_factory_with_config, fn_service};
+use ntex::util::{ByteString, Bytes, PoolId, Ready};
+use ntex::{server, time::sleep, time::Seconds};
 
 use ntex_mqtt::v5::{
-    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
-    PublishAck, Session,
+    client, codec, error, AcceptRateLimit, AckOrdering, ConnackProps, ConnectionCounter,
+    ConnectionEvent, ControlMessage, Handshake, HandshakeAck, MqttServer, MqttSink,
+    PayloadCodec, Publish, PublishAck, PublishOrdering, QosCeilingPolicy, Router, ServerLimits,
+    ServerStats, Session, WriteBudget,
 };
+use ntex_mqtt::TimeSource;
 
 struct St;
 
@@ -40,6 +48,23 @@ fn pkt_publish() -> codec::Publish {
     }
 }
 
+fn pkt_subscribe(id: u16) -> codec::Packet {
+    codec::Packet::Subscribe(codec::Subscribe {
+        packet_id: NonZeroU16::new(id).unwrap(),
+        topic_filters: vec![(
+            "topic1".into(),
+            codec::SubscriptionOptions {
+                qos: codec::QoS::AtLeastOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            },
+        )],
+        id: None,
+        user_properties: codec::UserProperties::default(),
+    })
+}
+
 async fn handshake(packet: Handshake) -> Result<HandshakeAck<St>, TestError> {
     Ok(packet.ack(St))
 }
@@ -61,7 +86,7 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
 
     sink.close();
@@ -91,6 +116,206 @@ async fn test_handshake_failed() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_connect_ack_timeout() -> std::io::Result<()> {
+    // server never sends CONNACK
+    let srv = server::test_server(|| {
+        fn_service(|io: ntex::io::Io| async move {
+            let codec = codec::Codec::default();
+            let _ = io.recv(&codec).await;
+            std::future::pending::<()>().await;
+            Ok::<_, ()>(())
+        })
+    });
+
+    // connect future completes only by handshake timeout
+    let connect =
+        client::MqttConnector::new(srv.addr()).client_id("user").handshake_timeout(Seconds(1));
+    let res = ntex::time::timeout(ntex::time::Millis(30_000), connect.connect()).await;
+    assert!(matches!(res, Ok(Err(error::ClientError::HandshakeTimeout))));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connect_validator() -> std::io::Result<()> {
+    let handshakes = Arc::new(AtomicUsize::new(0));
+    let handshakes2 = handshakes.clone();
+
+    let srv = server::test_server(move || {
+        let handshakes = handshakes2.clone();
+        MqttServer::new(fn_service(move |hnd: Handshake| {
+            handshakes.fetch_add(1, Relaxed);
+            Ready::Ok::<_, TestError>(hnd.ack(St))
+        }))
+        .connect_validator(|pkt: &codec::Connect| {
+            let id = pkt.client_id.as_bytes();
+            if id.starts_with(b"dev-") && id[4..].iter().all(|b| b.is_ascii_digit()) {
+                Ok(())
+            } else {
+                Err(codec::ConnectAckReason::ClientIdentifierNotValid)
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // client id does not match pattern
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("dev-x1").connect().await.unwrap_err();
+    match err {
+        error::ClientError::Ack(pkt) => {
+            assert_eq!(pkt.reason_code, codec::ConnectAckReason::ClientIdentifierNotValid);
+        }
+        _ => panic!("error"),
+    }
+    assert_eq!(handshakes.load(Relaxed), 0);
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("dev-12").connect().await.unwrap();
+    assert_eq!(client.packet().reason_code, codec::ConnectAckReason::Success);
+    assert_eq!(handshakes.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_accept_rate_limit() -> std::io::Result<()> {
+    let handshakes = Arc::new(AtomicUsize::new(0));
+    let handshakes2 = handshakes.clone();
+    let limit = AcceptRateLimit::new(1, 2);
+
+    let srv = server::test_server(move || {
+        let handshakes = handshakes2.clone();
+        MqttServer::new(fn_service(move |hnd: Handshake| {
+            handshakes.fetch_add(1, Relaxed);
+            Ready::Ok::<_, TestError>(hnd.ack(St))
+        }))
+        .accept_rate_limit(limit.clone())
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let mut clients = Vec::new();
+    let mut rejected = 0;
+    for i in 0..5 {
+        let result = client::MqttConnector::new(srv.addr())
+            .client_id(format!("user{}", i))
+            .connect()
+            .await;
+        match result {
+            Ok(client) => clients.push(client),
+            Err(error::ClientError::Ack(pkt)) => {
+                assert_eq!(pkt.reason_code, codec::ConnectAckReason::ServerBusy);
+                rejected += 1;
+            }
+            Err(err) => panic!("Unexpected error: {:?}", err),
+        }
+    }
+
+    // burst of two handshakes is accepted, excess handshakes are rejected
+    assert_eq!(clients.len(), 2);
+    assert_eq!(rejected, 3);
+    assert_eq!(handshakes.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_io_buffer_params() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let default_params = PoolId::P5.pool_ref().read_params();
+    let default_params = (default_params.high, default_params.low);
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .io_buffer_params(PoolId::P6, 32 * 1024, 4096, 48 * 1024, 2048)
+            .publish(fn_factory_with_config(move |session: Session<St>| {
+                let received = received.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    let pool = session.sink().memory_pool();
+                    received.lock().unwrap().push((
+                        pool.pool_id(),
+                        p.payload().len(),
+                        pool.read_params().high,
+                        pool.read_params().low,
+                        pool.write_params().high,
+                        pool.write_params().low,
+                    ));
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let payload = Bytes::from(vec![b'x'; 64 * 1024]);
+    for _ in 0..2 {
+        let res = sink
+            .publish(ByteString::from_static("test"), payload.clone())
+            .send_at_least_once()
+            .await;
+        assert!(res.is_ok());
+    }
+    sink.close();
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![(PoolId::P6, 64 * 1024, 32 * 1024, 4096, 48 * 1024, 2048); 2]
+    );
+    // params of server's default memory pool are not changed
+    let params = PoolId::P5.pool_ref().read_params();
+    assert_eq!((params.high, params.low), default_params);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_received_at() -> std::io::Result<()> {
+    let times = Arc::new(Mutex::new(Vec::new()));
+    let times2 = times.clone();
+
+    let srv = server::test_server(move || {
+        let times = times2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let times = times.clone();
+                async move {
+                    sleep(Duration::from_millis(20)).await;
+                    times.lock().unwrap().push((p.received_at(), Instant::now()));
+                    Ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    let start = Instant::now();
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    let times = times.lock().unwrap();
+    assert_eq!(times.len(), 1);
+    let (received_at, handled_at) = times[0];
+    assert!(received_at >= start);
+    assert!(handled_at.duration_since(received_at) >= Duration::from_millis(20));
+    assert!(start.elapsed() >= handled_at.duration_since(start));
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_disconnect() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -116,7 +341,7 @@ async fn test_disconnect() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_err());
 
     Ok(())
@@ -151,7 +376,7 @@ async fn test_disconnect_with_reason() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_err());
 
     Ok(())
@@ -365,7 +590,12 @@ async fn test_dups() {
 
     // send packet_id dup
     io.send(
-        codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }.into(),
+        codec::Publish {
+            dup: true,
+            packet_id: Some(NonZeroU16::new(1).unwrap()),
+            ..pkt_publish()
+        }
+        .into(),
         &codec,
     )
     .await
@@ -445,9 +675,50 @@ async fn test_dups() {
     );
 }
 
+#[ntex::test]
+async fn test_publish_packet_id_reuse() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(10000)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    for qos in [codec::QoS::AtLeastOnce, codec::QoS::ExactlyOnce] {
+        let io = srv.connect().await.unwrap();
+        let codec = codec::Codec::default();
+        io.send(
+            codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+            &codec,
+        )
+        .await
+        .unwrap();
+        let _ = io.recv(&codec).await.unwrap().unwrap();
+
+        io.send(codec::Publish { qos, ..pkt_publish() }.into(), &codec).await.unwrap();
+
+        // new publish with in-flight packet id
+        io.send(codec::Publish { qos, ..pkt_publish() }.into(), &codec).await.unwrap();
+
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        match pkt {
+            codec::Packet::Disconnect(pkt) => {
+                assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ProtocolError);
+            }
+            _ => panic!("Expected disconnect packet, got {:?}", pkt),
+        }
+    }
+}
+
 #[ntex::test]
 async fn test_max_receive() {
+    let reasons = Arc::new(Mutex::new(Vec::new()));
+    let reasons2 = reasons.clone();
+
     let srv = server::test_server(move || {
+        let reasons = reasons2.clone();
         MqttServer::new(handshake)
             .receive_max(1)
             .max_qos(codec::QoS::AtLeastOnce)
@@ -457,6 +728,10 @@ async fn test_max_receive() {
             })
             .control(move |msg| match msg {
                 ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                ControlMessage::ServerDisconnecting(msg) => {
+                    reasons.lock().unwrap().push(msg.packet().reason_code);
+                    Ready::Ok(msg.ack())
+                }
                 _ => Ready::Ok(msg.disconnect()),
             })
             .finish()
@@ -505,6 +780,10 @@ async fn test_max_receive() {
             user_properties: Default::default(),
         })
     );
+    assert_eq!(
+        *reasons.lock().unwrap(),
+        vec![codec::DisconnectReasonCode::ReceiveMaximumExceeded]
+    );
 }
 
 #[ntex::test]
@@ -575,11 +854,11 @@ async fn test_keepalive2() {
 
     assert!(sink.is_open());
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
     sleep(Duration::from_millis(500)).await;
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
     sleep(Duration::from_millis(2000)).await;
 
@@ -630,7 +909,7 @@ async fn test_sink_encoder_error_pub_qos1() {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
 }
 
@@ -675,7 +954,7 @@ async fn test_sink_encoder_error_pub_qos0() {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
 }
 
@@ -712,7 +991,7 @@ async fn test_request_problem_info() {
     ntex::rt::spawn(client.start_default());
 
     let res = sink
-        .publish(ByteString::from_static("#"), Bytes::new())
+        .publish(ByteString::from_static("test"), Bytes::new())
         .send_at_least_once()
         .await
         .unwrap();
@@ -780,56 +1059,3833 @@ async fn test_suback_with_reason() -> std::io::Result<()> {
 }
 
 #[ntex::test]
-async fn test_handle_incoming() -> std::io::Result<()> {
-    let publish = Arc::new(AtomicBool::new(false));
-    let publish2 = publish.clone();
-    let disconnect = Arc::new(AtomicBool::new(false));
-    let disconnect2 = disconnect.clone();
+async fn test_suback_after_store() -> std::io::Result<()> {
+    // store fails for topic filters starting with `fail`
+    async fn store(topic: ByteString) -> Result<(), ()> {
+        sleep(Duration::from_millis(200)).await;
+        if topic.starts_with("fail") {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
 
     let srv = server::test_server(move || {
-        let publish = publish2.clone();
-        let disconnect = disconnect2.clone();
         MqttServer::new(handshake)
-            .publish(move |p: Publish| {
-                publish.store(true, Relaxed);
-                Ready::Ok::<_, TestError>(p.ack())
+            .control(move |msg: ControlMessage<TestError>| async move {
+                match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut sub in msg.iter_mut() {
+                            match store(sub.topic().clone()).await {
+                                Ok(_) => sub.confirm(sub.qos()),
+                                Err(_) => sub.fail(
+                                    codec::SubscribeAckReason::ImplementationSpecificError,
+                                ),
+                            }
+                        }
+                        Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ok(msg.disconnect()),
+                }
             })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let start = Instant::now();
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![("topic1".into(), opts.clone()), ("fail/topic".into(), opts)],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+
+    // suback is sent after store completes for both filters
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![
+                codec::SubscribeAckReason::GrantedQos1,
+                codec::SubscribeAckReason::ImplementationSpecificError
+            ],
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscription_ttl() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .subscription_ttl(Seconds(1))
             .control(move |msg| match msg {
-                ControlMessage::Disconnect(msg) => {
-                    disconnect.store(true, Relaxed);
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| s.confirm(s.qos()));
                     Ready::Ok::<_, TestError>(msg.ack())
                 }
                 _ => Ready::Ok(msg.disconnect()),
             })
+            .publish(fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    // deliver to subscriber if subscription is active
+                    let sink = session.sink();
+                    let topic = if sink.is_subscribed("t/1") { "t/1" } else { "expired" };
+                    sink.publish(ByteString::from_static(topic), p.payload().clone())
+                        .send_at_most_once()
+                        .unwrap();
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
             .finish()
     });
 
     let io = srv.connect().await.unwrap();
-    let codec = codec::Codec::default();
-    io.encode(
+    let codec = codec::Codec::new();
+    io.send(
         codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
         &codec,
     )
+    .await
     .unwrap();
-    io.encode(pkt_publish().into(), &codec).unwrap();
-    io.encode(
-        codec::Packet::Disconnect(codec::Disconnect {
-            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
-            session_expiry_interval_secs: None,
-            server_reference: None,
-            reason_string: None,
-            user_properties: Default::default(),
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(
+                "t/#".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtMostOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+            id: None,
+            user_properties: codec::UserProperties::default(),
         }),
         &codec,
     )
+    .await
     .unwrap();
-    io.flush(true).await.unwrap();
-    sleep(Duration::from_millis(50)).await;
-    drop(io);
-    sleep(Duration::from_millis(50)).await;
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck(_)));
 
-    assert!(publish.load(Relaxed));
-    assert!(disconnect.load(Relaxed));
+    let publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtMostOnce,
+        topic: ByteString::from_static("test"),
+        packet_id: None,
+        payload: Bytes::from_static(b"data"),
+        properties: Default::default(),
+    };
+
+    // subscription is active
+    io.send(codec::Packet::Publish(publish.clone()), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Publish(pkt) = pkt {
+        assert_eq!(pkt.topic, "t/1");
+    } else {
+        panic!("unexpected packet: {:?}", pkt);
+    }
+
+    // subscription is expired
+    sleep(Duration::from_millis(1100)).await;
+    io.send(codec::Packet::Publish(publish), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Publish(pkt) = pkt {
+        assert_eq!(pkt.topic, "expired");
+    } else {
+        panic!("unexpected packet: {:?}", pkt);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_idempotency() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .idempotency(ByteString::from_static("idempotency-key"), Seconds(10))
+            .publish(move |p: Publish| {
+                // first attempt of publish "5" fails
+                let mut received = received.lock().unwrap();
+                let failed = p.payload().as_ref() == b"5" && !received.contains(p.payload());
+                received.push(p.payload().clone());
+                if failed {
+                    Ready::Ok::<_, TestError>(PublishAck::new(
+                        codec::PublishAckReason::UnspecifiedError,
+                    ))
+                } else {
+                    Ready::Ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let publish = |id: u16, qos, key: &'static str, payload: &'static [u8]| {
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos,
+            topic: ByteString::from_static("test"),
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::from_static(payload),
+            properties: codec::PublishProperties {
+                user_properties: vec![(
+                    ByteString::from_static("idempotency-key"),
+                    ByteString::from_static(key),
+                )],
+                ..Default::default()
+            },
+        })
+    };
+    let ack = |id: u16| codec::PublishAck {
+        packet_id: NonZeroU16::new(id).unwrap(),
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
 
+    io.send(publish(1, codec::QoS::AtLeastOnce, "k1", b"1"), &codec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), codec::Packet::PublishAck(ack(1)));
+
+    // same idempotency key, different packet id
+    io.send(publish(2, codec::QoS::AtLeastOnce, "k1", b"2"), &codec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), codec::Packet::PublishAck(ack(2)));
+
+    // qos2 publish with same idempotency key completes exchange
+    io.send(publish(3, codec::QoS::ExactlyOnce, "k1", b"3"), &codec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), codec::Packet::PublishReceived(ack(3)));
+    io.send(
+        codec::Packet::PublishRelease(codec::PublishAck2 {
+            packet_id: NonZeroU16::new(3).unwrap(),
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::PublishComplete(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::PublishAck2Reason::Success);
+    } else {
+        panic!("unexpected packet: {:?}", pkt);
+    }
+
+    // different idempotency key
+    io.send(publish(4, codec::QoS::AtLeastOnce, "k2", b"4"), &codec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), codec::Packet::PublishAck(ack(4)));
+
+    // key of failed publish is not recorded, retry is processed
+    io.send(publish(5, codec::QoS::AtLeastOnce, "k3", b"5"), &codec).await.unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::PublishAck(pkt) => {
+            assert_eq!(pkt.reason_code, codec::PublishAckReason::UnspecifiedError)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+    io.send(publish(6, codec::QoS::AtLeastOnce, "k3", b"5"), &codec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), codec::Packet::PublishAck(ack(6)));
+    io.send(publish(7, codec::QoS::AtLeastOnce, "k3", b"5"), &codec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap(), codec::Packet::PublishAck(ack(7)));
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![
+            Bytes::from_static(b"1"),
+            Bytes::from_static(b"4"),
+            Bytes::from_static(b"5"),
+            Bytes::from_static(b"5")
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handle_incoming() -> std::io::Result<()> {
+    let publish = Arc::new(AtomicBool::new(false));
+    let publish2 = publish.clone();
+    let disconnect = Arc::new(AtomicBool::new(false));
+    let disconnect2 = disconnect.clone();
+
+    let srv = server::test_server(move || {
+        let publish = publish2.clone();
+        let disconnect = disconnect2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                publish.store(true, Relaxed);
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Disconnect(msg) => {
+                    disconnect.store(true, Relaxed);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.encode(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .unwrap();
+    io.encode(pkt_publish().into(), &codec).unwrap();
+    io.encode(
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::ReceiveMaximumExceeded,
+            session_expiry_interval_secs: None,
+            server_reference: None,
+            reason_string: None,
+            user_properties: Default::default(),
+        }),
+        &codec,
+    )
+    .unwrap();
+    io.flush(true).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    drop(io);
+    sleep(Duration::from_millis(50)).await;
+
+    assert!(publish.load(Relaxed));
+    assert!(disconnect.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_resource_confirmed() -> std::io::Result<()> {
+    let confirmed = Arc::new(AtomicUsize::new(0));
+    let confirmed2 = confirmed.clone();
+
+    let srv = server::test_server(move || {
+        let confirmed = confirmed2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(fn_factory_with_config(|_: Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }))
+                .resource_confirmed(
+                    "confirmed",
+                    |p: Publish| Ready::Ok::<_, TestError>(p.ack()),
+                    move |_: &ByteString, _: &PublishAck| {
+                        confirmed.fetch_add(1, Relaxed);
+                    },
+                ),
+            )
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for _ in 0..3 {
+        sink.publish(ByteString::from_static("confirmed"), Bytes::new())
+            .send_at_most_once()
+            .unwrap();
+    }
+    let res =
+        sink.publish(ByteString::from_static("other"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(confirmed.load(Relaxed), 3);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_multi_match() -> std::io::Result<()> {
+    let first = Arc::new(AtomicUsize::new(0));
+    let first2 = first.clone();
+    let second = Arc::new(AtomicUsize::new(0));
+    let second2 = second.clone();
+
+    let srv = server::test_server(move || {
+        let first = first2.clone();
+        let second = second2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(fn_factory_with_config(|_: Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }))
+                .multi_match(true)
+                .resource("topic1", move |p: Publish| {
+                    first.fetch_add(1, Relaxed);
+                    Ready::Ok::<_, TestError>(p.ack())
+                })
+                .resource(["topic1", "topic2"], move |p: Publish| {
+                    second.fetch_add(1, Relaxed);
+                    Ready::Ok::<_, TestError>(p.ack())
+                }),
+            )
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("topic1"), Bytes::new())
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(first.load(Relaxed), 1);
+    assert_eq!(second.load(Relaxed), 1);
+
+    let res = sink
+        .publish(ByteString::from_static("topic2"), Bytes::new())
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(first.load(Relaxed), 1);
+    assert_eq!(second.load(Relaxed), 2);
+
+    // single ack per publish, connection is still in consistent state
+    assert!(sink.is_open());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sub_rate_limit() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .sub_rate_limit(2, 2)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    for id in 1..4 {
+        io.encode(pkt_subscribe(id), &codec).unwrap();
+    }
+    io.flush(true).await.unwrap();
+
+    // burst of two packets is processed immediately
+    for id in 1..3 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        if let codec::Packet::SubscribeAck(ack) = pkt {
+            assert_eq!(ack.packet_id.get(), id);
+        } else {
+            panic!("unexpected packet: {:?}", pkt);
+        }
+    }
+
+    // third packet waits for next token, 500 millis at 2 packets per second
+    let res = ntex::time::timeout(ntex::time::Millis(200), io.recv(&codec)).await;
+    assert!(res.is_err());
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::SubscribeAck(ack) = pkt {
+        assert_eq!(ack.packet_id.get(), 3);
+    } else {
+        panic!("unexpected packet: {:?}", pkt);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sub_rate_limit_disconnect() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .sub_rate_limit(1, 2)
+            .sub_rate_limit_disconnect(true)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                ControlMessage::ServerDisconnecting(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    for id in 1..3 {
+        io.send(pkt_subscribe(id), &codec).await.unwrap();
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert!(matches!(pkt, codec::Packet::SubscribeAck(_)));
+    }
+
+    io.send(pkt_subscribe(3), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::QuotaExceeded);
+    } else {
+        panic!("unexpected packet: {:?}", pkt);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_server_disconnecting() -> std::io::Result<()> {
+    let reasons = Arc::new(Mutex::new(Vec::new()));
+    let reasons2 = reasons.clone();
+
+    let srv = server::test_server(move || {
+        let reasons = reasons2.clone();
+        MqttServer::new(handshake)
+            .sub_rate_limit(1, 1)
+            .sub_rate_limit_disconnect(true)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                ControlMessage::ServerDisconnecting(msg) => {
+                    reasons.lock().unwrap().push(msg.packet().reason_code);
+                    Ready::Ok(
+                        msg.reason_code(codec::DisconnectReasonCode::ServerBusy)
+                            .reason_string(ByteString::from_static("slow down"))
+                            .ack(),
+                    )
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(pkt_subscribe(1), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck(_)));
+
+    io.send(pkt_subscribe(2), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ServerBusy);
+        assert_eq!(pkt.reason_string, Some(ByteString::from_static("slow down")));
+    } else {
+        panic!("unexpected packet: {:?}", pkt);
+    }
+    assert_eq!(*reasons.lock().unwrap(), vec![codec::DisconnectReasonCode::QuotaExceeded]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_wait_subscriptions_ready() -> std::io::Result<()> {
+    let acked = Arc::new(AtomicUsize::new(0));
+    let acked2 = acked.clone();
+
+    let srv = server::test_server(move || {
+        let acked = acked2.clone();
+        MqttServer::new(handshake)
+            .control(move |msg| {
+                let acked = acked.clone();
+                async move {
+                    match msg {
+                        ControlMessage::Subscribe(msg) => {
+                            sleep(Duration::from_millis(50)).await;
+                            acked.fetch_add(1, Relaxed);
+                            Ok::<_, TestError>(msg.ack())
+                        }
+                        _ => Ok(msg.disconnect()),
+                    }
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    // no in-flight subscriptions
+    assert!(client.wait_subscriptions_ready().await);
+
+    for idx in 0..3 {
+        let sink = sink.clone();
+        ntex::rt::spawn(async move {
+            sink.subscribe(None)
+                .topic_filter(
+                    ByteString::from(format!("topic{}", idx)),
+                    codec::SubscriptionOptions {
+                        qos: codec::QoS::AtLeastOnce,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: codec::RetainHandling::AtSubscribe,
+                    },
+                )
+                .send()
+                .await
+                .unwrap();
+        });
+    }
+    sleep(Duration::from_millis(10)).await;
+
+    let ready = client.wait_subscriptions_ready();
+    ntex::rt::spawn(client.start_default());
+
+    assert!(ready.await);
+    assert_eq!(acked.load(Relaxed), 3);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_topic_alias() -> std::io::Result<()> {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let reasons = Arc::new(Mutex::new(Vec::new()));
+    let reasons2 = reasons.clone();
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        let reasons = reasons2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                topics.lock().unwrap().push(p.publish_topic().to_string());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                ControlMessage::ServerDisconnecting(msg) => {
+                    reasons.lock().unwrap().push(msg.packet().reason_code);
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // establish alias
+    let mut pkt = pkt_publish();
+    pkt.properties.topic_alias = NonZeroU16::new(1);
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+
+    // alias only publish
+    let mut pkt = pkt_publish();
+    pkt.packet_id = NonZeroU16::new(2);
+    pkt.topic = ByteString::default();
+    pkt.properties.topic_alias = NonZeroU16::new(1);
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+    assert_eq!(*topics.lock().unwrap(), vec!["test".to_string(), "test".to_string()]);
+
+    // empty topic without alias
+    let mut pkt = pkt_publish();
+    pkt.packet_id = NonZeroU16::new(3);
+    pkt.topic = ByteString::default();
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ProtocolError);
+    } else {
+        panic!("unexpected packet: {:?}", pkt);
+    }
+    assert_eq!(*reasons.lock().unwrap(), vec![codec::DisconnectReasonCode::ProtocolError]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_qos2_retransmit() -> std::io::Result<()> {
+    let count = Arc::new(AtomicUsize::new(0));
+    let count2 = count.clone();
+
+    let srv = server::test_server(move || {
+        let count = count2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                count.fetch_add(1, Relaxed);
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pubrec = codec::Packet::PublishReceived(codec::PublishAck {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    });
+
+    io.send(codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() }.into(), &codec)
+        .await
+        .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, pubrec);
+
+    // retransmit before PUBREL
+    io.send(
+        codec::Publish { dup: true, qos: codec::QoS::ExactlyOnce, ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, pubrec);
+
+    io.send(
+        codec::Packet::PublishRelease(codec::PublishAck2 {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+    assert_eq!(count.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[derive(Clone)]
+struct MockClock(Arc<Mutex<(Instant, Vec<Waker>)>>);
+
+impl MockClock {
+    fn new() -> Self {
+        MockClock(Arc::new(Mutex::new((Instant::now(), Vec::new()))))
+    }
+
+    fn advance(&self, dur: Duration) {
+        let mut inner = self.0.lock().unwrap();
+        inner.0 += dur;
+        for waker in inner.1.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl TimeSource for MockClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().0
+    }
+
+    fn sleep(&self, dur: Duration) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(MockSleep { clock: self.clone(), deadline: self.now() + dur })
+    }
+}
+
+struct MockSleep {
+    clock: MockClock,
+    deadline: Instant,
+}
+
+impl Future for MockSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut inner = self.clock.0.lock().unwrap();
+        if inner.0 >= self.deadline {
+            Poll::Ready(())
+        } else {
+            inner.1.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[ntex::test]
+async fn test_keepalive_time_source() -> std::io::Result<()> {
+    let ka = Arc::new(AtomicBool::new(false));
+    let ka2 = ka.clone();
+    let clock = MockClock::new();
+    let clock2 = clock.clone();
+
+    let srv = server::test_server(move || {
+        let ka = ka2.clone();
+
+        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St).keep_alive(1)) })
+            .time_source(clock2.clone())
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    if let &error::ProtocolError::KeepAliveTimeout = msg.get_ref() {
+                        ka.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Ping(msg) => Ready::Ok(msg.ack()),
+                ControlMessage::ServerDisconnecting(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // activity resets keep-alive timer
+    clock.advance(Duration::from_millis(700));
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    clock.advance(Duration::from_millis(700));
+    sleep(Duration::from_millis(50)).await;
+    assert!(!ka.load(Relaxed));
+
+    // no activity within keep-alive interval
+    clock.advance(Duration::from_millis(700));
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = pkt {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::KeepAliveTimeout);
+    } else {
+        panic!("unexpected packet: {:?}", pkt);
+    }
+    assert!(ka.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_expiry_time_source() -> std::io::Result<()> {
+    let ended = Arc::new(Mutex::new(Vec::new()));
+    let ended2 = ended.clone();
+    let clock = MockClock::new();
+    let clock2 = clock.clone();
+
+    let srv = server::test_server(move || {
+        let ended = ended2.clone();
+        MqttServer::new(handshake)
+            .time_source(clock2.clone())
+            .on_session_end(move |info| {
+                ended.lock().unwrap().push((info.client_id().to_string(), info.is_expired()));
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect {
+            session_expiry_interval_secs: Some(3600),
+            ..codec::Connect::default().client_id("user")
+        })),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::Disconnect(codec::Disconnect::default()), &codec).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    // session expiry is driven by time source
+    clock.advance(Duration::from_secs(3599));
+    sleep(Duration::from_millis(100)).await;
+    assert!(ended.lock().unwrap().is_empty());
+
+    clock.advance(Duration::from_secs(1));
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(*ended.lock().unwrap(), vec![("user".to_string(), true)]);
+
+    Ok(())
+}
+
+fn upstream(name: &'static str, received: Arc<Mutex<Vec<Bytes>>>) -> server::TestServer {
+    server::test_server(move || {
+        let received = received.clone();
+        MqttServer::new(move |hs: Handshake| {
+            let sink = hs.sink();
+            ntex::rt::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                let _ = sink
+                    .publish(
+                        ByteString::from_static("upstream"),
+                        Bytes::from_static(name.as_bytes()),
+                    )
+                    .send_at_most_once();
+            });
+            Ready::Ok::<_, TestError>(hs.ack(St))
+        })
+        .publish(move |p: Publish| {
+            received.lock().unwrap().push(p.payload().clone());
+            Ready::Ok::<_, TestError>(p.ack())
+        })
+        .finish()
+    })
+}
+
+#[ntex::test]
+async fn test_client_pool() -> std::io::Result<()> {
+    let received_a = Arc::new(Mutex::new(Vec::new()));
+    let received_b = Arc::new(Mutex::new(Vec::new()));
+    let srv_a = upstream("a", received_a.clone());
+    let srv_b = upstream("b", received_b.clone());
+
+    let mut pool = client::ClientPool::new();
+    assert!(pool.is_empty());
+    pool.add(
+        "a",
+        client::MqttConnector::new(srv_a.addr()).client_id("a").connect().await.unwrap(),
+    );
+    pool.add(
+        "b",
+        client::MqttConnector::new(srv_b.addr()).client_id("b").connect().await.unwrap(),
+    );
+    assert_eq!(pool.len(), 2);
+
+    // publishes are tagged with source connection
+    let mut sources = Vec::new();
+    for _ in 0..2 {
+        let (id, publish) = pool.recv().await.unwrap();
+        assert_eq!(publish.publish_topic(), "upstream");
+        assert_eq!(publish.payload(), &Bytes::from_static(id.as_bytes()));
+        sources.push(id);
+    }
+    sources.sort_unstable();
+    assert_eq!(sources, vec!["a", "b"]);
+
+    // route publish to specific upstream
+    pool.publish(&"b", ByteString::from_static("test"), Bytes::from_static(b"to-b"))
+        .unwrap()
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert!(received_a.lock().unwrap().is_empty());
+    assert_eq!(*received_b.lock().unwrap(), vec![Bytes::from_static(b"to-b")]);
+    assert!(pool.publish(&"c", ByteString::from_static("test"), Bytes::new()).is_none());
+
+    // removed client gets disconnected
+    let sink = pool.remove(&"a").unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert!(!sink.is_open());
+    assert_eq!(pool.len(), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_enhanced_auth() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        fn_service(|io: ntex::io::Io| async move {
+            let codec = codec::Codec::default();
+            let pkt = io.recv(&codec).await.unwrap().unwrap();
+            if let codec::Packet::Connect(pkt) = pkt {
+                assert_eq!(pkt.auth_method, Some(ByteString::from_static("test")));
+                assert_eq!(pkt.auth_data, Some(Bytes::from_static(b"init")));
+            } else {
+                panic!("unexpected packet: {:?}", pkt);
+            }
+
+            for round in 0..2 {
+                io.send(
+                    codec::Packet::Auth(codec::Auth {
+                        reason_code: codec::AuthReasonCode::ContinueAuth,
+                        auth_method: Some(ByteString::from_static("test")),
+                        auth_data: Some(Bytes::from(format!("challenge{}", round))),
+                        reason_string: None,
+                        user_properties: Default::default(),
+                    }),
+                    &codec,
+                )
+                .await
+                .unwrap();
+
+                let pkt = io.recv(&codec).await.unwrap().unwrap();
+                if let codec::Packet::Auth(pkt) = pkt {
+                    assert_eq!(pkt.auth_data, Some(Bytes::from(format!("response{}", round))));
+                } else {
+                    panic!("unexpected packet: {:?}", pkt);
+                }
+            }
+
+            io.send(
+                codec::Packet::ConnectAck(Box::new(codec::ConnectAck {
+                    reason_code: codec::ConnectAckReason::Success,
+                    auth_method: Some(ByteString::from_static("test")),
+                    ..Default::default()
+                })),
+                &codec,
+            )
+            .await
+            .unwrap();
+            Ok::<_, ()>(())
+        })
+    });
+
+    let rounds = Rc::new(Cell::new(0));
+    let rounds2 = rounds.clone();
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .enhanced_auth(
+            ByteString::from_static("test"),
+            Bytes::from_static(b"init"),
+            move |pkt: codec::Auth| {
+                let data = pkt.auth_data.unwrap();
+                assert!(data.starts_with(b"challenge"));
+                rounds2.set(rounds2.get() + 1);
+                let mut response = b"response".to_vec();
+                response.extend_from_slice(&data[9..]);
+                Ok::<_, error::ClientError>(codec::Auth {
+                    reason_code: codec::AuthReasonCode::ContinueAuth,
+                    auth_method: pkt.auth_method,
+                    auth_data: Some(Bytes::from(response)),
+                    reason_string: None,
+                    user_properties: Default::default(),
+                })
+            },
+        )
+        .connect()
+        .await
+        .unwrap();
+
+    assert_eq!(rounds.get(), 2);
+    assert_eq!(client.packet().reason_code, codec::ConnectAckReason::Success);
+    assert_eq!(client.packet().auth_method, Some(ByteString::from_static("test")));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_enhanced_auth() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|hs: Handshake| async move {
+            assert_eq!(hs.auth_method(), Some("test"));
+            assert_eq!(hs.auth_data(), Some(&b"init"[..]));
+
+            // two continue authentication rounds
+            for round in 0..2 {
+                let pkt = hs
+                    .auth(codec::Auth {
+                        reason_code: codec::AuthReasonCode::ContinueAuth,
+                        auth_method: Some(ByteString::from_static("test")),
+                        auth_data: Some(Bytes::from(format!("challenge{}", round))),
+                        ..codec::Auth::default()
+                    })
+                    .await
+                    .map_err(|_| TestError)?;
+                assert_eq!(pkt.reason_code, codec::AuthReasonCode::ContinueAuth);
+                assert_eq!(pkt.auth_data, Some(Bytes::from(format!("response{}", round))));
+            }
+            Ok::<_, TestError>(hs.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let rounds = Rc::new(Cell::new(0));
+    let rounds2 = rounds.clone();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .enhanced_auth(
+            ByteString::from_static("test"),
+            Bytes::from_static(b"init"),
+            move |pkt: codec::Auth| {
+                assert_eq!(pkt.reason_code, codec::AuthReasonCode::ContinueAuth);
+                let data = pkt.auth_data.unwrap();
+                rounds2.set(rounds2.get() + 1);
+                let mut response = b"response".to_vec();
+                response.extend_from_slice(&data[9..]);
+                Ok::<_, error::ClientError>(codec::Auth {
+                    reason_code: codec::AuthReasonCode::ContinueAuth,
+                    auth_method: pkt.auth_method,
+                    auth_data: Some(Bytes::from(response)),
+                    ..codec::Auth::default()
+                })
+            },
+        )
+        .connect()
+        .await
+        .unwrap();
+
+    assert_eq!(rounds.get(), 2);
+    assert_eq!(client.packet().reason_code, codec::ConnectAckReason::Success);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_on_route() -> std::io::Result<()> {
+    let routes = Arc::new(Mutex::new(Vec::new()));
+    let routes2 = routes.clone();
+
+    let srv = server::test_server(move || {
+        let routes = routes2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(fn_factory_with_config(|_: Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }))
+                .resource("topic1", |p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+                .resource("{name}/sub", |p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+                .on_route(move |topic, info| {
+                    routes.lock().unwrap().push((
+                        topic.to_string(),
+                        info.map(|info| {
+                            (info.index, info.path.get("name").map(|s| s.to_string()))
+                        }),
+                    ));
+                }),
+            )
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for topic in &["topic1", "test/sub", "unknown"] {
+        let res = sink
+            .publish(ByteString::from_static(topic), Bytes::new())
+            .send_at_least_once()
+            .await;
+        assert!(res.is_ok());
+    }
+
+    assert_eq!(
+        *routes.lock().unwrap(),
+        vec![
+            ("topic1".to_string(), Some((0, None))),
+            ("test/sub".to_string(), Some((1, Some("test".to_string())))),
+            ("unknown".to_string(), None),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_global_write_budget() -> std::io::Result<()> {
+    let budget = WriteBudget::new(1024 * 1024);
+    let budget2 = budget.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .global_write_budget(budget2.clone())
+            .publish(fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    if p.publish_topic() == "fill" {
+                        let payload = Bytes::from(vec![0u8; 16 * 1024 * 1024]);
+                        let _ = session.sink().publish("big", payload).send_at_most_once();
+                    }
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+    let codec = codec::Codec::default();
+
+    let io1 = srv.connect().await.unwrap();
+    io1.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user1"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io1.recv(&codec).await.unwrap().unwrap();
+
+    let io2 = srv.connect().await.unwrap();
+    io2.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user2"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io2.recv(&codec).await.unwrap().unwrap();
+
+    // fill first connection's write buffer, do not read
+    io1.send(
+        codec::Publish { topic: ByteString::from("fill"), ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    // second connection is paused
+    io2.send(pkt_publish().into(), &codec).await.unwrap();
+    let res = ntex::time::timeout(Duration::from_millis(200), io2.recv(&codec)).await;
+    assert!(res.is_err());
+    assert!(budget.used() > budget.size());
+
+    // drain first connection
+    let pkt = io1.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.topic == "big"));
+
+    let pkt = io2.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+    assert!(budget.used() <= budget.size());
+
+    Ok(())
+}
+
+async fn session_disconnect(
+    connect_expiry: Option<u32>,
+    disconnect_expiry: Option<u32>,
+) -> (u32, bool) {
+    let result = Arc::new(Mutex::new(None));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Disconnect(msg) => {
+                    *result.lock().unwrap() =
+                        Some((msg.session_expiry_interval_secs(), msg.is_session_discarded()));
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect {
+            session_expiry_interval_secs: connect_expiry,
+            ..codec::Connect::default().client_id("user")
+        })),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::NormalDisconnection,
+            session_expiry_interval_secs: disconnect_expiry,
+            server_reference: None,
+            reason_string: None,
+            user_properties: Default::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    let res = result.lock().unwrap().take();
+    res.unwrap()
+}
+
+#[ntex::test]
+async fn test_disconnect_session_expiry_discard() {
+    assert_eq!(session_disconnect(None, None).await, (0, true));
+    assert_eq!(session_disconnect(Some(0), None).await, (0, true));
+    assert_eq!(session_disconnect(Some(60), Some(0)).await, (0, true));
+
+    // session expiry cannot be set on disconnect if it was 0 on connect
+    assert_eq!(session_disconnect(None, Some(60)).await, (0, true));
+}
+
+#[ntex::test]
+async fn test_disconnect_session_expiry_persist() {
+    assert_eq!(session_disconnect(Some(60), None).await, (60, false));
+    assert_eq!(session_disconnect(Some(60), Some(120)).await, (120, false));
+}
+
+#[ntex::test]
+async fn test_publish_qos1_ack_reason() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                let ack = match p.publish_topic() {
+                    "nosubs" => p
+                        .ack()
+                        .reason_code(codec::PublishAckReason::NoMatchingSubscribers)
+                        .reason(ByteString::from_static("no subscribers"))
+                        .properties(|props| {
+                            props.push(("key".into(), "value".into()));
+                        }),
+                    "denied" => p.ack().reason_code(codec::PublishAckReason::NotAuthorized),
+                    _ => p.ack(),
+                };
+                Ready::Ok::<_, TestError>(ack)
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let ack = sink.publish_qos1("test", Bytes::new()).await.unwrap();
+    assert_eq!(ack.reason_code(), codec::PublishAckReason::Success);
+
+    let ack = sink.publish_qos1("nosubs", Bytes::new()).await.unwrap();
+    assert_eq!(ack.reason_code(), codec::PublishAckReason::NoMatchingSubscribers);
+    assert_eq!(ack.reason_string(), Some(&ByteString::from_static("no subscribers")));
+    assert_eq!(
+        ack.properties(),
+        &vec![(ByteString::from_static("key"), ByteString::from_static("value"))]
+    );
+
+    let res = sink.publish_qos1("denied", Bytes::new()).await;
+    assert!(matches!(
+        res,
+        Err(error::PublishQos1Error::Fail(ref pkt))
+            if pkt.reason_code == codec::PublishAckReason::NotAuthorized
+    ));
+
+    // plain qos1 publish treats non-success reason as failure
+    let res = sink.publish("nosubs", Bytes::new()).send_at_least_once().await;
+    assert!(matches!(res, Err(error::PublishQos1Error::Fail(_))));
+
+    sink.close();
+    Ok(())
+}
+
+#[derive(Debug)]
+struct RouteError;
+
+impl From<()> for RouteError {
+    fn from(_: ()) -> Self {
+        RouteError
+    }
+}
+
+impl From<RouteError> for TestError {
+    fn from(_: RouteError) -> Self {
+        TestError
+    }
+}
+
+impl TryFrom<RouteError> for PublishAck {
+    type Error = TestError;
+
+    fn try_from(_: RouteError) -> Result<Self, Self::Error> {
+        Ok(PublishAck::new(codec::PublishAckReason::UnspecifiedError))
+    }
+}
+
+#[ntex::test]
+async fn test_router_circuit_breaker() -> std::io::Result<()> {
+    let handled = Arc::new(AtomicUsize::new(0));
+    let handled2 = handled.clone();
+    let dead_letters = Arc::new(AtomicUsize::new(0));
+    let dead_letters2 = dead_letters.clone();
+
+    let srv = server::test_server(move || {
+        let handled = handled2.clone();
+        let dead_letters = dead_letters2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(fn_factory_with_config(move |_: Session<St>| {
+                    let dead_letters = dead_letters.clone();
+                    Ready::Ok::<_, RouteError>(fn_service(move |p: Publish| {
+                        dead_letters.fetch_add(1, Relaxed);
+                        Ready::Ok::<_, RouteError>(p.ack())
+                    }))
+                }))
+                .resource("topic1", move |p: Publish| {
+                    handled.fetch_add(1, Relaxed);
+                    if p.payload().as_ref() == b"fail" {
+                        Ready::Err(RouteError)
+                    } else {
+                        Ready::Ok(p.ack())
+                    }
+                })
+                .circuit_breaker(2, ntex::time::Millis(300)),
+            )
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // failures open breaker
+    for _ in 0..2 {
+        let res =
+            sink.publish("topic1", Bytes::from_static(b"fail")).send_at_least_once().await;
+        assert!(res.is_err());
+    }
+    assert_eq!(handled.load(Relaxed), 2);
+
+    // publishes are dead-lettered during cooldown
+    let res = sink.publish("topic1", Bytes::from_static(b"ok")).send_at_least_once().await;
+    assert!(res.is_ok());
+    assert_eq!(handled.load(Relaxed), 2);
+    assert_eq!(dead_letters.load(Relaxed), 1);
+
+    // breaker closes after cooldown
+    sleep(Duration::from_millis(400)).await;
+    let res = sink.publish("topic1", Bytes::from_static(b"ok")).send_at_least_once().await;
+    assert!(res.is_ok());
+    assert_eq!(handled.load(Relaxed), 3);
+    assert_eq!(dead_letters.load(Relaxed), 1);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_duplicated_filters() -> std::io::Result<()> {
+    let subs = Arc::new(Mutex::new(Vec::new()));
+    let subs2 = subs.clone();
+
+    let srv = server::test_server(move || {
+        let subs = subs2.clone();
+        MqttServer::new(handshake)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut s in msg.iter_mut() {
+                        let qos = s.options().qos;
+                        subs.lock().unwrap().push((s.topic().clone(), qos));
+                        s.confirm(qos);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let opts = |qos| codec::SubscriptionOptions {
+        qos,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![
+                ("a/b".into(), opts(codec::QoS::AtMostOnce)),
+                ("c/d".into(), opts(codec::QoS::AtLeastOnce)),
+                ("a/b".into(), opts(codec::QoS::ExactlyOnce)),
+            ],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![
+                codec::SubscribeAckReason::GrantedQos2,
+                codec::SubscribeAckReason::GrantedQos1,
+                codec::SubscribeAckReason::GrantedQos2,
+            ],
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+    assert_eq!(
+        *subs.lock().unwrap(),
+        vec![
+            (ByteString::from_static("c/d"), codec::QoS::AtLeastOnce),
+            (ByteString::from_static("a/b"), codec::QoS::ExactlyOnce),
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_server_stats() -> std::io::Result<()> {
+    let stats = ServerStats::new();
+    let stats2 = stats.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .stats(stats2.clone())
+            .publish(fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    let _ = session.sink().publish("echo", Bytes::new()).send_at_most_once();
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+    let codec = codec::Codec::default();
+
+    let mut conns = Vec::new();
+    for id in &["user1", "user2"] {
+        let io = srv.connect().await.unwrap();
+        io.send(
+            codec::Packet::Connect(Box::new(codec::Connect::default().client_id(*id))),
+            &codec,
+        )
+        .await
+        .unwrap();
+        let _ = io.recv(&codec).await.unwrap().unwrap();
+        conns.push(io);
+    }
+
+    for io in &conns {
+        for pid in 1..3 {
+            io.send(
+                codec::Publish { packet_id: NonZeroU16::new(pid), ..pkt_publish() }.into(),
+                &codec,
+            )
+            .await
+            .unwrap();
+        }
+        for _ in 0..4 {
+            let _ = io.recv(&codec).await.unwrap().unwrap();
+        }
+    }
+
+    assert_eq!(stats.connections(), 2);
+    assert_eq!(stats.total_connections(), 2);
+    assert_eq!(stats.messages_in(), 4);
+    assert_eq!(stats.messages_out(), 4);
+    assert_eq!(stats.inflight(), 0);
+
+    #[cfg(feature = "metrics")]
+    {
+        let metrics = stats.render_prometheus();
+        assert!(metrics.contains("ntex_mqtt_connections 2\n"));
+        assert!(metrics.contains("ntex_mqtt_connections_total 2\n"));
+        assert!(metrics.contains("ntex_mqtt_messages_received_total 4\n"));
+        assert!(metrics.contains("ntex_mqtt_messages_sent_total 4\n"));
+    }
+
+    drop(conns);
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(stats.connections(), 0);
+    assert_eq!(stats.total_connections(), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_qos2_pubrec_failure() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        MqttServer::new(handshake)
+            .publish(fn_factory_with_config(move |session: Session<St>| {
+                let results = results.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    let fut = session.sink().publish("qos2", Bytes::new()).send_exactly_once();
+                    let results = results.clone();
+                    ntex::rt::spawn(async move {
+                        let res = fut.await;
+                        results.lock().unwrap().push(res);
+                    });
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let trigger =
+        codec::Publish { qos: codec::QoS::AtMostOnce, packet_id: None, ..pkt_publish() };
+
+    // failed PUBREC terminates exchange
+    io.send(trigger.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = if let codec::Packet::Publish(pkt) = pkt {
+        assert_eq!(pkt.qos, codec::QoS::ExactlyOnce);
+        pkt.packet_id.unwrap()
+    } else {
+        panic!("Expected publish packet")
+    };
+    io.send(
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id,
+            reason_code: codec::PublishAckReason::NotAuthorized,
+            ..Default::default()
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // no PUBREL
+    let res = ntex::time::timeout(Duration::from_millis(200), io.recv(&codec)).await;
+    assert!(res.is_err());
+    assert!(matches!(
+        results.lock().unwrap()[0],
+        Err(error::PublishQos2Error::Fail(ref pkt))
+            if pkt.reason_code == codec::PublishAckReason::NotAuthorized
+    ));
+
+    // successful exchange
+    io.send(trigger.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = if let codec::Packet::Publish(pkt) = pkt {
+        pkt.packet_id.unwrap()
+    } else {
+        panic!("Expected publish packet")
+    };
+    io.send(
+        codec::Packet::PublishReceived(codec::PublishAck { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(
+        matches!(pkt, codec::Packet::PublishRelease(ref pkt) if pkt.packet_id == packet_id)
+    );
+    io.send(
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert!(results.lock().unwrap()[1].is_ok());
+
+    Ok(())
+}
+
+struct ReverseCodec;
+
+impl PayloadCodec for ReverseCodec {
+    fn decode(&self, _: &ByteString, payload: Bytes) -> Bytes {
+        payload.iter().rev().copied().collect::<Vec<_>>().into()
+    }
+
+    fn encode(&self, _: &ByteString, payload: Bytes) -> Bytes {
+        payload.iter().rev().copied().collect::<Vec<_>>().into()
+    }
+}
+
+#[ntex::test]
+async fn test_payload_codec() -> std::io::Result<()> {
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let payloads2 = payloads.clone();
+
+    let srv = server::test_server(move || {
+        let payloads = payloads2.clone();
+        MqttServer::new(handshake)
+            .payload_codec(ReverseCodec)
+            .publish(fn_factory_with_config(move |session: Session<St>| {
+                let payloads = payloads.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    payloads.lock().unwrap().push(p.payload().clone());
+                    let _ =
+                        session.sink().publish("echo", p.payload().clone()).send_at_most_once();
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish { payload: Bytes::from_static(b"cba"), ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // wire carries encoded payload
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(
+        matches!(pkt, codec::Packet::Publish(ref p) if p.payload == Bytes::from_static(b"cba"))
+    );
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+
+    // handler sees decoded payload
+    assert_eq!(*payloads.lock().unwrap(), vec![Bytes::from_static(b"abc")]);
+
+    Ok(())
+}
+
+impl From<&TestError> for codec::DisconnectReasonCode {
+    fn from(_: &TestError) -> Self {
+        codec::DisconnectReasonCode::ImplementationSpecificError
+    }
+}
+
+async fn publish_error_reason(map: bool) -> codec::DisconnectReasonCode {
+    let srv = server::test_server(move || {
+        let server = MqttServer::new(handshake)
+            .publish(|_: Publish| Ready::Err::<PublishAck, _>(TestError));
+        if map {
+            server.error_reason(|e: &TestError| e.into()).finish()
+        } else {
+            server.finish()
+        }
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Disconnect(pkt) => pkt.reason_code,
+        pkt => panic!("Expected disconnect packet: {:?}", pkt),
+    }
+}
+
+#[ntex::test]
+async fn test_publish_error_reason() {
+    assert_eq!(
+        publish_error_reason(false).await,
+        codec::DisconnectReasonCode::UnspecifiedError
+    );
+    assert_eq!(
+        publish_error_reason(true).await,
+        codec::DisconnectReasonCode::ImplementationSpecificError
+    );
+}
+
+#[ntex::test]
+async fn test_on_session_end() -> std::io::Result<()> {
+    let ended = Arc::new(Mutex::new(Vec::new()));
+    let ended2 = ended.clone();
+
+    let srv = server::test_server(move || {
+        let ended = ended2.clone();
+        MqttServer::new(handshake)
+            .on_session_end(move |info| {
+                ended.lock().unwrap().push((info.client_id().to_string(), info.is_expired()));
+            })
+            .finish()
+    });
+    let codec = codec::Codec::default();
+
+    for (client_id, expiry) in &[("user1", None), ("user2", Some(1))] {
+        let io = srv.connect().await.unwrap();
+        io.send(
+            codec::Packet::Connect(Box::new(codec::Connect {
+                session_expiry_interval_secs: *expiry,
+                ..codec::Connect::default().client_id(*client_id)
+            })),
+            &codec,
+        )
+        .await
+        .unwrap();
+        let _ = io.recv(&codec).await.unwrap().unwrap();
+        io.send(codec::Packet::Disconnect(codec::Disconnect::default()), &codec).await.unwrap();
+    }
+    sleep(Duration::from_millis(200)).await;
+
+    // session with expiry 0 ends immediately
+    assert_eq!(*ended.lock().unwrap(), vec![("user1".to_string(), false)]);
+
+    // session with expiry ends once interval elapses
+    sleep(Duration::from_millis(1200)).await;
+    assert_eq!(
+        *ended.lock().unwrap(),
+        vec![("user1".to_string(), false), ("user2".to_string(), true)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_router_matches() {
+    let router: Router<Session<St>, TestError> =
+        Router::new(fn_factory_with_config(|_: Session<St>| {
+            Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                Ready::Ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .resource("test/{item}", |p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .resource("{name}/sub", |p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .resource("test/sub", |p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .resource("other", |p: Publish| Ready::Ok::<_, TestError>(p.ack()));
+
+    let matches: Vec<_> = router
+        .matches("test/sub")
+        .map(|(idx, path)| {
+            (
+                idx,
+                path.get("item").map(|s| s.to_string()),
+                path.get("name").map(|s| s.to_string()),
+            )
+        })
+        .collect();
+    assert_eq!(
+        matches,
+        vec![
+            (0, Some("sub".to_string()), None),
+            (1, None, Some("test".to_string())),
+            (2, None, None),
+        ]
+    );
+
+    assert_eq!(router.matches("test/other").map(|(idx, _)| idx).collect::<Vec<_>>(), vec![0]);
+    assert_eq!(router.matches("unknown").count(), 0);
+}
+
+struct BusyService {
+    ready: Arc<AtomicBool>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    count: Arc<AtomicUsize>,
+}
+
+impl ntex::service::Service<Publish> for BusyService {
+    type Response = PublishAck;
+    type Error = TestError;
+    type Future = Ready<PublishAck, TestError>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), TestError>> {
+        if self.ready.load(Relaxed) {
+            Poll::Ready(Ok(()))
+        } else {
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn call(&self, p: Publish) -> Self::Future {
+        self.count.fetch_add(1, Relaxed);
+        Ready::Ok(p.ack())
+    }
+}
+
+#[ntex::test]
+async fn test_publish_backpressure() -> std::io::Result<()> {
+    let ready = Arc::new(AtomicBool::new(false));
+    let waker: Arc<Mutex<Option<Waker>>> = Arc::new(Mutex::new(None));
+    let count = Arc::new(AtomicUsize::new(0));
+
+    let (ready2, waker2, count2) = (ready.clone(), waker.clone(), count.clone());
+    let srv = server::test_server(move || {
+        let (ready, waker, count) = (ready2.clone(), waker2.clone(), count2.clone());
+        MqttServer::new(handshake)
+            .publish(fn_factory_with_config(move |_: Session<St>| {
+                Ready::Ok::<_, TestError>(BusyService {
+                    ready: ready.clone(),
+                    waker: waker.clone(),
+                    count: count.clone(),
+                })
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    for id in 1..4 {
+        io.send(
+            codec::Publish { packet_id: NonZeroU16::new(id), ..pkt_publish() }.into(),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+
+    // handler is not ready, incoming packets stay unread
+    let res = ntex::time::timeout(Duration::from_millis(200), io.recv(&codec)).await;
+    assert!(res.is_err());
+    assert_eq!(count.load(Relaxed), 0);
+
+    ready.store(true, Relaxed);
+    if let Some(waker) = waker.lock().unwrap().take() {
+        waker.wake();
+    }
+
+    for id in 1..4 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+    }
+    assert_eq!(count.load(Relaxed), 3);
+
+    Ok(())
+}
+
+async fn suback_ordering(ordering: AckOrdering) -> Vec<codec::Packet> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .suback_ordering(ordering)
+            .control(fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut s in msg.iter_mut() {
+                            s.confirm(codec::QoS::AtMostOnce);
+                        }
+                        // retained message
+                        session
+                            .sink()
+                            .publish("topic", Bytes::from_static(b"retained"))
+                            .retain()
+                            .send_at_most_once()
+                            .unwrap();
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(
+                "topic".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    vec![io.recv(&codec).await.unwrap().unwrap(), io.recv(&codec).await.unwrap().unwrap()]
+}
+
+#[ntex::test]
+async fn test_suback_ordering_after() -> std::io::Result<()> {
+    let pkts = suback_ordering(AckOrdering::After).await;
+    assert!(matches!(pkts[0], codec::Packet::Publish(_)));
+    assert_eq!(
+        pkts[1],
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![codec::SubscribeAckReason::GrantedQos0],
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_suback_ordering_before() -> std::io::Result<()> {
+    let pkts = suback_ordering(AckOrdering::Before).await;
+    assert_eq!(
+        pkts[0],
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![codec::SubscribeAckReason::GrantedQos1],
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+    assert!(matches!(pkts[1], codec::Packet::Publish(_)));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_receive_max() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    for _ in 0..5 {
+                        let fut = session
+                            .sink()
+                            .publish("topic", Bytes::from_static(b"data"))
+                            .send_at_least_once();
+                        ntex::rt::spawn(async move {
+                            let _ = fut.await;
+                        });
+                    }
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect {
+            receive_max: NonZeroU16::new(2),
+            ..codec::Connect::default().client_id("user")
+        })),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish { qos: codec::QoS::AtMostOnce, packet_id: None, ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let recv_publish = || async {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => pkt.packet_id.unwrap().get(),
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        }
+    };
+    let ack = |id| {
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    };
+
+    // only receive maximum publishes are in flight
+    assert_eq!(recv_publish().await, 1);
+    assert_eq!(recv_publish().await, 2);
+    let res = ntex::time::timeout(Duration::from_millis(200), io.recv(&codec)).await;
+    assert!(res.is_err());
+
+    // ack releases window
+    io.send(ack(1), &codec).await.unwrap();
+    assert_eq!(recv_publish().await, 3);
+    let res = ntex::time::timeout(Duration::from_millis(200), io.recv(&codec)).await;
+    assert!(res.is_err());
+
+    io.send(ack(2), &codec).await.unwrap();
+    io.send(ack(3), &codec).await.unwrap();
+    assert_eq!(recv_publish().await, 4);
+    assert_eq!(recv_publish().await, 5);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_grant_deny() -> std::io::Result<()> {
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(handshake)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    ids.lock().unwrap().push(msg.id().map(|id| id.get()));
+                    for mut s in msg.iter_mut() {
+                        if s.topic().starts_with("private/") {
+                            s.fail(codec::SubscribeAckReason::NotAuthorized);
+                        } else {
+                            let qos = s.qos();
+                            s.confirm(qos);
+                        }
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let opts = |qos| codec::SubscriptionOptions {
+        qos,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![
+                ("public/a".into(), opts(codec::QoS::AtLeastOnce)),
+                ("private/b".into(), opts(codec::QoS::AtMostOnce)),
+                ("public/c".into(), opts(codec::QoS::ExactlyOnce)),
+            ],
+            id: std::num::NonZeroU32::new(7),
+            user_properties: codec::UserProperties::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![
+                codec::SubscribeAckReason::GrantedQos1,
+                codec::SubscribeAckReason::NotAuthorized,
+                codec::SubscribeAckReason::GrantedQos2,
+            ],
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+    assert_eq!(*ids.lock().unwrap(), vec![Some(7)]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_echo() -> std::io::Result<()> {
+    let srv = server::test_server(|| ntex_mqtt::v5::echo::<TestError>().finish());
+
+    let echoed = Arc::new(Mutex::new(Vec::new()));
+    let echoed2 = echoed.clone();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(
+        client
+            .resource("echo/foo", move |p: Publish| {
+                echoed2.lock().unwrap().push(p.payload().clone());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .start_default(),
+    );
+
+    let res = sink
+        .publish(ByteString::from_static("foo"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(*echoed.lock().unwrap(), vec![Bytes::from_static(b"data")]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connection_change() -> std::io::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let events = events2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .on_connection_change(move |ev| events.lock().unwrap().push(ev))
+            .finish()
+    });
+
+    let mut sinks = Vec::new();
+    for _ in 0..3 {
+        let client =
+            client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+        sinks.push(client.sink());
+        ntex::rt::spawn(client.start_default());
+    }
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            ConnectionEvent::Connected(1),
+            ConnectionEvent::Connected(2),
+            ConnectionEvent::Connected(3)
+        ]
+    );
+
+    for sink in sinks {
+        sink.close();
+        sleep(Duration::from_millis(50)).await;
+    }
+    let events = events.lock().unwrap();
+    assert_eq!(
+        events[3..],
+        [
+            ConnectionEvent::Disconnected(2),
+            ConnectionEvent::Disconnected(1),
+            ConnectionEvent::Disconnected(0)
+        ]
+    );
+    assert_eq!(events.last().unwrap().count(), 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connection_change_workers() -> std::io::Result<()> {
+    let counter = ConnectionCounter::new();
+    let counter2 = counter.clone();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    // server with two workers, connections are distributed between workers
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let sys = ntex::rt::System::new("test-workers");
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        tx.send((sys.system(), tcp.local_addr().unwrap())).unwrap();
+        sys.run(move || {
+            server::Server::build()
+                .listen("test", tcp, move |_| {
+                    let events = events2.clone();
+                    MqttServer::new(handshake)
+                        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+                        .on_connection_change(move |ev| events.lock().unwrap().push(ev))
+                        .connection_counter(counter2.clone())
+                        .finish()
+                })?
+                .workers(2)
+                .disable_signals()
+                .run();
+            Ok(())
+        })
+    });
+    let (system, addr) = rx.recv().unwrap();
+
+    let mut sinks = Vec::new();
+    for _ in 0..4 {
+        let client =
+            client::MqttConnector::new(addr).client_id("user").connect().await.unwrap();
+        sinks.push(client.sink());
+        ntex::rt::spawn(client.start_default());
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(counter.count(), 4);
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            ConnectionEvent::Connected(1),
+            ConnectionEvent::Connected(2),
+            ConnectionEvent::Connected(3),
+            ConnectionEvent::Connected(4)
+        ]
+    );
+
+    for sink in sinks {
+        sink.close();
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(counter.count(), 0);
+    assert_eq!(events.lock().unwrap().last(), Some(&ConnectionEvent::Disconnected(0)));
+
+    system.stop();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_payload() -> std::io::Result<()> {
+    let payload = Bytes::from(vec![0x5a; 64 * 1024]);
+    let received = Arc::new(Mutex::new(None));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(fn_service(move |hnd: Handshake| {
+            *received.lock().unwrap() = hnd.will_payload().cloned();
+            Ready::Ok::<_, TestError>(hnd.ack(St))
+        }))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .last_will(codec::LastWill {
+            qos: codec::QoS::AtMostOnce,
+            retain: false,
+            topic: ByteString::from_static("will"),
+            message: payload.clone(),
+            will_delay_interval_sec: None,
+            correlation_data: None,
+            message_expiry_interval: None,
+            content_type: None,
+            user_properties: Vec::new(),
+            is_utf8_payload: None,
+            response_topic: None,
+        })
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(received.lock().unwrap().as_ref(), Some(&payload));
+
+    client.sink().close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_handle_disconnect() -> std::io::Result<()> {
+    let handle = Arc::new(Mutex::new(None));
+    let handle2 = handle.clone();
+
+    let srv = server::test_server(move || {
+        let handle = handle2.clone();
+        MqttServer::new(fn_service(move |hnd: Handshake| {
+            *handle.lock().unwrap() = Some(hnd.session_handle());
+            Ready::Ok::<_, TestError>(hnd.ack(St))
+        }))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let reason = Arc::new(Mutex::new(None));
+    let reason2 = reason.clone();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(fn_service(
+        move |msg: client::ControlMessage<()>| match msg {
+            client::ControlMessage::Disconnect(msg) => {
+                *reason2.lock().unwrap() = Some(msg.packet().reason_code);
+                Ready::Ok(msg.ack())
+            }
+            client::ControlMessage::Closed(msg) => Ready::Ok(msg.ack()),
+            client::ControlMessage::PeerGone(msg) => Ready::Ok(msg.ack()),
+            _ => Ready::Ok(msg.disconnect(codec::Disconnect::default())),
+        },
+    )));
+    sleep(Duration::from_millis(50)).await;
+    assert!(sink.is_open());
+
+    // handle could be used from other thread
+    let handle = handle.lock().unwrap().take().unwrap();
+    std::thread::spawn(move || {
+        handle.disconnect(codec::DisconnectReasonCode::AdministrativeAction);
+    })
+    .join()
+    .unwrap();
+
+    sleep(Duration::from_millis(100)).await;
+    assert!(!sink.is_open());
+    assert_eq!(
+        *reason.lock().unwrap(),
+        Some(codec::DisconnectReasonCode::AdministrativeAction)
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_auth_data() -> std::io::Result<()> {
+    let auth = Arc::new(Mutex::new(None));
+    let auth2 = auth.clone();
+
+    let srv = server::test_server(move || {
+        let auth = auth2.clone();
+        MqttServer::new(fn_service(move |hnd: Handshake| {
+            *auth.lock().unwrap() = Some((
+                hnd.auth_method().map(|s| s.to_string()),
+                hnd.auth_data().map(|d| d.to_vec()),
+            ));
+            Ready::Ok::<_, TestError>(hnd.ack(St))
+        }))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .auth(ByteString::from_static("custom"), Bytes::from_static(b"\x00\xff\x01binary"))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(
+        *auth.lock().unwrap(),
+        Some((Some("custom".to_string()), Some(b"\x00\xff\x01binary".to_vec())))
+    );
+
+    client.sink().close();
+    Ok(())
+}
+
+async fn qos_ceiling(
+    policy: QosCeilingPolicy,
+) -> (Result<codec::PublishAck, error::PublishQos1Error>, Vec<codec::QoS>) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                received.lock().unwrap().push(p.qos());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .topic_qos_ceiling(policy, |topic| {
+                if topic.starts_with("telemetry/") {
+                    codec::QoS::AtMostOnce
+                } else {
+                    codec::QoS::ExactlyOnce
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // topic without ceiling
+    let res = sink
+        .publish(ByteString::from_static("firmware/1"), Bytes::new())
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+
+    let res = sink
+        .publish(ByteString::from_static("telemetry/1"), Bytes::new())
+        .send_at_least_once()
+        .await;
+    sink.close();
+
+    let received = received.lock().unwrap().clone();
+    (res, received)
+}
+
+#[ntex::test]
+async fn test_topic_qos_ceiling_downgrade() -> std::io::Result<()> {
+    let (res, received) = qos_ceiling(QosCeilingPolicy::Downgrade).await;
+    assert!(res.is_ok());
+    assert_eq!(received, vec![codec::QoS::AtLeastOnce, codec::QoS::AtMostOnce]);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_topic_qos_ceiling_reject() -> std::io::Result<()> {
+    let (res, received) = qos_ceiling(QosCeilingPolicy::Reject).await;
+    match res {
+        Err(error::PublishQos1Error::Fail(ack)) => {
+            assert_eq!(ack.reason_code, codec::PublishAckReason::ImplementationSpecificError)
+        }
+        _ => panic!("publish must be rejected"),
+    }
+    assert_eq!(received, vec![codec::QoS::AtLeastOnce]);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_inflight_qos2() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(100)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .max_inflight_qos2(1)
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // qos1 publishes are not affected
+    let futs: Vec<_> = (0..3)
+        .map(|_| {
+            sink.publish(ByteString::from_static("qos1"), Bytes::new()).send_at_least_once()
+        })
+        .collect();
+    for fut in futs {
+        assert!(fut.await.is_ok());
+    }
+
+    // single qos2 exchange
+    let res =
+        sink.publish(ByteString::from_static("qos2"), Bytes::new()).send_exactly_once().await;
+    assert!(res.is_ok());
+
+    // second concurrent qos2 exchange
+    for _ in 0..2 {
+        let fut =
+            sink.publish(ByteString::from_static("qos2"), Bytes::new()).send_exactly_once();
+        ntex::rt::spawn(async move {
+            let _ = fut.await;
+        });
+    }
+    sleep(Duration::from_millis(50)).await;
+    assert!(!sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_server_limits() -> std::io::Result<()> {
+    let limits = ServerLimits {
+        max_size: 1024,
+        receive_max: 4,
+        max_topic_alias: 8,
+        max_qos: Some(codec::QoS::AtLeastOnce),
+        ..ServerLimits::default()
+    };
+    assert_eq!(ServerLimits::default().receive_max, 15);
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .limits(limits)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let ack = client.packet();
+    assert_eq!(ack.max_packet_size, Some(1024));
+    assert_eq!(ack.receive_max, NonZeroU16::new(4));
+    assert_eq!(ack.topic_alias_max, 8);
+    assert_eq!(ack.max_qos, Some(codec::QoS::AtLeastOnce));
+    client.sink().close();
+
+    // individual setters override limits
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .limits(limits)
+            .receive_max(6)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let ack = client.packet();
+    assert_eq!(ack.max_packet_size, Some(1024));
+    assert_eq!(ack.receive_max, NonZeroU16::new(6));
+    client.sink().close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_keepalive() -> std::io::Result<()> {
+    let keepalive = Arc::new(Mutex::new(None));
+    let keepalive2 = keepalive.clone();
+
+    let srv = server::test_server(move || {
+        let keepalive = keepalive2.clone();
+        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St).keep_alive(5)) })
+            .publish(fn_factory_with_config(move |session: Session<St>| {
+                *keepalive.lock().unwrap() =
+                    Some((session.requested_keepalive(), session.effective_keepalive()));
+                Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(10))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(*keepalive.lock().unwrap(), Some((Seconds(10), Seconds(5))));
+
+    client.sink().close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_no_local() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        // topic filter, subscription options and sink of subscribers
+        let subs: Rc<RefCell<Vec<(ByteString, codec::SubscriptionOptions, MqttSink)>>> =
+            Rc::new(RefCell::new(Vec::new()));
+        let subs2 = subs.clone();
+
+        MqttServer::new(handshake)
+            .control(fn_factory_with_config(move |session: Session<St>| {
+                let subs = subs.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut s in msg.iter_mut() {
+                            subs.borrow_mut().push((
+                                s.topic().clone(),
+                                s.options().clone(),
+                                session.sink().clone(),
+                            ));
+                            s.confirm(codec::QoS::AtMostOnce);
+                        }
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .publish(fn_factory_with_config(move |session: Session<St>| {
+                let subs = subs2.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    for (filter, opts, sink) in subs.borrow().iter() {
+                        if &filter[..] == p.publish_topic()
+                            && sink.accepts(session.sink(), opts)
+                        {
+                            sink.publish(filter.clone(), p.payload().clone())
+                                .send_at_most_once()
+                                .unwrap();
+                        }
+                    }
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let opts = |no_local| codec::SubscriptionOptions {
+        qos: codec::QoS::AtMostOnce,
+        no_local,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+
+    let mut clients = Vec::new();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    for (id, no_local) in [("publisher", true), ("subscriber", false)] {
+        let client =
+            client::MqttConnector::new(srv.addr()).client_id(id).connect().await.unwrap();
+        let sink = client.sink();
+        let received = received.clone();
+        ntex::rt::spawn(
+            client
+                .resource("topic", move |p: Publish| {
+                    received.lock().unwrap().push(id);
+                    Ready::Ok::<_, TestError>(p.ack())
+                })
+                .start_default(),
+        );
+        sink.subscribe(None)
+            .topic_filter(ByteString::from_static("topic"), opts(no_local))
+            .send()
+            .await
+            .unwrap();
+        clients.push(sink);
+    }
+
+    clients[0]
+        .publish(ByteString::from_static("topic"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(*received.lock().unwrap(), vec!["subscriber"]);
+
+    for sink in clients {
+        sink.close();
+    }
+    Ok(())
+}
+
+async fn publish_ordering(ordering: Option<PublishOrdering>) -> Vec<String> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let events = events2.clone();
+        let srv = MqttServer::new(handshake).publish(move |p: Publish| {
+            let events = events.clone();
+            async move {
+                let name = String::from_utf8(p.payload().to_vec()).unwrap();
+                events.lock().unwrap().push(format!("start {}", name));
+                if name == "a1" {
+                    sleep(Duration::from_millis(200)).await;
+                }
+                events.lock().unwrap().push(format!("end {}", name));
+                Ok::<_, TestError>(p.ack())
+            }
+        });
+        if let Some(ordering) = ordering {
+            srv.publish_ordering(ordering).finish()
+        } else {
+            srv.finish()
+        }
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for (topic, name) in [("a", "a1"), ("a", "a2"), ("b", "b1")] {
+        sink.publish(ByteString::from_static(topic), Bytes::from_static(name.as_bytes()))
+            .send_at_most_once()
+            .unwrap();
+    }
+    sleep(Duration::from_millis(400)).await;
+    sink.close();
+
+    let mut events = events.lock().unwrap();
+    std::mem::take(&mut *events)
+}
+
+#[ntex::test]
+async fn test_publish_ordering() -> std::io::Result<()> {
+    // calls are not serialized
+    assert_eq!(
+        publish_ordering(None).await,
+        vec!["start a1", "start a2", "end a2", "start b1", "end b1", "end a1"]
+    );
+
+    // different topics overlap, same topic is serialized
+    assert_eq!(
+        publish_ordering(Some(PublishOrdering::PerTopic)).await,
+        vec!["start a1", "start b1", "end b1", "end a1", "start a2", "end a2"]
+    );
+
+    // all calls are serialized
+    assert_eq!(
+        publish_ordering(Some(PublishOrdering::Global)).await,
+        vec!["start a1", "end a1", "start a2", "end a2", "start b1", "end b1"]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_connect_size() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_connect_size(64)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    // only fixed header of 1MB connect packet is sent
+    let io = srv.connect().await.unwrap();
+    io.send(Bytes::from_static(b"\x10\x80\x80\x40"), &ntex::codec::BytesCodec).await.unwrap();
+
+    // connect is rejected without waiting for packet body
+    let codec = codec::Codec::default();
+    let pkt = ntex::time::timeout(Duration::from_millis(500), io.recv(&codec))
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    match pkt {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::PacketTooLarge)
+        }
+        _ => panic!("Expected CONNACK, got {:?}", pkt),
+    }
+
+    // small connect packet is accepted
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::Success)
+        }
+        _ => panic!("Expected CONNACK, got {:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_custom_packets() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .custom_packets(true)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::CustomPacket(pkt) => {
+                    received.lock().unwrap().push((pkt.first_byte(), pkt.payload().clone()));
+                    Ready::Ok(pkt.ack())
+                }
+                ControlMessage::Ping(ping) => Ready::Ok(ping.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // packet with reserved packet type
+    io.send(Bytes::from_static(b"\x00\x03abc"), &ntex::codec::BytesCodec).await.unwrap();
+
+    // connection is still alive
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    assert_eq!(&*received.lock().unwrap(), &[(0x00, Bytes::from_static(b"abc"))]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_take_one() -> std::io::Result<()> {
+    let unsubscribed = Arc::new(Mutex::new(Vec::new()));
+    let unsubscribed2 = unsubscribed.clone();
+    let subscribes = Arc::new(AtomicUsize::new(0));
+
+    let srv = server::test_server(move || {
+        let unsubscribed = unsubscribed2.clone();
+        let subscribes = subscribes.clone();
+        MqttServer::new(handshake)
+            .control(fn_factory_with_config(move |session: Session<St>| {
+                let unsubscribed = unsubscribed.clone();
+                let subscribes = subscribes.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut s in msg.iter_mut() {
+                            s.confirm(codec::QoS::AtLeastOnce);
+                            if &**s.topic() != "response/+" {
+                                continue;
+                            }
+                            let sink = session.sink();
+                            let n = subscribes.fetch_add(1, Relaxed);
+                            ntex::rt::spawn(async move {
+                                if n == 0 {
+                                    // not matching publish goes to publish handler
+                                    let _ = sink
+                                        .publish("news/1", Bytes::from_static(b"news"))
+                                        .send_at_least_once()
+                                        .await;
+                                    let _ = sink
+                                        .publish("response/1", Bytes::from_static(b"data"))
+                                        .properties(|p| p.topic_alias = NonZeroU16::new(1))
+                                        .send_at_least_once()
+                                        .await;
+                                } else {
+                                    // topic alias is resolved
+                                    let _ = sink
+                                        .publish("", Bytes::from_static(b"data2"))
+                                        .properties(|p| p.topic_alias = NonZeroU16::new(1))
+                                        .send_at_least_once()
+                                        .await;
+                                }
+                            });
+                        }
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    ControlMessage::Unsubscribe(msg) => {
+                        for topic in msg.iter() {
+                            unsubscribed.lock().unwrap().push(topic.to_string());
+                        }
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(fn_service(
+        move |msg: client::ControlMessage<()>| match msg {
+            client::ControlMessage::Publish(msg) => {
+                received2.lock().unwrap().push(msg.packet().topic.clone());
+                Ready::Ok(msg.ack(codec::PublishAckReason::Success))
+            }
+            client::ControlMessage::Closed(msg) => Ready::Ok(msg.ack()),
+            _ => Ready::Ok(msg.disconnect(codec::Disconnect::default())),
+        },
+    )));
+
+    let publish =
+        sink.take_one(ByteString::from_static("response/+"), Seconds(5)).await.unwrap();
+    assert_eq!(publish.publish_topic(), "response/1");
+    assert_eq!(publish.payload(), &Bytes::from_static(b"data"));
+    assert_eq!(&*unsubscribed.lock().unwrap(), &["response/+".to_string()]);
+    assert_eq!(&*received.lock().unwrap(), &[ByteString::from_static("news/1")]);
+
+    let publish =
+        sink.take_one(ByteString::from_static("response/+"), Seconds(5)).await.unwrap();
+    assert_eq!(publish.publish_topic(), "response/1");
+    assert_eq!(publish.payload(), &Bytes::from_static(b"data2"));
+
+    // no matching publish, subscription is removed after timeout
+    let res = sink.take_one(ByteString::from_static("other"), Seconds(1)).await;
+    assert!(matches!(res, Err(error::TakeOneError::Timeout)));
+    assert_eq!(
+        &*unsubscribed.lock().unwrap(),
+        &["response/+".to_string(), "response/+".to_string(), "other".to_string()]
+    );
+
+    // connection is alive
+    sink.publish("other", Bytes::new()).send_at_least_once().await.unwrap();
+    assert!(sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_pacing() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                received.lock().unwrap().push((p.payload().clone(), Instant::now()));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // 20 publishes per second, one publish every 50 millis
+    sink.pacing(20);
+    for i in 0..8u8 {
+        sink.publish(ByteString::from_static("test"), Bytes::from(vec![i]))
+            .send_at_most_once()
+            .unwrap();
+    }
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from(vec![8u8]))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(50)).await;
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 9);
+    for (i, (payload, _)) in received.iter().enumerate() {
+        assert_eq!(payload, &Bytes::from(vec![i as u8]));
+    }
+    let elapsed = received[8].1.duration_since(received[0].1);
+    assert!(elapsed >= Duration::from_millis(350));
+    assert!(elapsed < Duration::from_millis(1000));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_adaptive_pacing() -> std::io::Result<()> {
+    let rates = Arc::new(Mutex::new(Vec::new()));
+    let rates2 = rates.clone();
+
+    let srv = server::test_server(move || {
+        let rates = rates2.clone();
+        MqttServer::new(move |hs: Handshake| {
+            let sink = hs.sink();
+            let rates = rates.clone();
+            ntex::rt::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                sink.adaptive_pacing(50, ntex::time::Millis(20));
+                rates.lock().unwrap().push(sink.pacing_rate().unwrap());
+                for _ in 0..4 {
+                    let res = sink
+                        .publish(ByteString::from_static("test"), Bytes::new())
+                        .send_at_least_once()
+                        .await;
+                    assert!(res.is_ok());
+                    rates.lock().unwrap().push(sink.pacing_rate().unwrap());
+                }
+                for _ in 0..3 {
+                    sink.publish(ByteString::from_static("test"), Bytes::new())
+                        .send_at_most_once()
+                        .unwrap();
+                }
+            });
+            Ready::Ok::<_, TestError>(hs.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // mock client delays acks more and more
+    for delay in [10, 100, 200, 400] {
+        let packet_id = match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+            pkt => panic!("Expected publish packet, got {:?}", pkt),
+        };
+        sleep(Duration::from_millis(delay)).await;
+        io.send(
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id,
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            }),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+
+    // server sends slower
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        let _ = io.recv(&codec).await.unwrap().unwrap();
+        received.push(Instant::now());
+    }
+    assert!(received[2].duration_since(received[0]) >= Duration::from_millis(150));
+
+    let rates = rates.lock().unwrap();
+    assert_eq!(rates[0], 50);
+    assert!(rates.windows(2).all(|w| w[1] <= w[0]));
+    assert!(rates[4] < rates[1]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_session_present() -> std::io::Result<()> {
+    for present in [true, false] {
+        let srv = server::test_server(move || {
+            MqttServer::new(move |hs: Handshake| {
+                Ready::Ok::<_, TestError>(hs.ack(St).with(|ack| ack.session_present = present))
+            })
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+        });
+
+        let client =
+            client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+        assert_eq!(client.session_present(), present);
+        assert_eq!(client.packet().session_present, present);
+    }
+
+    Ok(())
+}
+
+async fn retained_replay(rate: u16) -> (server::TestServer, ntex::io::Io, Vec<codec::Packet>) {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .retained_replay_limit(3)
+            .retained_replay_rate(rate)
+            .control(fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut s in msg.iter_mut() {
+                            s.confirm(codec::QoS::AtMostOnce);
+                        }
+                        let retained = (0..10).map(|i| codec::Publish {
+                            retain: true,
+                            qos: codec::QoS::AtMostOnce,
+                            topic: ByteString::from(format!("topic/{}", i)),
+                            packet_id: None,
+                            ..pkt_publish()
+                        });
+                        assert_eq!(session.sink().replay_retained(retained), 3);
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(
+                "topic/#".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtMostOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // retained messages sent immediately precede SUBACK
+    let mut pkts = Vec::new();
+    loop {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::SubscribeAck(_) => break,
+            pkt => pkts.push(pkt),
+        }
+    }
+    (srv, io, pkts)
+}
+
+#[ntex::test]
+async fn test_retained_replay_limit() -> std::io::Result<()> {
+    let (_srv, io, pkts) = retained_replay(0).await;
+    assert_eq!(pkts.len(), 3);
+    assert!(pkts.iter().all(|p| matches!(p, codec::Packet::Publish(p) if p.retain)));
+
+    // messages over limit are dropped
+    let codec = codec::Codec::new();
+    let res = ntex::time::timeout(Duration::from_millis(200), io.recv(&codec)).await;
+    assert!(res.is_err());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retained_replay_rate() -> std::io::Result<()> {
+    let (_srv, io, pkts) = retained_replay(100).await;
+    assert_eq!(pkts.len(), 3);
+
+    // remaining messages are streamed
+    let codec = codec::Codec::new();
+    for i in 3..10 {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(p) => assert_eq!(p.topic, format!("topic/{}", i)),
+            pkt => panic!("Unexpected packet {:?}", pkt),
+        }
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_response_information() -> std::io::Result<()> {
+    for requested in [true, false] {
+        let srv = server::test_server(move || {
+            MqttServer::new(|hs: Handshake| {
+                Ready::Ok::<_, TestError>(
+                    hs.ack(St).response_information(ByteString::from_static("response/")),
+                )
+            })
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+        });
+
+        let io = srv.connect().await.unwrap();
+        let codec = codec::Codec::new();
+        io.send(
+            codec::Packet::Connect(Box::new(codec::Connect {
+                request_response_info: requested,
+                ..codec::Connect::default().client_id("user")
+            })),
+            &codec,
+        )
+        .await
+        .unwrap();
+
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::ConnectAck(ack) => {
+                if requested {
+                    assert_eq!(ack.response_info, Some(ByteString::from_static("response/")));
+                } else {
+                    assert_eq!(ack.response_info, None);
+                }
+            }
+            pkt => panic!("Unexpected packet {:?}", pkt),
+        }
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_negotiated() -> std::io::Result<()> {
+    let negotiated = Arc::new(Mutex::new(None));
+    let negotiated2 = negotiated.clone();
+
+    let srv = server::test_server(move || {
+        let negotiated = negotiated2.clone();
+        MqttServer::new(|hs: Handshake| {
+            Ready::Ok::<_, TestError>(
+                hs.ack(St)
+                    .keep_alive(20)
+                    .with(|ack| ack.wildcard_subscription_available = Some(false)),
+            )
+        })
+        .max_size(2048)
+        .receive_max(8)
+        .max_topic_alias(4)
+        .max_qos(codec::QoS::AtLeastOnce)
+        .publish(fn_factory_with_config(move |session: Session<St>| {
+            *negotiated.lock().unwrap() = Some(session.negotiated());
+            Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                Ready::Ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(60))
+        .max_packet_size(1024)
+        .receive_max(10)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sleep(Duration::from_millis(50)).await;
+
+    let negotiated = negotiated.lock().unwrap().take().unwrap();
+    assert_eq!(negotiated.version, ntex_mqtt::MqttVersion::V5);
+    assert_eq!(negotiated.keepalive, Seconds(20));
+    assert_eq!(negotiated.max_packet_size, Some(2048));
+    assert_eq!(negotiated.client_max_packet_size, Some(1024));
+    assert_eq!(negotiated.receive_max, 8);
+    assert_eq!(negotiated.client_receive_max, 10);
+    assert_eq!(negotiated.topic_alias_max, 4);
+    assert_eq!(negotiated.max_qos, codec::QoS::AtLeastOnce);
+    assert!(negotiated.retain_available);
+    assert!(!negotiated.wildcard_subscription_available);
+    assert!(negotiated.shared_subscription_available);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connack_defaults() -> std::io::Result<()> {
+    for overridden in [false, true] {
+        let srv = server::test_server(move || {
+            MqttServer::new(move |hs: Handshake| {
+                let ack = hs.ack(St);
+                let ack = if overridden {
+                    ack.with(|ack| ack.retain_available = Some(true))
+                } else {
+                    ack
+                };
+                Ready::Ok::<_, TestError>(ack)
+            })
+            .connack_defaults(ConnackProps {
+                max_packet_size: Some(1024),
+                receive_max: NonZeroU16::new(10),
+                topic_alias_max: Some(5),
+                max_qos: Some(codec::QoS::AtLeastOnce),
+                retain_available: Some(false),
+            })
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+        });
+
+        let io = srv.connect().await.unwrap();
+        let codec = codec::Codec::new();
+        io.send(
+            codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+            &codec,
+        )
+        .await
+        .unwrap();
+
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::ConnectAck(ack) => {
+                assert_eq!(ack.max_packet_size, Some(1024));
+                assert_eq!(ack.receive_max, NonZeroU16::new(10));
+                assert_eq!(ack.topic_alias_max, 5);
+                assert_eq!(ack.max_qos, Some(codec::QoS::AtLeastOnce));
+                assert_eq!(ack.retain_available, Some(overridden));
+            }
+            pkt => panic!("Unexpected packet {:?}", pkt),
+        }
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_retain() -> std::io::Result<()> {
+    for retain in [true, false] {
+        let will_retain = Arc::new(Mutex::new(Vec::new()));
+        let will_retain2 = will_retain.clone();
+
+        let srv = server::test_server(move || {
+            // sink of subscriber connection
+            let subscriber_sink: Rc<RefCell<Option<MqttSink>>> = Rc::new(RefCell::new(None));
+            let subscriber_sink2 = subscriber_sink.clone();
+            let will_retain = will_retain2.clone();
+            MqttServer::new(move |hs: Handshake| {
+                let will = hs.last_will().cloned();
+                if will.is_none() {
+                    *subscriber_sink.borrow_mut() = Some(hs.sink());
+                } else {
+                    will_retain.lock().unwrap().push(hs.will_retain());
+                }
+                Ready::Ok::<_, TestError>(hs.ack(will))
+            })
+            .control(fn_factory_with_config(
+                move |session: Session<Option<codec::LastWill>>| {
+                    let subscriber_sink = subscriber_sink2.clone();
+                    Ready::Ok::<_, TestError>(fn_service(move |msg| {
+                        // abnormal disconnect, publish will message
+                        if let ControlMessage::Closed(ref closed) = msg {
+                            if let Some(ref will) = session.state() {
+                                if !closed.is_clean() {
+                                    if let Some(ref sink) = *subscriber_sink.borrow() {
+                                        sink.publish_will(will).send_at_most_once().unwrap();
+                                    }
+                                }
+                            }
+                        }
+                        Ready::Ok::<_, TestError>(msg.disconnect())
+                    }))
+                },
+            ))
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+        });
+
+        let codec = codec::Codec::new();
+        let subscriber = srv.connect().await.unwrap();
+        subscriber
+            .send(
+                codec::Packet::Connect(Box::new(codec::Connect::default().client_id("sub"))),
+                &codec,
+            )
+            .await
+            .unwrap();
+        let _ = subscriber.recv(&codec).await.unwrap().unwrap();
+
+        let io = srv.connect().await.unwrap();
+        io.send(
+            codec::Packet::Connect(Box::new(codec::Connect {
+                last_will: Some(codec::LastWill {
+                    qos: codec::QoS::AtMostOnce,
+                    retain,
+                    topic: ByteString::from_static("will"),
+                    message: Bytes::from_static(b"gone"),
+                    will_delay_interval_sec: None,
+                    correlation_data: None,
+                    message_expiry_interval: None,
+                    content_type: None,
+                    user_properties: Vec::new(),
+                    is_utf8_payload: None,
+                    response_topic: None,
+                }),
+                ..codec::Connect::default().client_id("user")
+            })),
+            &codec,
+        )
+        .await
+        .unwrap();
+        let _ = io.recv(&codec).await.unwrap().unwrap();
+        assert_eq!(*will_retain.lock().unwrap(), vec![retain]);
+
+        // drop connection without DISCONNECT
+        drop(io);
+
+        match subscriber.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => {
+                assert_eq!(pkt.topic, "will");
+                assert_eq!(pkt.retain, retain);
+            }
+            pkt => panic!("Unexpected packet {:?}", pkt),
+        }
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_closed_is_clean() -> std::io::Result<()> {
+    let closed = Arc::new(Mutex::new(Vec::new()));
+    let closed2 = closed.clone();
+
+    let srv = server::test_server(move || {
+        let closed = closed2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Disconnect(msg) => Ready::Ok(msg.ack()),
+                ControlMessage::Closed(msg) => {
+                    closed.lock().unwrap().push((msg.is_clean(), msg.reason_code()));
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // client sends DISCONNECT, only normal disconnection is clean
+    let codec = codec::Codec::default();
+    for (reason_code, clean) in [
+        (codec::DisconnectReasonCode::NormalDisconnection, true),
+        (codec::DisconnectReasonCode::DisconnectWithWillMessage, false),
+        (codec::DisconnectReasonCode::UnspecifiedError, false),
+    ] {
+        let io = srv.connect().await.unwrap();
+        io.send(
+            codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+            &codec,
+        )
+        .await
+        .unwrap();
+        let _ = io.recv(&codec).await.unwrap().unwrap();
+        io.send(
+            codec::Packet::Disconnect(codec::Disconnect { reason_code, ..Default::default() }),
+            &codec,
+        )
+        .await
+        .unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(closed.lock().unwrap().pop(), Some((clean, Some(reason_code))));
+    }
+
+    // connection is dropped
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    drop(io);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(closed.lock().unwrap().pop(), Some((false, None)));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_lenient_utf8() -> std::io::Result<()> {
+    // PUBLISH with invalid utf-8 value of user property
+    let pkt = Bytes::from_static(b"\x30\x0d\x00\x01t\x08\x26\x00\x01k\x00\x02\xc3\x28p");
+
+    // strict mode
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(pkt.clone(), &ntex::codec::BytesCodec).await.unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::MalformedPacket);
+        }
+        pkt => panic!("Expected disconnect packet, got {:?}", pkt),
+    }
+
+    // lenient mode
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .lenient_utf8(true)
+            .publish(move |p: Publish| {
+                received.lock().unwrap().extend(p.packet().properties.user_properties.clone());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(pkt, &ntex::codec::BytesCodec).await.unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    assert_eq!(
+        &*received.lock().unwrap(),
+        &[(ByteString::from_static("k"), ByteString::from_static("\u{FFFD}("))]
+    );
+
+    // topic name with invalid utf-8 is rejected in lenient mode
+    io.send(Bytes::from_static(b"\x30\x06\x00\x02\xc3\x28\x00p"), &ntex::codec::BytesCodec)
+        .await
+        .unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::MalformedPacket);
+        }
+        pkt => panic!("Expected disconnect packet, got {:?}", pkt),
+    }
+    assert_eq!(received.lock().unwrap().len(), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_require_tls() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .require_tls(true)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    // plain connection is rejected
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::NotAuthorized)
+        }
+        pkt => panic!("Unexpected packet {:?}", pkt),
+    }
+    assert!(!matches!(io.recv(&codec).await, Ok(Some(_))));
+
+    // custom tls check
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .require_tls_with(|_| true)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::Success)
+        }
+        pkt => panic!("Unexpected packet {:?}", pkt),
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_require_tls_openssl() -> std::io::Result<()> {
+    use ntex::service::{pipeline_factory, Service, ServiceFactory};
+    use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+
+    let srv = server::test_server(|| {
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key_file("./tests/key.pem", SslFiletype::PEM).unwrap();
+        acceptor.set_certificate_chain_file("./tests/cert.pem").unwrap();
+        pipeline_factory(server::openssl::Acceptor::new(acceptor.build()).map_err(|_| ()))
+            .and_then(
+                MqttServer::new(handshake)
+                    .require_tls(true)
+                    .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+                    .finish()
+                    .map_err(|_| ())
+                    .map_init_err(|_| ()),
+            )
+    });
+    // tls connection without client certificate is accepted
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    let con = ntex::connect::openssl::Connector::new(builder.build());
+    let addr = format!("127.0.0.1:{}", srv.addr().port());
+    let io = con.call(addr.into()).await.unwrap();
+
+    let codec = codec::Codec::new();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::Success)
+        }
+        pkt => panic!("Unexpected packet {:?}", pkt),
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_replay() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|hs: Handshake| {
+            let resumed = !hs.packet().clean_start;
+            let sink = hs.sink();
+            ntex::rt::spawn(async move {
+                let topics =
+                    if resumed { vec!["offline1", "offline2"] } else { vec!["inflight"] };
+                for topic in topics {
+                    let fut = sink
+                        .publish(ByteString::from_static(topic), Bytes::new())
+                        .send_at_least_once();
+                    ntex::rt::spawn(async move {
+                        let _ = fut.await;
+                    });
+                }
+            });
+            Ready::Ok::<_, TestError>(hs.ack(St).with(|ack| ack.session_present = resumed))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let connect = |clean_start| {
+        let mut pkt = codec::Connect::default().client_id("user");
+        pkt.clean_start = clean_start;
+        pkt.session_expiry_interval_secs = Some(60);
+        codec::Packet::Connect(Box::new(pkt))
+    };
+
+    // connection is closed before PUBACK
+    let io = srv.connect().await.unwrap();
+    io.send(connect(true), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.topic, "inflight");
+            assert!(!pkt.dup);
+            pkt.packet_id
+        }
+        pkt => panic!("Unexpected packet {:?}", pkt),
+    };
+    io.close();
+    sleep(Duration::from_millis(50)).await;
+
+    // un-acked publish is re-sent with dup flag before offline publishes
+    let io = srv.connect().await.unwrap();
+    io.send(connect(false), &codec).await.unwrap();
+    match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => assert!(ack.session_present),
+        pkt => panic!("Unexpected packet {:?}", pkt),
+    }
+    let mut packets = Vec::new();
+    while packets.len() < 3 {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => {
+                if packets.is_empty() {
+                    assert_eq!(pkt.packet_id, packet_id);
+                }
+                packets.push((pkt.topic.to_string(), pkt.dup))
+            }
+            pkt => panic!("Unexpected packet {:?}", pkt),
+        }
+    }
+    assert_eq!(
+        packets,
+        vec![
+            ("inflight".to_string(), true),
+            ("offline1".to_string(), false),
+            ("offline2".to_string(), false)
+        ]
+    );
     Ok(())
 }